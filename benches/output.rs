@@ -0,0 +1,101 @@
+//! Benchmarks for serializing annotated sentences.
+//!
+//! The server is a binary crate, so the serialization modules are
+//! included directly.
+
+// The included modules are only partially used, and their tests are
+// compiled without the test harness.
+#![allow(dead_code, unused_imports)]
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::executor::block_on;
+use futures::io::{AsyncReadExt, Error};
+use futures::stream::{self, StreamExt};
+use udgraph::graph::Sentence;
+use udgraph::token::Token;
+
+#[path = "../src/async_conllu/mod.rs"]
+mod async_conllu;
+use async_conllu::SentenceStreamReader;
+
+#[path = "../src/format.rs"]
+mod format;
+
+#[path = "../src/async_syntaxdot/timing.rs"]
+mod timing;
+
+mod async_syntaxdot {
+    pub use super::timing::StageTimings;
+}
+
+/// Sentence counts of the benchmarked responses.
+const RESPONSE_SENTENCES: &[usize] = &[1_000, 4_000, 16_000];
+
+/// Batches of a response with `n_sentences` sentences of ten tokens.
+fn batches(n_sentences: usize, batch_size: usize) -> Vec<Vec<Sentence>> {
+    let sentence: Sentence = (0..10).map(|i| Token::new(format!("token{}", i))).collect();
+    (0..n_sentences)
+        .collect::<Vec<_>>()
+        .chunks(batch_size)
+        .map(|chunk| vec![sentence.clone(); chunk.len()])
+        .collect()
+}
+
+/// Read the response in reads of `read_size` bytes.
+fn read_response(batches: Vec<Vec<Sentence>>, buffer_size: usize, read_size: usize) -> usize {
+    let mut reader = SentenceStreamReader::new(stream::iter(batches).map(Ok::<_, Error>))
+        .buffer_size(buffer_size);
+    let mut buf = vec![0; read_size];
+    let mut len = 0;
+    loop {
+        match block_on(reader.read(&mut buf)).unwrap() {
+            0 => return len,
+            n => len += n,
+        }
+    }
+}
+
+/// Serialize batches of one sentence, one batch at a time or buffered.
+fn output_buffering(c: &mut Criterion) {
+    let mut group = c.benchmark_group("output_buffering");
+    for &buffer_size in &[0, 64 * 1024] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(buffer_size),
+            &buffer_size,
+            |b, &buffer_size| {
+                b.iter_batched(
+                    || batches(16_000, 1),
+                    |batches| read_response(batches, buffer_size, 8 * 1024),
+                    criterion::BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Read responses of increasing size in 7-byte reads.
+///
+/// The time per byte should not grow with the response size.
+fn small_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("small_reads");
+    for &n_sentences in RESPONSE_SENTENCES {
+        let len = read_response(batches(n_sentences, 32), 64 * 1024, 7);
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(n_sentences),
+            &n_sentences,
+            |b, &n_sentences| {
+                b.iter_batched(
+                    || batches(n_sentences, 32),
+                    |batches| read_response(batches, 64 * 1024, 7),
+                    criterion::BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, output_buffering, small_reads);
+criterion_main!(benches);
@@ -0,0 +1,78 @@
+//! Benchmarks for unicode cleanup of tokenized sentences.
+//!
+//! The server is a binary crate, so the cleanup modules are included
+//! directly.
+
+// The included modules are only partially used, and their tests are
+// compiled without the test harness.
+#![allow(dead_code, unused_imports)]
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::executor::block_on_stream;
+use futures::io::Error;
+use futures::stream::{self, StreamExt};
+use udgraph::graph::Sentence;
+use udgraph::token::Token;
+
+#[path = "../src/runtime.rs"]
+mod runtime;
+
+#[path = "../src/async_syntaxdot/unicode.rs"]
+mod unicode;
+use unicode::Normalization;
+
+#[path = "../src/async_syntaxdot/unicode_cleanup.rs"]
+mod unicode_cleanup;
+use unicode_cleanup::ToUnicodeCleanup;
+
+/// Number of sentences that are cleaned up per iteration.
+const N_SENTENCES: usize = 4_096;
+
+/// Sentence lengths in tokens.
+const SENTENCE_LENGTHS: &[usize] = &[10, 100];
+
+/// Sentences of `len` tokens, some of which have to be cleaned up.
+fn sentences(len: usize) -> Vec<Sentence> {
+    let sentence: Sentence = (0..len)
+        .map(|i| match i % 4 {
+            0 => Token::new("«citaat»"),
+            _ => Token::new(format!("token{}", i)),
+        })
+        .collect();
+    vec![sentence; N_SENTENCES]
+}
+
+fn cleanup(sentences: Vec<Sentence>, offload: bool) -> usize {
+    block_on_stream(
+        stream::iter(sentences)
+            .map(Ok::<_, Error>)
+            .unicode_cleanup(Normalization::Nfc)
+            .offload(offload),
+    )
+    .count()
+}
+
+/// Clean up sentences on the polling thread or in spawned tasks.
+fn offload(c: &mut Criterion) {
+    let mut group = c.benchmark_group("unicode_cleanup");
+    group.throughput(Throughput::Elements(N_SENTENCES as u64));
+    for &len in SENTENCE_LENGTHS {
+        for &offload in &[false, true] {
+            group.bench_with_input(
+                BenchmarkId::new(if offload { "offload" } else { "inline" }, len),
+                &len,
+                |b, &len| {
+                    b.iter_batched(
+                        || sentences(len),
+                        |sentences| cleanup(sentences, offload),
+                        criterion::BatchSize::LargeInput,
+                    )
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, offload);
+criterion_main!(benches);
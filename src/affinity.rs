@@ -0,0 +1,104 @@
+//! Pinning of annotation threads to CPU cores.
+//!
+//! On machines with multiple NUMA nodes, memory that is accessed by a
+//! thread on another node is slower than local memory. Pinning the
+//! annotation threads to the cores of the node that holds the models
+//! avoids such cross-node accesses.
+//!
+//! Pinning is experimental and requires the `thread-affinity` feature.
+//! It has not been benchmarked yet, whether it improves throughput
+//! depends on the machine and the models. It should be measured by
+//! comparing runs with and without pinning before it is enabled.
+
+/// Select the core of annotation thread `index`.
+///
+/// Threads are assigned round-robin to the cores in `cores` that are
+/// available, or to all available cores when `cores` is empty.
+#[cfg_attr(not(feature = "thread-affinity"), allow(dead_code))]
+fn thread_core(index: usize, available: &[usize], cores: &[usize]) -> Option<usize> {
+    let candidates = if cores.is_empty() {
+        available.to_vec()
+    } else {
+        cores
+            .iter()
+            .copied()
+            .filter(|core| available.contains(core))
+            .collect()
+    };
+
+    if candidates.is_empty() {
+        None
+    } else {
+        Some(candidates[index % candidates.len()])
+    }
+}
+
+/// Whether this build supports pinning threads to cores.
+pub const SUPPORTED: bool = cfg!(feature = "thread-affinity");
+
+#[cfg(feature = "thread-affinity")]
+pub use self::core_affinity_pinning::pin_current_thread;
+
+#[cfg(feature = "thread-affinity")]
+mod core_affinity_pinning {
+    use core_affinity::CoreId;
+
+    use super::thread_core;
+
+    /// Pin the current thread, annotation thread `index`, to a core.
+    ///
+    /// This is a no-op on platforms where the cores cannot be queried.
+    pub fn pin_current_thread(index: usize, cores: &[usize]) {
+        let available = match core_affinity::get_core_ids() {
+            Some(core_ids) => core_ids.into_iter().map(|core| core.id).collect::<Vec<_>>(),
+            None => {
+                log::warn!("Cannot pin annotation thread {}, cores are unknown", index);
+                return;
+            }
+        };
+
+        match thread_core(index, &available, cores) {
+            Some(id) => {
+                core_affinity::set_for_current(CoreId { id });
+                log::debug!("Pinned annotation thread {} to core {}", index, id);
+            }
+            None => log::warn!(
+                "Cannot pin annotation thread {}, none of the configured cores is available",
+                index
+            ),
+        }
+    }
+}
+
+#[cfg(not(feature = "thread-affinity"))]
+pub use self::unsupported::pin_current_thread;
+
+#[cfg(not(feature = "thread-affinity"))]
+mod unsupported {
+    /// Placeholder for pinning threads, which requires the
+    /// `thread-affinity` feature.
+    pub fn pin_current_thread(_index: usize, _cores: &[usize]) {
+        unreachable!("Threads cannot be pinned without the `thread-affinity` feature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::thread_core;
+
+    #[test]
+    fn threads_are_assigned_round_robin() {
+        let available = [0, 1, 2, 3];
+        assert_eq!(thread_core(0, &available, &[2, 3]), Some(2));
+        assert_eq!(thread_core(1, &available, &[2, 3]), Some(3));
+        assert_eq!(thread_core(2, &available, &[2, 3]), Some(2));
+        assert_eq!(thread_core(5, &available, &[]), Some(1));
+    }
+
+    #[test]
+    fn unavailable_cores_are_skipped() {
+        let available = [0, 1];
+        assert_eq!(thread_core(1, &available, &[1, 8]), Some(1));
+        assert_eq!(thread_core(0, &available, &[8]), None);
+    }
+}
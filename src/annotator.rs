@@ -1,20 +1,36 @@
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
+use std::hash::Hash;
 use std::io::BufReader;
+use std::iter;
 use std::ops::Deref;
 use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
-use rayon::prelude::{ParallelIterator, ParallelSliceMut};
+use anyhow::{anyhow, Context, Result};
+use futures::channel::oneshot;
+use futures::future;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
 use syntaxdot::config::{BiaffineParserConfig, Config, PretrainConfig, TomlRead};
 use syntaxdot::encoders::Encoders;
 use syntaxdot::model::bert::BertModel;
 use syntaxdot::tagger::Tagger;
 use syntaxdot_encoders::dependency::ImmutableDependencyEncoder;
+use syntaxdot_encoders::{EncodingProb, SentenceDecoder};
 use syntaxdot_tch_ext::RootExt;
 use syntaxdot_tokenizers::{SentenceWithPieces, Tokenize};
 use tch::nn::VarStore;
 use tch::Device;
-use udgraph::graph::Sentence;
+use udgraph::graph::{Node, Sentence};
+use udgraph::token::Token;
+
+use crate::async_syntaxdot::Layer;
+use crate::histogram::PieceLengthHistogram;
+use crate::priority::AnnotationQueue;
+use crate::runtime::Delay;
 
 /// A wrapper of `Tagger` that is `Send + Sync`.
 ///
@@ -37,19 +53,192 @@ impl Deref for TaggerWrap {
     }
 }
 
+/// Handling of sentences that exceed the maximum length.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizedSentences {
+    /// Leave out the sentence.
+    #[default]
+    Skip,
+
+    /// Annotate the tokens that fit in the maximum length.
+    Truncate,
+
+    /// Fail the annotation.
+    Error,
+}
+
+/// Floating point precision of model parameters.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    /// 32-bit floating point.
+    #[default]
+    Fp32,
+
+    /// 16-bit floating point, requires a CUDA device.
+    Fp16,
+
+    /// 16-bit brain floating point.
+    Bf16,
+}
+
+impl Precision {
+    /// Check that the precision can be used on a device.
+    ///
+    /// Only fp32 can be used for now. tch 0.4 cannot change the kind of
+    /// the parameters of a constructed model, so the parameters cannot
+    /// be converted to fp16 or bf16 after loading.
+    pub fn check_device(self, device: Device) -> Result<()> {
+        match (self, device) {
+            (Precision::Fp32, _) => Ok(()),
+            (Precision::Fp16, Device::Cpu) => Err(anyhow!(
+                "fp16 precision is not supported on the CPU, use fp32"
+            )),
+            (Precision::Fp16, _) | (Precision::Bf16, _) => Err(anyhow!(
+                "Model parameters cannot be converted to {:?} precision, use fp32",
+                self
+            )),
+        }
+    }
+}
+
+/// Handling of sentences that are shorter than the minimum length.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UndersizedSentences {
+    /// Leave out the sentence.
+    #[default]
+    Skip,
+
+    /// Fail the annotation.
+    Error,
+}
+
+/// Unit of a sentence length.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LengthUnit {
+    /// Word pieces.
+    #[default]
+    Pieces,
+
+    /// Tokens.
+    Tokens,
+}
+
+impl LengthUnit {
+    /// Length of a sentence in this unit.
+    fn len(self, sentence: &SentenceWithPieces) -> usize {
+        match self {
+            LengthUnit::Pieces => sentence.pieces.len(),
+            LengthUnit::Tokens => sentence.token_offsets.len(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            LengthUnit::Pieces => "pieces",
+            LengthUnit::Tokens => "tokens",
+        }
+    }
+}
+
+/// Annotation of tokenized sentences.
+///
+/// This is implemented by [`Annotator`], but can also be implemented
+/// by other annotators to construct pipelines without model files.
+pub trait Annotate {
+    /// Split the tokens of a sentence into the model's word pieces.
+    fn pieces(&self, sentence: Sentence) -> SentenceWithPieces;
+
+    /// Annotate sentences.
+    ///
+    /// The returned sentences are in the same order as the sentences
+    /// provided through the `sentences` argument. If `dedup` is `true`,
+    /// identical sentences are only annotated once.
+    fn annotate_sentences(
+        &self,
+        sentences: &[Sentence],
+        batch_size: usize,
+        dedup: bool,
+    ) -> Result<Vec<SentenceWithPieces>>;
+
+    /// Label set of an annotation layer.
+    ///
+    /// Returns `None` when the labels of the layer are not known.
+    fn labels(&self, _layer: Layer) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Name of the device that the annotator runs on, e.g. `cuda:0`.
+    ///
+    /// Returns `None` when the annotator does not run on a Torch device.
+    fn device(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Name of a Torch device, e.g. `cpu` or `cuda:0`.
+fn device_name(device: Device) -> String {
+    match device {
+        Device::Cpu => "cpu".to_string(),
+        Device::Cuda(idx) => format!("cuda:{}", idx),
+    }
+}
+
+/// Parse the name of a Torch device, e.g. `cpu` or `cuda:0`.
+///
+/// `cuda` is the first CUDA device. Fails when a CUDA device is
+/// requested, but CUDA is not available.
+pub fn parse_device(name: &str) -> Result<Device> {
+    let device = match name {
+        "cpu" => return Ok(Device::Cpu),
+        "cuda" => Device::Cuda(0),
+        _ => match name.strip_prefix("cuda:").map(str::parse) {
+            Some(Ok(idx)) => Device::Cuda(idx),
+            _ => return Err(anyhow!("Unknown device `{}`, use cpu or cuda:N", name)),
+        },
+    };
+
+    if !tch::Cuda::is_available() {
+        return Err(anyhow!(
+            "Cannot use device `{}`: CUDA is not available",
+            name
+        ));
+    }
+
+    Ok(device)
+}
+
 /// An annotator.
 pub struct Annotator {
+    device: Device,
+    histogram: Option<PieceLengthHistogram>,
+    labels: Vec<(Layer, Vec<String>)>,
     max_len: Option<usize>,
+    max_pieces_per_batch: Option<usize>,
+    min_len: Option<(usize, LengthUnit)>,
+    oversized_sentences: OversizedSentences,
     tagger: TaggerWrap,
     tokenizer: Box<dyn Tokenize>,
+    undersized_sentences: UndersizedSentences,
 }
 
 impl Annotator {
     /// Load an annotator onto the given device.
-    pub fn load<P>(device: Device, config_path: P, max_len: Option<usize>) -> Result<Self>
+    pub fn load<P>(
+        device: Device,
+        config_path: P,
+        max_len: Option<usize>,
+        oversized_sentences: OversizedSentences,
+        precision: Precision,
+    ) -> Result<Self>
     where
         P: AsRef<Path>,
     {
+        precision.check_device(device)?;
+
         let r = BufReader::new(File::open(&config_path)?);
         let mut config = Config::from_toml_read(r)?;
         config.relativize_paths(config_path)?;
@@ -57,10 +246,11 @@ impl Annotator {
         let biaffine_decoder = config
             .biaffine
             .as_ref()
-            .map(|config| load_biaffine_decoder(config))
+            .map(load_biaffine_decoder)
             .transpose()?;
 
         let encoders = load_encoders(&config)?;
+        let labels = encoder_labels(&encoders);
         let tokenizer = load_tokenizer(&config)?;
         let pretrain_config = load_pretrain_config(&config)?;
 
@@ -89,34 +279,152 @@ impl Annotator {
         let tagger = Tagger::new(device, model, biaffine_decoder, encoders);
 
         Ok(Annotator {
+            device,
+            histogram: None,
+            labels,
             max_len,
+            max_pieces_per_batch: None,
+            min_len: None,
+            oversized_sentences,
             tagger: TaggerWrap(tagger),
             tokenizer,
+            undersized_sentences: UndersizedSentences::default(),
         })
     }
 
+    /// Limit the total number of pieces in a batch.
+    ///
+    /// This bounds the memory use of a batch, regardless of the batch
+    /// size. Sentences are sorted by length before batching, so that
+    /// batches of short sentences still use the full batch size.
+    pub fn max_pieces_per_batch(mut self, max_pieces_per_batch: Option<usize>) -> Self {
+        self.max_pieces_per_batch = max_pieces_per_batch;
+        self
+    }
+
+    /// Set the minimum sentence length.
+    ///
+    /// Sentences that are shorter are handled as configured through
+    /// [`UndersizedSentences`].
+    pub fn min_len(
+        mut self,
+        min_len: Option<usize>,
+        unit: LengthUnit,
+        undersized_sentences: UndersizedSentences,
+    ) -> Self {
+        self.min_len = min_len.map(|min_len| (min_len, unit));
+        self.undersized_sentences = undersized_sentences;
+        self
+    }
+
+    /// Periodically log a histogram of sentence lengths in pieces.
+    pub fn log_piece_lengths(mut self, name: Option<&str>) -> Self {
+        self.histogram = name.map(PieceLengthHistogram::new);
+        self
+    }
+}
+
+impl Annotate for Annotator {
+    fn pieces(&self, sentence: Sentence) -> SentenceWithPieces {
+        self.tokenizer.tokenize(sentence)
+    }
+
+    fn device(&self) -> Option<String> {
+        Some(device_name(self.device))
+    }
+
+    fn labels(&self, layer: Layer) -> Option<Vec<String>> {
+        self.labels
+            .iter()
+            .find(|(labels_layer, _)| *labels_layer == layer)
+            .map(|(_, labels)| labels.clone())
+    }
+
     /// Annotate sentences.
     ///
     /// The sentences are sorted by length in pieces, batched and then annotated. The
     /// returned sentences are in the same order as the sentences provided through the
-    /// `sentences` argument.
-    pub fn annotate_sentences(
+    /// `sentences` argument. Sentences that exceed the maximum length are handled
+    /// as configured through [`OversizedSentences`], sentences that are shorter
+    /// than the minimum length as configured through [`UndersizedSentences`].
+    ///
+    /// If `dedup` is `true`, identical sentences are only annotated once.
+    fn annotate_sentences(
         &self,
         sentences: &[Sentence],
         batch_size: usize,
-    ) -> Result<Vec<SentenceWithPieces>> where {
-        let mut sentences_with_pieces = sentences
+        dedup: bool,
+    ) -> Result<Vec<SentenceWithPieces>> {
+        let annotated = if dedup {
+            map_unique(
+                sentences,
+                |sentence| {
+                    sentence
+                        .iter()
+                        .filter_map(Node::token)
+                        .map(|token| token.form())
+                        .collect::<Vec<_>>()
+                },
+                |a, b| {
+                    a.iter()
+                        .filter_map(Node::token)
+                        .eq(b.iter().filter_map(Node::token))
+                },
+                |unique| self.annotate_sentence_refs(unique, batch_size),
+                |annotated, sentence| {
+                    annotated.as_ref().map(|annotated| {
+                        // Duplicates only share tokens, keep their own comments.
+                        let mut copy = annotated.sentence.clone();
+                        *copy.comments_mut() = sentence.comments().to_owned();
+                        SentenceWithPieces {
+                            pieces: annotated.pieces.clone(),
+                            sentence: copy,
+                            token_offsets: annotated.token_offsets.clone(),
+                        }
+                    })
+                },
+            )?
+        } else {
+            self.annotate_sentence_refs(&sentences.iter().collect::<Vec<_>>(), batch_size)?
+        };
+
+        Ok(annotated.into_iter().flatten().collect())
+    }
+}
+
+impl Annotator {
+    /// Annotate sentences, returning `None` for skipped sentences.
+    fn annotate_sentence_refs(
+        &self,
+        sentences: &[&Sentence],
+        batch_size: usize,
+    ) -> Result<Vec<Option<SentenceWithPieces>>> {
+        let sentences_with_pieces = sentences
             .iter()
-            .map(|s| self.tokenizer.tokenize(s.clone()))
-            .filter(|s| {
-                self.max_len
-                    .map(|max_len| s.pieces.len() <= max_len)
-                    .unwrap_or(true)
-            })
+            .map(|&s| self.tokenizer.tokenize(s.clone()))
             .collect::<Vec<_>>();
 
+        // Record lengths before limiting, to include oversized sentences.
+        if let Some(histogram) = &self.histogram {
+            histogram.record(sentences_with_pieces.iter().map(|s| s.pieces.len()));
+        }
+        let mut sentences_with_pieces = match self.max_len {
+            Some(max_len) => {
+                limit_length(sentences_with_pieces, max_len, self.oversized_sentences)?
+            }
+            None => sentences_with_pieces.into_iter().map(Some).collect(),
+        };
+        if let Some((min_len, unit)) = self.min_len {
+            sentences_with_pieces = require_min_length(
+                sentences_with_pieces,
+                min_len,
+                unit,
+                self.undersized_sentences,
+            )?;
+        }
+
         // Sort sentences by length.
-        let mut sent_refs: Vec<_> = sentences_with_pieces.iter_mut().collect();
+        let mut sent_refs: Vec<_> = sentences_with_pieces.iter_mut().flatten().collect();
         sent_refs.sort_unstable_by_key(|s| s.pieces.len());
 
         // Convince the type system that we are not borrowing Annotator, which is
@@ -124,14 +432,506 @@ impl Annotator {
         let tagger = &self.tagger;
 
         // Split in batches, tag, and merge results.
-        sent_refs
-            .par_chunks_mut(batch_size)
+        let lengths = sent_refs.iter().map(|s| s.pieces.len()).collect::<Vec<_>>();
+        let mut batches = Vec::new();
+        let mut rest = &mut sent_refs[..];
+        for len in batch_lengths(&lengths, batch_size, self.max_pieces_per_batch) {
+            let (batch, tail) = std::mem::take(&mut rest).split_at_mut(len);
+            batches.push(batch);
+            rest = tail;
+        }
+        batches
+            .into_par_iter()
             .try_for_each(|batch| tagger.tag_sentences(batch))?;
 
         Ok(sentences_with_pieces)
     }
 }
 
+/// Apply `f` to the unique items, copying the results to duplicate items.
+///
+/// Items with the same key are compared using `same`, items that are not
+/// the same are treated as unique. `f` must return one result per unique
+/// item. The result for an item is made by `copy` from the result of its
+/// unique item. The results are returned in the order of `items`.
+fn map_unique<'a, T, K, U>(
+    items: &'a [T],
+    key: impl Fn(&'a T) -> K,
+    same: impl Fn(&T, &T) -> bool,
+    f: impl FnOnce(&[&'a T]) -> Result<Vec<U>>,
+    copy: impl Fn(&U, &T) -> U,
+) -> Result<Vec<U>>
+where
+    K: Eq + Hash,
+{
+    let mut unique: Vec<&T> = Vec::new();
+    let mut candidates: HashMap<K, Vec<usize>> = HashMap::new();
+    let mut positions = Vec::with_capacity(items.len());
+
+    for item in items {
+        let bucket = candidates.entry(key(item)).or_default();
+        let idx = match bucket.iter().copied().find(|&idx| same(unique[idx], item)) {
+            Some(idx) => idx,
+            None => {
+                unique.push(item);
+                bucket.push(unique.len() - 1);
+                unique.len() - 1
+            }
+        };
+        positions.push(idx);
+    }
+
+    let results = f(&unique)?;
+    if results.len() != unique.len() {
+        return Err(anyhow!(
+            "Expected {} results for unique items, got {}",
+            unique.len(),
+            results.len()
+        ));
+    }
+
+    Ok(positions
+        .into_iter()
+        .zip(items)
+        .map(|(idx, item)| copy(&results[idx], item))
+        .collect())
+}
+
+/// Lengths of consecutive batches of sentences with the given lengths.
+///
+/// Batches have at most `batch_size` sentences. If `max_pieces` is set,
+/// the total number of pieces in a batch does not exceed it, except for
+/// batches of a single sentence that exceeds it by itself.
+fn batch_lengths(lengths: &[usize], batch_size: usize, max_pieces: Option<usize>) -> Vec<usize> {
+    let mut batch_lengths = Vec::new();
+    let mut batch_len = 0;
+    let mut batch_pieces = 0;
+
+    for &len in lengths {
+        let exceeds_pieces = max_pieces
+            .map(|max_pieces| batch_pieces + len > max_pieces)
+            .unwrap_or(false);
+        if batch_len > 0 && (batch_len == batch_size || exceeds_pieces) {
+            batch_lengths.push(batch_len);
+            batch_len = 0;
+            batch_pieces = 0;
+        }
+
+        batch_len += 1;
+        batch_pieces += len;
+    }
+
+    if batch_len > 0 {
+        batch_lengths.push(batch_len);
+    }
+
+    batch_lengths
+}
+
+/// Apply the maximum length to sentences.
+///
+/// Returns `None` for sentences that are skipped.
+fn limit_length(
+    sentences: Vec<SentenceWithPieces>,
+    max_len: usize,
+    oversized_sentences: OversizedSentences,
+) -> Result<Vec<Option<SentenceWithPieces>>> {
+    let mut limited = Vec::with_capacity(sentences.len());
+
+    for sentence in sentences {
+        if sentence.pieces.len() <= max_len {
+            limited.push(Some(sentence));
+            continue;
+        }
+
+        match oversized_sentences {
+            OversizedSentences::Skip => limited.push(None),
+            OversizedSentences::Truncate => {
+                limited.push(Some(truncate_sentence(sentence, max_len)))
+            }
+            OversizedSentences::Error => {
+                return Err(anyhow!(
+                    "Sentence of {} pieces exceeds the maximum length of {} pieces",
+                    sentence.pieces.len(),
+                    max_len
+                ))
+            }
+        }
+    }
+
+    Ok(limited)
+}
+
+/// Apply the minimum length to sentences.
+///
+/// Returns `None` for sentences that are skipped.
+fn require_min_length(
+    sentences: Vec<Option<SentenceWithPieces>>,
+    min_len: usize,
+    unit: LengthUnit,
+    undersized_sentences: UndersizedSentences,
+) -> Result<Vec<Option<SentenceWithPieces>>> {
+    sentences
+        .into_iter()
+        .map(|sentence| match sentence {
+            Some(sentence) if unit.len(&sentence) < min_len => match undersized_sentences {
+                UndersizedSentences::Skip => Ok(None),
+                UndersizedSentences::Error => Err(anyhow!(
+                    "Sentence of {} {} is shorter than the minimum length of {} {}",
+                    unit.len(&sentence),
+                    unit.name(),
+                    min_len,
+                    unit.name()
+                )),
+            },
+            sentence => Ok(sentence),
+        })
+        .collect()
+}
+
+/// Truncate a sentence to its first `max_len` pieces.
+///
+/// Only the tokens that start within the first `max_len` pieces are retained.
+fn truncate_sentence(sentence: SentenceWithPieces, max_len: usize) -> SentenceWithPieces {
+    let token_offsets = sentence
+        .token_offsets
+        .into_iter()
+        .take_while(|&offset| offset < max_len)
+        .collect::<Vec<_>>();
+
+    let mut truncated = sentence
+        .sentence
+        .iter()
+        .filter_map(Node::token)
+        .take(token_offsets.len())
+        .cloned()
+        .collect::<Sentence>();
+    *truncated.comments_mut() = sentence.sentence.comments().to_vec();
+
+    SentenceWithPieces {
+        pieces: sentence.pieces.iter().take(max_len).cloned().collect(),
+        sentence: truncated,
+        token_offsets,
+    }
+}
+
+/// Load status of an annotator.
+#[derive(Clone)]
+pub enum AnnotatorStatus {
+    /// The annotator is still loading.
+    Loading,
+
+    /// The annotator is loaded.
+    Ready(Arc<dyn Annotate + Send + Sync>),
+
+    /// The annotator could not be loaded.
+    Failed(String),
+
+    /// The annotator is loaded on demand and not loaded yet, or was
+    /// unloaded.
+    Unloaded,
+}
+
+/// Function that loads an annotator on demand.
+pub type AnnotatorLoader = Box<dyn Fn() -> Result<Arc<dyn Annotate + Send + Sync>> + Send + Sync>;
+
+/// Loading of an annotator on demand.
+struct LazyLoad {
+    loader: AnnotatorLoader,
+    name: String,
+    resident: Arc<ResidentAnnotators>,
+}
+
+/// Cell holding an annotator that may still be loading.
+pub struct AnnotatorCell {
+    lazy: Option<LazyLoad>,
+    queue: Option<Arc<AnnotationQueue>>,
+    status: RwLock<AnnotatorStatus>,
+    waiters: Mutex<Vec<oneshot::Sender<()>>>,
+}
+
+impl AnnotatorCell {
+    /// Construct a cell for an annotator that is still loading.
+    pub fn loading() -> Self {
+        AnnotatorCell {
+            lazy: None,
+            queue: None,
+            status: RwLock::new(AnnotatorStatus::Loading),
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Construct a cell for an annotator that is loaded on first use.
+    ///
+    /// The annotator counts towards the resident annotators once it is
+    /// loaded and is unloaded again when it is the least recently used
+    /// annotator.
+    pub fn lazy(
+        name: impl Into<String>,
+        loader: AnnotatorLoader,
+        resident: Arc<ResidentAnnotators>,
+    ) -> Self {
+        AnnotatorCell {
+            lazy: Some(LazyLoad {
+                loader,
+                name: name.into(),
+                resident,
+            }),
+            queue: None,
+            status: RwLock::new(AnnotatorStatus::Unloaded),
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Annotate at most `max_concurrent` chunks at a time.
+    ///
+    /// Chunks that wait for the annotator are annotated in the order of
+    /// their priority. With `None`, chunks are annotated as they
+    /// arrive, regardless of their priority.
+    pub fn max_concurrent_chunks(mut self, max_concurrent: Option<usize>) -> Self {
+        self.queue =
+            max_concurrent.map(|max_concurrent| Arc::new(AnnotationQueue::new(max_concurrent)));
+        self
+    }
+
+    /// Queue of chunks that wait for the annotator, if any.
+    pub fn queue(&self) -> Option<Arc<AnnotationQueue>> {
+        self.queue.clone()
+    }
+
+    /// Get the annotator, failing when it is not loaded (yet).
+    ///
+    /// If the annotator is loaded on demand and not loaded, or failed to
+    /// load, loading is started in the background.
+    pub fn get(self: &Arc<Self>) -> Result<Arc<dyn Annotate + Send + Sync>> {
+        match self.status() {
+            AnnotatorStatus::Loading => Err(anyhow!("Annotator is still loading")),
+            AnnotatorStatus::Ready(annotator) => {
+                if let Some(lazy) = &self.lazy {
+                    lazy.resident.touch(&lazy.name);
+                }
+                Ok(annotator)
+            }
+            AnnotatorStatus::Failed(err) => {
+                self.load_lazy()?;
+                Err(anyhow!("Annotator could not be loaded: {}", err))
+            }
+            AnnotatorStatus::Unloaded => {
+                self.load_lazy()?;
+                Err(anyhow!("Annotator is still loading"))
+            }
+        }
+    }
+
+    /// Wait until an annotator that is loaded on demand is loaded.
+    ///
+    /// Loading is started when the annotator is not loaded or failed to
+    /// load. Returns when loading is done, successful or not, or after
+    /// `timeout`. Returns immediately for other annotators.
+    pub async fn wait_loaded(self: &Arc<Self>, timeout: Option<Duration>) {
+        if self.lazy.is_none() {
+            return;
+        }
+
+        if matches!(
+            self.status(),
+            AnnotatorStatus::Unloaded | AnnotatorStatus::Failed(_)
+        ) && self.load_lazy().is_err()
+        {
+            return;
+        }
+
+        let loaded = {
+            let mut waiters = self.waiters.lock().expect("Waiters lock is poisoned");
+            // The status is checked while holding the lock, so that the
+            // loader cannot finish between the check and registration.
+            if !matches!(self.status(), AnnotatorStatus::Loading) {
+                return;
+            }
+            let (tx, rx) = oneshot::channel();
+            waiters.push(tx);
+            rx
+        };
+
+        match timeout {
+            Some(timeout) => {
+                future::select(loaded, Delay::new(timeout)).await;
+            }
+            None => {
+                let _ = loaded.await;
+            }
+        }
+    }
+
+    /// Start loading an annotator that is loaded on demand.
+    ///
+    /// Fails when no slot can be reserved for the annotator, because the
+    /// maximum number of annotators is still loading.
+    fn load_lazy(self: &Arc<Self>) -> Result<()> {
+        let lazy = match &self.lazy {
+            Some(lazy) => lazy,
+            None => return Ok(()),
+        };
+
+        if !lazy.resident.reserve(&lazy.name, self)? {
+            // Another request started loading in the meanwhile.
+            return Ok(());
+        }
+
+        log::info!("Loading annotator `{}` on demand", lazy.name);
+
+        let cell = self.clone();
+        thread::spawn(move || {
+            let lazy = cell.lazy.as_ref().expect("Lazy annotator without loader");
+            let annotator = (lazy.loader)();
+
+            let loaded = annotator.is_ok();
+            *cell.status.write().expect("Annotator lock is poisoned") = match annotator {
+                Ok(annotator) => AnnotatorStatus::Ready(annotator),
+                Err(err) => {
+                    log::error!("Cannot load annotator `{}`: {:#}", lazy.name, err);
+                    AnnotatorStatus::Failed(format!("{:#}", err))
+                }
+            };
+
+            // Failed annotators do not take a slot, loading is retried
+            // on the next use.
+            if !loaded {
+                lazy.resident.release(&lazy.name);
+            }
+
+            for waiter in cell
+                .waiters
+                .lock()
+                .expect("Waiters lock is poisoned")
+                .drain(..)
+            {
+                let _ = waiter.send(());
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Unload the annotator, it is loaded again on the next use.
+    ///
+    /// The annotator is dropped when requests that use it are done.
+    fn unload(&self) {
+        *self.status.write().expect("Annotator lock is poisoned") = AnnotatorStatus::Unloaded;
+    }
+
+    /// Store the result of loading the annotator.
+    pub fn set<A>(&self, annotator: Result<A>)
+    where
+        A: Annotate + Send + Sync + 'static,
+    {
+        *self.status.write().expect("Annotator lock is poisoned") = match annotator {
+            Ok(annotator) => AnnotatorStatus::Ready(Arc::new(annotator)),
+            Err(err) => AnnotatorStatus::Failed(format!("{:#}", err)),
+        };
+    }
+
+    /// Get the load status.
+    pub fn status(&self) -> AnnotatorStatus {
+        self.status
+            .read()
+            .expect("Annotator lock is poisoned")
+            .clone()
+    }
+}
+
+/// Bound on the number of annotators that are loaded on demand.
+///
+/// When an annotator is loaded while the bound is reached, the least
+/// recently used annotator is unloaded. Annotators take a slot from the
+/// start of loading, so concurrent loads do not exceed the bound.
+pub struct ResidentAnnotators {
+    loaded: Mutex<VecDeque<(String, Weak<AnnotatorCell>)>>,
+    max_loaded: usize,
+}
+
+impl ResidentAnnotators {
+    pub fn new(max_loaded: usize) -> Self {
+        ResidentAnnotators {
+            loaded: Mutex::new(VecDeque::new()),
+            max_loaded,
+        }
+    }
+
+    /// Reserve a slot for an annotator and mark it as loading.
+    ///
+    /// The least recently used annotators are unloaded to free a slot.
+    /// Annotators that are still loading cannot be unloaded, reserving
+    /// fails when they take all slots. Returns `false` without reserving
+    /// when the annotator is already loading or loaded.
+    fn reserve(&self, name: &str, cell: &Arc<AnnotatorCell>) -> Result<bool> {
+        // The resident annotators lock is always taken before the status
+        // locks of annotators.
+        let mut loaded = self
+            .loaded
+            .lock()
+            .expect("Resident annotators lock is poisoned");
+        let mut status = cell.status.write().expect("Annotator lock is poisoned");
+        if !matches!(
+            *status,
+            AnnotatorStatus::Unloaded | AnnotatorStatus::Failed(_)
+        ) {
+            return Ok(false);
+        }
+
+        loaded.retain(|(loaded_name, _)| loaded_name != name);
+        while loaded.len() >= self.max_loaded {
+            let evictable = loaded.iter().position(|(_, loaded_cell)| {
+                loaded_cell.upgrade().is_none_or(|loaded_cell| {
+                    !matches!(loaded_cell.status(), AnnotatorStatus::Loading)
+                })
+            });
+            let idx = evictable.ok_or_else(|| {
+                anyhow!(
+                    "Cannot load annotator `{}`, {} annotators are already loading",
+                    name,
+                    loaded.len()
+                )
+            })?;
+
+            let (evicted_name, evicted) = loaded.remove(idx).expect("No loaded annotators");
+            if let Some(evicted) = evicted.upgrade() {
+                log::info!("Unloading least recently used annotator `{}`", evicted_name);
+                evicted.unload();
+            }
+        }
+
+        loaded.push_back((name.to_string(), Arc::downgrade(cell)));
+        *status = AnnotatorStatus::Loading;
+
+        Ok(true)
+    }
+
+    /// Release the slot of an annotator.
+    fn release(&self, name: &str) {
+        self.loaded
+            .lock()
+            .expect("Resident annotators lock is poisoned")
+            .retain(|(loaded_name, _)| loaded_name != name);
+    }
+
+    /// Mark an annotator as most recently used.
+    fn touch(&self, name: &str) {
+        let mut loaded = self
+            .loaded
+            .lock()
+            .expect("Resident annotators lock is poisoned");
+        if let Some(idx) = loaded
+            .iter()
+            .position(|(loaded_name, _)| loaded_name == name)
+        {
+            if let Some(entry) = loaded.remove(idx) {
+                loaded.push_back(entry);
+            }
+        }
+    }
+}
+
 pub fn load_pretrain_config(config: &Config) -> Result<PretrainConfig> {
     config
         .model
@@ -163,7 +963,7 @@ fn load_encoders(config: &Config) -> Result<Encoders> {
         config.labeler.labels
     ))?;
 
-    for encoder in &*encoders {
+    for encoder in encoders.iter() {
         log::info!(
             "Loaded labels for encoder '{}': {} labels",
             encoder.name(),
@@ -174,8 +974,327 @@ fn load_encoders(config: &Config) -> Result<Encoders> {
     Ok(encoders)
 }
 
+/// Label sets of the part-of-speech layers of the encoders.
+///
+/// Every label of an encoder is decoded onto a placeholder token, the
+/// layers that the label sets on the token determine the layer of the
+/// encoder.
+fn encoder_labels(encoders: &Encoders) -> Vec<(Layer, Vec<String>)> {
+    let mut labels: Vec<(Layer, Vec<String>)> = Vec::new();
+
+    for encoder in encoders.iter() {
+        for label in 0..encoder.encoder().len() {
+            let mut sentence: Sentence = iter::once(Token::new("_")).collect();
+            if encoder
+                .encoder()
+                .decode(&[[EncodingProb::new(label, 1.0)]], &mut sentence)
+                .is_err()
+            {
+                continue;
+            }
+
+            let token = match sentence.iter().find_map(Node::token) {
+                Some(token) => token,
+                None => continue,
+            };
+
+            for (layer, value) in [(Layer::Upos, token.upos()), (Layer::Xpos, token.xpos())]
+                .iter()
+                .filter_map(|&(layer, value)| value.map(|value| (layer, value)))
+            {
+                match labels
+                    .iter_mut()
+                    .find(|(labels_layer, _)| *labels_layer == layer)
+                {
+                    Some((_, layer_labels)) => layer_labels.push(value.to_string()),
+                    None => labels.push((layer, vec![value.to_string()])),
+                }
+            }
+        }
+    }
+
+    for (_, layer_labels) in &mut labels {
+        layer_labels.sort();
+        layer_labels.dedup();
+    }
+
+    labels
+}
+
 pub fn load_tokenizer(config: &Config) -> Result<Box<dyn Tokenize>> {
     config
         .tokenizer()
         .context("Cannot read tokenizer vocabulary")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use futures::executor::block_on;
+    use syntaxdot_tokenizers::SentenceWithPieces;
+    use tch::Device;
+    use udgraph::graph::{Node, Sentence};
+    use udgraph::token::Token;
+
+    use super::{
+        batch_lengths, device_name, limit_length, map_unique, parse_device, require_min_length,
+        Annotate, AnnotatorCell, AnnotatorLoader, AnnotatorStatus, LengthUnit, OversizedSentences,
+        Precision, ResidentAnnotators, UndersizedSentences,
+    };
+    use crate::test_annotator::TestAnnotator;
+
+    fn lazy_cell_with(
+        name: &str,
+        resident: &Arc<ResidentAnnotators>,
+        loader: AnnotatorLoader,
+    ) -> Arc<AnnotatorCell> {
+        Arc::new(AnnotatorCell::lazy(name, loader, resident.clone()))
+    }
+
+    /// Lazy annotator cell that is loaded.
+    fn lazy_cell(name: &str, resident: &Arc<ResidentAnnotators>) -> Arc<AnnotatorCell> {
+        let cell = lazy_cell_with(
+            name,
+            resident,
+            Box::new(|| Ok(Arc::new(TestAnnotator::default()) as Arc<dyn Annotate + Send + Sync>)),
+        );
+        block_on(cell.wait_loaded(None));
+        cell
+    }
+
+    fn sentence_with_pieces(forms: &[&str]) -> SentenceWithPieces {
+        // Two pieces per token.
+        SentenceWithPieces {
+            pieces: (0..forms.len() as i64 * 2).collect(),
+            sentence: forms.iter().map(|form| Token::new(*form)).collect(),
+            token_offsets: (0..forms.len()).map(|idx| idx * 2).collect(),
+        }
+    }
+
+    fn forms(sentence: &Sentence) -> Vec<&str> {
+        sentence
+            .iter()
+            .filter_map(Node::token)
+            .map(|token| token.form())
+            .collect()
+    }
+
+    fn sentences() -> Vec<SentenceWithPieces> {
+        vec![
+            sentence_with_pieces(&["Kort"]),
+            sentence_with_pieces(&["Dit", "is", "lang"]),
+        ]
+    }
+
+    #[test]
+    fn oversized_sentences_are_skipped() {
+        let limited = limit_length(sentences(), 4, OversizedSentences::Skip)
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        assert_eq!(limited.len(), 1);
+        assert_eq!(forms(&limited[0].sentence), vec!["Kort"]);
+    }
+
+    #[test]
+    fn oversized_sentences_are_truncated() {
+        let limited = limit_length(sentences(), 3, OversizedSentences::Truncate)
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        assert_eq!(limited.len(), 2);
+        assert_eq!(forms(&limited[1].sentence), vec!["Dit", "is"]);
+        assert_eq!(limited[1].pieces.len(), 3);
+        assert_eq!(limited[1].token_offsets, vec![0, 2]);
+    }
+
+    #[test]
+    fn map_unique_matches_naive_map() {
+        let items = vec![3, 1, 3, 2, 1, 3];
+        let annotate = |items: &[&usize]| Ok(items.iter().map(|&&item| item * 10).collect());
+
+        let mut n_unique = 0;
+        let deduplicated = map_unique(
+            &items,
+            |&item| item % 2,
+            |a, b| a == b,
+            |unique| {
+                n_unique = unique.len();
+                annotate(unique)
+            },
+            |&result, _| result,
+        )
+        .unwrap();
+
+        let naive: Vec<usize> = annotate(&items.iter().collect::<Vec<_>>()).unwrap();
+        assert_eq!(deduplicated, naive);
+        assert_eq!(n_unique, 3);
+    }
+
+    #[test]
+    fn map_unique_rejects_missing_results() {
+        let items = vec![1, 2, 1];
+        assert!(map_unique(
+            &items,
+            |&item| item,
+            |a, b| a == b,
+            |_| Ok(vec![10]),
+            |&result, _| result,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn least_recently_used_annotator_is_unloaded() {
+        let resident = Arc::new(ResidentAnnotators::new(2));
+        let first = lazy_cell("first", &resident);
+        let second = lazy_cell("second", &resident);
+
+        // Using the first annotator makes the second the least recently used.
+        assert!(first.get().is_ok());
+        let _third = lazy_cell("third", &resident);
+
+        assert!(matches!(first.status(), AnnotatorStatus::Ready(_)));
+        assert!(matches!(second.status(), AnnotatorStatus::Unloaded));
+    }
+
+    #[test]
+    fn failed_lazy_load_is_retried() {
+        let resident = Arc::new(ResidentAnnotators::new(1));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let cell = {
+            let attempts = attempts.clone();
+            lazy_cell_with(
+                "flaky",
+                &resident,
+                Box::new(move || {
+                    if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                        Err(anyhow!("Out of memory"))
+                    } else {
+                        Ok(Arc::new(TestAnnotator::default()) as Arc<dyn Annotate + Send + Sync>)
+                    }
+                }),
+            )
+        };
+
+        block_on(cell.wait_loaded(None));
+        assert!(matches!(cell.status(), AnnotatorStatus::Failed(_)));
+
+        // The failed load does not take the only slot.
+        block_on(cell.wait_loaded(None));
+        assert!(cell.get().is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn loading_annotators_are_not_unloaded() {
+        let resident = Arc::new(ResidentAnnotators::new(1));
+        let (tx, rx) = mpsc::channel::<()>();
+        let rx = Mutex::new(rx);
+        let slow = lazy_cell_with(
+            "slow",
+            &resident,
+            Box::new(move || {
+                rx.lock().unwrap().recv().unwrap();
+                Ok(Arc::new(TestAnnotator::default()) as Arc<dyn Annotate + Send + Sync>)
+            }),
+        );
+        assert!(slow.get().is_err());
+        assert!(matches!(slow.status(), AnnotatorStatus::Loading));
+
+        // The only slot is taken by the annotator that is loading.
+        let other = lazy_cell_with(
+            "other",
+            &resident,
+            Box::new(|| Ok(Arc::new(TestAnnotator::default()) as Arc<dyn Annotate + Send + Sync>)),
+        );
+        assert!(other.get().is_err());
+        assert!(matches!(other.status(), AnnotatorStatus::Unloaded));
+
+        // With a timeout, waiting returns while the annotator is loading.
+        block_on(slow.wait_loaded(Some(Duration::from_millis(10))));
+        assert!(matches!(slow.status(), AnnotatorStatus::Loading));
+
+        tx.send(()).unwrap();
+        block_on(slow.wait_loaded(None));
+        assert!(slow.get().is_ok());
+    }
+
+    #[test]
+    fn undersized_sentences_are_skipped() {
+        let optional = || sentences().into_iter().map(Some).collect::<Vec<_>>();
+
+        let by_tokens =
+            require_min_length(optional(), 2, LengthUnit::Tokens, UndersizedSentences::Skip)
+                .unwrap();
+        assert!(by_tokens[0].is_none());
+        assert_eq!(
+            forms(&by_tokens[1].as_ref().unwrap().sentence),
+            ["Dit", "is", "lang"]
+        );
+
+        // The short sentence has two pieces.
+        let by_pieces =
+            require_min_length(optional(), 2, LengthUnit::Pieces, UndersizedSentences::Skip)
+                .unwrap();
+        assert!(by_pieces.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn undersized_sentences_are_errors() {
+        let sentences = sentences().into_iter().map(Some).collect::<Vec<_>>();
+        assert!(
+            require_min_length(sentences, 3, LengthUnit::Pieces, UndersizedSentences::Error)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn device_names() {
+        assert_eq!(device_name(Device::Cpu), "cpu");
+        assert_eq!(device_name(Device::Cuda(1)), "cuda:1");
+    }
+
+    #[test]
+    fn device_names_are_parsed() {
+        assert_eq!(parse_device("cpu").unwrap(), Device::Cpu);
+        assert!(parse_device("tpu").is_err());
+        assert!(parse_device("cuda:first").is_err());
+        if tch::Cuda::is_available() {
+            assert_eq!(parse_device("cuda").unwrap(), Device::Cuda(0));
+            assert_eq!(parse_device("cuda:1").unwrap(), Device::Cuda(1));
+        } else {
+            assert!(parse_device("cuda:0").is_err());
+        }
+    }
+
+    #[test]
+    fn precision_is_checked_against_device() {
+        assert!(Precision::Fp32.check_device(Device::Cpu).is_ok());
+        assert!(Precision::Fp32.check_device(Device::Cuda(0)).is_ok());
+        assert!(Precision::Bf16.check_device(Device::Cpu).is_err());
+        assert!(Precision::Fp16.check_device(Device::Cpu).is_err());
+        assert!(Precision::Fp16.check_device(Device::Cuda(0)).is_err());
+    }
+
+    #[test]
+    fn batches_are_limited_by_pieces() {
+        let lengths = [2, 2, 3, 5, 9, 10];
+        assert_eq!(batch_lengths(&lengths, 4, None), [4, 2]);
+        assert_eq!(batch_lengths(&lengths, 4, Some(8)), [3, 1, 1, 1]);
+        assert_eq!(batch_lengths(&lengths, 2, Some(100)), [2, 2, 2]);
+        assert!(batch_lengths(&[], 4, Some(8)).is_empty());
+    }
+
+    #[test]
+    fn oversized_sentences_are_errors() {
+        assert!(limit_length(sentences(), 4, OversizedSentences::Error).is_err());
+        assert!(limit_length(sentences(), 6, OversizedSentences::Error).is_ok());
+    }
+}
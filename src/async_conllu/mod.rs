@@ -1,27 +1,217 @@
+use std::num::NonZeroUsize;
 use std::pin::Pin;
+use std::time::Instant;
 
-use conllu::io::{WriteSentence, Writer};
 use futures::io::{AsyncRead, Error, ErrorKind};
-use futures::ready;
 use futures::stream::Stream;
 use futures::task::{Context, Poll};
 use udgraph::graph::Sentence;
 
-/// Read [udgraph::graph::Sentence]s from a stream, output CoNLL-U.
+use crate::async_syntaxdot::StageTimings;
+use crate::format::{reorder_features, FormatState, OutputFormat};
+
+mod sentences;
+pub use sentences::ToConlluSentences;
+
+mod validate;
+use validate::validate_conllu;
+pub use validate::OutputValidation;
+
+/// Separator between documents of a split output.
+const DOCUMENT_SEPARATOR: &[u8] = b"\x0c\n";
+
+/// Framing state of the output.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Framing {
+    /// The header still has to be written.
+    Header,
+
+    /// Sentences are written, `first` is `true` until the first sentence
+    /// of the current document is written. `sentences` is the number of
+    /// sentences in the current document.
+    Body {
+        first: bool,
+        sentences: usize,
+        state: FormatState,
+    },
+
+    /// The footer was written, the output is complete.
+    Footer,
+}
+
+impl Framing {
+    /// Write a sentence, preceded by a separator if necessary.
+    ///
+    /// When `split_every` is set, a new document is started after every
+    /// `split_every` sentences.
+    ///
+    /// Sentences are separated by `separator`, or by the separator of
+    /// the format when it is `None`. CoNLL-U sentences are parsed again
+    /// after serialization when `validation` is enabled.
+    fn write_sentence(
+        &mut self,
+        format: OutputFormat,
+        buf: &mut Vec<u8>,
+        sentence: &Sentence,
+        separator: Option<&[u8]>,
+        split_every: Option<NonZeroUsize>,
+        validation: OutputValidation,
+    ) -> Result<(), Error> {
+        if let Framing::Body { sentences, .. } = self {
+            if split_every.map(NonZeroUsize::get) == Some(*sentences) {
+                self.write_footer(format, buf);
+                buf.extend_from_slice(DOCUMENT_SEPARATOR);
+                *self = Framing::Header;
+                self.write_header(format, buf);
+            }
+        }
+
+        match self {
+            Framing::Body {
+                first,
+                sentences,
+                state,
+            } => {
+                if !*first {
+                    buf.extend_from_slice(separator.unwrap_or_else(|| format.separator()));
+                }
+                let start = buf.len();
+                format.write_sentence(buf, sentence, state)?;
+                if format == OutputFormat::Conllu && validation != OutputValidation::None {
+                    if let Err(err) = validate_conllu(&buf[start..], sentence) {
+                        log::error!("Invalid CoNLL-U output: {}", err);
+                        if validation == OutputValidation::Fail {
+                            return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                format!("Invalid CoNLL-U output: {}", err),
+                            ));
+                        }
+                    }
+                }
+                *first = false;
+                *sentences += 1;
+                Ok(())
+            }
+            _ => unreachable!("Sentence written outside the output body"),
+        }
+    }
+
+    /// Write the header and start the body.
+    fn write_header(&mut self, format: OutputFormat, buf: &mut Vec<u8>) {
+        debug_assert_eq!(*self, Framing::Header);
+        buf.extend_from_slice(format.header());
+        *self = Framing::Body {
+            first: true,
+            sentences: 0,
+            state: FormatState::default(),
+        };
+    }
+
+    /// Write the footer and finish the output.
+    fn write_footer(&mut self, format: OutputFormat, buf: &mut Vec<u8>) {
+        match self {
+            Framing::Body { state, .. } => format.write_footer(buf, state),
+            _ => unreachable!("Footer written outside the output body"),
+        }
+        *self = Framing::Footer;
+    }
+}
+
+/// Read [udgraph::graph::Sentence]s from a stream, output CoNLL-U or JSON.
 pub struct SentenceStreamReader<A> {
     annotations: Pin<Box<A>>,
-    first_output: bool,
+    buffer_size: usize,
+    error: Option<Error>,
+    feats_order: Option<Vec<String>>,
+    format: OutputFormat,
+    framing: Framing,
     parse_buf: Vec<u8>,
+    parse_pos: usize,
+    separator: Option<Vec<u8>>,
+    split_every: Option<NonZeroUsize>,
+    timings: Option<StageTimings>,
+    validation: OutputValidation,
 }
 
 impl<A> SentenceStreamReader<A> {
     pub fn new(annotations: A) -> Self {
         SentenceStreamReader {
-            first_output: true,
             annotations: Box::pin(annotations),
+            buffer_size: 0,
+            error: None,
+            feats_order: None,
+            format: OutputFormat::Conllu,
+            framing: Framing::Header,
             parse_buf: Vec::new(),
+            parse_pos: 0,
+            separator: None,
+            split_every: None,
+            timings: None,
+            validation: OutputValidation::None,
         }
     }
+
+    /// Write morphological features in CoNLL-U output in this order.
+    ///
+    /// Listed features come first, other features follow in the default
+    /// alphabetical order. See [`reorder_features`].
+    pub fn feats_order(mut self, feats_order: Option<Vec<String>>) -> Self {
+        self.feats_order = feats_order;
+        self
+    }
+
+    /// Set the output format, CoNLL-U by default.
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Serialize batches until the output buffer holds `buffer_size` bytes.
+    ///
+    /// Batches that are already available are serialized together, which
+    /// reduces the number of reads for large responses. Buffered output is
+    /// returned as soon as no further batch is ready, so this does not add
+    /// latency. With the default size of `0`, one batch is serialized at a
+    /// time.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Separate sentences by `separator` rather than by the separator of
+    /// the output format.
+    ///
+    /// For CoNLL-U, the default separator is the blank line that the
+    /// format requires, a custom separator makes the output unparseable
+    /// for CoNLL-U readers.
+    pub fn separator(mut self, separator: Option<Vec<u8>>) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Split the output into documents of at most `split_every` sentences.
+    ///
+    /// Each document is a complete output in the output format, documents
+    /// are separated by a form feed line.
+    pub fn split_every(mut self, split_every: Option<NonZeroUsize>) -> Self {
+        self.split_every = split_every;
+        self
+    }
+
+    /// Record the time spent serializing sentences in `timings`.
+    pub fn timings(mut self, timings: Option<StageTimings>) -> Self {
+        self.timings = timings;
+        self
+    }
+
+    /// Parse CoNLL-U output again to validate it before it is sent.
+    ///
+    /// This is expensive, it is meant to catch regressions in staging.
+    /// Other output formats are not validated.
+    pub fn validation(mut self, validation: OutputValidation) -> Self {
+        self.validation = validation;
+        self
+    }
 }
 
 impl<A> AsyncRead for SentenceStreamReader<A>
@@ -35,38 +225,314 @@ where
     ) -> Poll<Result<usize, Error>> {
         let SentenceStreamReader {
             annotations,
-            first_output,
+            buffer_size,
+            error,
+            feats_order,
+            format,
+            framing,
             parse_buf,
+            parse_pos,
+            separator,
+            split_every,
+            timings,
+            validation,
         } = &mut *self;
 
-        if parse_buf.is_empty() {
-            let sentences = match ready!(annotations.as_mut().poll_next(cx)) {
-                None => return Poll::Ready(Ok(0)),
-                Some(Err(err)) => return Poll::Ready(Err(err)),
-                Some(Ok(sentences)) => sentences,
-            };
+        // Output before parse_pos was already read. Remove it once it is at
+        // least as large as the unread output, so that the unread output is
+        // moved at most once per byte read.
+        if *parse_pos > 0 && *parse_pos >= parse_buf.len() - *parse_pos {
+            parse_buf.drain(..*parse_pos);
+            *parse_pos = 0;
+        }
 
-            let mut write_buf = if *first_output {
-                *first_output = false;
-                Vec::new()
-            } else {
-                vec![b'\n']
+        if *framing == Framing::Header {
+            framing.write_header(*format, parse_buf);
+        }
+
+        while *framing != Framing::Footer
+            && error.is_none()
+            && (parse_buf.len() == *parse_pos || parse_buf.len() - *parse_pos < *buffer_size)
+        {
+            let sentences = match annotations.as_mut().poll_next(cx) {
+                Poll::Pending if parse_buf.len() == *parse_pos => return Poll::Pending,
+                Poll::Pending => break,
+                Poll::Ready(None) => {
+                    framing.write_footer(*format, parse_buf);
+                    break;
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    *error = Some(err);
+                    break;
+                }
+                Poll::Ready(Some(Ok(sentences))) => sentences,
             };
 
-            let mut writer = Writer::new(&mut write_buf);
-            for sentence in sentences {
-                if let Err(err) = writer.write_sentence(&sentence) {
-                    return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, err)));
+            let start = Instant::now();
+            for sentence in &sentences {
+                let sentence_start = parse_buf.len();
+                if let Err(err) = framing.write_sentence(
+                    *format,
+                    parse_buf,
+                    sentence,
+                    separator.as_deref(),
+                    *split_every,
+                    *validation,
+                ) {
+                    return Poll::Ready(Err(err));
                 }
+
+                if let (Some(order), OutputFormat::Conllu) = (feats_order.as_ref(), *format) {
+                    let reordered = String::from_utf8(parse_buf.split_off(sentence_start))
+                        .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+                        .map(|conllu| reorder_features(&conllu, order));
+                    match reordered {
+                        Ok(reordered) => parse_buf.extend_from_slice(reordered.as_bytes()),
+                        Err(err) => return Poll::Ready(Err(err)),
+                    }
+                }
+            }
+            if let Some(timings) = timings {
+                timings.record_serialization(start.elapsed());
             }
+        }
 
-            std::mem::swap(&mut write_buf, parse_buf);
+        // Return buffered output before reporting an error.
+        if parse_buf.len() == *parse_pos {
+            if let Some(err) = error.take() {
+                return Poll::Ready(Err(err));
+            }
         }
 
-        let bytes_to_copy = std::cmp::min(buf.len(), parse_buf.len());
-        buf[..bytes_to_copy].copy_from_slice(&parse_buf[..bytes_to_copy]);
-        parse_buf.drain(..bytes_to_copy);
+        let unread = &parse_buf[*parse_pos..];
+        let bytes_to_copy = std::cmp::min(buf.len(), unread.len());
+        buf[..bytes_to_copy].copy_from_slice(&unread[..bytes_to_copy]);
+        *parse_pos += bytes_to_copy;
 
         Poll::Ready(Ok(bytes_to_copy))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use futures::executor::block_on;
+    use futures::io::{AsyncReadExt, Error};
+    use futures::stream::{self, StreamExt};
+    use udgraph::graph::Sentence;
+    use udgraph::token::Token;
+
+    use super::SentenceStreamReader;
+    use crate::format::OutputFormat;
+
+    fn batches() -> Vec<Vec<Sentence>> {
+        vec![
+            vec![
+                vec![Token::new("Een"), Token::new("zin")]
+                    .into_iter()
+                    .collect(),
+                vec![Token::new("Nog"), Token::new("een")]
+                    .into_iter()
+                    .collect(),
+            ],
+            vec![],
+            vec![vec![Token::new("Laatste")].into_iter().collect()],
+        ]
+    }
+
+    fn read_to_string(buffer_size: usize) -> String {
+        let mut reader =
+            SentenceStreamReader::new(stream::iter(batches()).map(Ok)).buffer_size(buffer_size);
+        let mut output = String::new();
+        block_on(reader.read_to_string(&mut output)).unwrap();
+        output
+    }
+
+    #[test]
+    fn features_are_serialized_in_order() {
+        let mut token = Token::new("huis");
+        token
+            .features_mut()
+            .insert("Number".to_string(), "Sing".to_string());
+        token
+            .features_mut()
+            .insert("Gender".to_string(), "Neut".to_string());
+        token
+            .features_mut()
+            .insert("Definite".to_string(), "Def".to_string());
+        let sentence: Sentence = vec![token].into_iter().collect();
+
+        let mut reader =
+            SentenceStreamReader::new(stream::iter(vec![vec![sentence.clone()]]).map(Ok));
+        let mut output = String::new();
+        block_on(reader.read_to_string(&mut output)).unwrap();
+
+        assert!(output.contains("\tDefinite=Def|Gender=Neut|Number=Sing\t"));
+
+        let mut reader = SentenceStreamReader::new(stream::iter(vec![vec![sentence]]).map(Ok))
+            .feats_order(Some(vec!["Number".to_string(), "Gender".to_string()]));
+        let mut output = String::new();
+        block_on(reader.read_to_string(&mut output)).unwrap();
+
+        assert!(output.contains("\tNumber=Sing|Gender=Neut|Definite=Def\t"));
+    }
+
+    #[test]
+    fn writes_json_array() {
+        let mut reader =
+            SentenceStreamReader::new(stream::iter(batches()).map(Ok)).format(OutputFormat::Json);
+        let mut output = String::new();
+        block_on(reader.read_to_string(&mut output)).unwrap();
+
+        let sentences: Vec<serde_json::Value> = serde_json::from_str(&output).unwrap();
+        assert_eq!(sentences.len(), 3);
+        assert_eq!(sentences[2]["tokens"][0]["form"], "Laatste");
+    }
+
+    #[test]
+    fn writes_empty_json_array() {
+        let mut reader = SentenceStreamReader::new(stream::empty::<Result<Vec<Sentence>, Error>>())
+            .format(OutputFormat::Json);
+        let mut output = String::new();
+        block_on(reader.read_to_string(&mut output)).unwrap();
+        assert_eq!(output, "[]");
+    }
+
+    #[test]
+    fn writes_ndjson() {
+        let mut reader =
+            SentenceStreamReader::new(stream::iter(batches()).map(Ok)).format(OutputFormat::Ndjson);
+        let mut output = String::new();
+        block_on(reader.read_to_string(&mut output)).unwrap();
+        assert_eq!(output.lines().count(), 3);
+    }
+
+    fn read_format(batches: Vec<Vec<Sentence>>, format: OutputFormat) -> String {
+        let mut reader = SentenceStreamReader::new(stream::iter(batches).map(Ok)).format(format);
+        let mut output = String::new();
+        block_on(reader.read_to_string(&mut output)).unwrap();
+        output
+    }
+
+    #[test]
+    fn framing_without_sentences() {
+        for batches in [vec![], vec![vec![]], vec![vec![], vec![]]] {
+            assert_eq!(read_format(batches.clone(), OutputFormat::Conllu), "");
+            assert_eq!(read_format(batches.clone(), OutputFormat::Json), "[]");
+            assert_eq!(read_format(batches, OutputFormat::Ndjson), "");
+        }
+    }
+
+    #[test]
+    fn framing_with_one_sentence() {
+        let one = || vec![vec![], vec![vec![Token::new("Een")].into_iter().collect()]];
+
+        let conllu = read_format(one(), OutputFormat::Conllu);
+        assert!(conllu.starts_with("1\tEen\t"));
+        assert!(!conllu.contains("\n\n"));
+
+        let json = read_format(one(), OutputFormat::Json);
+        let sentences: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(sentences.len(), 1);
+
+        let ndjson = read_format(one(), OutputFormat::Ndjson);
+        assert!(ndjson.starts_with('{'));
+        assert!(ndjson.ends_with("}\n"));
+        assert_eq!(ndjson.lines().count(), 1);
+    }
+
+    #[test]
+    fn framing_separates_sentences_across_batches() {
+        let conllu = read_format(batches(), OutputFormat::Conllu);
+        assert!(conllu.starts_with("1\tEen\t"));
+        assert_eq!(conllu.matches("\n\n").count(), 2);
+
+        let json = read_format(batches(), OutputFormat::Json);
+        let sentences: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(sentences.len(), 3);
+
+        let ndjson = read_format(batches(), OutputFormat::Ndjson);
+        assert_eq!(ndjson.lines().count(), 3);
+        assert!(!ndjson.contains("\n\n"));
+    }
+
+    fn read_split(format: OutputFormat, split_every: usize) -> Vec<String> {
+        let mut reader = SentenceStreamReader::new(stream::iter(batches()).map(Ok))
+            .format(format)
+            .split_every(NonZeroUsize::new(split_every));
+        let mut output = String::new();
+        block_on(reader.read_to_string(&mut output)).unwrap();
+        output.split("\x0c\n").map(ToOwned::to_owned).collect()
+    }
+
+    #[test]
+    fn splits_output_into_documents() {
+        let json = read_split(OutputFormat::Json, 2);
+        assert_eq!(json.len(), 2);
+        let first: Vec<serde_json::Value> = serde_json::from_str(&json[0]).unwrap();
+        assert_eq!(first.len(), 2);
+        let second: Vec<serde_json::Value> = serde_json::from_str(&json[1]).unwrap();
+        assert_eq!(second.len(), 1);
+
+        let conllu = read_split(OutputFormat::Conllu, 1);
+        assert_eq!(conllu.len(), 3);
+        assert!(conllu.iter().all(|doc| doc.starts_with("1\t")));
+
+        assert_eq!(read_split(OutputFormat::Conllu, 3).len(), 1);
+    }
+
+    #[test]
+    fn small_reads_return_all_output() {
+        let batches = (0..1000)
+            .map(|_| batches().concat())
+            .collect::<Vec<Vec<Sentence>>>();
+        let expected = read_format(batches.clone(), OutputFormat::Conllu);
+
+        for &buffer_size in &[0, 1 << 16] {
+            let mut reader = SentenceStreamReader::new(stream::iter(batches.clone()).map(Ok))
+                .buffer_size(buffer_size);
+            let mut output = Vec::new();
+            let mut buf = [0u8; 7];
+            loop {
+                let n = block_on(reader.read(&mut buf)).unwrap();
+                if n == 0 {
+                    break;
+                }
+                output.extend_from_slice(&buf[..n]);
+            }
+
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn custom_separator_separates_sentences() {
+        let mut reader = SentenceStreamReader::new(stream::iter(batches()).map(Ok))
+            .separator(Some(b"\x1e\n".to_vec()));
+        let mut output = String::new();
+        block_on(reader.read_to_string(&mut output)).unwrap();
+
+        let sentences = output.split("\x1e\n").collect::<Vec<_>>();
+        assert_eq!(sentences.len(), 3);
+        assert!(sentences.iter().all(|sentence| sentence.starts_with("1\t")));
+        assert!(!output.contains("\n\n"));
+        assert!(!output.ends_with('\x1e'));
+    }
+
+    #[test]
+    fn default_separator_is_blank_line() {
+        let mut reader = SentenceStreamReader::new(stream::iter(batches()).map(Ok)).separator(None);
+        let mut output = String::new();
+        block_on(reader.read_to_string(&mut output)).unwrap();
+        assert_eq!(output, read_format(batches(), OutputFormat::Conllu));
+    }
+
+    #[test]
+    fn buffering_does_not_change_output() {
+        let unbuffered = read_to_string(0);
+        assert_eq!(unbuffered.matches("\n\n").count(), 2);
+        assert!(!unbuffered.contains("\n\n\n"));
+        assert_eq!(read_to_string(1 << 16), unbuffered);
+    }
+}
@@ -0,0 +1,158 @@
+use std::io::Cursor;
+use std::pin::Pin;
+
+use conllu::io::{ReadSentence, Reader};
+use futures::io::{Error, ErrorKind};
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use udgraph::graph::Sentence;
+
+/// Stream that parses CoNLL-U sentences from lines.
+pub struct ConlluSentences<L> {
+    block: String,
+    lines: Pin<Box<L>>,
+    preserve_comments: bool,
+}
+
+impl<L> ConlluSentences<L>
+where
+    L: Stream<Item = Result<String, Error>>,
+{
+    pub fn new(lines: L) -> Self {
+        ConlluSentences {
+            block: String::new(),
+            lines: Box::pin(lines),
+            preserve_comments: false,
+        }
+    }
+
+    /// Keep the comments of the input sentences.
+    pub fn preserve_comments(mut self, preserve_comments: bool) -> Self {
+        self.preserve_comments = preserve_comments;
+        self
+    }
+}
+
+fn parse_sentence(block: String, preserve_comments: bool) -> Result<Sentence, Error> {
+    let mut sentence = Reader::new(Cursor::new(block))
+        .read_sentence()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Empty CoNLL-U sentence"))?;
+
+    if !preserve_comments {
+        sentence.comments_mut().clear();
+    }
+
+    Ok(sentence)
+}
+
+impl<L> Stream for ConlluSentences<L>
+where
+    L: Stream<Item = Result<String, Error>>,
+{
+    type Item = Result<Sentence, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let ConlluSentences {
+            block,
+            lines,
+            preserve_comments,
+        } = &mut *self;
+
+        loop {
+            match ready!(lines.as_mut().poll_next(cx)) {
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                Some(Ok(line)) => {
+                    if !line.trim().is_empty() {
+                        block.push_str(&line);
+                        block.push('\n');
+                        continue;
+                    }
+
+                    // Skip consecutive empty lines.
+                    if !block.is_empty() {
+                        return Poll::Ready(Some(parse_sentence(
+                            std::mem::take(block),
+                            *preserve_comments,
+                        )));
+                    }
+                }
+                None => {
+                    if block.is_empty() {
+                        return Poll::Ready(None);
+                    }
+
+                    return Poll::Ready(Some(parse_sentence(
+                        std::mem::take(block),
+                        *preserve_comments,
+                    )));
+                }
+            }
+        }
+    }
+}
+
+pub trait ToConlluSentences<L> {
+    fn conllu_sentences(self) -> ConlluSentences<L>;
+}
+
+impl<L> ToConlluSentences<L> for L
+where
+    L: Stream<Item = Result<String, Error>>,
+{
+    fn conllu_sentences(self) -> ConlluSentences<L> {
+        ConlluSentences::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on_stream;
+    use futures::stream::{self, StreamExt};
+    use udgraph::graph::{Node, Sentence};
+
+    use super::ToConlluSentences;
+
+    static CONLLU: &[&str] = &[
+        "# sent_id = 1",
+        "1\tDit\t_\t_\t_\t_\t_\t_\t_\t_",
+        "2\tis\t_\t_\t_\t_\t_\t_\t_\t_",
+        "",
+        "",
+        "# sent_id = 2",
+        "1\tNog\t_\t_\t_\t_\t_\t_\t_\t_",
+    ];
+
+    fn read_sentences(preserve_comments: bool) -> Vec<Sentence> {
+        block_on_stream(
+            stream::iter(CONLLU.iter().map(ToString::to_string))
+                .map(Ok)
+                .conllu_sentences()
+                .preserve_comments(preserve_comments),
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_sentences() {
+        let sentences = read_sentences(false);
+        assert_eq!(sentences.len(), 2);
+
+        let forms = sentences[0]
+            .iter()
+            .filter_map(Node::token)
+            .map(|token| token.form())
+            .collect::<Vec<_>>();
+        assert_eq!(forms, vec!["Dit", "is"]);
+        assert!(sentences[0].comments().is_empty());
+    }
+
+    #[test]
+    fn preserves_comments() {
+        let sentences = read_sentences(true);
+        assert_eq!(sentences[0].comments().len(), 1);
+        assert_eq!(sentences[1].comments().len(), 1);
+    }
+}
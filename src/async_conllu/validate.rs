@@ -0,0 +1,91 @@
+use std::io::Cursor;
+
+use conllu::io::{ReadSentence, Reader};
+use serde::{Deserialize, Serialize};
+use udgraph::graph::Sentence;
+
+/// Validation of serialized CoNLL-U output.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputValidation {
+    /// Do not validate the output.
+    #[default]
+    None,
+
+    /// Log invalid sentences, but send them anyway.
+    Log,
+
+    /// Fail the response on the first invalid sentence.
+    Fail,
+}
+
+/// Check that `conllu` is a valid serialization of `sentence`.
+///
+/// The output is parsed again and must contain the same number of
+/// tokens as the sentence, with heads that refer to tokens of the
+/// sentence.
+pub(crate) fn validate_conllu(conllu: &[u8], sentence: &Sentence) -> Result<(), String> {
+    // Sentences without tokens, such as truncation markers, only have
+    // comments.
+    if sentence.len() == 1 {
+        return Ok(());
+    }
+
+    let parsed = Reader::new(Cursor::new(conllu))
+        .read_sentence()
+        .map_err(|err| format!("Cannot parse output: {}", err))?
+        .ok_or_else(|| "Output does not contain a sentence".to_string())?;
+
+    if parsed.len() != sentence.len() {
+        return Err(format!(
+            "Output has {} tokens, the sentence has {}",
+            parsed.len() - 1,
+            sentence.len() - 1
+        ));
+    }
+
+    let dep_graph = parsed.dep_graph();
+    for dependent in 1..parsed.len() {
+        if let Some(triple) = dep_graph.head(dependent) {
+            if triple.head() >= parsed.len() || triple.head() == dependent {
+                return Err(format!(
+                    "Token {} has invalid head {}",
+                    dependent,
+                    triple.head()
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use udgraph::graph::Sentence;
+    use udgraph::token::Token;
+
+    use super::validate_conllu;
+    use crate::format::{FormatState, OutputFormat};
+
+    fn sentence(forms: &[&str]) -> Sentence {
+        forms.iter().map(|&form| Token::new(form)).collect()
+    }
+
+    #[test]
+    fn serialized_sentences_are_valid() {
+        let sentence = sentence(&["Katten", "slapen"]);
+        let mut buf = Vec::new();
+        OutputFormat::Conllu
+            .write_sentence(&mut buf, &sentence, &mut FormatState::default())
+            .unwrap();
+        assert!(validate_conllu(&buf, &sentence).is_ok());
+    }
+
+    #[test]
+    fn mismatching_output_is_invalid() {
+        let sentence = sentence(&["Katten", "slapen"]);
+        assert!(validate_conllu(b"1\tKatten\t_\t_\t_\t_\t_\t_\t_\t_\n", &sentence).is_err());
+        assert!(validate_conllu(b"1\tKatten\n2\tslapen\n", &sentence).is_err());
+    }
+}
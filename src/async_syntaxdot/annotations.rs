@@ -1,46 +1,471 @@
 use std::future::Future;
 use std::io::ErrorKind;
 use std::pin::Pin;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::Result;
-use async_std::task::spawn;
 use futures::io::Error;
 use futures::ready;
-use futures::stream::Stream;
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
 use futures::task::{Context, Poll};
 use syntaxdot_tokenizers::SentenceWithPieces;
-use udgraph::graph::Sentence;
+use udgraph::graph::{Comment, DepTriple, Node, Sentence};
 
-use crate::annotator::Annotator;
+use super::errors::{log_failed_input, ErrorBudget};
+use super::stats::AnnotationStats;
+use super::timing::StageTimings;
+use crate::annotator::Annotate;
+use crate::priority::AnnotationQueue;
+use crate::runtime::spawn;
 
-enum AnnotationsState {
-    Sentences,
-    Annotate(
-        Pin<Box<dyn Future<Output = Result<Vec<SentenceWithPieces>, anyhow::Error>> + Send + Sync>>,
-    ),
+/// Annotated chunk, with the time at which it entered the stage.
+type AnnotationFuture = Pin<
+    Box<
+        dyn Future<Output = Result<(Instant, Vec<SentenceWithPieces>), anyhow::Error>>
+            + Send
+            + Sync,
+    >,
+>;
+
+/// Log up to `max_bytes` of the forms of sentences that failed.
+fn log_failed_sentences(sentences: &[Sentence], max_bytes: Option<usize>) {
+    if let Some(max_bytes) = max_bytes {
+        let input = sentences
+            .iter()
+            .map(|sentence| forms(sentence).join(" "))
+            .collect::<Vec<_>>()
+            .join("\n");
+        log_failed_input("annotation", &input, max_bytes);
+    }
+}
+
+/// Annotate sentences, isolating the sentences that fail.
+///
+/// When a batch fails, its sentences are annotated one by one to find the
+/// sentences that cause the failure.
+fn isolate_failures<'a>(
+    annotator: &dyn Annotate,
+    sentences: &'a [Sentence],
+    batch_size: usize,
+    dedup: bool,
+    log_input: Option<usize>,
+) -> Vec<Result<SentenceWithPieces, (&'a Sentence, anyhow::Error)>> {
+    match annotator.annotate_sentences(sentences, batch_size, dedup) {
+        Ok(annotated) => annotated.into_iter().map(Ok).collect(),
+        Err(_) if sentences.len() > 1 => sentences
+            .iter()
+            .flat_map(|sentence| {
+                isolate_failures(
+                    annotator,
+                    slice::from_ref(sentence),
+                    batch_size,
+                    false,
+                    log_input,
+                )
+            })
+            .collect(),
+        Err(err) => {
+            log_failed_sentences(sentences, log_input);
+            vec![Err((&sentences[0], err))]
+        }
+    }
+}
+
+/// Annotate sentences, replacing sentences that fail by error sentences.
+fn annotate_isolated(
+    annotator: &dyn Annotate,
+    sentences: &[Sentence],
+    batch_size: usize,
+    dedup: bool,
+    log_input: Option<usize>,
+) -> Vec<SentenceWithPieces> {
+    isolate_failures(annotator, sentences, batch_size, dedup, log_input)
+        .into_iter()
+        .map(|annotated| {
+            annotated.unwrap_or_else(|(sentence, err)| {
+                log::warn!("Cannot annotate sentence: {:#}", err);
+                error_sentence(sentence, &err)
+            })
+        })
+        .collect()
+}
+
+/// Annotate sentences, leaving out sentences that fail.
+///
+/// Failures are recorded in the error budget, annotation fails when the
+/// budget is exhausted. Returns the annotated sentences and the number
+/// of sentences that failed.
+fn annotate_skipping(
+    annotator: &dyn Annotate,
+    sentences: &[Sentence],
+    batch_size: usize,
+    dedup: bool,
+    error_budget: &ErrorBudget,
+    log_input: Option<usize>,
+) -> Result<(Vec<SentenceWithPieces>, usize)> {
+    let mut annotated = Vec::with_capacity(sentences.len());
+    let mut failed = 0;
+    for sentence in isolate_failures(annotator, sentences, batch_size, dedup, log_input) {
+        match sentence {
+            Ok(sentence) => annotated.push(sentence),
+            Err((_, err)) => {
+                error_budget.record("sentence", format!("{:#}", err))?;
+                failed += 1;
+            }
+        }
+    }
+
+    Ok((annotated, failed))
+}
+
+/// Unannotated sentence with an `# error = ...` comment.
+///
+/// The sentence does not have pieces, since it was not annotated.
+fn error_sentence(sentence: &Sentence, err: &anyhow::Error) -> SentenceWithPieces {
+    let mut sentence = sentence.clone();
+    sentence.comments_mut().push(Comment::AttrVal {
+        attr: "error".to_string(),
+        // Comments cannot span multiple lines.
+        val: format!("{:#}", err).replace('\n', " "),
+    });
+
+    SentenceWithPieces {
+        pieces: Default::default(),
+        sentence,
+        token_offsets: Vec::new(),
+    }
+}
+
+/// Add the vocabulary indices of the pieces of each token to its
+/// `pieces` misc feature, e.g. `pieces=1043,27`.
+fn add_piece_alignment(sentence: SentenceWithPieces) -> Sentence {
+    let SentenceWithPieces {
+        pieces,
+        mut sentence,
+        token_offsets,
+    } = sentence;
+
+    for (idx, token) in sentence.iter_mut().filter_map(Node::token_mut).enumerate() {
+        let start = match token_offsets.get(idx) {
+            Some(&start) => start,
+            // Without pieces, e.g. due to truncation.
+            None => break,
+        };
+        let end = token_offsets.get(idx + 1).copied().unwrap_or(pieces.len());
+
+        let token_pieces = pieces
+            .iter()
+            .skip(start)
+            .take(end.saturating_sub(start))
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        token
+            .misc_mut()
+            .insert("pieces".to_string(), Some(token_pieces));
+    }
+
+    sentence
+}
+
+/// Keep the layers of the input tokens that are filled in.
+///
+/// The lemma, tags, and features of a token are kept when they are
+/// present in the input. The heads and relations are kept when every
+/// input token has a head, otherwise all heads are taken from
+/// `annotated`. Mixing heads of both trees could introduce cycles.
+/// Other layers are taken from `annotated`.
+fn merge_input(input: &Sentence, annotated: &mut Sentence) {
+    for (input_token, token) in input
+        .iter()
+        .filter_map(Node::token)
+        .zip(annotated.iter_mut().filter_map(Node::token_mut))
+    {
+        if let Some(lemma) = input_token.lemma() {
+            token.set_lemma(Some(lemma));
+        }
+        if let Some(upos) = input_token.upos() {
+            token.set_upos(Some(upos));
+        }
+        if let Some(xpos) = input_token.xpos() {
+            token.set_xpos(Some(xpos));
+        }
+        if !input_token.features().is_empty() {
+            *token.features_mut() = input_token.features().clone();
+        }
+    }
+
+    if input.len() != annotated.len()
+        || (1..input.len()).any(|dependent| input.dep_graph().head(dependent).is_none())
+    {
+        return;
+    }
+
+    for dependent in 1..input.len() {
+        if let Some(triple) = input.dep_graph().head(dependent) {
+            annotated.dep_graph_mut().add_deprel(DepTriple::new(
+                triple.head(),
+                triple.relation(),
+                dependent,
+            ));
+        }
+    }
+}
+
+/// Merge the input sentences into their annotations.
+///
+/// The annotator leaves out sentences that it cannot annotate, so input
+/// sentences are matched to annotations by their forms, in order.
+fn merge_inputs(inputs: &[Sentence], annotated: &mut [SentenceWithPieces]) {
+    let mut inputs = inputs.iter();
+    for annotated in annotated {
+        let annotated_forms = forms(&annotated.sentence);
+        if let Some(input) = inputs
+            .by_ref()
+            .find(|input| forms(input) == annotated_forms)
+        {
+            merge_input(input, &mut annotated.sentence);
+        }
+    }
+}
+
+fn forms(sentence: &Sentence) -> Vec<&str> {
+    sentence
+        .iter()
+        .filter_map(Node::token)
+        .map(|token| token.form())
+        .collect()
+}
+
+/// Add a `# latency_ms = X` comment with the time since `received`.
+fn add_latency(sentence: &mut Sentence, received: Instant) {
+    sentence.comments_mut().push(Comment::AttrVal {
+        attr: "latency_ms".to_string(),
+        val: received.elapsed().as_millis().to_string(),
+    });
 }
 
 /// Stream that produces annotations for tokenized sentences.
+///
+/// Chunks that wait in the annotation queue are not annotated anymore
+/// once the stream is dropped, for instance when a response is truncated.
+/// Chunks that are being annotated finish in the background.
 pub struct Annotations<S> {
-    annotator: Arc<Annotator>,
+    annotator: Arc<dyn Annotate + Send + Sync>,
     batch_size: usize,
+    cancelled: Arc<AtomicBool>,
+    dedup: bool,
+    error_budget: Option<ErrorBudget>,
+    input_done: bool,
+    isolate_errors: bool,
+    latency: bool,
+    log_failed_input: Option<usize>,
+    max_in_flight: usize,
+    partial: bool,
+    pending: FuturesUnordered<AnnotationFuture>,
+    piece_alignment: bool,
+    priority: i32,
+    queue: Option<Arc<AnnotationQueue>>,
     sentences: Pin<Box<S>>,
-    state: AnnotationsState,
+    stats: Option<AnnotationStats>,
+    summarized: bool,
+    timings: Option<StageTimings>,
 }
 
 impl<S> Annotations<S>
 where
     S: Stream<Item = Result<Vec<Sentence>, Error>>,
 {
-    pub fn new(annotator: Arc<Annotator>, batch_size: usize, sentences: S) -> Self {
+    pub fn new(
+        annotator: Arc<dyn Annotate + Send + Sync>,
+        batch_size: usize,
+        sentences: S,
+    ) -> Self {
         Annotations {
             annotator,
             batch_size,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            dedup: false,
+            error_budget: None,
+            input_done: false,
+            isolate_errors: false,
+            latency: false,
+            log_failed_input: None,
+            max_in_flight: 1,
+            partial: false,
+            pending: FuturesUnordered::new(),
+            piece_alignment: false,
+            priority: 0,
+            queue: None,
             sentences: Box::pin(sentences),
-            state: AnnotationsState::Sentences,
+            stats: None,
+            summarized: false,
+            timings: None,
         }
     }
+
+    /// Annotate identical sentences in a batch only once.
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Skip sentences that cannot be annotated, recording them in the budget.
+    ///
+    /// The stream fails once the budget is exhausted. Otherwise, if
+    /// sentences were skipped, the stream ends with a sentence without
+    /// tokens that has a `# skipped = N` comment with the number of errors. This takes
+    /// precedence over [`Annotations::isolate_errors`].
+    pub fn error_budget(mut self, error_budget: Option<ErrorBudget>) -> Self {
+        self.error_budget = error_budget;
+        self
+    }
+
+    /// Replace sentences that cannot be annotated by error sentences.
+    ///
+    /// Rather than ending the stream with an error, a sentence that cannot
+    /// be annotated is returned unannotated with an `# error = ...`
+    /// comment.
+    pub fn isolate_errors(mut self, isolate_errors: bool) -> Self {
+        self.isolate_errors = isolate_errors;
+        self
+    }
+
+    /// Add a `# latency_ms = X` comment to every sentence.
+    ///
+    /// The latency is the time in milliseconds from when the chunk of
+    /// the sentence entered this stage to when the sentence is produced,
+    /// including the time waiting for other chunks and for the
+    /// annotation queue.
+    pub fn latency(mut self, latency: bool) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Log up to `max_bytes` of the forms of sentences that cannot be
+    /// annotated.
+    ///
+    /// Without isolation of errors, the sentences of the whole chunk are
+    /// logged.
+    pub fn log_failed_input(mut self, max_bytes: Option<usize>) -> Self {
+        self.log_failed_input = max_bytes;
+        self
+    }
+
+    /// Only fill in the layers that are missing from the input.
+    ///
+    /// The lemma, tags, features, and dependency relations of the input
+    /// sentences are kept when they are present, predictions are only
+    /// used for the remaining layers.
+    pub fn partial(mut self, partial: bool) -> Self {
+        self.partial = partial;
+        self
+    }
+
+    /// Add the pieces of each token to its misc features.
+    pub fn piece_alignment(mut self, piece_alignment: bool) -> Self {
+        self.piece_alignment = piece_alignment;
+        self
+    }
+
+    /// Wait in `queue` with the given priority before annotating a chunk.
+    ///
+    /// Without a queue, chunks are annotated immediately.
+    pub fn queue(mut self, queue: Option<Arc<AnnotationQueue>>, priority: i32) -> Self {
+        self.queue = queue;
+        self.priority = priority;
+        self
+    }
+
+    /// Record the sentences that the annotator skips in `stats`.
+    pub fn stats(mut self, stats: Option<AnnotationStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Record the time spent annotating chunks in `timings`.
+    pub fn timings(mut self, timings: Option<StageTimings>) -> Self {
+        self.timings = timings;
+        self
+    }
+
+    /// Annotate up to `max_in_flight` chunks concurrently.
+    ///
+    /// Chunks are produced as soon as they are annotated, so with more
+    /// than one chunk in flight, chunks can be produced out of input
+    /// order. The default is a single chunk, which preserves the order.
+    pub fn unordered(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    fn annotate_chunk(&self, sentences: Vec<Sentence>) -> AnnotationFuture {
+        let annotator = self.annotator.clone();
+        let batch_size = self.batch_size;
+        let cancelled = self.cancelled.clone();
+        let dedup = self.dedup;
+        let error_budget = self.error_budget.clone();
+        let isolate_errors = self.isolate_errors;
+        let log_input = self.log_failed_input;
+        let partial = self.partial;
+        let priority = self.priority;
+        let queue = self.queue.clone();
+        let stats = self.stats.clone();
+        let timings = self.timings.clone();
+        let received = Instant::now();
+
+        Box::pin(spawn(async move {
+            let _permit = match queue {
+                Some(queue) => Some(queue.acquire(priority).await),
+                None => None,
+            };
+
+            if cancelled.load(Ordering::SeqCst) {
+                return Ok((received, Vec::new()));
+            }
+
+            let start = Instant::now();
+
+            let (mut annotated, failed) = if let Some(error_budget) = error_budget {
+                annotate_skipping(
+                    annotator.as_ref(),
+                    &sentences,
+                    batch_size,
+                    dedup,
+                    &error_budget,
+                    log_input,
+                )?
+            } else if isolate_errors {
+                let annotated =
+                    annotate_isolated(annotator.as_ref(), &sentences, batch_size, dedup, log_input);
+                (annotated, 0)
+            } else {
+                let annotated = annotator
+                    .annotate_sentences(&sentences, batch_size, dedup)
+                    .inspect_err(|_| log_failed_sentences(&sentences, log_input))?;
+                (annotated, 0)
+            };
+
+            // The annotator leaves out sentences that are too long or
+            // too short.
+            if let Some(stats) = stats {
+                stats.record_skipped(sentences.len() - annotated.len() - failed);
+            }
+
+            if let Some(timings) = timings {
+                timings.record_annotation(start.elapsed());
+            }
+
+            if partial {
+                merge_inputs(&sentences, &mut annotated);
+            }
+
+            Ok((received, annotated))
+        }))
+    }
 }
 
 impl<S> Stream for Annotations<S>
@@ -50,54 +475,283 @@ where
     type Item = Result<Vec<Sentence>, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let Annotations {
-            annotator,
-            batch_size,
-            sentences,
-            state,
-        } = &mut *self;
-
-        // Ensure that we do not borrow batch_size in the closure.
-        let batch_size = *batch_size;
-
-        loop {
-            match state {
-                AnnotationsState::Sentences => match ready!(sentences.as_mut().poll_next(cx)) {
-                    None => return Poll::Ready(None),
-                    Some(Err(err)) => return Poll::Ready(Some(Err(err))),
-                    Some(Ok(sentences)) => {
-                        let annotator = annotator.clone();
-                        let future =
-                            spawn(
-                                async move { annotator.annotate_sentences(&sentences, batch_size) },
-                            );
-                        *state = AnnotationsState::Annotate(Box::pin(future));
-                    }
-                },
-                AnnotationsState::Annotate(future) => match ready!(future.as_mut().poll(cx)) {
-                    Err(err) => {
-                        return Poll::Ready(Some(Err(Error::new(ErrorKind::InvalidData, err))))
-                    }
-                    Ok(sentences) => {
-                        let sentences = sentences.into_iter().map(|s| s.sentence).collect();
-                        *state = AnnotationsState::Sentences;
-                        return Poll::Ready(Some(Ok(sentences)));
-                    }
-                },
+        // Start annotating chunks until the maximum number of chunks
+        // is in flight.
+        while !self.input_done && self.pending.len() < self.max_in_flight {
+            match self.sentences.as_mut().poll_next(cx) {
+                Poll::Pending => break,
+                Poll::Ready(None) => self.input_done = true,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(Some(Ok(sentences))) => {
+                    let future = self.annotate_chunk(sentences);
+                    self.pending.push(future);
+                }
+            }
+        }
+
+        if self.pending.is_empty() {
+            if !self.input_done {
+                return Poll::Pending;
+            }
+
+            return match &self.error_budget {
+                Some(budget) if !self.summarized && budget.errors() > 0 => {
+                    let summary = budget.summary();
+                    self.summarized = true;
+                    Poll::Ready(Some(Ok(vec![summary])))
+                }
+                _ => Poll::Ready(None),
+            };
+        }
+
+        match ready!(self.pending.poll_next_unpin(cx)) {
+            Some(Err(err)) => Poll::Ready(Some(Err(Error::new(ErrorKind::InvalidData, err)))),
+            Some(Ok((received, sentences))) => {
+                let latency = self.latency;
+                let piece_alignment = self.piece_alignment;
+                let sentences = sentences
+                    .into_iter()
+                    .map(|s| {
+                        let mut sentence = if piece_alignment {
+                            add_piece_alignment(s)
+                        } else {
+                            s.sentence
+                        };
+                        if latency {
+                            add_latency(&mut sentence, received);
+                        }
+                        sentence
+                    })
+                    .collect();
+                Poll::Ready(Some(Ok(sentences)))
             }
+            None => unreachable!("Polled annotations without pending chunks"),
         }
     }
 }
 
+impl<S> Drop for Annotations<S> {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
 pub trait ToAnnotations<S> {
-    fn annotations(self, annotator: Arc<Annotator>, batch_size: usize) -> Annotations<S>;
+    fn annotations(
+        self,
+        annotator: Arc<dyn Annotate + Send + Sync>,
+        batch_size: usize,
+    ) -> Annotations<S>;
 }
 
 impl<S> ToAnnotations<S> for S
 where
     S: Stream<Item = Result<Vec<Sentence>, Error>>,
 {
-    fn annotations(self, annotator: Arc<Annotator>, batch_size: usize) -> Annotations<S> {
+    fn annotations(
+        self,
+        annotator: Arc<dyn Annotate + Send + Sync>,
+        batch_size: usize,
+    ) -> Annotations<S> {
         Annotations::new(annotator, batch_size, self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use syntaxdot_tokenizers::SentenceWithPieces;
+    use udgraph::graph::{Comment, DepTriple, Node, Sentence};
+    use udgraph::token::{Token, TokenBuilder};
+
+    use super::{
+        add_latency, add_piece_alignment, annotate_isolated, annotate_skipping, merge_inputs,
+    };
+    use crate::annotator::Annotate;
+    use crate::async_syntaxdot::ErrorBudget;
+    use crate::test_annotator::TestAnnotator;
+
+    /// Annotator that fails on sentences containing the form `boem`.
+    fn failing() -> TestAnnotator {
+        TestAnnotator::tagging("X").fail_on("boem")
+    }
+
+    fn sentence(forms: &[&str]) -> Sentence {
+        forms.iter().map(|&form| Token::new(form)).collect()
+    }
+
+    #[test]
+    fn failures_are_isolated_to_sentences() {
+        let sentences = vec![
+            sentence(&["Een", "zin"]),
+            sentence(&["boem"]),
+            sentence(&["Nog"]),
+        ];
+        let annotated = annotate_isolated(&failing(), &sentences, 2, false, None)
+            .into_iter()
+            .map(|s| s.sentence)
+            .collect::<Vec<_>>();
+
+        assert_eq!(annotated.len(), 3);
+        assert_eq!(
+            annotated[0]
+                .iter()
+                .filter_map(Node::token)
+                .next()
+                .unwrap()
+                .upos(),
+            Some("X")
+        );
+        assert_eq!(
+            annotated[2]
+                .iter()
+                .filter_map(Node::token)
+                .next()
+                .unwrap()
+                .upos(),
+            Some("X")
+        );
+
+        let failed = annotated[1].iter().filter_map(Node::token).next().unwrap();
+        assert_eq!(failed.form(), "boem");
+        assert_eq!(failed.upos(), None);
+        assert_eq!(
+            annotated[1].comments(),
+            &[Comment::AttrVal {
+                attr: "error".to_string(),
+                val: "Cannot annotate `boem`".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn failures_are_skipped_within_budget() {
+        let sentences = vec![
+            sentence(&["boem"]),
+            sentence(&["Een", "zin"]),
+            sentence(&["Nog", "boem"]),
+        ];
+
+        let budget = ErrorBudget::new(2);
+        let (annotated, failed) =
+            annotate_skipping(&failing(), &sentences, 2, false, &budget, None).unwrap();
+        assert_eq!(annotated.len(), 1);
+        assert_eq!(failed, 2);
+        assert_eq!(budget.errors(), 2);
+
+        let budget = ErrorBudget::new(1);
+        assert!(annotate_skipping(&failing(), &sentences, 2, false, &budget, None).is_err());
+    }
+
+    #[test]
+    fn latency_is_added_as_comment() {
+        let mut sentence = sentence(&["Katten"]);
+        add_latency(&mut sentence, Instant::now() - Duration::from_millis(25));
+
+        match sentence.comments() {
+            [Comment::AttrVal { attr, val }] => {
+                assert_eq!(attr, "latency_ms");
+                assert!(val.parse::<u128>().unwrap() >= 25);
+            }
+            comments => panic!("Unexpected comments: {:?}", comments),
+        }
+    }
+
+    #[test]
+    fn input_layers_are_kept() {
+        let mut input: Sentence = vec![
+            TokenBuilder::new("Katten").lemma("kat").into(),
+            Token::new("slapen"),
+        ]
+        .into_iter()
+        .collect();
+        input
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(2, Some("nsubj"), 1));
+        input
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(0, Some("root"), 2));
+
+        let mut predicted: Sentence = vec![
+            TokenBuilder::new("Katten")
+                .lemma("katten")
+                .upos("NOUN")
+                .into(),
+            TokenBuilder::new("slapen").lemma("slapen").into(),
+        ]
+        .into_iter()
+        .collect();
+        predicted
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(0, Some("root"), 1));
+        predicted
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(1, Some("obj"), 2));
+
+        // The first input sentence was skipped by the annotator.
+        let inputs = vec![sentence(&["Lang"]), input];
+        let mut annotated = vec![TestAnnotator::default().pieces(predicted)];
+        merge_inputs(&inputs, &mut annotated);
+
+        let merged = &annotated[0].sentence;
+        let katten = merged[1].token().unwrap();
+        assert_eq!(katten.lemma(), Some("kat"));
+        assert_eq!(katten.upos(), Some("NOUN"));
+        assert_eq!(merged[2].token().unwrap().lemma(), Some("slapen"));
+
+        // The heads form the input tree.
+        let head = merged.dep_graph().head(1).unwrap();
+        assert_eq!(head.head(), 2);
+        assert_eq!(head.relation(), Some("nsubj"));
+        let head = merged.dep_graph().head(2).unwrap();
+        assert_eq!(head.head(), 0);
+        assert_eq!(head.relation(), Some("root"));
+    }
+
+    #[test]
+    fn partial_input_trees_are_not_mixed() {
+        let mut input = sentence(&["Katten", "slapen"]);
+        input
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(2, Some("nsubj"), 1));
+
+        let mut predicted = sentence(&["Katten", "slapen"]);
+        predicted
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(0, Some("root"), 1));
+        predicted
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(1, Some("obj"), 2));
+
+        let mut annotated = vec![TestAnnotator::default().pieces(predicted)];
+        merge_inputs(&[input], &mut annotated);
+
+        // Taking the head of the first token from the input would form
+        // a cycle, so the predicted tree is kept.
+        let merged = &annotated[0].sentence;
+        assert_eq!(merged.dep_graph().head(1).unwrap().head(), 0);
+        let head = merged.dep_graph().head(2).unwrap();
+        assert_eq!(head.head(), 1);
+        assert_eq!(head.relation(), Some("obj"));
+    }
+
+    #[test]
+    fn pieces_are_aligned_to_tokens() {
+        let aligned = add_piece_alignment(SentenceWithPieces {
+            pieces: vec![2, 1043, 27, 8].into(),
+            sentence: sentence(&["Katten", "slapen"]),
+            token_offsets: vec![1, 3],
+        });
+
+        let pieces = aligned
+            .iter()
+            .filter_map(Node::token)
+            .map(|token| token.misc().get("pieces").cloned().flatten())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pieces,
+            vec![Some("1043,27".to_string()), Some("8".to_string())]
+        );
+    }
+}
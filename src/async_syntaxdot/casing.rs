@@ -0,0 +1,136 @@
+use std::pin::Pin;
+
+use futures::io::Error;
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use serde::{Deserialize, Serialize};
+use udgraph::graph::{Node, Sentence};
+
+/// Casing transformation of token forms.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Casing {
+    /// Keep forms as they are.
+    #[default]
+    None,
+
+    /// Lowercase forms.
+    Lower,
+
+    /// Uppercase forms.
+    Upper,
+}
+
+/// Apply a casing transformation to the forms of a sentence.
+///
+/// The original form is stored in the `orth` misc feature, unless the
+/// token already has one (e.g. from unicode cleanup).
+fn apply_sentence_casing(sentence: &mut Sentence, casing: Casing) {
+    if casing == Casing::None {
+        return;
+    }
+
+    for token in sentence.iter_mut().filter_map(Node::token_mut) {
+        let form = token.form();
+        let cased_form = match casing {
+            Casing::None => unreachable!(),
+            Casing::Lower => form.to_lowercase(),
+            Casing::Upper => form.to_uppercase(),
+        };
+
+        if form != cased_form {
+            let form = form.to_string();
+            token
+                .misc_mut()
+                .entry("orth".to_string())
+                .or_insert(Some(form));
+            token.set_form(cased_form);
+        }
+    }
+}
+
+/// Stream that applies a casing transformation to token forms.
+pub struct ApplyCasing<S> {
+    casing: Casing,
+    sentences: Pin<Box<S>>,
+}
+
+impl<S> ApplyCasing<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    pub fn new(casing: Casing, sentences: S) -> Self {
+        ApplyCasing {
+            casing,
+            sentences: Box::pin(sentences),
+        }
+    }
+}
+
+impl<S> Stream for ApplyCasing<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    type Item = Result<Vec<Sentence>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let ApplyCasing { casing, sentences } = &mut *self;
+
+        match ready!(sentences.as_mut().poll_next(cx)) {
+            Some(Ok(mut batch)) => {
+                for sentence in &mut batch {
+                    apply_sentence_casing(sentence, *casing);
+                }
+                Poll::Ready(Some(Ok(batch)))
+            }
+            other => Poll::Ready(other),
+        }
+    }
+}
+
+pub trait ToApplyCasing<S> {
+    fn apply_casing(self, casing: Casing) -> ApplyCasing<S>;
+}
+
+impl<S> ToApplyCasing<S> for S
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    fn apply_casing(self, casing: Casing) -> ApplyCasing<S> {
+        ApplyCasing::new(casing, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use udgraph::graph::{Node, Sentence};
+    use udgraph::token::{Token, TokenBuilder};
+
+    use super::{apply_sentence_casing, Casing};
+
+    #[test]
+    fn casing_keeps_original_form() {
+        let mut sentence: Sentence = vec![
+            Token::new("De"),
+            TokenBuilder::new("HUIS")
+                .misc(iter::once(("orth".to_string(), Some("HUÎS".to_string()))).collect())
+                .into(),
+            Token::new("is"),
+        ]
+        .into_iter()
+        .collect();
+
+        apply_sentence_casing(&mut sentence, Casing::Lower);
+
+        let tokens = sentence.iter().filter_map(Node::token).collect::<Vec<_>>();
+        assert_eq!(tokens[0].form(), "de");
+        assert_eq!(tokens[0].misc()["orth"].as_deref(), Some("De"));
+        assert_eq!(tokens[1].form(), "huis");
+        assert_eq!(tokens[1].misc()["orth"].as_deref(), Some("HUÎS"));
+        assert_eq!(tokens[2].form(), "is");
+        assert!(tokens[2].misc().get("orth").is_none());
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::io::Error;
+use udgraph::graph::{Comment, Sentence};
+
+/// Number of errors that a response can recover from.
+///
+/// Stream stages that share a budget skip input that fails, until more
+/// than the maximum number of errors occurred.
+#[derive(Clone, Debug)]
+pub struct ErrorBudget {
+    errors: Arc<AtomicUsize>,
+    max_errors: usize,
+}
+
+impl ErrorBudget {
+    pub fn new(max_errors: usize) -> Self {
+        ErrorBudget {
+            errors: Arc::new(AtomicUsize::new(0)),
+            max_errors,
+        }
+    }
+
+    /// Record an error.
+    ///
+    /// Returns an error when the maximum number of errors is exceeded.
+    pub fn record(&self, what: &str, err: impl Display) -> Result<(), Error> {
+        let errors = self.errors.fetch_add(1, Ordering::SeqCst) + 1;
+        log::warn!("Skipping {}: {}", what, err);
+
+        if errors > self.max_errors {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Too many errors: {} errors, at most {} are allowed",
+                    errors, self.max_errors
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Number of errors recorded.
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::SeqCst)
+    }
+
+    /// Sentence without tokens that summarizes the skipped input, as
+    /// a `# skipped = N` comment.
+    pub fn summary(&self) -> Sentence {
+        let mut sentence = Sentence::new();
+        sentence.comments_mut().push(Comment::AttrVal {
+            attr: "skipped".to_string(),
+            val: self.errors().to_string(),
+        });
+        sentence
+    }
+}
+
+/// Log the input that caused an error, truncated to `max_bytes`.
+///
+/// The input may contain personal data, so this should only be done when
+/// it is enabled in the configuration.
+pub fn log_failed_input(what: &str, input: &str, max_bytes: usize) {
+    let truncated = truncate_input(input, max_bytes);
+    if truncated.len() < input.len() {
+        log::warn!(
+            "Input of failed {} (truncated to {} of {} bytes): {:?}",
+            what,
+            truncated.len(),
+            input.len(),
+            truncated
+        );
+    } else {
+        log::warn!("Input of failed {}: {:?}", what, truncated);
+    }
+}
+
+/// Truncate input to at most `max_bytes`, at a character boundary.
+fn truncate_input(input: &str, max_bytes: usize) -> &str {
+    let mut end = max_bytes.min(input.len());
+    while !input.is_char_boundary(end) {
+        end -= 1;
+    }
+    &input[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{truncate_input, ErrorBudget};
+
+    #[test]
+    fn budget_is_shared() {
+        let budget = ErrorBudget::new(2);
+        let other = budget.clone();
+
+        assert!(budget.record("line 1", "failed").is_ok());
+        assert!(other.record("line 2", "failed").is_ok());
+        assert!(budget.record("line 3", "failed").is_err());
+        assert_eq!(other.errors(), 3);
+    }
+
+    #[test]
+    fn input_is_truncated_at_char_boundary() {
+        assert_eq!(truncate_input("Katten slapen", 6), "Katten");
+        assert_eq!(truncate_input("Caf\u{e9}", 4), "Caf");
+        assert_eq!(truncate_input("Kat", 16), "Kat");
+    }
+}
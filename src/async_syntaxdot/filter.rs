@@ -0,0 +1,109 @@
+use std::pin::Pin;
+
+use futures::io::Error;
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use regex::Regex;
+use udgraph::graph::{Node, Sentence};
+
+fn has_matching_form(sentence: &Sentence, filter: &Regex) -> bool {
+    sentence
+        .iter()
+        .filter_map(Node::token)
+        .any(|token| filter.is_match(token.form()))
+}
+
+/// Stream that only retains sentences with a token form matching a regular expression.
+///
+/// Sentences without tokens, such as the `# skipped = N` summary, are
+/// always retained.
+pub struct FilterSentences<S> {
+    filter: Option<Regex>,
+    sentences: Pin<Box<S>>,
+}
+
+impl<S> FilterSentences<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    /// Construct a filter, a filter of `None` retains all sentences.
+    pub fn new(filter: Option<Regex>, sentences: S) -> Self {
+        FilterSentences {
+            filter,
+            sentences: Box::pin(sentences),
+        }
+    }
+}
+
+impl<S> Stream for FilterSentences<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    type Item = Result<Vec<Sentence>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let FilterSentences { filter, sentences } = &mut *self;
+
+        match ready!(sentences.as_mut().poll_next(cx)) {
+            Some(Ok(mut sentences)) => {
+                if let Some(filter) = filter {
+                    sentences.retain(|sentence| {
+                        sentence.len() == 1 || has_matching_form(sentence, filter)
+                    });
+                }
+                Poll::Ready(Some(Ok(sentences)))
+            }
+            other => Poll::Ready(other),
+        }
+    }
+}
+
+pub trait ToFilterSentences<S> {
+    fn filter_sentences(self, filter: Option<Regex>) -> FilterSentences<S>;
+}
+
+impl<S> ToFilterSentences<S> for S
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    fn filter_sentences(self, filter: Option<Regex>) -> FilterSentences<S> {
+        FilterSentences::new(filter, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on_stream;
+    use futures::stream::{self, StreamExt};
+    use regex::Regex;
+    use udgraph::graph::{Node, Sentence};
+    use udgraph::token::Token;
+
+    use super::ToFilterSentences;
+
+    #[test]
+    fn retains_matching_sentences() {
+        let sentences: Vec<Sentence> = vec![
+            vec![Token::new("Een"), Token::new("huis")]
+                .into_iter()
+                .collect(),
+            vec![Token::new("Een"), Token::new("boom")]
+                .into_iter()
+                .collect(),
+        ];
+
+        let filtered = block_on_stream(
+            stream::iter(vec![sentences])
+                .map(Ok)
+                .filter_sentences(Some(Regex::new("^boom$").unwrap())),
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].len(), 1);
+        let last_token = filtered[0][0].iter().filter_map(Node::token).last();
+        assert_eq!(last_token.unwrap().form(), "boom");
+    }
+}
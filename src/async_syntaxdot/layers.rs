@@ -0,0 +1,181 @@
+use std::pin::Pin;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use futures::io::Error;
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use udgraph::graph::{Node, Sentence};
+use udgraph::token::Features;
+
+/// Annotation layer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Layer {
+    /// Dependency relations.
+    Deps,
+
+    /// Morphological features.
+    Features,
+
+    /// Lemmas.
+    Lemma,
+
+    /// Universal part-of-speech tags.
+    Upos,
+
+    /// Language-specific part-of-speech tags.
+    Xpos,
+}
+
+impl FromStr for Layer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deps" => Ok(Layer::Deps),
+            "feats" => Ok(Layer::Features),
+            "lemma" => Ok(Layer::Lemma),
+            "upos" => Ok(Layer::Upos),
+            "xpos" => Ok(Layer::Xpos),
+            _ => Err(anyhow!("Unknown annotation layer: {}", s)),
+        }
+    }
+}
+
+/// Remove all annotation layers from a sentence, except the given layers.
+fn select_sentence_layers(sentence: Sentence, layers: &[Layer]) -> Sentence {
+    let mut selected = if layers.contains(&Layer::Deps) {
+        sentence
+    } else {
+        // Rebuild the sentence without the dependency graph.
+        let mut without_deps = sentence
+            .iter()
+            .filter_map(Node::token)
+            .cloned()
+            .collect::<Sentence>();
+        *without_deps.comments_mut() = sentence.comments().to_vec();
+        without_deps
+    };
+
+    for token in selected.iter_mut().filter_map(Node::token_mut) {
+        if !layers.contains(&Layer::Features) {
+            token.set_features(Features::default());
+        }
+
+        if !layers.contains(&Layer::Lemma) {
+            token.set_lemma(None::<String>);
+        }
+
+        if !layers.contains(&Layer::Upos) {
+            token.set_upos(None::<String>);
+        }
+
+        if !layers.contains(&Layer::Xpos) {
+            token.set_xpos(None::<String>);
+        }
+    }
+
+    selected
+}
+
+/// Stream that only retains the selected annotation layers.
+pub struct SelectLayers<S> {
+    layers: Vec<Layer>,
+    sentences: Pin<Box<S>>,
+}
+
+impl<S> SelectLayers<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    pub fn new(layers: Vec<Layer>, sentences: S) -> Self {
+        SelectLayers {
+            layers,
+            sentences: Box::pin(sentences),
+        }
+    }
+}
+
+impl<S> Stream for SelectLayers<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    type Item = Result<Vec<Sentence>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let SelectLayers { layers, sentences } = &mut *self;
+
+        match ready!(sentences.as_mut().poll_next(cx)) {
+            None => Poll::Ready(None),
+            Some(Err(err)) => Poll::Ready(Some(Err(err))),
+            Some(Ok(batch)) => Poll::Ready(Some(Ok(batch
+                .into_iter()
+                .map(|sentence| select_sentence_layers(sentence, layers))
+                .collect()))),
+        }
+    }
+}
+
+pub trait ToSelectLayers<S> {
+    fn select_layers(self, layers: Vec<Layer>) -> SelectLayers<S>;
+}
+
+impl<S> ToSelectLayers<S> for S
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    fn select_layers(self, layers: Vec<Layer>) -> SelectLayers<S> {
+        SelectLayers::new(layers, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udgraph::graph::{DepTriple, Node, Sentence};
+    use udgraph::token::TokenBuilder;
+
+    use super::{select_sentence_layers, Layer};
+
+    fn sentence() -> Sentence {
+        let mut sentence: Sentence = vec![
+            TokenBuilder::new("Katten")
+                .lemma("kat")
+                .upos("NOUN")
+                .xpos("N")
+                .into(),
+            TokenBuilder::new("slapen")
+                .lemma("slapen")
+                .upos("VERB")
+                .xpos("WW")
+                .into(),
+        ]
+        .into_iter()
+        .collect();
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(2, Some("nsubj"), 1));
+        sentence
+    }
+
+    #[test]
+    fn selects_lemma_layer() {
+        let selected = select_sentence_layers(sentence(), &[Layer::Lemma]);
+        let tokens = selected.iter().filter_map(Node::token).collect::<Vec<_>>();
+        assert_eq!(tokens[0].lemma(), Some("kat"));
+        assert_eq!(tokens[0].upos(), None);
+        assert_eq!(tokens[1].xpos(), None);
+        assert!(selected.dep_graph().head(1).is_none());
+    }
+
+    #[test]
+    fn selects_deps_layer() {
+        let selected = select_sentence_layers(sentence(), &[Layer::Deps]);
+        let tokens = selected.iter().filter_map(Node::token).collect::<Vec<_>>();
+        assert_eq!(tokens[0].lemma(), None);
+        assert_eq!(
+            selected.dep_graph().head(1).map(|triple| triple.head()),
+            Some(2)
+        );
+    }
+}
@@ -3,12 +3,58 @@ use std::io::ErrorKind;
 use std::pin::Pin;
 
 use anyhow::Result;
-use async_std::task::spawn;
 use futures::io::Error;
 use futures::ready;
 use futures::stream::Stream;
 use futures::task::{Context, Poll};
-use udgraph::graph::{Comment, Sentence};
+use serde::{Deserialize, Serialize};
+use udgraph::graph::{Comment, Node, Sentence};
+
+use crate::runtime::spawn;
+
+/// Source of the `# text = ...` comment of sentences.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SentenceText {
+    /// The original text of the sentence in the input.
+    ///
+    /// Falls back to the tokens of the sentence when the original text
+    /// is not available.
+    Line,
+
+    /// The tokens of the sentence, separated by spaces.
+    Tokens,
+}
+
+/// Set the `text` comment of a sentence.
+fn set_sentence_text(sentence: &mut Sentence, sentence_text: SentenceText) {
+    let has_text = sentence
+        .comments()
+        .iter()
+        .any(|comment| matches!(comment, Comment::AttrVal { attr, .. } if attr == "text"));
+
+    if sentence_text == SentenceText::Line && has_text {
+        return;
+    }
+
+    let text = sentence
+        .iter()
+        .filter_map(Node::token)
+        .map(|token| token.form())
+        .collect::<Vec<_>>()
+        .join(" ");
+    set_attr_val(sentence, "text", &text);
+}
+
+/// Set an attribute-value comment, replacing an existing value.
+fn set_attr_val(sentence: &mut Sentence, attr: &str, val: &str) {
+    let comments = sentence.comments_mut();
+    comments.retain(|comment| !matches!(comment, Comment::AttrVal { attr: a, .. } if a == attr));
+    comments.push(Comment::AttrVal {
+        attr: attr.to_string(),
+        val: val.to_string(),
+    });
+}
 
 enum MetadataState {
     Sentences,
@@ -17,7 +63,9 @@ enum MetadataState {
 
 /// Stream that adds metadata to sentences.
 pub struct Metadata<S> {
+    device: Option<String>,
     pipeline_name: String,
+    sentence_text: Option<SentenceText>,
     sentences: Pin<Box<S>>,
     state: MetadataState,
 }
@@ -28,11 +76,25 @@ where
 {
     pub fn new(pipeline_name: String, sentences: S) -> Self {
         Metadata {
+            device: None,
             pipeline_name,
+            sentence_text: None,
             sentences: Box::pin(sentences),
             state: MetadataState::Sentences,
         }
     }
+
+    /// Add `# device = ...` comments with the device of the annotator.
+    pub fn device(mut self, device: Option<String>) -> Self {
+        self.device = device;
+        self
+    }
+
+    /// Add `# text = ...` comments to sentences.
+    pub fn sentence_text(mut self, sentence_text: Option<SentenceText>) -> Self {
+        self.sentence_text = sentence_text;
+        self
+    }
 }
 
 impl<S> Stream for Metadata<S>
@@ -43,7 +105,9 @@ where
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         let Metadata {
+            device,
             pipeline_name,
+            sentence_text,
             sentences,
             state,
         } = &mut *self;
@@ -56,14 +120,21 @@ where
                     Some(Ok(sentences)) => {
                         let mut sentences_with_metadata = sentences.clone();
 
+                        let device = device.clone();
                         let pipeline_name = pipeline_name.clone();
+                        let sentence_text = *sentence_text;
 
                         let future = spawn(async move {
                             for sentence in &mut sentences_with_metadata {
-                                sentence.comments_mut().push(Comment::AttrVal {
-                                    attr: "pipeline".to_string(),
-                                    val: pipeline_name.to_owned(),
-                                });
+                                set_attr_val(sentence, "pipeline", &pipeline_name);
+
+                                if let Some(device) = &device {
+                                    set_attr_val(sentence, "device", device);
+                                }
+
+                                if let Some(sentence_text) = sentence_text {
+                                    set_sentence_text(sentence, sentence_text);
+                                }
                             }
 
                             Ok(sentences_with_metadata)
@@ -97,3 +168,53 @@ where
         Metadata::new(pipline_name.to_string(), self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use udgraph::graph::{Comment, Sentence};
+    use udgraph::token::Token;
+
+    use super::{set_sentence_text, SentenceText};
+
+    fn sentence(text: Option<&str>) -> Sentence {
+        let mut sentence: Sentence = vec![Token::new("Een"), Token::new("zin"), Token::new(".")]
+            .into_iter()
+            .collect();
+        if let Some(text) = text {
+            sentence.comments_mut().push(Comment::AttrVal {
+                attr: "text".to_string(),
+                val: text.to_string(),
+            });
+        }
+        sentence
+    }
+
+    fn text(sentence: &Sentence) -> Vec<&str> {
+        sentence
+            .comments()
+            .iter()
+            .filter_map(|comment| match comment {
+                Comment::AttrVal { attr, val } if attr == "text" => Some(val.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn line_text_is_preserved() {
+        let mut with_text = sentence(Some("Een zin."));
+        set_sentence_text(&mut with_text, SentenceText::Line);
+        assert_eq!(text(&with_text), vec!["Een zin."]);
+
+        let mut without_text = sentence(None);
+        set_sentence_text(&mut without_text, SentenceText::Line);
+        assert_eq!(text(&without_text), vec!["Een zin ."]);
+    }
+
+    #[test]
+    fn tokens_text_replaces_line_text() {
+        let mut with_text = sentence(Some("Een zin."));
+        set_sentence_text(&mut with_text, SentenceText::Tokens);
+        assert_eq!(text(&with_text), vec!["Een zin ."]);
+    }
+}
@@ -1,14 +1,53 @@
 mod annotations;
-pub use annotations::{Annotations, ToAnnotations};
+pub use annotations::ToAnnotations;
+
+mod casing;
+pub use casing::{Casing, ToApplyCasing};
+
+mod errors;
+pub use errors::ErrorBudget;
+
+mod filter;
+pub use filter::ToFilterSentences;
+
+mod layers;
+pub use layers::{Layer, ToSelectLayers};
 
 mod metadata;
-pub use metadata::ToMetadata;
+pub use metadata::{SentenceText, ToMetadata};
+
+mod post_process;
+pub use post_process::ToPostProcess;
+
+mod publish;
+pub use publish::ToPublish;
 
 mod unicode_cleanup;
-pub use unicode_cleanup::{ToUnicodeCleanup, UnicodeCleanup};
+pub use unicode_cleanup::ToUnicodeCleanup;
 
 mod sentences;
-pub use sentences::{Sentences, ToSentences};
+pub use sentences::ToSentences;
+
+mod shutdown;
+pub use shutdown::ToFinishOnShutdown;
+
+mod slow;
+pub use slow::ToLogSlow;
+
+mod stats;
+pub use stats::AnnotationStats;
+
+mod tag_map;
+pub use tag_map::{TagMap, ToMapTags};
+
+mod timing;
+pub use timing::StageTimings;
+
+mod truncate;
+pub use truncate::ToTruncate;
+
+mod upos;
+pub use upos::{ToFilterUpos, UposFilterMode};
 
 mod unicode;
-pub use unicode::Normalization;
+pub use unicode::{ControlCharacters, Normalization};
@@ -0,0 +1,103 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::io::Error;
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use udgraph::graph::Sentence;
+
+use crate::plugin::WasmPlugin;
+use crate::runtime::spawn_blocking;
+
+/// Post-process a batch of sentences with a plugin.
+fn process_batch(plugin: &WasmPlugin, batch: &[Sentence]) -> Result<Vec<Sentence>, Error> {
+    batch
+        .iter()
+        .map(|sentence| plugin.process(sentence))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| Error::other(format!("Plugin failed: {:#}", err)))
+}
+
+/// Batch that is post-processed on a thread for blocking tasks.
+type PendingBatch = Pin<Box<dyn Future<Output = Result<Vec<Sentence>, Error>> + Send + Sync>>;
+
+/// Stream that post-processes annotated sentences with a WASM plugin.
+///
+/// Plugins run on the threads for blocking tasks, one batch at a time,
+/// so that slow plugins do not block the thread that polls the stream.
+pub struct PostProcess<S> {
+    pending: Option<PendingBatch>,
+    plugin: Option<Arc<WasmPlugin>>,
+    sentences: Pin<Box<S>>,
+}
+
+impl<S> PostProcess<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    /// Construct the stage, a plugin of `None` leaves all sentences
+    /// unchanged.
+    pub fn new(plugin: Option<Arc<WasmPlugin>>, sentences: S) -> Self {
+        PostProcess {
+            pending: None,
+            plugin,
+            sentences: Box::pin(sentences),
+        }
+    }
+}
+
+impl<S> Stream for PostProcess<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    type Item = Result<Vec<Sentence>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let PostProcess {
+            pending,
+            plugin,
+            sentences,
+        } = &mut *self;
+
+        let plugin = match plugin {
+            Some(plugin) => plugin,
+            None => return sentences.as_mut().poll_next(cx),
+        };
+
+        if pending.is_none() {
+            match ready!(sentences.as_mut().poll_next(cx)) {
+                Some(Ok(batch)) => {
+                    let plugin = plugin.clone();
+                    *pending = Some(Box::pin(spawn_blocking(move || {
+                        process_batch(&plugin, &batch)
+                    })));
+                }
+                other => return Poll::Ready(other),
+            }
+        }
+
+        let processed = ready!(pending
+            .as_mut()
+            .expect("No pending post-processing")
+            .as_mut()
+            .poll(cx));
+        *pending = None;
+
+        Poll::Ready(Some(processed))
+    }
+}
+
+pub trait ToPostProcess<S> {
+    fn post_process(self, plugin: Option<Arc<WasmPlugin>>) -> PostProcess<S>;
+}
+
+impl<S> ToPostProcess<S> for S
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    fn post_process(self, plugin: Option<Arc<WasmPlugin>>) -> PostProcess<S> {
+        PostProcess::new(plugin, self)
+    }
+}
@@ -0,0 +1,91 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::io::Error;
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use udgraph::graph::Sentence;
+
+use crate::kafka::KafkaSink;
+
+type PublishFuture = Pin<Box<dyn Future<Output = Result<(), Error>> + Send + Sync>>;
+
+/// Stream that publishes annotated sentences to a Kafka sink.
+///
+/// A batch is produced once its sentences are delivered. When the sink
+/// skips the response, batches are produced without sentences.
+pub struct Publish<S> {
+    pending: Option<(PublishFuture, Vec<Sentence>)>,
+    sentences: Pin<Box<S>>,
+    sink: Option<Arc<KafkaSink>>,
+}
+
+impl<S> Publish<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    /// Construct the stage, a sink of `None` does not publish.
+    pub fn new(sink: Option<Arc<KafkaSink>>, sentences: S) -> Self {
+        Publish {
+            pending: None,
+            sentences: Box::pin(sentences),
+            sink,
+        }
+    }
+}
+
+impl<S> Stream for Publish<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    type Item = Result<Vec<Sentence>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let Publish {
+            pending,
+            sentences,
+            sink,
+        } = &mut *self;
+
+        let sink = match sink {
+            Some(sink) => sink,
+            None => return sentences.as_mut().poll_next(cx),
+        };
+
+        if pending.is_none() {
+            match ready!(sentences.as_mut().poll_next(cx)) {
+                Some(Ok(batch)) => {
+                    let future = Box::pin(sink.publish(&batch));
+                    let batch = if sink.skip_response() {
+                        Vec::new()
+                    } else {
+                        batch
+                    };
+                    *pending = Some((future, batch));
+                }
+                other => return Poll::Ready(other),
+            }
+        }
+
+        let (future, _) = pending.as_mut().expect("No pending publication");
+        let result = ready!(future.as_mut().poll(cx));
+        let (_, batch) = pending.take().expect("No pending publication");
+
+        Poll::Ready(Some(result.map(|()| batch)))
+    }
+}
+
+pub trait ToPublish<S> {
+    fn publish(self, sink: Option<Arc<KafkaSink>>) -> Publish<S>;
+}
+
+impl<S> ToPublish<S> for S
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    fn publish(self, sink: Option<Arc<KafkaSink>>) -> Publish<S> {
+        Publish::new(sink, self)
+    }
+}
@@ -1,31 +1,185 @@
 use std::collections::VecDeque;
 use std::future::Future;
 use std::io::ErrorKind;
+use std::mem;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use alpino_tokenizer::Tokenizer;
-use async_std::task::spawn;
 use futures::io::Error;
 use futures::ready;
-use futures::stream::Stream;
+use futures::stream::{FuturesOrdered, Stream, StreamExt};
 use futures::task::{Context, Poll};
-use udgraph::graph::Sentence;
+use udgraph::graph::{Comment, Sentence};
 use udgraph::token::Token;
 
+use super::errors::{log_failed_input, ErrorBudget};
+use super::timing::StageTimings;
+use super::unicode::{clean_control_characters, normalize, ControlCharacters, Normalization};
+use crate::runtime::{sleep, spawn};
+
 type TokenizedSentences = Vec<Vec<String>>;
 
-enum SentencesState {
-    Lines,
-    Tokenize(Pin<Box<dyn Future<Output = Option<TokenizedSentences>> + Send + Sync>>),
-    Sentences(VecDeque<Sentence>),
+/// Initial delay before retrying a failed tokenization, doubled on every retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Tokenization of an input line.
+struct TokenizedLine {
+    /// Number of control characters that were removed or replaced.
+    control_characters: usize,
+
+    /// Line number, starting at 1.
+    line: usize,
+
+    /// Input text of the line, only retained for `# input_text = ...`
+    /// comments.
+    input_text: Option<String>,
+
+    /// Whether the line starts a document.
+    new_document: bool,
+
+    /// Text of the line, only retained for `# text = ...` comments.
+    text: Option<String>,
+
+    tokens: Result<TokenizedSentences, Error>,
+}
+
+/// Remove tokens with empty or whitespace-only forms.
+///
+/// Tokenizers can produce such tokens for inputs with consecutive
+/// separators, but they cannot be represented in the output formats.
+fn remove_empty_tokens(sentences: &mut TokenizedSentences) {
+    for sentence in sentences.iter_mut() {
+        sentence.retain(|token| !token.trim().is_empty());
+    }
+}
+
+/// Find the text of each sentence in the line that it was tokenized from.
+///
+/// Returns `None` for a sentence when its tokens cannot be found in
+/// order in the line.
+fn sentence_texts<'a>(line: &'a str, sentences: &[Vec<String>]) -> Vec<Option<&'a str>> {
+    let mut cursor = 0;
+    let mut texts = Vec::with_capacity(sentences.len());
+
+    for sentence in sentences {
+        let mut start = None;
+        let mut end = cursor;
+        let mut found = true;
+
+        for token in sentence {
+            match line[end..].find(token.as_str()) {
+                Some(idx) => {
+                    start.get_or_insert(end + idx);
+                    end += idx + token.len();
+                }
+                None => {
+                    found = false;
+                    break;
+                }
+            }
+        }
+
+        match start {
+            Some(start) if found => {
+                texts.push(Some(&line[start..end]));
+                cursor = end;
+            }
+            _ => texts.push(None),
+        }
+    }
+
+    texts
+}
+
+/// Tokenize a line, converting tokenizer failures and panics to errors.
+fn tokenize_line(tokenizer: &dyn Tokenizer, line: &str) -> Result<TokenizedSentences, Error> {
+    match catch_unwind(AssertUnwindSafe(|| tokenizer.tokenize(line))) {
+        Ok(Some(tokens)) => Ok(tokens),
+        Ok(None) => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Cannot tokenize data".to_string(),
+        )),
+        Err(_) => Err(Error::other("Tokenizer panicked".to_string())),
+    }
+}
+
+/// Check whether a tokenization error can be resolved by retrying.
+///
+/// A tokenizer that cannot tokenize a line will fail on every attempt,
+/// so only panics are retried.
+fn is_transient(err: &Error) -> bool {
+    err.kind() != ErrorKind::InvalidData
+}
+
+/// Tokenize a line, retrying transient failures up to `retries` times
+/// with exponential backoff.
+///
+/// Every attempt is a separate task, the backoff between attempts does
+/// not occupy a tokenization task.
+async fn tokenize_with_retries(
+    tokenizer: Arc<dyn Tokenizer + Send + Sync>,
+    mut line: String,
+    retries: usize,
+) -> (String, Result<TokenizedSentences, Error>) {
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 0;
+
+    loop {
+        let attempt_tokenizer = tokenizer.clone();
+        let (attempt_line, tokens) = spawn(async move {
+            let tokens = tokenize_line(attempt_tokenizer.as_ref(), &line);
+            (line, tokens)
+        })
+        .await;
+        line = attempt_line;
+
+        match tokens {
+            Ok(tokens) => return (line, Ok(tokens)),
+            Err(err) if is_transient(&err) && attempt < retries => {
+                attempt += 1;
+                log::warn!(
+                    "Tokenization failed ({}), retry {} of {}",
+                    err,
+                    attempt,
+                    retries
+                );
+                sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(err) => return (line, Err(err)),
+        }
+    }
 }
 
-/// Stream that tokenizes sentences.
 /// Stream that tokenizes sentences.
 pub struct Sentences<L> {
+    blank_text: String,
+    control_characters: ControlCharacters,
+    document_start: bool,
+    documents: bool,
+    error_budget: Option<ErrorBudget>,
+    input_ids: bool,
+    input_text: bool,
+    line: usize,
+    line_numbers: bool,
     lines: Pin<Box<L>>,
-    state: SentencesState,
+    lines_done: bool,
+    log_failed_input: Option<usize>,
+    max_concurrent_tokenizations: usize,
+    newdoc_pending: bool,
+    normalization: Option<Normalization>,
+    pending: FuturesOrdered<Pin<Box<dyn Future<Output = TokenizedLine> + Send + Sync>>>,
+    retries: usize,
+    sentence_per_line: bool,
+    sentence_text: bool,
+    sentences: VecDeque<Sentence>,
+    sentenceless_text: String,
+    skip_empty_sentences: bool,
+    strip_bom: bool,
+    timings: Option<StageTimings>,
     tokenizer: Arc<dyn Tokenizer + Send + Sync>,
 }
 
@@ -35,11 +189,279 @@ where
 {
     pub fn new(tokenizer: Arc<dyn Tokenizer + Send + Sync>, lines: L) -> Self {
         Sentences {
+            blank_text: String::new(),
+            control_characters: ControlCharacters::default(),
+            document_start: true,
+            documents: false,
+            error_budget: None,
+            input_ids: false,
+            input_text: false,
+            line: 0,
+            line_numbers: false,
             lines: Box::pin(lines),
-            state: SentencesState::Lines,
+            lines_done: false,
+            log_failed_input: None,
+            max_concurrent_tokenizations: 1,
+            newdoc_pending: false,
+            normalization: None,
+            pending: FuturesOrdered::new(),
+            retries: 0,
+            sentence_per_line: false,
+            sentence_text: false,
+            sentences: VecDeque::new(),
+            sentenceless_text: String::new(),
+            skip_empty_sentences: false,
+            strip_bom: true,
+            timings: None,
             tokenizer,
         }
     }
+
+    /// Strip or replace control characters before tokenization.
+    ///
+    /// The first sentence of a line with control characters gets a
+    /// `# control_characters = N` comment with their number.
+    pub fn control_characters(mut self, control_characters: ControlCharacters) -> Self {
+        self.control_characters = control_characters;
+        self
+    }
+
+    /// Treat blank lines as document separators.
+    ///
+    /// The first sentence of each document gets a `# newdoc` comment.
+    /// Consecutive blank lines separate documents only once.
+    pub fn documents(mut self, documents: bool) -> Self {
+        self.documents = documents;
+        self
+    }
+
+    /// Skip lines that cannot be tokenized, recording them in the budget.
+    ///
+    /// The stream fails once the budget is exhausted.
+    pub fn error_budget(mut self, error_budget: Option<ErrorBudget>) -> Self {
+        self.error_budget = error_budget;
+        self
+    }
+
+    /// Add an `# input_id = N` comment to every sentence of input line `N`.
+    ///
+    /// This identifies the input line of sentences that are output out
+    /// of order. Lines are numbered from 1, as with
+    /// [`Sentences::line_numbers`].
+    pub fn input_ids(mut self, input_ids: bool) -> Self {
+        self.input_ids = input_ids;
+        self
+    }
+
+    /// Add an `# input_text = ...` comment with the input text to the
+    /// first sentence of every line.
+    ///
+    /// The text is the line as it was read, before control character
+    /// cleanup and normalization, followed by a newline. Preceding empty
+    /// lines and lines without sentences are included, so that the
+    /// comments together contain the input up to the last sentence.
+    pub fn input_text(mut self, input_text: bool) -> Self {
+        self.input_text = input_text;
+        self
+    }
+
+    /// Add a `# line = N` comment to the first sentence of input line `N`.
+    ///
+    /// Lines are numbered from 1. Empty lines are counted, but do not
+    /// produce sentences.
+    pub fn line_numbers(mut self, line_numbers: bool) -> Self {
+        self.line_numbers = line_numbers;
+        self
+    }
+
+    /// Log up to `max_bytes` of lines that cannot be tokenized.
+    pub fn log_failed_input(mut self, max_bytes: Option<usize>) -> Self {
+        self.log_failed_input = max_bytes;
+        self
+    }
+
+    /// Tokenize up to this number of lines concurrently, one by default.
+    ///
+    /// Reading of lines is suspended while the maximum number of
+    /// tokenizations is in flight. Sentences are produced in input order.
+    pub fn max_concurrent_tokenizations(mut self, max_concurrent_tokenizations: usize) -> Self {
+        self.max_concurrent_tokenizations = max_concurrent_tokenizations.max(1);
+        self
+    }
+
+    /// Normalize lines before tokenization.
+    ///
+    /// This is separate from [`UnicodeCleanup`](super::UnicodeCleanup),
+    /// which cleans up tokens after tokenization.
+    pub fn normalization(mut self, normalization: Option<Normalization>) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Retry failed tokenizations up to `retries` times.
+    ///
+    /// Lines that the tokenizer cannot tokenize are not retried.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Produce exactly one sentence per input line.
+    ///
+    /// The tokenizer is still used to split tokens, but its sentence
+    /// segmentation is discarded.
+    pub fn sentence_per_line(mut self, sentence_per_line: bool) -> Self {
+        self.sentence_per_line = sentence_per_line;
+        self
+    }
+
+    /// Skip sentences without tokens.
+    ///
+    /// Tokenizers can produce empty sentences, for instance for a line
+    /// that only consists of separators. These are kept by default.
+    pub fn skip_empty_sentences(mut self, skip_empty_sentences: bool) -> Self {
+        self.skip_empty_sentences = skip_empty_sentences;
+        self
+    }
+
+    /// Remove a byte order mark at the start of the input, enabled by
+    /// default.
+    ///
+    /// The byte order mark (U+FEFF) would otherwise become part of the
+    /// first token.
+    pub fn strip_bom(mut self, strip_bom: bool) -> Self {
+        self.strip_bom = strip_bom;
+        self
+    }
+
+    /// Record the time spent tokenizing lines in `timings`.
+    pub fn timings(mut self, timings: Option<StageTimings>) -> Self {
+        self.timings = timings;
+        self
+    }
+
+    /// Add a `# text = ...` comment with the original text of sentences.
+    ///
+    /// The comment is only added when the sentence text can be found in
+    /// the input line.
+    pub fn sentence_text(mut self, sentence_text: bool) -> Self {
+        self.sentence_text = sentence_text;
+        self
+    }
+}
+
+impl<L> Sentences<L> {
+    /// Convert the tokenization of a line to sentences.
+    ///
+    /// Fails when the line cannot be tokenized and there is no error
+    /// budget for skipping it.
+    fn line_sentences(&mut self, tokenized: TokenizedLine) -> Result<VecDeque<Sentence>, Error> {
+        let TokenizedLine {
+            control_characters,
+            input_text,
+            line,
+            new_document,
+            text,
+            tokens,
+        } = tokenized;
+
+        // Text of lines without sentences is passed on to the next line.
+        if let Some(input_text) = input_text {
+            self.sentenceless_text.push_str(&input_text);
+        }
+
+        let mut tokens = match (tokens, &self.error_budget) {
+            (Ok(tokens), _) => tokens,
+            (Err(err), Some(budget)) => {
+                budget.record(&format!("line {}", line), err)?;
+                return Ok(VecDeque::new());
+            }
+            (Err(err), None) => return Err(err),
+        };
+
+        if self.sentence_per_line {
+            tokens = vec![tokens.into_iter().flatten().collect()];
+        }
+
+        remove_empty_tokens(&mut tokens);
+        if self.skip_empty_sentences {
+            tokens.retain(|sentence| !sentence.is_empty());
+        }
+
+        let texts = match &text {
+            Some(text) => sentence_texts(text, &tokens),
+            None => vec![None; tokens.len()],
+        };
+
+        let mut sentences: VecDeque<_> = tokens
+            .into_iter()
+            .zip(texts)
+            .map(|(s, text)| {
+                // Tokenizers return syntactic words, so contractions are
+                // already split into separate tokens. udgraph sentences
+                // cannot represent multiword tokens, so tokens get
+                // consecutive 1-based IDs without `1-2` ranges.
+                let mut sentence = s.into_iter().map(Token::new).collect::<Sentence>();
+                if let Some(text) = text {
+                    sentence.comments_mut().push(Comment::AttrVal {
+                        attr: "text".to_string(),
+                        val: text.to_string(),
+                    });
+                }
+                sentence
+            })
+            .collect();
+
+        // A line without sentences passes the start of its document on
+        // to the next line.
+        self.newdoc_pending |= new_document;
+        if self.documents && self.newdoc_pending {
+            if let Some(sentence) = sentences.front_mut() {
+                sentence
+                    .comments_mut()
+                    .push(Comment::String("newdoc".to_string()));
+                self.newdoc_pending = false;
+            }
+        }
+
+        if self.input_ids {
+            for sentence in &mut sentences {
+                sentence.comments_mut().push(Comment::AttrVal {
+                    attr: "input_id".to_string(),
+                    val: line.to_string(),
+                });
+            }
+        }
+
+        if control_characters > 0 {
+            if let Some(sentence) = sentences.front_mut() {
+                sentence.comments_mut().push(Comment::AttrVal {
+                    attr: "control_characters".to_string(),
+                    val: control_characters.to_string(),
+                });
+            }
+        }
+
+        if self.line_numbers {
+            if let Some(sentence) = sentences.front_mut() {
+                sentence.comments_mut().push(Comment::AttrVal {
+                    attr: "line".to_string(),
+                    val: line.to_string(),
+                });
+            }
+        }
+
+        if self.input_text {
+            if let Some(sentence) = sentences.front_mut() {
+                sentence.comments_mut().push(Comment::AttrVal {
+                    attr: "input_text".to_string(),
+                    val: mem::take(&mut self.sentenceless_text),
+                });
+            }
+        }
+
+        Ok(sentences)
+    }
 }
 
 impl<L> Stream for Sentences<L>
@@ -49,53 +471,104 @@ where
     type Item = Result<Sentence, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let Sentences {
-            lines,
-            state,
-            tokenizer,
-        } = &mut *self;
-
         loop {
-            match state {
-                SentencesState::Lines => match ready!(lines.as_mut().poll_next(cx)) {
-                    None => return Poll::Ready(None),
-                    Some(Err(err)) => return Poll::Ready(Some(Err(err))),
-                    Some(Ok(line)) => {
-                        // Do not process empty lines.
-                        if line.trim().is_empty() {
-                            continue;
-                        }
-
-                        let tokenizer = tokenizer.clone();
-                        let future = spawn(async move { tokenizer.tokenize(&line) });
-                        *state = SentencesState::Tokenize(Box::pin(future));
+            if let Some(sentence) = self.sentences.pop_front() {
+                return Poll::Ready(Some(Ok(sentence)));
+            }
+
+            // Read lines until the maximum number of tokenizations is in
+            // flight, reading is resumed when tokenizations finish.
+            while !self.lines_done && self.pending.len() < self.max_concurrent_tokenizations {
+                let mut text = match self.lines.as_mut().poll_next(cx) {
+                    Poll::Pending => break,
+                    Poll::Ready(None) => {
+                        self.lines_done = true;
+                        break;
                     }
-                },
-                SentencesState::Tokenize(future) => match ready!(future.as_mut().poll(cx)) {
-                    None => {
-                        return Poll::Ready(Some(Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "Cannot tokenize data".to_string(),
-                        ))))
+                    Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                    Poll::Ready(Some(Ok(text))) => text,
+                };
+
+                self.line += 1;
+
+                if self.line == 1 && self.strip_bom {
+                    if let Some(stripped) = text.strip_prefix('\u{feff}') {
+                        text = stripped.to_string();
                     }
-                    Some(tokens) => {
-                        let sentences = tokens
-                            .into_iter()
-                            .map(|s| s.into_iter().map(Token::new).collect::<Sentence>())
-                            .collect();
-                        *state = SentencesState::Sentences(sentences);
+                }
+
+                let input_text = if self.input_text {
+                    let mut input_text = mem::take(&mut self.blank_text);
+                    input_text.push_str(&text);
+                    input_text.push('\n');
+                    Some(input_text)
+                } else {
+                    None
+                };
+
+                let (cleaned, control_characters) =
+                    clean_control_characters(&text, self.control_characters);
+                text = cleaned;
+
+                if let Some(normalization) = self.normalization {
+                    text = normalize(&text, normalization);
+                }
+
+                // Do not process empty lines.
+                if text.trim().is_empty() {
+                    if let Some(input_text) = input_text {
+                        self.blank_text = input_text;
                     }
-                },
-                SentencesState::Sentences(sentences) => {
-                    if sentences.is_empty() {
-                        *state = SentencesState::Lines;
-                        continue;
+                    self.document_start = true;
+                    continue;
+                }
+
+                let line = self.line;
+                let new_document = mem::replace(&mut self.document_start, false);
+                let line_text = if self.sentence_text {
+                    Some(text.clone())
+                } else {
+                    None
+                };
+                let tokenizer = self.tokenizer.clone();
+                let log_input = self.log_failed_input;
+                let retries = self.retries;
+                let timings = self.timings.clone();
+
+                self.pending.push_back(Box::pin(spawn(async move {
+                    let start = Instant::now();
+                    let (text, tokens) = tokenize_with_retries(tokenizer, text, retries).await;
+                    if let Some(timings) = timings {
+                        timings.record_tokenization(start.elapsed());
                     }
 
-                    return Poll::Ready(Some(Ok(sentences
-                        .pop_front()
-                        .expect("Attempted to pop from empty buffer?"))));
-                }
+                    if let (Err(_), Some(max_bytes)) = (&tokens, log_input) {
+                        log_failed_input(
+                            &format!("tokenization of line {}", line),
+                            &text,
+                            max_bytes,
+                        );
+                    }
+
+                    TokenizedLine {
+                        control_characters,
+                        input_text,
+                        line,
+                        new_document,
+                        text: line_text,
+                        tokens,
+                    }
+                })));
+            }
+
+            match ready!(self.pending.poll_next_unpin(cx)) {
+                Some(tokenized) => match self.line_sentences(tokenized) {
+                    Ok(sentences) => self.sentences = sentences,
+                    Err(err) => return Poll::Ready(Some(Err(err))),
+                },
+                None if self.lines_done => return Poll::Ready(None),
+                // Waiting for the next line.
+                None => return Poll::Pending,
             }
         }
     }
@@ -113,3 +586,448 @@ where
         Sentences::new(tokenizer, self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use alpino_tokenizer::Tokenizer;
+    use futures::executor::block_on_stream;
+    use futures::stream::{self, StreamExt};
+    use udgraph::graph::{Comment, Node, Sentence};
+
+    use super::{remove_empty_tokens, sentence_texts, ToSentences};
+    use crate::async_syntaxdot::{ControlCharacters, Normalization};
+
+    /// Tokenizer that splits sentences on `.` and tokens on spaces.
+    struct PeriodTokenizer;
+
+    impl Tokenizer for PeriodTokenizer {
+        fn tokenize(&self, text: &str) -> Option<Vec<Vec<String>>> {
+            Some(
+                text.split('.')
+                    .filter(|s| !s.trim().is_empty())
+                    .map(|s| s.split_whitespace().map(ToOwned::to_owned).collect())
+                    .collect(),
+            )
+        }
+    }
+
+    /// Tokenizer that splits tokens on every space, producing empty
+    /// tokens for consecutive spaces.
+    struct SpaceTokenizer;
+
+    impl Tokenizer for SpaceTokenizer {
+        fn tokenize(&self, text: &str) -> Option<Vec<Vec<String>>> {
+            Some(
+                text.split('\n')
+                    .map(|s| s.split(' ').map(ToOwned::to_owned).collect())
+                    .collect(),
+            )
+        }
+    }
+
+    /// Tokenizer that panics on the first `failures` calls, and splits
+    /// tokens on spaces afterwards.
+    struct FlakyTokenizer {
+        calls: AtomicUsize,
+        failures: usize,
+    }
+
+    impl Tokenizer for FlakyTokenizer {
+        fn tokenize(&self, text: &str) -> Option<Vec<Vec<String>>> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                panic!("Flaky tokenizer failure");
+            }
+            Some(vec![text.split(' ').map(ToOwned::to_owned).collect()])
+        }
+    }
+
+    /// Tokenizer that cannot tokenize any input.
+    #[derive(Default)]
+    struct RejectingTokenizer {
+        calls: AtomicUsize,
+    }
+
+    impl Tokenizer for RejectingTokenizer {
+        fn tokenize(&self, _text: &str) -> Option<Vec<Vec<String>>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            None
+        }
+    }
+
+    fn forms(sentence: &Sentence) -> Vec<String> {
+        sentence
+            .iter()
+            .filter_map(Node::token)
+            .map(|t| t.form().to_string())
+            .collect()
+    }
+
+    fn tokenize(lines: &[&str], sentence_per_line: bool) -> Vec<Vec<String>> {
+        let lines = lines.iter().map(ToString::to_string).collect::<Vec<_>>();
+        block_on_stream(
+            stream::iter(lines)
+                .map(Ok)
+                .sentences(Arc::new(PeriodTokenizer))
+                .sentence_per_line(sentence_per_line),
+        )
+        .map(|s| forms(&s.unwrap()))
+        .collect()
+    }
+
+    #[test]
+    fn line_numbers_mark_first_sentence_of_line() {
+        let lines = vec!["Een zin. Nog een zin.", "", "Derde zin"]
+            .into_iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let comments = block_on_stream(
+            stream::iter(lines)
+                .map(Ok)
+                .sentences(Arc::new(PeriodTokenizer))
+                .line_numbers(true),
+        )
+        .map(|s| s.unwrap().comments().to_vec())
+        .collect::<Vec<_>>();
+
+        let line = |n: usize| Comment::AttrVal {
+            attr: "line".to_string(),
+            val: n.to_string(),
+        };
+
+        assert_eq!(comments, vec![vec![line(1)], vec![], vec![line(3)]]);
+    }
+
+    #[test]
+    fn control_characters_are_cleaned_up() {
+        let sentences = |handling| {
+            let lines = vec!["Een\u{0}zin\u{7}", "Geen\tprobleem", "Nog\u{85}een"]
+                .into_iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            block_on_stream(
+                stream::iter(lines)
+                    .map(Ok)
+                    .sentences(Arc::new(PeriodTokenizer))
+                    .control_characters(handling),
+            )
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+        };
+
+        let stripped = sentences(ControlCharacters::Strip);
+        assert_eq!(
+            stripped.iter().map(forms).collect::<Vec<_>>(),
+            vec![vec!["Eenzin"], vec!["Geen", "probleem"], vec!["Nogeen"]]
+        );
+        assert_eq!(
+            stripped[0].comments(),
+            &[Comment::AttrVal {
+                attr: "control_characters".to_string(),
+                val: "2".to_string(),
+            }]
+        );
+        assert!(stripped[1].comments().is_empty());
+
+        let replaced = sentences(ControlCharacters::Replace);
+        assert_eq!(
+            replaced.iter().map(forms).collect::<Vec<_>>(),
+            vec![
+                vec!["Een", "zin"],
+                vec!["Geen", "probleem"],
+                vec!["Nog", "een"]
+            ]
+        );
+
+        let kept = sentences(ControlCharacters::Keep);
+        assert_eq!(forms(&kept[0]), vec!["Een\u{0}zin\u{7}"]);
+        assert!(kept[0].comments().is_empty());
+    }
+
+    #[test]
+    fn empty_tokens_are_removed() {
+        let mut tokens = vec![
+            vec!["".to_string(), "Een".to_string(), " ".to_string()],
+            vec!["".to_string(), "\t".to_string()],
+            vec!["zin".to_string()],
+        ];
+        remove_empty_tokens(&mut tokens);
+        assert_eq!(
+            tokens,
+            vec![vec!["Een".to_string()], vec![], vec!["zin".to_string()]]
+        );
+
+        let lines = vec![" Een  zin ", "  ", "Nog  een"]
+            .into_iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let sentences = block_on_stream(
+            stream::iter(lines)
+                .map(Ok)
+                .sentences(Arc::new(SpaceTokenizer)),
+        )
+        .map(|s| forms(&s.unwrap()))
+        .collect::<Vec<_>>();
+        assert_eq!(sentences, vec![vec!["Een", "zin"], vec!["Nog", "een"]]);
+    }
+
+    #[test]
+    fn empty_sentences_are_skipped_when_requested() {
+        let tokenize_empty = |skip_empty_sentences| {
+            block_on_stream(
+                stream::iter(vec![Ok("Een\n \nzin".to_string())])
+                    .sentences(Arc::new(SpaceTokenizer))
+                    .skip_empty_sentences(skip_empty_sentences),
+            )
+            .map(|s| forms(&s.unwrap()))
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokenize_empty(false),
+            vec![vec!["Een".to_string()], vec![], vec!["zin".to_string()]]
+        );
+        assert_eq!(
+            tokenize_empty(true),
+            vec![vec!["Een".to_string()], vec!["zin".to_string()]]
+        );
+    }
+
+    #[test]
+    fn concurrent_tokenization_preserves_order() {
+        let lines = (0..20)
+            .map(|i| format!("Zin {}. Nog {}.", i, i))
+            .collect::<Vec<_>>();
+        let tokenize = |max_concurrent| {
+            block_on_stream(
+                stream::iter(lines.clone())
+                    .map(Ok)
+                    .sentences(Arc::new(PeriodTokenizer))
+                    .line_numbers(true)
+                    .max_concurrent_tokenizations(max_concurrent),
+            )
+            .map(|s| s.unwrap())
+            .collect::<Vec<_>>()
+        };
+
+        let sentences = tokenize(4);
+        assert_eq!(sentences.len(), 40);
+        assert_eq!(forms(&sentences[2]), vec!["Zin", "1"]);
+        assert_eq!(sentences, tokenize(1));
+    }
+
+    #[test]
+    fn transient_tokenizer_failures_are_retried() {
+        let tokenizer = Arc::new(FlakyTokenizer {
+            calls: AtomicUsize::new(0),
+            failures: 2,
+        });
+        let sentences = block_on_stream(
+            stream::iter(vec![Ok("Een zin".to_string())])
+                .sentences(tokenizer.clone())
+                .retries(2),
+        )
+        .map(|s| forms(&s.unwrap()))
+        .collect::<Vec<_>>();
+        assert_eq!(sentences, vec![vec!["Een", "zin"]]);
+        assert_eq!(tokenizer.calls.load(Ordering::SeqCst), 3);
+
+        let tokenizer = Arc::new(FlakyTokenizer {
+            calls: AtomicUsize::new(0),
+            failures: 2,
+        });
+        let mut sentences = block_on_stream(
+            stream::iter(vec![Ok("Een zin".to_string())])
+                .sentences(tokenizer)
+                .retries(1),
+        );
+        assert!(sentences.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn untokenizable_lines_are_not_retried() {
+        let tokenizer = Arc::new(RejectingTokenizer::default());
+        let mut sentences = block_on_stream(
+            stream::iter(vec![Ok("Een zin".to_string())])
+                .sentences(tokenizer.clone())
+                .retries(3),
+        );
+        assert!(sentences.next().unwrap().is_err());
+        assert_eq!(tokenizer.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn blank_lines_separate_documents() {
+        let lines = vec!["Een zin. Nog een zin.", "", "", "Derde zin", "Vierde"]
+            .into_iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let comments = block_on_stream(
+            stream::iter(lines)
+                .map(Ok)
+                .sentences(Arc::new(PeriodTokenizer))
+                .documents(true),
+        )
+        .map(|s| s.unwrap().comments().to_vec())
+        .collect::<Vec<_>>();
+
+        let newdoc = Comment::String("newdoc".to_string());
+        assert_eq!(
+            comments,
+            vec![vec![newdoc.clone()], vec![], vec![newdoc], vec![]]
+        );
+    }
+
+    #[test]
+    fn input_text_covers_input_up_to_last_sentence() {
+        let lines = vec!["Een zin. Nog een zin.", "", " . ", "Derde zin", ""]
+            .into_iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let comments = block_on_stream(
+            stream::iter(lines)
+                .map(Ok)
+                .sentences(Arc::new(PeriodTokenizer))
+                .input_text(true),
+        )
+        .map(|s| s.unwrap().comments().to_vec())
+        .collect::<Vec<_>>();
+
+        let input_text = |text: &str| Comment::AttrVal {
+            attr: "input_text".to_string(),
+            val: text.to_string(),
+        };
+
+        assert_eq!(
+            comments,
+            vec![
+                vec![input_text("Een zin. Nog een zin.\n")],
+                vec![],
+                vec![input_text("\n . \nDerde zin\n")]
+            ]
+        );
+    }
+
+    #[test]
+    fn input_ids_mark_every_sentence_of_line() {
+        let lines = vec!["Een zin. Nog een zin.", "", "Derde zin"]
+            .into_iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let comments = block_on_stream(
+            stream::iter(lines)
+                .map(Ok)
+                .sentences(Arc::new(PeriodTokenizer))
+                .input_ids(true),
+        )
+        .map(|s| s.unwrap().comments().to_vec())
+        .collect::<Vec<_>>();
+
+        let input_id = |n: usize| Comment::AttrVal {
+            attr: "input_id".to_string(),
+            val: n.to_string(),
+        };
+
+        assert_eq!(
+            comments,
+            vec![vec![input_id(1)], vec![input_id(1)], vec![input_id(3)]]
+        );
+    }
+
+    #[test]
+    fn leading_bom_is_stripped() {
+        let tokenize_bom = |strip_bom| {
+            let lines = vec!["\u{feff}Een zin.".to_string(), "\u{feff}Twee".to_string()];
+            block_on_stream(
+                stream::iter(lines)
+                    .map(Ok)
+                    .sentences(Arc::new(PeriodTokenizer))
+                    .strip_bom(strip_bom),
+            )
+            .map(|s| forms(&s.unwrap()))
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokenize_bom(true),
+            vec![
+                vec!["Een".to_string(), "zin".to_string()],
+                vec!["\u{feff}Twee".to_string()]
+            ]
+        );
+        assert_eq!(tokenize_bom(false)[0][0], "\u{feff}Een");
+    }
+
+    #[test]
+    fn finds_sentence_texts_in_line() {
+        let tokens = |sentence: &[&str]| sentence.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            sentence_texts(
+                "  Een zin.  Nog een (zin).",
+                &[
+                    tokens(&["Een", "zin", "."]),
+                    tokens(&["Nog", "een", "(", "zin", ")", "."])
+                ]
+            ),
+            vec![Some("Een zin."), Some("Nog een (zin).")]
+        );
+        assert_eq!(
+            sentence_texts("Een zin", &[tokens(&["Eén", "zin"])]),
+            vec![None]
+        );
+    }
+
+    #[test]
+    fn tokenizer_segments_sentences() {
+        assert_eq!(
+            tokenize(&["Een zin. Nog een zin.", "Derde zin"], false),
+            vec![
+                vec!["Een".to_string(), "zin".to_string()],
+                vec!["Nog".to_string(), "een".to_string(), "zin".to_string()],
+                vec!["Derde".to_string(), "zin".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn normalization_precedes_tokenization() {
+        // NFKC normalizes the fullwidth full stop to a full stop.
+        let tokenize_normalized = |normalization| {
+            block_on_stream(
+                stream::iter(vec![Ok("Een zin．Nog een zin".to_string())])
+                    .sentences(Arc::new(PeriodTokenizer))
+                    .normalization(normalization),
+            )
+            .map(|s| forms(&s.unwrap()))
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(tokenize_normalized(None).len(), 1);
+        assert_eq!(
+            tokenize_normalized(Some(Normalization::Nfkc)),
+            vec![
+                vec!["Een".to_string(), "zin".to_string()],
+                vec!["Nog".to_string(), "een".to_string(), "zin".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn sentence_per_line_overrides_segmentation() {
+        assert_eq!(
+            tokenize(&["Een zin. Nog een zin.", "Derde zin"], true),
+            vec![
+                vec![
+                    "Een".to_string(),
+                    "zin".to_string(),
+                    "Nog".to_string(),
+                    "een".to_string(),
+                    "zin".to_string()
+                ],
+                vec!["Derde".to_string(), "zin".to_string()],
+            ]
+        );
+    }
+}
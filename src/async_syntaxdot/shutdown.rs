@@ -0,0 +1,120 @@
+use std::pin::Pin;
+
+use futures::io::Error;
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use udgraph::graph::{Comment, Sentence};
+
+use crate::shutdown::Shutdown;
+
+/// Sentence without tokens that marks a response cut short by shutdown.
+fn shutdown_marker() -> Sentence {
+    let mut sentence = Sentence::new();
+    sentence.comments_mut().push(Comment::AttrVal {
+        attr: "shutdown".to_string(),
+        val: "true".to_string(),
+    });
+    sentence
+}
+
+/// Stream that ends when the server shuts down.
+///
+/// Shutdown is only checked between batches, so the batch that is in
+/// progress is completed. The stream then ends with a sentence that only
+/// consists of a `# shutdown = true` comment.
+pub struct FinishOnShutdown<S> {
+    finished: bool,
+    sentences: Pin<Box<S>>,
+    shutdown: Shutdown,
+}
+
+impl<S> FinishOnShutdown<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    pub fn new(shutdown: Shutdown, sentences: S) -> Self {
+        FinishOnShutdown {
+            finished: false,
+            sentences: Box::pin(sentences),
+            shutdown,
+        }
+    }
+}
+
+impl<S> Stream for FinishOnShutdown<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    type Item = Result<Vec<Sentence>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let FinishOnShutdown {
+            finished,
+            sentences,
+            shutdown,
+        } = &mut *self;
+
+        if *finished {
+            return Poll::Ready(None);
+        }
+
+        if shutdown.is_triggered() {
+            log::warn!("Server is shutting down, ending response");
+            *finished = true;
+            return Poll::Ready(Some(Ok(vec![shutdown_marker()])));
+        }
+
+        let batch = ready!(sentences.as_mut().poll_next(cx));
+        if batch.is_none() {
+            *finished = true;
+        }
+
+        Poll::Ready(batch)
+    }
+}
+
+pub trait ToFinishOnShutdown<S> {
+    fn finish_on_shutdown(self, shutdown: Shutdown) -> FinishOnShutdown<S>;
+}
+
+impl<S> ToFinishOnShutdown<S> for S
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    fn finish_on_shutdown(self, shutdown: Shutdown) -> FinishOnShutdown<S> {
+        FinishOnShutdown::new(shutdown, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on_stream;
+    use futures::io::Error;
+    use futures::stream;
+    use udgraph::graph::Sentence;
+
+    use super::ToFinishOnShutdown;
+    use crate::shutdown::Shutdown;
+
+    #[test]
+    fn ends_with_marker_after_shutdown() {
+        let shutdown = Shutdown::default();
+        let mut batches = block_on_stream(
+            stream::iter(vec![
+                Ok::<_, Error>(vec![Sentence::new()]),
+                Ok(vec![Sentence::new()]),
+            ])
+            .finish_on_shutdown(shutdown.clone()),
+        );
+
+        assert_eq!(batches.next().unwrap().unwrap()[0].comments().len(), 0);
+
+        shutdown.trigger();
+
+        let marker = batches.next().unwrap().unwrap();
+        assert_eq!(marker.len(), 1);
+        assert_eq!(marker[0].comments().len(), 1);
+        assert!(batches.next().is_none());
+    }
+}
@@ -0,0 +1,111 @@
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use futures::io::Error;
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use udgraph::graph::Sentence;
+
+/// Stream that logs a warning when producing the sentences was slow.
+///
+/// The duration is measured from the construction of the stream until it
+/// is dropped, so this also covers responses that are aborted.
+pub struct LogSlow<S> {
+    n_sentences: usize,
+    pipeline: String,
+    sentences: Pin<Box<S>>,
+    start: Instant,
+    threshold: Option<Duration>,
+}
+
+impl<S> LogSlow<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    /// Construct the stream, a threshold of `None` disables logging.
+    pub fn new(pipeline: impl Into<String>, threshold: Option<Duration>, sentences: S) -> Self {
+        LogSlow {
+            n_sentences: 0,
+            pipeline: pipeline.into(),
+            sentences: Box::pin(sentences),
+            start: Instant::now(),
+            threshold,
+        }
+    }
+}
+
+impl<S> Drop for LogSlow<S> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        if let Some(threshold) = self.threshold {
+            if elapsed >= threshold {
+                log::warn!(
+                    "Slow request for pipeline {}: {} sentences in {:.3}s",
+                    self.pipeline,
+                    self.n_sentences,
+                    elapsed.as_secs_f64()
+                );
+            }
+        }
+    }
+}
+
+impl<S> Stream for LogSlow<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    type Item = Result<Vec<Sentence>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let LogSlow {
+            n_sentences,
+            sentences,
+            ..
+        } = &mut *self;
+
+        let batch = ready!(sentences.as_mut().poll_next(cx));
+        if let Some(Ok(batch)) = &batch {
+            *n_sentences += batch.len();
+        }
+
+        Poll::Ready(batch)
+    }
+}
+
+pub trait ToLogSlow<S> {
+    fn log_slow(self, pipeline: impl Into<String>, threshold: Option<Duration>) -> LogSlow<S>;
+}
+
+impl<S> ToLogSlow<S> for S
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    fn log_slow(self, pipeline: impl Into<String>, threshold: Option<Duration>) -> LogSlow<S> {
+        LogSlow::new(pipeline, threshold, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::io::Error;
+    use futures::stream::{self, StreamExt};
+    use udgraph::graph::Sentence;
+
+    use super::ToLogSlow;
+
+    #[test]
+    fn counts_sentences() {
+        let mut stream = stream::iter(vec![
+            Ok::<_, Error>(vec![Sentence::new(), Sentence::new()]),
+            Ok(vec![]),
+            Ok(vec![Sentence::new()]),
+        ])
+        .log_slow("test", None);
+
+        while block_on(stream.next()).is_some() {}
+
+        assert_eq!(stream.n_sentences, 3);
+    }
+}
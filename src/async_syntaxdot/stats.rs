@@ -0,0 +1,133 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde::Serialize;
+use udgraph::graph::{Comment, Sentence};
+
+/// Statistics of an annotation request.
+///
+/// Clones share their counts, so that each stream stage can record
+/// what it observes.
+#[derive(Clone, Debug)]
+pub struct AnnotationStats {
+    counts: Arc<Counts>,
+    start: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    errors: AtomicUsize,
+    sentences: AtomicUsize,
+    skipped: AtomicUsize,
+    tokens: AtomicUsize,
+}
+
+/// Summary of an annotation request, see [`AnnotationStats::summary`].
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct StatsSummary {
+    pub sentences: usize,
+    pub tokens: usize,
+    pub skipped: usize,
+    pub errors: usize,
+    pub duration_ms: u128,
+}
+
+impl AnnotationStats {
+    /// Construct statistics, the duration is measured from now.
+    pub fn new() -> Self {
+        AnnotationStats {
+            counts: Arc::new(Counts::default()),
+            start: Instant::now(),
+        }
+    }
+
+    /// Record sentences that were skipped by the annotator, because they
+    /// are too long or too short.
+    pub fn record_skipped(&self, n_sentences: usize) {
+        self.counts
+            .skipped
+            .fetch_add(n_sentences, Ordering::Relaxed);
+    }
+
+    /// Record output sentences.
+    ///
+    /// Sentences without tokens are markers and are not counted, except
+    /// for the errors reported by a `# skipped = N` comment. Sentences
+    /// with an `# error = ...` comment are counted as errors.
+    pub fn record_sentences(&self, sentences: &[Sentence]) {
+        for sentence in sentences {
+            for comment in sentence.comments() {
+                if let Comment::AttrVal { attr, val } = comment {
+                    let errors = match attr.as_str() {
+                        "error" => 1,
+                        "skipped" if sentence.len() == 1 => val.parse().unwrap_or(0),
+                        _ => 0,
+                    };
+                    self.counts.errors.fetch_add(errors, Ordering::Relaxed);
+                }
+            }
+
+            // The root node is not a token.
+            let n_tokens = sentence.len() - 1;
+            if n_tokens > 0 {
+                self.counts.sentences.fetch_add(1, Ordering::Relaxed);
+                self.counts.tokens.fetch_add(n_tokens, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Summarize the statistics recorded so far.
+    pub fn summary(&self) -> StatsSummary {
+        StatsSummary {
+            sentences: self.counts.sentences.load(Ordering::Relaxed),
+            tokens: self.counts.tokens.load(Ordering::Relaxed),
+            skipped: self.counts.skipped.load(Ordering::Relaxed),
+            errors: self.counts.errors.load(Ordering::Relaxed),
+            duration_ms: self.start.elapsed().as_millis(),
+        }
+    }
+}
+
+impl Default for AnnotationStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udgraph::graph::{Comment, Sentence};
+    use udgraph::token::Token;
+
+    use super::AnnotationStats;
+
+    #[test]
+    fn markers_are_not_counted_as_sentences() {
+        let stats = AnnotationStats::new();
+
+        let sentence: Sentence = vec![Token::new("Een"), Token::new("zin")]
+            .into_iter()
+            .collect();
+        let mut failed = sentence.clone();
+        failed.comments_mut().push(Comment::AttrVal {
+            attr: "error".to_string(),
+            val: "Cannot annotate".to_string(),
+        });
+        let mut summary = Sentence::new();
+        summary.comments_mut().push(Comment::AttrVal {
+            attr: "skipped".to_string(),
+            val: "2".to_string(),
+        });
+
+        stats.record_sentences(&[sentence, failed]);
+        stats.record_sentences(&[summary]);
+        stats.record_skipped(3);
+
+        let summary = stats.summary();
+        assert_eq!(summary.sentences, 2);
+        assert_eq!(summary.tokens, 4);
+        assert_eq!(summary.skipped, 3);
+        assert_eq!(summary.errors, 3);
+    }
+}
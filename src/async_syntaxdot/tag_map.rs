@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::io::Error;
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use serde::Deserialize;
+use udgraph::graph::{DepTriple, Node, Sentence};
+
+/// Mapping of annotated tags to a custom tag set.
+///
+/// Tags that are not in the mapping are left unchanged.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub struct TagMap {
+    /// Mapping of dependency relations.
+    pub deprel: HashMap<String, String>,
+
+    /// Mapping of universal part-of-speech tags.
+    pub upos: HashMap<String, String>,
+}
+
+impl TagMap {
+    /// Read a tag map from a YAML file with `upos` and `deprel` mappings.
+    pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let read = BufReader::new(File::open(path)?);
+        Ok(serde_yaml::from_reader(read)?)
+    }
+
+    /// Map the tags of a sentence.
+    fn map_sentence(&self, sentence: &mut Sentence) {
+        for token in sentence.iter_mut().filter_map(Node::token_mut) {
+            if let Some(upos) = token.upos().and_then(|upos| self.upos.get(upos)) {
+                let upos = upos.clone();
+                token.set_upos(Some(upos));
+            }
+        }
+
+        if self.deprel.is_empty() {
+            return;
+        }
+
+        let mapped = (1..sentence.len())
+            .filter_map(|dependent| {
+                let triple = sentence.dep_graph().head(dependent)?;
+                let relation = self.deprel.get(triple.relation()?)?;
+                Some((triple.head(), relation, dependent))
+            })
+            .collect::<Vec<_>>();
+
+        for (head, relation, dependent) in mapped {
+            sentence.dep_graph_mut().add_deprel(DepTriple::new(
+                head,
+                Some(relation.as_str()),
+                dependent,
+            ));
+        }
+    }
+}
+
+/// Stream that maps annotated tags to a custom tag set.
+pub struct MapTags<S> {
+    sentences: Pin<Box<S>>,
+    tag_map: Option<Arc<TagMap>>,
+}
+
+impl<S> MapTags<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    /// Construct the stage, a tag map of `None` leaves all tags unchanged.
+    pub fn new(tag_map: Option<Arc<TagMap>>, sentences: S) -> Self {
+        MapTags {
+            sentences: Box::pin(sentences),
+            tag_map,
+        }
+    }
+}
+
+impl<S> Stream for MapTags<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    type Item = Result<Vec<Sentence>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let MapTags { sentences, tag_map } = &mut *self;
+
+        match ready!(sentences.as_mut().poll_next(cx)) {
+            Some(Ok(mut batch)) => {
+                if let Some(tag_map) = tag_map {
+                    for sentence in &mut batch {
+                        tag_map.map_sentence(sentence);
+                    }
+                }
+                Poll::Ready(Some(Ok(batch)))
+            }
+            other => Poll::Ready(other),
+        }
+    }
+}
+
+pub trait ToMapTags<S> {
+    fn map_tags(self, tag_map: Option<Arc<TagMap>>) -> MapTags<S>;
+}
+
+impl<S> ToMapTags<S> for S
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    fn map_tags(self, tag_map: Option<Arc<TagMap>>) -> MapTags<S> {
+        MapTags::new(tag_map, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udgraph::graph::{DepTriple, Sentence};
+    use udgraph::token::TokenBuilder;
+
+    use super::TagMap;
+
+    #[test]
+    fn maps_upos_and_deprel() {
+        let mut sentence: Sentence = vec![
+            TokenBuilder::new("De").upos("DET").into(),
+            TokenBuilder::new("kat").upos("NOUN").into(),
+            TokenBuilder::new("slaapt").upos("VERB").into(),
+        ]
+        .into_iter()
+        .collect();
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(0, Some("root"), 3));
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(2, Some("det"), 1));
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(3, Some("nsubj"), 2));
+
+        let tag_map: TagMap = serde_yaml::from_str(
+            r#"
+upos:
+  NOUN: N
+  VERB: V
+deprel:
+  nsubj: su
+"#,
+        )
+        .unwrap();
+        tag_map.map_sentence(&mut sentence);
+
+        let upos = (1..4)
+            .map(|idx| sentence[idx].token().unwrap().upos())
+            .collect::<Vec<_>>();
+        assert_eq!(upos, [Some("DET"), Some("N"), Some("V")]);
+
+        let dep_graph = sentence.dep_graph();
+        assert_eq!(dep_graph.head(2).unwrap().relation(), Some("su"));
+        assert_eq!(dep_graph.head(2).unwrap().head(), 3);
+        assert_eq!(dep_graph.head(1).unwrap().relation(), Some("det"));
+        assert_eq!(dep_graph.head(3).unwrap().relation(), Some("root"));
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Cumulative time spent in the stages of an annotation request.
+///
+/// Clones share their durations, so that each stream stage can record
+/// the time that it spends. Stages that process work concurrently
+/// record the time of each unit of work, so a stage can report more
+/// time than the request took.
+#[derive(Clone, Debug, Default)]
+pub struct StageTimings {
+    nanos: Arc<Nanos>,
+}
+
+#[derive(Debug, Default)]
+struct Nanos {
+    annotation: AtomicU64,
+    serialization: AtomicU64,
+    tokenization: AtomicU64,
+}
+
+/// Summary of the stage timings, see [`StageTimings::summary`].
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct TimingSummary {
+    pub tokenization_ms: u64,
+    pub annotation_ms: u64,
+    pub serialization_ms: u64,
+}
+
+impl TimingSummary {
+    /// Format the timings as `tokenization_ms=N annotation_ms=N serialization_ms=N`.
+    pub fn to_attr_val(&self) -> String {
+        format!(
+            "tokenization_ms={} annotation_ms={} serialization_ms={}",
+            self.tokenization_ms, self.annotation_ms, self.serialization_ms
+        )
+    }
+}
+
+impl StageTimings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(counter: &AtomicU64, duration: Duration) {
+        counter.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Record time spent annotating sentences.
+    pub fn record_annotation(&self, duration: Duration) {
+        Self::record(&self.nanos.annotation, duration);
+    }
+
+    /// Record time spent serializing sentences.
+    pub fn record_serialization(&self, duration: Duration) {
+        Self::record(&self.nanos.serialization, duration);
+    }
+
+    /// Record time spent tokenizing lines.
+    pub fn record_tokenization(&self, duration: Duration) {
+        Self::record(&self.nanos.tokenization, duration);
+    }
+
+    /// Summarize the timings recorded so far.
+    pub fn summary(&self) -> TimingSummary {
+        let millis = |counter: &AtomicU64| counter.load(Ordering::Relaxed) / 1_000_000;
+
+        TimingSummary {
+            tokenization_ms: millis(&self.nanos.tokenization),
+            annotation_ms: millis(&self.nanos.annotation),
+            serialization_ms: millis(&self.nanos.serialization),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{StageTimings, TimingSummary};
+
+    #[test]
+    fn clones_share_timings() {
+        let timings = StageTimings::new();
+        timings
+            .clone()
+            .record_tokenization(Duration::from_micros(1500));
+        timings.record_tokenization(Duration::from_micros(1500));
+        timings.clone().record_annotation(Duration::from_millis(10));
+
+        let summary = timings.summary();
+        assert_eq!(
+            summary,
+            TimingSummary {
+                tokenization_ms: 3,
+                annotation_ms: 10,
+                serialization_ms: 0,
+            }
+        );
+        assert_eq!(
+            summary.to_attr_val(),
+            "tokenization_ms=3 annotation_ms=10 serialization_ms=0"
+        );
+    }
+}
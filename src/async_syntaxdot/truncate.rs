@@ -0,0 +1,136 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures::io::Error;
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use udgraph::graph::{Comment, Sentence};
+
+use crate::runtime::Delay;
+
+/// Sentence without tokens that marks a truncated response.
+fn truncation_marker() -> Sentence {
+    let mut sentence = Sentence::new();
+    sentence.comments_mut().push(Comment::AttrVal {
+        attr: "truncated".to_string(),
+        val: "true".to_string(),
+    });
+    sentence
+}
+
+/// Stream that ends when a deadline is exceeded.
+///
+/// When the deadline passes, the batch that is in progress is discarded
+/// and the stream ends with a sentence that only consists of a
+/// `# truncated = true` comment. The underlying stream is dropped at
+/// that point, so that it does not start further work.
+pub struct Truncate<S> {
+    deadline: Option<Delay>,
+    sentences: Option<Pin<Box<S>>>,
+}
+
+impl<S> Truncate<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    pub fn new(max_duration: Option<Duration>, sentences: S) -> Self {
+        Truncate {
+            deadline: max_duration.map(Delay::new),
+            sentences: Some(Box::pin(sentences)),
+        }
+    }
+}
+
+impl<S> Stream for Truncate<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    type Item = Result<Vec<Sentence>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let Truncate {
+            deadline,
+            sentences,
+        } = &mut *self;
+
+        let stream = match sentences {
+            Some(stream) => stream,
+            None => return Poll::Ready(None),
+        };
+
+        if let Some(deadline) = deadline {
+            if Pin::new(deadline).poll(cx).is_ready() {
+                log::warn!("Maximum response time exceeded, truncating response");
+                *sentences = None;
+                return Poll::Ready(Some(Ok(vec![truncation_marker()])));
+            }
+        }
+
+        let batch = ready!(stream.as_mut().poll_next(cx));
+        if batch.is_none() {
+            *sentences = None;
+        }
+
+        Poll::Ready(batch)
+    }
+}
+
+pub trait ToTruncate<S> {
+    fn truncate_after(self, max_duration: Option<Duration>) -> Truncate<S>;
+}
+
+impl<S> ToTruncate<S> for S
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    fn truncate_after(self, max_duration: Option<Duration>) -> Truncate<S> {
+        Truncate::new(max_duration, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::executor::block_on_stream;
+    use futures::io::Error;
+    use futures::stream::{self, StreamExt};
+    use udgraph::graph::Sentence;
+
+    use super::ToTruncate;
+
+    #[test]
+    fn truncates_stalled_stream() {
+        let batches = block_on_stream(
+            stream::pending::<Result<Vec<Sentence>, Error>>()
+                .truncate_after(Some(Duration::from_millis(10))),
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].len(), 1);
+        assert_eq!(batches[0][0].comments().len(), 1);
+    }
+
+    #[test]
+    fn truncation_drops_stream() {
+        let stream = Arc::new(());
+        let guard = stream.clone();
+        let mut batches = block_on_stream(
+            stream::pending::<Result<Vec<Sentence>, Error>>()
+                .map(move |batch| {
+                    let _guard = &guard;
+                    batch
+                })
+                .truncate_after(Some(Duration::from_millis(10))),
+        );
+
+        assert!(batches.next().unwrap().is_ok());
+        assert_eq!(Arc::strong_count(&stream), 1);
+        assert!(batches.next().is_none());
+    }
+}
@@ -1,7 +1,9 @@
+use serde::{Deserialize, Serialize};
 use unicode_normalization::UnicodeNormalization;
 
 /// Types of unicode normalization.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 #[allow(unused)]
 pub enum Normalization {
     None,
@@ -168,6 +170,57 @@ pub fn simplify_unicode_lookup(c: char) -> Conversion {
     }
 }
 
+/// Handling of C0 and C1 control characters in the input.
+///
+/// Control characters can come from copy-pasted or badly converted
+/// text and confuse tokenizers. Tabs and newlines are not considered to
+/// be control characters, since they separate tokens and lines.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlCharacters {
+    /// Pass control characters to the tokenizer.
+    #[default]
+    Keep,
+
+    /// Remove control characters.
+    Strip,
+
+    /// Replace control characters by a space.
+    Replace,
+}
+
+/// Clean up the control characters of a line.
+///
+/// Returns the cleaned up line and the number of control characters
+/// that were removed or replaced.
+pub fn clean_control_characters(s: &str, handling: ControlCharacters) -> (String, usize) {
+    let is_control = |c: char| c.is_control() && c != '\t' && c != '\n';
+
+    let n_control = match handling {
+        ControlCharacters::Keep => 0,
+        _ => s.chars().filter(|&c| is_control(c)).count(),
+    };
+    if n_control == 0 {
+        return (s.to_string(), 0);
+    }
+
+    let cleaned = match handling {
+        ControlCharacters::Keep => unreachable!(),
+        ControlCharacters::Strip => s.chars().filter(|&c| !is_control(c)).collect(),
+        ControlCharacters::Replace => s
+            .chars()
+            .map(|c| if is_control(c) { ' ' } else { c })
+            .collect(),
+    };
+
+    (cleaned, n_control)
+}
+
+/// Apply unicode normalization, without simplifying characters.
+pub fn normalize(s: &str, norm: Normalization) -> String {
+    normalization_iter(s.chars(), norm).collect()
+}
+
 pub fn simplify_unicode(s: &str, norm: Normalization) -> String {
     normalization_iter(s.chars(), norm).fold(String::with_capacity(s.len()), |mut s, c| {
         match simplify_unicode_lookup(c) {
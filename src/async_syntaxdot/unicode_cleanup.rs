@@ -1,12 +1,21 @@
+use std::collections::VecDeque;
+use std::future::Future;
 use std::pin::Pin;
 
 use futures::io::Error;
 use futures::ready;
-use futures::stream::Stream;
+use futures::stream::{FuturesOrdered, Stream, StreamExt};
 use futures::task::{Context, Poll};
 use udgraph::graph::{Node, Sentence};
 
 use super::unicode::{simplify_unicode, Normalization};
+use crate::runtime::spawn;
+
+/// Maximum number of sentences that are cleaned up in one task.
+const OFFLOAD_CHUNK_LEN: usize = 32;
+
+/// Maximum number of cleanup tasks that run concurrently for a stream.
+const MAX_CONCURRENT_CLEANUPS: usize = 4;
 
 fn cleanup_sentence_unicode(sentence: &mut Sentence, normalization: Normalization) {
     for token in sentence.iter_mut().filter_map(Node::token_mut) {
@@ -23,8 +32,13 @@ fn cleanup_sentence_unicode(sentence: &mut Sentence, normalization: Normalizatio
 
 /// Stream that normalizes unicode and replaces certain punctuation signs by ASCII approximations.
 pub struct UnicodeCleanup<L> {
-    sentences: Pin<Box<L>>,
+    cleaned: VecDeque<Sentence>,
+    error: Option<Error>,
     normalization: Normalization,
+    offload: bool,
+    pending: FuturesOrdered<Pin<Box<dyn Future<Output = Vec<Sentence>> + Send + Sync>>>,
+    sentences: Pin<Box<L>>,
+    sentences_done: bool,
 }
 
 impl<L> UnicodeCleanup<L>
@@ -33,10 +47,26 @@ where
 {
     pub fn new(normalization: Normalization, sentences: L) -> Self {
         Self {
-            sentences: Box::pin(sentences),
+            cleaned: VecDeque::new(),
+            error: None,
             normalization,
+            offload: false,
+            pending: FuturesOrdered::new(),
+            sentences: Box::pin(sentences),
+            sentences_done: false,
         }
     }
+
+    /// Clean up sentences in spawned tasks.
+    ///
+    /// This moves cleanup of long sentences off the thread that polls
+    /// the stream, like tokenization. The sentences that are available
+    /// are cleaned up in chunks of up to [`OFFLOAD_CHUNK_LEN`] sentences,
+    /// with up to [`MAX_CONCURRENT_CLEANUPS`] chunks at a time.
+    pub fn offload(mut self, offload: bool) -> Self {
+        self.offload = offload;
+        self
+    }
 }
 
 impl<L> Stream for UnicodeCleanup<L>
@@ -47,16 +77,76 @@ where
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         let Self {
-            sentences,
+            cleaned,
+            error,
             normalization,
+            offload,
+            pending,
+            sentences,
+            sentences_done,
         } = &mut *self;
 
-        match ready!(sentences.as_mut().poll_next(cx)) {
-            None => Poll::Ready(None),
-            Some(Err(err)) => Poll::Ready(Some(Err(err))),
-            Some(Ok(mut sentence)) => {
-                cleanup_sentence_unicode(&mut sentence, *normalization);
-                Poll::Ready(Some(Ok(sentence)))
+        if !*offload {
+            return match ready!(sentences.as_mut().poll_next(cx)) {
+                Some(Ok(mut sentence)) => {
+                    cleanup_sentence_unicode(&mut sentence, *normalization);
+                    Poll::Ready(Some(Ok(sentence)))
+                }
+                item => Poll::Ready(item),
+            };
+        }
+
+        loop {
+            if let Some(sentence) = cleaned.pop_front() {
+                return Poll::Ready(Some(Ok(sentence)));
+            }
+
+            // Start cleanups until the maximum number of cleanups is in
+            // flight or no sentences are available. An error is returned
+            // once the cleanups before it are done.
+            while !*sentences_done && error.is_none() && pending.len() < MAX_CONCURRENT_CLEANUPS {
+                let mut chunk = Vec::new();
+                while chunk.len() < OFFLOAD_CHUNK_LEN {
+                    match sentences.as_mut().poll_next(cx) {
+                        Poll::Pending => break,
+                        Poll::Ready(None) => {
+                            *sentences_done = true;
+                            break;
+                        }
+                        Poll::Ready(Some(Err(err))) => {
+                            *error = Some(err);
+                            break;
+                        }
+                        Poll::Ready(Some(Ok(sentence))) => chunk.push(sentence),
+                    }
+                }
+
+                let full = chunk.len() == OFFLOAD_CHUNK_LEN;
+                if !chunk.is_empty() {
+                    let normalization = *normalization;
+                    pending.push_back(Box::pin(spawn(async move {
+                        for sentence in &mut chunk {
+                            cleanup_sentence_unicode(sentence, normalization);
+                        }
+                        chunk
+                    })));
+                }
+
+                if !full {
+                    break;
+                }
+            }
+
+            match ready!(pending.poll_next_unpin(cx)) {
+                Some(chunk) => *cleaned = chunk.into(),
+                None => {
+                    return match error.take() {
+                        Some(err) => Poll::Ready(Some(Err(err))),
+                        None if *sentences_done => Poll::Ready(None),
+                        // Waiting for the next sentence.
+                        None => Poll::Pending,
+                    };
+                }
             }
         }
     }
@@ -80,12 +170,81 @@ mod tests {
     use std::iter;
 
     use futures::executor::block_on_stream;
+    use futures::io::{Error, ErrorKind};
     use futures::stream::{self, StreamExt};
-    use udgraph::graph::Sentence;
+    use udgraph::graph::{Node, Sentence};
     use udgraph::token::{Token, TokenBuilder};
 
     use super::{Normalization, ToUnicodeCleanup};
 
+    fn check_sentence() -> Sentence {
+        vec![
+            TokenBuilder::new("\"")
+                .misc(iter::once(("orth".to_string(), Some("«".to_string()))).collect())
+                .into(),
+            Token::new("test"),
+            TokenBuilder::new("\"")
+                .misc(iter::once(("orth".to_string(), Some("»".to_string()))).collect())
+                .into(),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn offloaded_unicode_cleanup_works() {
+        let sentence: Sentence = vec![Token::new("«"), Token::new("test"), Token::new("»")]
+            .into_iter()
+            .collect();
+        let chunks = block_on_stream(
+            stream::iter(vec![sentence.clone(), sentence])
+                .map(Ok)
+                .unicode_cleanup(Normalization::Nfc)
+                .offload(true),
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        assert_eq!(chunks, vec![check_sentence(), check_sentence()]);
+    }
+
+    #[test]
+    fn offloaded_unicode_cleanup_preserves_order() {
+        let sentences = (0..200)
+            .map(|idx| {
+                if idx == 150 {
+                    Err(Error::new(ErrorKind::InvalidData, "Invalid sentence"))
+                } else {
+                    Ok(vec![Token::new("«"), Token::new(idx.to_string())]
+                        .into_iter()
+                        .collect::<Sentence>())
+                }
+            })
+            .collect::<Vec<_>>();
+        let cleaned = block_on_stream(
+            stream::iter(sentences)
+                .unicode_cleanup(Normalization::Nfc)
+                .offload(true),
+        )
+        .collect::<Vec<_>>();
+
+        assert_eq!(cleaned.len(), 200);
+        for (idx, sentence) in cleaned.into_iter().enumerate() {
+            if idx == 150 {
+                assert!(sentence.is_err());
+                continue;
+            }
+
+            let sentence = sentence.unwrap();
+            let forms = sentence
+                .iter()
+                .filter_map(Node::token)
+                .map(|token| token.form().to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(forms, vec!["\"".to_string(), idx.to_string()]);
+        }
+    }
+
     #[test]
     fn unicode_cleanup_works() {
         let sentence: Sentence = vec![Token::new("«"), Token::new("test"), Token::new("»")]
@@ -99,18 +258,6 @@ mod tests {
         .collect::<Result<Vec<_>, _>>()
         .unwrap();
 
-        let check_sentence = vec![
-            TokenBuilder::new("\"")
-                .misc(iter::once(("orth".to_string(), Some("«".to_string()))).collect())
-                .into(),
-            Token::new("test"),
-            TokenBuilder::new("\"")
-                .misc(iter::once(("orth".to_string(), Some("»".to_string()))).collect())
-                .into(),
-        ]
-        .into_iter()
-        .collect();
-
-        assert_eq!(chunks, vec![check_sentence]);
+        assert_eq!(chunks, vec![check_sentence()]);
     }
 }
@@ -0,0 +1,187 @@
+use std::pin::Pin;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use futures::io::Error;
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use udgraph::graph::{Node, Sentence};
+use udgraph::token::{Features, Token};
+
+/// Handling of tokens with a part-of-speech tag that is not retained.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UposFilterMode {
+    /// Remove the annotations of the token, except its form, tag and
+    /// dependency relation.
+    ///
+    /// This retains the structure of the sentence.
+    Blank,
+
+    /// Remove the token.
+    ///
+    /// Head indices would not be valid after removing tokens, so the
+    /// dependency relations of the sentence are removed as well.
+    #[default]
+    Drop,
+}
+
+impl FromStr for UposFilterMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blank" => Ok(UposFilterMode::Blank),
+            "drop" => Ok(UposFilterMode::Drop),
+            _ => Err(anyhow!("Unknown part-of-speech filter mode: {}", s)),
+        }
+    }
+}
+
+fn is_retained(token: &Token, tags: &[String]) -> bool {
+    token
+        .upos()
+        .map(|upos| tags.iter().any(|tag| tag == upos))
+        .unwrap_or(false)
+}
+
+/// Apply the part-of-speech filter to a sentence.
+fn filter_sentence_upos(mut sentence: Sentence, tags: &[String], mode: UposFilterMode) -> Sentence {
+    match mode {
+        UposFilterMode::Blank => {
+            for token in sentence.iter_mut().filter_map(Node::token_mut) {
+                if !is_retained(token, tags) {
+                    token.set_lemma(None::<String>);
+                    token.set_xpos(None::<String>);
+                    token.set_features(Features::default());
+                    token.misc_mut().clear();
+                }
+            }
+            sentence
+        }
+        UposFilterMode::Drop => {
+            let mut filtered = sentence
+                .iter()
+                .filter_map(Node::token)
+                .filter(|token| is_retained(token, tags))
+                .cloned()
+                .collect::<Sentence>();
+            *filtered.comments_mut() = sentence.comments().to_vec();
+            filtered
+        }
+    }
+}
+
+/// Stream that only retains the tokens with the given part-of-speech tags.
+pub struct FilterUpos<S> {
+    filter: Option<(Vec<String>, UposFilterMode)>,
+    sentences: Pin<Box<S>>,
+}
+
+impl<S> FilterUpos<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    /// Construct a filter, a filter of `None` retains all tokens.
+    pub fn new(filter: Option<(Vec<String>, UposFilterMode)>, sentences: S) -> Self {
+        FilterUpos {
+            filter,
+            sentences: Box::pin(sentences),
+        }
+    }
+}
+
+impl<S> Stream for FilterUpos<S>
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    type Item = Result<Vec<Sentence>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let FilterUpos { filter, sentences } = &mut *self;
+
+        match ready!(sentences.as_mut().poll_next(cx)) {
+            Some(Ok(batch)) => match filter {
+                Some((tags, mode)) => Poll::Ready(Some(Ok(batch
+                    .into_iter()
+                    .map(|sentence| filter_sentence_upos(sentence, tags, *mode))
+                    .collect()))),
+                None => Poll::Ready(Some(Ok(batch))),
+            },
+            other => Poll::Ready(other),
+        }
+    }
+}
+
+pub trait ToFilterUpos<S> {
+    fn filter_upos(self, filter: Option<(Vec<String>, UposFilterMode)>) -> FilterUpos<S>;
+}
+
+impl<S> ToFilterUpos<S> for S
+where
+    S: Stream<Item = Result<Vec<Sentence>, Error>>,
+{
+    fn filter_upos(self, filter: Option<(Vec<String>, UposFilterMode)>) -> FilterUpos<S> {
+        FilterUpos::new(filter, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udgraph::graph::{DepTriple, Node, Sentence};
+    use udgraph::token::TokenBuilder;
+
+    use super::{filter_sentence_upos, UposFilterMode};
+
+    fn sentence() -> Sentence {
+        let mut sentence: Sentence = vec![
+            TokenBuilder::new("De").upos("DET").lemma("de").into(),
+            TokenBuilder::new("katten").upos("NOUN").lemma("kat").into(),
+            TokenBuilder::new("slapen")
+                .upos("VERB")
+                .lemma("slapen")
+                .into(),
+        ]
+        .into_iter()
+        .collect();
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(0, Some("root"), 3));
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(2, Some("det"), 1));
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(3, Some("nsubj"), 2));
+        sentence
+    }
+
+    fn tags() -> Vec<String> {
+        vec!["NOUN".to_string(), "VERB".to_string()]
+    }
+
+    #[test]
+    fn blanking_keeps_structure() {
+        let filtered = filter_sentence_upos(sentence(), &tags(), UposFilterMode::Blank);
+        assert_eq!(filtered.len(), 4);
+
+        let det = filtered[1].token().unwrap();
+        assert_eq!(det.form(), "De");
+        assert_eq!(det.upos(), Some("DET"));
+        assert_eq!(det.lemma(), None);
+        assert_eq!(filtered[2].token().unwrap().lemma(), Some("kat"));
+        assert_eq!(filtered.dep_graph().head(1).unwrap().head(), 2);
+    }
+
+    #[test]
+    fn dropping_removes_tokens() {
+        let filtered = filter_sentence_upos(sentence(), &tags(), UposFilterMode::Drop);
+        let forms = filtered
+            .iter()
+            .filter_map(Node::token)
+            .map(|token| token.form())
+            .collect::<Vec<_>>();
+        assert_eq!(forms, ["katten", "slapen"]);
+        assert!(filtered.dep_graph().head(1).is_none());
+    }
+}
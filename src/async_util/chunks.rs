@@ -1,15 +1,53 @@
+use std::future::Future;
 use std::pin::Pin;
+use std::time::Duration;
 
 use futures::io::Error;
-use futures::ready;
 use futures::stream::Stream;
 use futures::task::{Context, Poll};
 
+use crate::runtime::Delay;
+
+/// Timer that expires a fixed interval after the first item of a chunk.
+#[derive(Default)]
+struct FlushTimer {
+    interval: Option<Duration>,
+    timer: Option<Delay>,
+}
+
+impl FlushTimer {
+    /// Start the timer when it is not running yet.
+    fn start(&mut self) {
+        if let (Some(interval), None) = (self.interval, &self.timer) {
+            self.timer = Some(Delay::new(interval));
+        }
+    }
+
+    /// Check whether the timer expired, stopping it when it did.
+    fn poll_expired(&mut self, cx: &mut Context) -> bool {
+        let expired = match &mut self.timer {
+            Some(timer) => Pin::new(timer).poll(cx).is_ready(),
+            None => false,
+        };
+
+        if expired {
+            self.timer = None;
+        }
+
+        expired
+    }
+
+    fn stop(&mut self) {
+        self.timer = None;
+    }
+}
+
 /// A stream that chunks items.
 pub struct TryChunks<St, I> {
     inner: Pin<Box<St>>,
     buf: Vec<I>,
     chunk_len: usize,
+    flush_timer: FlushTimer,
 }
 
 impl<St, I> TryChunks<St, I> {
@@ -18,8 +56,19 @@ impl<St, I> TryChunks<St, I> {
             inner: Box::pin(stream),
             buf: Vec::with_capacity(chunk_len),
             chunk_len,
+            flush_timer: FlushTimer::default(),
         }
     }
+
+    /// Return an incomplete chunk when `interval` passed since its first
+    /// item was received, disabled when `None`.
+    ///
+    /// This bounds the time that items of a slow stream wait for the
+    /// chunk to fill up.
+    pub fn flush_interval(mut self, interval: Option<Duration>) -> Self {
+        self.flush_timer.interval = interval;
+        self
+    }
 }
 
 impl<St, I> Stream for TryChunks<St, I>
@@ -34,16 +83,29 @@ where
             inner,
             buf,
             chunk_len,
+            flush_timer,
         } = &mut *self;
 
         loop {
-            match ready!(inner.as_mut().poll_next(cx)) {
+            let item = match inner.as_mut().poll_next(cx) {
+                Poll::Pending if flush_timer.poll_expired(cx) => {
+                    let mut fresh = Vec::with_capacity(*chunk_len);
+                    std::mem::swap(buf, &mut fresh);
+                    return Poll::Ready(Some(Ok(fresh)));
+                }
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(item) => item,
+            };
+
+            match item {
                 Some(Err(err)) => return Poll::Ready(Some(Err(err))),
                 Some(Ok(item)) => {
                     buf.push(item);
+                    flush_timer.start();
 
                     // Return the buffer if it reached the maximum length.
                     if buf.len() == *chunk_len {
+                        flush_timer.stop();
                         let mut fresh = Vec::with_capacity(*chunk_len);
                         std::mem::swap(buf, &mut fresh);
                         return Poll::Ready(Some(Ok(fresh)));
@@ -63,6 +125,132 @@ where
     }
 }
 
+/// A stream that chunks items up to a maximum total weight.
+///
+/// Items are added to a chunk as long as its total weight does not
+/// exceed the maximum weight. An item that is heavier than the maximum
+/// weight forms a chunk by itself.
+pub struct TryChunksByWeight<St, I, F> {
+    inner: Pin<Box<St>>,
+    buf: Vec<I>,
+    buf_weight: usize,
+    flush_timer: FlushTimer,
+    max_len: Option<usize>,
+    max_weight: usize,
+    weight: F,
+}
+
+impl<St, I, F> TryChunksByWeight<St, I, F> {
+    fn new(stream: St, max_weight: usize, weight: F) -> Self {
+        Self {
+            inner: Box::pin(stream),
+            buf: Vec::new(),
+            buf_weight: 0,
+            flush_timer: FlushTimer::default(),
+            max_len: None,
+            max_weight,
+            weight,
+        }
+    }
+
+    /// Return an incomplete chunk when `interval` passed since its first
+    /// item was received, see [`TryChunks::flush_interval`].
+    pub fn flush_interval(mut self, interval: Option<Duration>) -> Self {
+        self.flush_timer.interval = interval;
+        self
+    }
+
+    /// Return a chunk when it holds `max_len` items, regardless of its
+    /// weight.
+    ///
+    /// This bounds the number of buffered items when items are light.
+    pub fn max_len(mut self, max_len: Option<usize>) -> Self {
+        self.max_len = max_len;
+        self
+    }
+}
+
+impl<St, I, F> Stream for TryChunksByWeight<St, I, F>
+where
+    St: Stream<Item = Result<I, Error>>,
+    I: Unpin,
+    F: Fn(&I) -> usize + Unpin,
+{
+    type Item = Result<Vec<I>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let TryChunksByWeight {
+            inner,
+            buf,
+            buf_weight,
+            flush_timer,
+            max_len,
+            max_weight,
+            weight,
+        } = &mut *self;
+
+        let is_full = |buf: &Vec<I>, buf_weight: usize| {
+            buf_weight >= *max_weight || max_len.is_some_and(|max_len| buf.len() >= max_len)
+        };
+
+        // An item that did not fit in the previous chunk can fill the new
+        // chunk by itself, it is returned without waiting for more items.
+        if !buf.is_empty() && is_full(buf, *buf_weight) {
+            *buf_weight = 0;
+            flush_timer.stop();
+            return Poll::Ready(Some(Ok(std::mem::take(buf))));
+        }
+
+        loop {
+            let item = match inner.as_mut().poll_next(cx) {
+                Poll::Pending if flush_timer.poll_expired(cx) => {
+                    *buf_weight = 0;
+                    return Poll::Ready(Some(Ok(std::mem::take(buf))));
+                }
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(item) => item,
+            };
+
+            match item {
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                Some(Ok(item)) => {
+                    let item_weight = weight(&item);
+
+                    // Return the buffer if the item does not fit, the item
+                    // starts a new chunk.
+                    if !buf.is_empty() && *buf_weight + item_weight > *max_weight {
+                        let chunk = std::mem::replace(buf, vec![item]);
+                        *buf_weight = item_weight;
+                        flush_timer.stop();
+                        flush_timer.start();
+                        return Poll::Ready(Some(Ok(chunk)));
+                    }
+
+                    buf.push(item);
+                    *buf_weight += item_weight;
+                    flush_timer.start();
+
+                    // Return the buffer if it reached the maximum weight
+                    // or length.
+                    if is_full(buf, *buf_weight) {
+                        *buf_weight = 0;
+                        flush_timer.stop();
+                        return Poll::Ready(Some(Ok(std::mem::take(buf))));
+                    }
+                }
+                None => {
+                    if !buf.is_empty() {
+                        *buf_weight = 0;
+                        return Poll::Ready(Some(Ok(std::mem::take(buf))));
+                    }
+
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+}
+
 pub trait ToTryChunks<St, I> {
     fn try_chunks(self, chunk_len: usize) -> TryChunks<St, I>;
 }
@@ -76,12 +264,71 @@ where
     }
 }
 
+pub trait ToTryChunksByWeight<St, I, F> {
+    fn try_chunks_by_weight(self, max_weight: usize, weight: F) -> TryChunksByWeight<St, I, F>;
+}
+
+impl<St, I, F> ToTryChunksByWeight<St, I, F> for St
+where
+    St: Stream<Item = Result<I, Error>>,
+    F: Fn(&I) -> usize,
+{
+    fn try_chunks_by_weight(self, max_weight: usize, weight: F) -> TryChunksByWeight<St, I, F> {
+        TryChunksByWeight::new(self, max_weight, weight)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use futures::executor::block_on_stream;
     use futures::stream::{self, StreamExt};
 
-    use super::ToTryChunks;
+    use super::{ToTryChunks, ToTryChunksByWeight};
+
+    #[test]
+    fn can_chunk_by_weight() {
+        let chunks = block_on_stream(
+            stream::iter(vec![1, 2, 3, 4, 1, 1, 8, 1])
+                .map(Ok)
+                .try_chunks_by_weight(5, |&item: &usize| item),
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![vec![1, 2], vec![3], vec![4, 1], vec![1], vec![8], vec![1]]
+        );
+    }
+
+    #[test]
+    fn heavy_item_is_returned_without_waiting() {
+        let mut chunks = block_on_stream(
+            stream::iter(vec![1, 8])
+                .map(Ok)
+                .chain(stream::pending())
+                .try_chunks_by_weight(5, |&item: &usize| item),
+        );
+
+        assert_eq!(chunks.next().unwrap().unwrap(), vec![1]);
+        assert_eq!(chunks.next().unwrap().unwrap(), vec![8]);
+    }
+
+    #[test]
+    fn can_chunk_by_weight_up_to_max_len() {
+        let chunks = block_on_stream(
+            stream::iter(vec![1, 1, 1, 1, 4])
+                .map(Ok)
+                .try_chunks_by_weight(5, |&item: &usize| item)
+                .max_len(Some(3)),
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        assert_eq!(chunks, vec![vec![1, 1, 1], vec![1, 4]]);
+    }
 
     #[test]
     fn can_chunk() {
@@ -109,4 +356,17 @@ mod tests {
 
         assert_eq!(chunks, vec![vec![1, 2, 3], vec![4, 5, 6]]);
     }
+
+    #[test]
+    fn flushes_incomplete_chunk_after_interval() {
+        let mut chunks = block_on_stream(
+            stream::iter(vec![1, 2])
+                .map(Ok)
+                .chain(stream::pending())
+                .try_chunks(3)
+                .flush_interval(Some(Duration::from_millis(10))),
+        );
+
+        assert_eq!(chunks.next().unwrap().unwrap(), vec![1, 2]);
+    }
 }
@@ -0,0 +1,135 @@
+use std::pin::Pin;
+
+use futures::io::{AsyncRead, Error, ErrorKind};
+use futures::ready;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+
+/// Reader that fails when more than a given number of bytes is read.
+pub struct LimitReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R> LimitReader<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        LimitReader {
+            inner,
+            remaining: limit,
+        }
+    }
+}
+
+impl<R> AsyncRead for LimitReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Error>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        if n as u64 > self.remaining {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::InvalidData,
+                "Input exceeds the maximum size",
+            )));
+        }
+
+        self.remaining -= n as u64;
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Stream that fails when it yields more than a given number of items.
+pub struct TryLimit<S> {
+    inner: Pin<Box<S>>,
+    remaining: usize,
+}
+
+impl<S> TryLimit<S> {
+    pub fn new(inner: S, limit: usize) -> Self {
+        TryLimit {
+            inner: Box::pin(inner),
+            remaining: limit,
+        }
+    }
+}
+
+impl<S, I> Stream for TryLimit<S>
+where
+    S: Stream<Item = Result<I, Error>>,
+{
+    type Item = Result<I, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let TryLimit { inner, remaining } = &mut *self;
+
+        match ready!(inner.as_mut().poll_next(cx)) {
+            Some(Ok(_)) if *remaining == 0 => Poll::Ready(Some(Err(Error::new(
+                ErrorKind::InvalidData,
+                "Input exceeds the maximum number of items",
+            )))),
+            Some(Ok(item)) => {
+                *remaining -= 1;
+                Poll::Ready(Some(Ok(item)))
+            }
+            item => Poll::Ready(item),
+        }
+    }
+}
+
+pub trait ToTryLimit<S> {
+    fn try_limit(self, limit: usize) -> TryLimit<S>;
+}
+
+impl<S, I> ToTryLimit<S> for S
+where
+    S: Stream<Item = Result<I, Error>>,
+{
+    fn try_limit(self, limit: usize) -> TryLimit<S> {
+        TryLimit::new(self, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::{block_on, block_on_stream};
+    use futures::io::{AsyncReadExt, Cursor};
+    use futures::stream::{self, StreamExt};
+
+    use super::{LimitReader, ToTryLimit};
+
+    #[test]
+    fn stream_within_limit() {
+        let items = block_on_stream(stream::iter(1..=3).map(Ok).try_limit(3))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(items, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn stream_fails_beyond_limit() {
+        let items = block_on_stream(stream::iter(1..=3).map(Ok).try_limit(2))
+            .collect::<Result<Vec<_>, _>>();
+        assert!(items.is_err());
+    }
+
+    #[test]
+    fn reads_within_limit() {
+        let mut reader = LimitReader::new(Cursor::new(b"hello"), 5);
+        let mut data = String::new();
+        block_on(reader.read_to_string(&mut data)).unwrap();
+        assert_eq!(data, "hello");
+    }
+
+    #[test]
+    fn fails_beyond_limit() {
+        let mut reader = LimitReader::new(Cursor::new(b"hello"), 4);
+        let mut data = String::new();
+        assert!(block_on(reader.read_to_string(&mut data)).is_err());
+    }
+}
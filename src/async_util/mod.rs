@@ -1,2 +1,8 @@
 mod chunks;
-pub use chunks::ToTryChunks;
+pub use chunks::{ToTryChunks, ToTryChunksByWeight};
+
+mod limit;
+pub use limit::{LimitReader, ToTryLimit};
+
+mod progress;
+pub use progress::ProgressReader;
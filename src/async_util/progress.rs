@@ -0,0 +1,81 @@
+use std::pin::Pin;
+
+use futures::io::{AsyncRead, Error};
+use futures::ready;
+use futures::task::{Context, Poll};
+
+/// Number of progress reports over the length of the input.
+const PROGRESS_STEPS: u64 = 10;
+
+/// Reader that logs how much of the input of a known length was read.
+pub struct ProgressReader<R> {
+    inner: R,
+    name: String,
+    next_report: u64,
+    read: u64,
+    step: u64,
+    total: u64,
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(inner: R, name: impl Into<String>, total: u64) -> Self {
+        let step = (total / PROGRESS_STEPS).max(1);
+
+        ProgressReader {
+            inner,
+            name: name.into(),
+            next_report: step,
+            read: 0,
+            step,
+            total,
+        }
+    }
+}
+
+impl<R> AsyncRead for ProgressReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Error>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        self.read += n as u64;
+
+        if self.read >= self.next_report && self.total != 0 {
+            log::info!(
+                "{}: read {} of {} bytes ({}%)",
+                self.name,
+                self.read,
+                self.total,
+                self.read * 100 / self.total
+            );
+
+            // Skip steps that were completed by a single large read.
+            self.next_report = (self.read / self.step + 1) * self.step;
+        }
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::io::{AsyncReadExt, Cursor};
+
+    use super::ProgressReader;
+
+    #[test]
+    fn reads_all_data() {
+        let mut reader = ProgressReader::new(Cursor::new(b"hello world"), "test", 11);
+        let mut data = String::new();
+        block_on(reader.read_to_string(&mut data)).unwrap();
+        assert_eq!(data, "hello world");
+        assert_eq!(reader.read, 11);
+        assert_eq!(reader.next_report, 12);
+    }
+}
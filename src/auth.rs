@@ -0,0 +1,149 @@
+use sha2::{Digest, Sha256};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::config::AuthConfig;
+
+/// Compare two byte strings in constant time.
+///
+/// The strings are compared through their digests, so that the time
+/// does not depend on their lengths either.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Get the API key from the `Authorization` or `X-API-Key` header.
+///
+/// An `Authorization` header without a bearer token, such as basic
+/// authentication added by a proxy, does not hide the `X-API-Key` header.
+pub fn request_key<State>(request: &Request<State>) -> Option<&str> {
+    request
+        .header("Authorization")
+        .and_then(|authorization| authorization.last().as_str().strip_prefix("Bearer "))
+        .or_else(|| request.header("X-API-Key").map(|key| key.last().as_str()))
+}
+
+/// API key of a request that was validated by [`ApiKeyAuth`].
+///
+/// This is a request extension, requests without it do not have a
+/// valid key or were not checked because authentication is disabled.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedKey(pub String);
+
+/// Middleware that requires a valid API key.
+///
+/// Requests for public paths are passed through without a key. Other
+/// requests get a *401 Unauthorized* response when the key is missing
+/// or not one of the configured keys. Valid keys are stored in the
+/// [`AuthenticatedKey`] extension of the request, also for public paths.
+pub struct ApiKeyAuth {
+    config: AuthConfig,
+}
+
+impl ApiKeyAuth {
+    pub fn new(config: AuthConfig) -> Self {
+        ApiKeyAuth { config }
+    }
+
+    fn is_public(&self, path: &str) -> bool {
+        self.config
+            .public_paths
+            .iter()
+            .any(|public| match public.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == public,
+            })
+    }
+
+    fn is_valid_key(&self, key: &str) -> bool {
+        // Check all keys, so that timing does not reveal which key matched.
+        self.config.api_keys.iter().fold(false, |valid, api_key| {
+            constant_time_eq(api_key.as_bytes(), key.as_bytes()) | valid
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<State> Middleware<State> for ApiKeyAuth
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let key = request_key(&request)
+            .filter(|key| self.is_valid_key(key))
+            .map(ToOwned::to_owned);
+        let authenticated = key.is_some();
+        if let Some(key) = key {
+            request.set_ext(AuthenticatedKey(key));
+        }
+
+        if authenticated || self.is_public(request.url().path()) {
+            Ok(next.run(request).await)
+        } else {
+            Ok(Response::builder(StatusCode::Unauthorized)
+                .header("WWW-Authenticate", "Bearer")
+                .build())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{Method, Request as HttpRequest, Url};
+    use tide::Request;
+
+    use super::{constant_time_eq, request_key, ApiKeyAuth};
+    use crate::config::AuthConfig;
+
+    fn auth() -> ApiKeyAuth {
+        ApiKeyAuth::new(AuthConfig {
+            api_keys: vec!["secret".to_string(), "other".to_string()],
+            public_paths: vec!["/health".to_string(), "/static/*".to_string()],
+        })
+    }
+
+    #[test]
+    fn compares_keys() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+    }
+
+    #[test]
+    fn falls_back_to_api_key_header() {
+        let request = |headers: &[(&str, &str)]| {
+            let mut request = HttpRequest::new(Method::Get, Url::parse("http://x/").unwrap());
+            for (name, value) in headers {
+                request.insert_header(*name, *value);
+            }
+            Into::<Request<()>>::into(request)
+        };
+
+        let bearer = request(&[("Authorization", "Bearer secret"), ("X-API-Key", "other")]);
+        assert_eq!(request_key(&bearer), Some("secret"));
+
+        let basic = request(&[("Authorization", "Basic dXNlcg=="), ("X-API-Key", "other")]);
+        assert_eq!(request_key(&basic), Some("other"));
+
+        let basic_only = request(&[("Authorization", "Basic dXNlcg==")]);
+        assert_eq!(request_key(&basic_only), None);
+    }
+
+    #[test]
+    fn validates_keys() {
+        let auth = auth();
+        assert!(auth.is_valid_key("secret"));
+        assert!(auth.is_valid_key("other"));
+        assert!(!auth.is_valid_key(""));
+        assert!(!auth.is_valid_key("unknown"));
+    }
+
+    #[test]
+    fn matches_public_paths() {
+        let auth = auth();
+        assert!(auth.is_public("/health"));
+        assert!(auth.is_public("/static/index.js"));
+        assert!(!auth.is_public("/health/extra"));
+        assert!(!auth.is_public("/annotations/nl"));
+    }
+}
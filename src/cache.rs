@@ -0,0 +1,401 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::io::{AsyncRead, Error};
+use futures::ready;
+use futures::task::{Context, Poll};
+use serde::Serialize;
+use udgraph::graph::{Comment, Sentence};
+
+/// Counters of an annotation cache.
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Cache key of a request.
+///
+/// The key holds all of the request data that affects the output, so
+/// that different requests cannot share an entry through a hash collision.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CacheKey {
+    params: Vec<(String, String)>,
+    pipeline: String,
+    text: String,
+}
+
+/// Cache entries with the order in which they were used.
+///
+/// Every use of an entry is recorded with a new generation, so that
+/// marking an entry as recently used does not reorder the entries. The
+/// least recently used entry is the entry of the oldest record that has
+/// the current generation of the entry, older records are stale.
+#[derive(Default)]
+struct Entries {
+    generation: u64,
+    outputs: HashMap<Arc<CacheKey>, (u64, Arc<Vec<u8>>)>,
+    uses: VecDeque<(u64, Arc<CacheKey>)>,
+}
+
+impl Entries {
+    fn get(&mut self, key: &CacheKey) -> Option<Arc<Vec<u8>>> {
+        let key = self.outputs.get_key_value(key)?.0.clone();
+        let generation = self.record_use(key.clone());
+        let (entry_generation, output) = self
+            .outputs
+            .get_mut(&key)
+            .expect("Used entry is not in the cache");
+        *entry_generation = generation;
+        let output = output.clone();
+        self.remove_stale_uses();
+        Some(output)
+    }
+
+    fn insert(&mut self, key: CacheKey, output: Arc<Vec<u8>>) {
+        let key = Arc::new(key);
+        let generation = self.record_use(key.clone());
+        self.outputs.insert(key, (generation, output));
+        self.remove_stale_uses();
+    }
+
+    /// Remove the least recently used entry.
+    fn evict(&mut self) {
+        while let Some((generation, key)) = self.uses.pop_front() {
+            if is_current_use(&self.outputs, generation, &key) {
+                self.outputs.remove(&key);
+                return;
+            }
+        }
+    }
+
+    /// Record a use of an entry, returning the generation of the use.
+    fn record_use(&mut self, key: Arc<CacheKey>) -> u64 {
+        self.generation += 1;
+        self.uses.push_back((self.generation, key));
+        self.generation
+    }
+
+    /// Remove stale records once they outnumber the entries.
+    ///
+    /// This keeps the number of records below about twice the number of
+    /// entries, at an amortized constant cost per use.
+    fn remove_stale_uses(&mut self) {
+        if self.uses.len() > 2 * self.outputs.len() + 1 {
+            let outputs = &self.outputs;
+            self.uses
+                .retain(|(generation, key)| is_current_use(outputs, *generation, key));
+        }
+    }
+}
+
+/// Check whether a use record has the current generation of its entry.
+fn is_current_use(
+    outputs: &HashMap<Arc<CacheKey>, (u64, Arc<Vec<u8>>)>,
+    generation: u64,
+    key: &CacheKey,
+) -> bool {
+    outputs
+        .get(key)
+        .map(|&(entry_generation, _)| entry_generation == generation)
+        .unwrap_or(false)
+}
+
+/// LRU cache of serialized annotation responses.
+pub struct AnnotationCache {
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    max_entries: usize,
+    misses: AtomicU64,
+}
+
+impl AnnotationCache {
+    pub fn new(max_entries: usize) -> Self {
+        AnnotationCache {
+            entries: Mutex::new(Entries::default()),
+            hits: AtomicU64::new(0),
+            max_entries,
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Cache key of a request.
+    ///
+    /// `params` are the request parameters that affect the output, such
+    /// as the output format and query parameters.
+    pub fn key(pipeline: &str, params: &[(&str, &str)], text: &str) -> CacheKey {
+        CacheKey {
+            params: params
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            pipeline: pipeline.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    /// Remove all entries.
+    pub fn clear(&self) {
+        *self.entries.lock().expect("Cache lock is poisoned") = Entries::default();
+    }
+
+    /// Get the output for a key, marking it as recently used.
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<Vec<u8>>> {
+        let mut entries = self.entries.lock().expect("Cache lock is poisoned");
+        match entries.get(key) {
+            Some(output) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(output)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Insert the output for a key, evicting the least recently used entry.
+    pub fn insert(&self, key: CacheKey, output: Vec<u8>) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().expect("Cache lock is poisoned");
+        if !entries.outputs.contains_key(&key) && entries.outputs.len() >= self.max_entries {
+            entries.evict();
+        }
+        entries.insert(key, Arc::new(output));
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self
+                .entries
+                .lock()
+                .expect("Cache lock is poisoned")
+                .outputs
+                .len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Normalize input text before computing its cache key.
+///
+/// Line endings are normalized and trailing whitespace is removed, since
+/// these do not affect the annotations.
+pub fn normalize_input(text: &str) -> String {
+    let mut normalized = text
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n");
+    normalized.truncate(normalized.trim_end().len());
+    normalized
+}
+
+/// Check whether a sentence marks a response that was cut short.
+///
+/// Such responses are incomplete and must not be cached.
+pub fn is_cut_short_marker(sentence: &Sentence) -> bool {
+    // The root is the only node of a marker sentence.
+    sentence.len() == 1
+        && sentence.comments().iter().any(|comment| match comment {
+            Comment::AttrVal { attr, .. } => attr == "shutdown" || attr == "truncated",
+            _ => false,
+        })
+}
+
+/// Check whether a response with this sentence can be cached.
+///
+/// Responses that were cut short cannot be cached. Neither can responses
+/// with sentences that failed (`# error = ...`) or with skipped input
+/// (`# skipped = N`), since these failures can be transient.
+pub fn is_cacheable(sentence: &Sentence) -> bool {
+    !is_cut_short_marker(sentence)
+        && !sentence.comments().iter().any(|comment| match comment {
+            Comment::AttrVal { attr, .. } => attr == "error" || attr == "skipped",
+            _ => false,
+        })
+}
+
+/// Reader that stores its output in the cache once it is complete.
+pub struct CachingReader<R> {
+    cache: Arc<AnnotationCache>,
+    cacheable: Arc<AtomicBool>,
+    inner: Pin<Box<R>>,
+    key: CacheKey,
+    output: Vec<u8>,
+}
+
+impl<R> CachingReader<R> {
+    /// Construct a caching reader.
+    ///
+    /// The output is not stored when `cacheable` is `false` when the
+    /// reader is exhausted.
+    pub fn new(
+        cache: Arc<AnnotationCache>,
+        key: CacheKey,
+        cacheable: Arc<AtomicBool>,
+        inner: R,
+    ) -> Self {
+        CachingReader {
+            cache,
+            cacheable,
+            inner: Box::pin(inner),
+            key,
+            output: Vec::new(),
+        }
+    }
+}
+
+impl<R> AsyncRead for CachingReader<R>
+where
+    R: AsyncRead,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Error>> {
+        let CachingReader {
+            cache,
+            cacheable,
+            inner,
+            key,
+            output,
+        } = &mut *self;
+
+        let n = ready!(inner.as_mut().poll_read(cx, buf))?;
+        if n == 0 {
+            // Store the output only once, also when read after EOF.
+            if cacheable.swap(false, Ordering::SeqCst) {
+                cache.insert(key.clone(), std::mem::take(output));
+            }
+        } else {
+            output.extend_from_slice(&buf[..n]);
+        }
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+    use futures::io::{AsyncReadExt, Cursor};
+    use udgraph::graph::{Comment, Sentence};
+    use udgraph::token::Token;
+
+    use super::{
+        is_cacheable, is_cut_short_marker, normalize_input, AnnotationCache, CacheKey,
+        CachingReader,
+    };
+
+    fn key(text: &str) -> CacheKey {
+        AnnotationCache::key("test", &[("format", "text/x-conllu")], text)
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = AnnotationCache::new(2);
+        cache.insert(key("a"), b"a".to_vec());
+        cache.insert(key("b"), b"b".to_vec());
+        assert!(cache.get(&key("a")).is_some());
+        cache.insert(key("c"), b"c".to_vec());
+
+        assert!(cache.get(&key("b")).is_none());
+        assert_eq!(cache.get(&key("a")).unwrap().as_slice(), b"a");
+        assert_eq!(cache.get(&key("c")).unwrap().as_slice(), b"c");
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 1);
+    }
+
+    #[test]
+    fn repeated_uses_do_not_grow_the_cache() {
+        let cache = AnnotationCache::new(2);
+        cache.insert(key("a"), b"a".to_vec());
+        cache.insert(key("b"), b"b".to_vec());
+        for _ in 0..100 {
+            assert!(cache.get(&key("b")).is_some());
+        }
+        cache.insert(key("a"), b"a2".to_vec());
+        cache.insert(key("c"), b"c".to_vec());
+
+        // `b` was used after `a` was inserted, but not after `a` was replaced.
+        assert!(cache.get(&key("b")).is_none());
+        assert_eq!(cache.get(&key("a")).unwrap().as_slice(), b"a2");
+        assert!(cache.entries.lock().unwrap().uses.len() <= 5);
+    }
+
+    #[test]
+    fn normalizes_input() {
+        assert_eq!(
+            normalize_input("Een zin. \r\nNog een zin.\n\n"),
+            "Een zin.\nNog een zin."
+        );
+    }
+
+    #[test]
+    fn detects_markers() {
+        let mut marker = Sentence::new();
+        marker.comments_mut().push(Comment::AttrVal {
+            attr: "truncated".to_string(),
+            val: "true".to_string(),
+        });
+        assert!(is_cut_short_marker(&marker));
+
+        let mut sentence: Sentence = vec![Token::new("Een")].into_iter().collect();
+        *sentence.comments_mut() = marker.comments().to_vec();
+        assert!(!is_cut_short_marker(&sentence));
+    }
+
+    #[test]
+    fn failures_are_not_cacheable() {
+        let sentence: Sentence = vec![Token::new("Een")].into_iter().collect();
+        assert!(is_cacheable(&sentence));
+
+        for &attr in &["error", "skipped", "truncated"] {
+            let mut failed = if attr == "error" {
+                sentence.clone()
+            } else {
+                Sentence::new()
+            };
+            failed.comments_mut().push(Comment::AttrVal {
+                attr: attr.to_string(),
+                val: "1".to_string(),
+            });
+            assert!(!is_cacheable(&failed));
+        }
+    }
+
+    #[test]
+    fn reader_stores_complete_output() {
+        let cache = Arc::new(AnnotationCache::new(2));
+
+        for &(text, cacheable) in &[("a", true), ("b", false)] {
+            let mut reader = CachingReader::new(
+                cache.clone(),
+                key(text),
+                Arc::new(AtomicBool::new(cacheable)),
+                Cursor::new(b"output".to_vec()),
+            );
+            let mut output = String::new();
+            block_on(reader.read_to_string(&mut output)).unwrap();
+            assert_eq!(output, "output");
+        }
+
+        assert_eq!(cache.get(&key("a")).unwrap().as_slice(), b"output");
+        assert!(cache.get(&key("b")).is_none());
+    }
+}
@@ -0,0 +1,104 @@
+use std::net::{IpAddr, SocketAddr};
+
+use tide::Request;
+
+/// Get the IP address of the client that sent a request.
+///
+/// When the socket peer is one of the trusted proxies, the client is
+/// taken from the `X-Forwarded-For` header. The header is read from
+/// right to left, skipping trusted proxies, since only the entries
+/// added by trusted proxies can be relied on. Without trusted proxies,
+/// the socket peer address is used.
+pub fn client_ip<State>(request: &Request<State>, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = request.peer_addr().and_then(parse_ip)?;
+
+    let forwarded_for = request
+        .header("X-Forwarded-For")
+        .map(|values| {
+            values
+                .iter()
+                .map(|value| value.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+
+    Some(resolve_client(peer, &forwarded_for, trusted_proxies))
+}
+
+/// Resolve the client address from the peer address and the value of
+/// `X-Forwarded-For`.
+fn resolve_client(peer: IpAddr, forwarded_for: &str, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    if !trusted_proxies.contains(&client) {
+        return client;
+    }
+
+    for hop in forwarded_for.rsplit(',') {
+        match parse_ip(hop) {
+            Some(ip) => {
+                client = ip;
+                if !trusted_proxies.contains(&ip) {
+                    break;
+                }
+            }
+            // Entries beyond a malformed entry cannot be trusted.
+            None => break,
+        }
+    }
+
+    client
+}
+
+/// Parse an IP address that may include a port.
+fn parse_ip(addr: &str) -> Option<IpAddr> {
+    let addr = addr.trim();
+    addr.parse::<IpAddr>()
+        .ok()
+        .or_else(|| addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{parse_ip, resolve_client};
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_addresses_with_ports() {
+        assert_eq!(parse_ip("10.0.0.1:8080"), Some(ip("10.0.0.1")));
+        assert_eq!(parse_ip(" ::1 "), Some(ip("::1")));
+        assert_eq!(parse_ip("[::1]:8080"), Some(ip("::1")));
+        assert_eq!(parse_ip("unknown"), None);
+    }
+
+    #[test]
+    fn untrusted_peer_is_client() {
+        assert_eq!(
+            resolve_client(ip("192.0.2.1"), "198.51.100.1", &[ip("10.0.0.1")]),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_client_behind_trusted_proxies() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        assert_eq!(
+            resolve_client(
+                ip("10.0.0.1"),
+                "203.0.113.9, 198.51.100.1, 10.0.0.2",
+                &trusted
+            ),
+            ip("198.51.100.1")
+        );
+        assert_eq!(resolve_client(ip("10.0.0.1"), "", &trusted), ip("10.0.0.1"));
+        assert_eq!(
+            resolve_client(ip("10.0.0.1"), "bogus, 10.0.0.2", &trusted),
+            ip("10.0.0.2")
+        );
+    }
+}
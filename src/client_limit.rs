@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::io::{AsyncBufRead, AsyncRead};
+use tide::{Body, Middleware, Next, Request, Response, StatusCode};
+
+use crate::auth::AuthenticatedKey;
+use crate::client::client_ip;
+
+/// Number of open requests per client.
+///
+/// Clients are identified by their API key when it was validated, or by
+/// their IP address otherwise.
+pub struct ClientConnections {
+    connections: Mutex<HashMap<String, usize>>,
+    max_connections: usize,
+}
+
+impl ClientConnections {
+    pub fn new(max_connections: usize) -> Self {
+        ClientConnections {
+            connections: Mutex::new(HashMap::new()),
+            max_connections,
+        }
+    }
+
+    /// Acquire a slot for a client, returns `None` when the client has
+    /// the maximum number of open requests.
+    fn acquire(self: &Arc<Self>, client: String) -> Option<ClientGuard> {
+        let mut connections = self
+            .connections
+            .lock()
+            .expect("Client connections lock is poisoned");
+        let count = connections.entry(client.clone()).or_insert(0);
+        if *count >= self.max_connections {
+            return None;
+        }
+        *count += 1;
+
+        Some(ClientGuard {
+            client,
+            connections: self.clone(),
+        })
+    }
+
+    fn release(&self, client: &str) {
+        let mut connections = self
+            .connections
+            .lock()
+            .expect("Client connections lock is poisoned");
+        if let Some(count) = connections.get_mut(client) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(client);
+            }
+        }
+    }
+}
+
+/// Client slot, released when dropped.
+struct ClientGuard {
+    client: String,
+    connections: Arc<ClientConnections>,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.connections.release(&self.client);
+    }
+}
+
+/// Response body that holds a client slot until it is dropped.
+///
+/// Streaming responses are still written after the handler returns,
+/// so the slot is only released once the body is written or the
+/// connection is closed.
+struct GuardedBody {
+    body: Body,
+    _guard: ClientGuard,
+}
+
+impl AsyncRead for GuardedBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for GuardedBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
+
+/// Middleware that limits the number of open requests per client.
+///
+/// Requests of a client that has the maximum number of open requests
+/// get a *429 Too Many Requests* response. This is separate from the
+/// global connection limit, which applies to all clients together.
+pub struct ClientLimit {
+    connections: Arc<ClientConnections>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl ClientLimit {
+    pub fn new(connections: Arc<ClientConnections>, trusted_proxies: Vec<IpAddr>) -> Self {
+        ClientLimit {
+            connections,
+            trusted_proxies,
+        }
+    }
+
+    /// Identify the client of a request.
+    ///
+    /// Only keys that were validated by [`ApiKeyAuth`] are used, so that
+    /// clients cannot get a new limit by sending arbitrary keys.
+    ///
+    /// [`ApiKeyAuth`]: crate::auth::ApiKeyAuth
+    fn client<State>(&self, request: &Request<State>) -> Option<String> {
+        match request.ext::<AuthenticatedKey>() {
+            Some(AuthenticatedKey(key)) => Some(format!("key:{}", key)),
+            None => client_ip(request, &self.trusted_proxies).map(|ip| format!("ip:{}", ip)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<State> Middleware<State> for ClientLimit
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // Requests from unknown clients cannot be attributed.
+        let client = match self.client(&request) {
+            Some(client) => client,
+            None => return Ok(next.run(request).await),
+        };
+
+        let guard = match self.connections.acquire(client) {
+            Some(guard) => guard,
+            None => {
+                return Ok(Response::builder(StatusCode::TooManyRequests)
+                    .body("Too many open requests from this client")
+                    .build())
+            }
+        };
+
+        let mut response = next.run(request).await;
+        let body = response.take_body();
+        let len = body.len();
+        let mime = body.mime().clone();
+        let mut guarded = Body::from_reader(
+            GuardedBody {
+                body,
+                _guard: guard,
+            },
+            len,
+        );
+        guarded.set_mime(mime);
+        response.set_body(guarded);
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::http::{Method, Request as HttpRequest, Url};
+    use tide::Request;
+
+    use super::{ClientConnections, ClientLimit};
+    use crate::auth::AuthenticatedKey;
+
+    #[test]
+    fn clients_are_identified_by_authenticated_keys() {
+        let limit = ClientLimit::new(Arc::new(ClientConnections::new(1)), Vec::new());
+
+        let mut request = HttpRequest::new(Method::Get, Url::parse("http://x/").unwrap());
+        request.set_peer_addr(Some("10.0.0.1:4000"));
+        request.insert_header("X-API-Key", "unchecked");
+        let mut request: Request<()> = request.into();
+        assert_eq!(limit.client(&request).as_deref(), Some("ip:10.0.0.1"));
+
+        request.set_ext(AuthenticatedKey("secret".to_string()));
+        assert_eq!(limit.client(&request).as_deref(), Some("key:secret"));
+    }
+
+    #[test]
+    fn limits_connections_per_client() {
+        let connections = Arc::new(ClientConnections::new(2));
+
+        let first = connections.acquire("ip:10.0.0.1".to_string()).unwrap();
+        let _second = connections.acquire("ip:10.0.0.1".to_string()).unwrap();
+        assert!(connections.acquire("ip:10.0.0.1".to_string()).is_none());
+
+        // Other clients have their own limit.
+        assert!(connections.acquire("ip:10.0.0.2".to_string()).is_some());
+
+        drop(first);
+        assert!(connections.acquire("ip:10.0.0.1".to_string()).is_some());
+    }
+
+    #[test]
+    fn released_clients_are_removed() {
+        let connections = Arc::new(ClientConnections::new(1));
+        drop(connections.acquire("key:secret".to_string()).unwrap());
+        assert!(connections.connections.lock().unwrap().is_empty());
+    }
+}
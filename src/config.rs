@@ -1,46 +1,136 @@
+use std::env;
 use std::fs::File;
 use std::io::{BufReader, Read};
-use std::path::Path;
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use alpino_tokenizer::{AlpinoTokenizer, Tokenizer};
 use anyhow::{anyhow, Result};
 use indexmap::IndexMap;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tch::Device;
 
-use crate::annotator::Annotator;
-use crate::pipeline::Pipeline;
-use crate::tokenizer::WhitespaceTokenizer;
+use crate::annotator::{
+    parse_device, Annotate, Annotator, AnnotatorCell, AnnotatorLoader, LengthUnit,
+    OversizedSentences, Precision, ResidentAnnotators, UndersizedSentences,
+};
+use crate::async_conllu::OutputValidation;
+use crate::async_syntaxdot::{Casing, ControlCharacters, Normalization, SentenceText, TagMap};
+use crate::format::OutputFormat;
+use crate::kafka::{KafkaConfig, KafkaSink};
+use crate::pipeline::{Pipeline, DEFAULT_MAX_CHUNK_LEN};
+use crate::plugin::{WasmPlugin, DEFAULT_PLUGIN_FUEL, DEFAULT_PLUGIN_MAX_MEMORY};
+use crate::tokenizer::{AbbreviationTokenizer, WhitespaceTokenizer};
 
 /// SyntaxDot REST server configuration
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
+    /// Only warn about pipelines that combine an annotator and tokenizer
+    /// for different languages, rather than rejecting the configuration.
+    #[serde(default)]
+    allow_language_mismatch: bool,
+
+    /// Pinning of annotation threads to CPU cores, disabled when absent.
+    annotation_thread_affinity: Option<ThreadAffinityConfig>,
+
     /// Tokenizer + annotation pipelines
     annotators: IndexMap<String, AnnotatorConfig>,
 
+    /// API key authentication, disabled when absent.
+    auth: Option<AuthConfig>,
+
+    /// Caching of annotation responses, disabled when absent.
+    cache: Option<CacheConfig>,
+
+    /// Maximum number of sentences that a pipeline reads ahead per
+    /// request, regardless of its batch size and read-ahead.
+    max_chunk_sentences: Option<usize>,
+
+    /// Maximum number of annotators that are loaded at the same time.
+    ///
+    /// When set, annotators are loaded on first use and the least
+    /// recently used annotator is unloaded when the maximum is exceeded.
+    /// The first request waits for the annotator to load, for at most the
+    /// load timeout. When absent, all annotators are loaded at startup.
+    max_loaded_models: Option<usize>,
+
     /// Pipelines
     pipelines: IndexMap<String, PipelineConfig>,
 
+    /// Log annotation requests that take at least this number of
+    /// milliseconds, disabled when absent.
+    slow_request_ms: Option<u64>,
+
+    /// Spooling of large request bodies to disk, disabled when absent.
+    spool: Option<SpoolConfig>,
+
     /// Tokenizers
     tokenizers: IndexMap<String, TokenizerConfig>,
+
+    /// Addresses of proxies whose `X-Forwarded-For` header is trusted
+    /// to identify clients.
+    #[serde(default)]
+    trusted_proxies: Vec<IpAddr>,
+
+    /// Annotation of input fetched from URLs, disabled when absent.
+    url_input: Option<UrlInputConfig>,
+
+    /// Directory of the configuration file, absent for parsed configurations.
+    #[serde(skip)]
+    dir: Option<PathBuf>,
 }
 
 impl Config {
-    /// Read a SyntaxDot REST server configuration file.
-    pub fn read<P, R>(config_path: P, mut read: R) -> Result<Self>
+    /// Parse a SyntaxDot REST server configuration.
+    ///
+    /// In contrast to [`Config::read`], file names are not canonicalized.
+    /// This makes it possible to validate a configuration without access
+    /// to the files it refers to.
+    pub fn parse<R>(mut read: R) -> Result<Self>
     where
-        P: AsRef<Path>,
         R: Read,
     {
         let mut yaml = String::new();
         read.read_to_string(&mut yaml)?;
 
-        let mut config: Config = serde_yaml::from_str(&yaml)?;
+        let config: Config = serde_yaml::from_str(&yaml)?;
+        config.check_references()?;
+        config.check_languages()?;
+
+        Ok(config)
+    }
+
+    /// Read a SyntaxDot REST server configuration file.
+    pub fn read<P, R>(config_path: P, read: R) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        R: Read,
+    {
+        let mut config = Self::parse(read)?;
+        config.dir = config_path
+            .as_ref()
+            .canonicalize()?
+            .parent()
+            .map(ToOwned::to_owned);
 
         for tokenizer_config in config.tokenizers.values_mut() {
-            if let TokenizerConfig::AlpinoTokenizer(ref mut protobuf) = tokenizer_config {
-                *protobuf = canonicalize_path(config_path.as_ref(), &protobuf)?;
+            if let TokenizerConfig::AlpinoTokenizer(ref mut alpino_config) = tokenizer_config {
+                alpino_config.protobuf =
+                    canonicalize_path(config_path.as_ref(), &alpino_config.protobuf)?;
+            }
+        }
+
+        for pipeline_config in config.pipelines.values_mut() {
+            if let Some(tag_map) = &mut pipeline_config.tag_map {
+                *tag_map = canonicalize_path(config_path.as_ref(), tag_map)?;
+            }
+
+            if let Some(plugin) = &mut pipeline_config.wasm_plugin {
+                plugin.path = canonicalize_path(config_path.as_ref(), &plugin.path)?;
             }
         }
 
@@ -49,52 +139,604 @@ impl Config {
                 canonicalize_path(config_path.as_ref(), &annotator_config.syntaxdot_config)?;
         }
 
+        if let Some(dir) = config.spool.as_mut().and_then(|spool| spool.dir.as_mut()) {
+            *dir = canonicalize_path(config_path.as_ref(), dir)?;
+        }
+
         Ok(config)
     }
 
-    /// Load tokenizers and annotators and construct pipelines out of them.
-    pub fn load(&self) -> Result<IndexMap<String, Pipeline>> {
+    /// Load the tokenizers.
+    pub fn load_tokenizers(&self) -> Result<IndexMap<String, Arc<dyn Tokenizer + Send + Sync>>> {
         let mut tokenizers = IndexMap::new();
         for (name, tokenizer_config) in &self.tokenizers {
             let tokenizer = tokenizer_config.load()?;
             tokenizers.insert(name.to_string(), tokenizer);
         }
 
-        let mut annotators = IndexMap::new();
+        Ok(tokenizers)
+    }
+
+    /// Tokenizer configurations.
+    pub fn tokenizers(&self) -> &IndexMap<String, TokenizerConfig> {
+        &self.tokenizers
+    }
+
+    /// Name of the tokenizer that is used by a pipeline.
+    ///
+    /// Only pipelines of the configuration are considered, not pipelines
+    /// that were added at runtime.
+    pub fn pipeline_tokenizer(&self, pipeline: &str) -> Option<&str> {
+        self.pipelines
+            .get(pipeline)
+            .map(|pipeline_config| pipeline_config.tokenizer.as_str())
+    }
+
+    /// Names of the tokenizers that can be used with a pipeline.
+    ///
+    /// These are the tokenizers of the pipelines of the configuration
+    /// that use the same annotator as the given pipeline.
+    pub fn compatible_tokenizers(&self, pipeline: &str) -> Vec<&str> {
+        let annotator = match self.pipelines.get(pipeline) {
+            Some(pipeline_config) => &pipeline_config.annotator,
+            None => return Vec::new(),
+        };
+
+        let mut tokenizers = Vec::new();
+        for pipeline_config in self.pipelines.values() {
+            let tokenizer = pipeline_config.tokenizer.as_str();
+            if &pipeline_config.annotator == annotator && !tokenizers.contains(&tokenizer) {
+                tokenizers.push(tokenizer);
+            }
+        }
+
+        tokenizers
+    }
+
+    /// Authentication configuration.
+    pub fn auth(&self) -> Option<&AuthConfig> {
+        self.auth.as_ref()
+    }
+
+    /// Configuration for pinning annotation threads to cores.
+    pub fn annotation_thread_affinity(&self) -> Option<&ThreadAffinityConfig> {
+        self.annotation_thread_affinity.as_ref()
+    }
+
+    /// Annotation cache configuration.
+    pub fn cache(&self) -> Option<&CacheConfig> {
+        self.cache.as_ref()
+    }
+
+    /// Maximum number of sentences that a pipeline reads ahead.
+    pub fn max_chunk_len(&self) -> usize {
+        self.max_chunk_sentences.unwrap_or(DEFAULT_MAX_CHUNK_LEN)
+    }
+
+    /// Threshold for logging slow annotation requests.
+    pub fn slow_request_threshold(&self) -> Option<Duration> {
+        self.slow_request_ms.map(Duration::from_millis)
+    }
+
+    /// Configuration for spooling request bodies to disk.
+    pub fn spool(&self) -> Option<&SpoolConfig> {
+        self.spool.as_ref()
+    }
+
+    /// Proxies that are trusted to forward client addresses.
+    pub fn trusted_proxies(&self) -> &[IpAddr] {
+        &self.trusted_proxies
+    }
+
+    /// Configuration for annotating input from URLs.
+    pub fn url_input(&self) -> Option<&UrlInputConfig> {
+        self.url_input.as_ref()
+    }
+
+    /// Check a pipeline configuration that is added at runtime.
+    ///
+    /// Such pipelines cannot publish to Kafka or use WASM plugins. The tag
+    /// map is resolved against the directory of the configuration file and
+    /// must be inside that directory.
+    pub fn check_runtime_pipeline(&self, pipeline_config: &mut PipelineConfig) -> Result<()> {
+        if pipeline_config.kafka.is_some() {
+            return Err(anyhow!("Pipelines added at runtime cannot use Kafka"));
+        }
+
+        if pipeline_config.wasm_plugin.is_some() {
+            return Err(anyhow!(
+                "Pipelines added at runtime cannot use WASM plugins"
+            ));
+        }
+
+        if let Some(tag_map) = &mut pipeline_config.tag_map {
+            let dir = self.dir.as_ref().ok_or_else(|| {
+                anyhow!("Tag maps require a configuration file to resolve against")
+            })?;
+            let path = dir
+                .join(&tag_map)
+                .canonicalize()
+                .map_err(|err| anyhow!("Cannot read tag map `{}`: {}", tag_map, err))?;
+            if !path.starts_with(dir) {
+                return Err(anyhow!(
+                    "Tag map `{}` is outside the configuration directory",
+                    tag_map
+                ));
+            }
+            *tag_map = path.to_string_lossy().into_owned();
+        }
+
+        Ok(())
+    }
+
+    /// Check that pipelines refer to configured annotators and tokenizers.
+    fn check_references(&self) -> Result<()> {
+        for (name, pipeline_config) in &self.pipelines {
+            if !self.annotators.contains_key(&pipeline_config.annotator) {
+                return Err(anyhow!(
+                    "Pipeline `{}` uses unknown annotator `{}`",
+                    name,
+                    pipeline_config.annotator
+                ));
+            }
+
+            if !self.tokenizers.contains_key(&pipeline_config.tokenizer) {
+                return Err(anyhow!(
+                    "Pipeline `{}` uses unknown tokenizer `{}`",
+                    name,
+                    pipeline_config.tokenizer
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that pipelines use an annotator and tokenizer for the same
+    /// language.
+    ///
+    /// Annotators and tokenizers without a language match any language.
+    fn check_languages(&self) -> Result<()> {
+        for (name, pipeline_config) in &self.pipelines {
+            let annotator_language = self.annotators[&pipeline_config.annotator]
+                .language
+                .as_deref();
+            let tokenizer_language = self.tokenizers[&pipeline_config.tokenizer].language();
+
+            if let (Some(annotator_language), Some(tokenizer_language)) =
+                (annotator_language, tokenizer_language)
+            {
+                if annotator_language.eq_ignore_ascii_case(tokenizer_language) {
+                    continue;
+                }
+
+                let message = format!(
+                    "Pipeline `{}` uses annotator `{}` for language `{}` with tokenizer `{}` for language `{}`",
+                    name,
+                    pipeline_config.annotator,
+                    annotator_language,
+                    pipeline_config.tokenizer,
+                    tokenizer_language
+                );
+
+                if self.allow_language_mismatch {
+                    log::warn!("{}", message);
+                } else {
+                    return Err(anyhow!(message));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load tokenizers and annotators and construct pipelines out of them.
+    ///
+    /// If `background` is `true`, this method returns as soon as the
+    /// tokenizers are loaded. The annotators are then loaded in background
+    /// threads and pipelines report their status through
+    /// [`Pipeline::status`].
+    ///
+    /// Pipelines are warmed up after their annotator is loaded.
+    ///
+    /// If `max_loaded_models` is set, annotators are not loaded here,
+    /// but on first use.
+    pub fn load(&self, background: bool) -> Result<Loaded> {
+        self.load_reporting(background, &Mutex::new(None))
+    }
+
+    /// Load the tokenizers and annotators, failing when loading takes
+    /// longer than `timeout`.
+    ///
+    /// The error names the pipelines that were still loading. This only
+    /// bounds loading that happens before this method returns, so with
+    /// `background` set, loading of the annotators is not bounded.
+    pub fn load_with_timeout(&self, background: bool, timeout: Duration) -> Result<Loaded> {
+        let loading = Arc::new(Mutex::new(None));
+        let (sender, receiver) = mpsc::channel();
+
+        {
+            let config = self.clone();
+            let loading = loading.clone();
+            thread::spawn(move || {
+                // The receiver is gone when loading timed out.
+                let _ = sender.send(config.load_reporting(background, &loading));
+            });
+        }
+
+        match receiver.recv_timeout(timeout) {
+            Ok(loaded) => loaded,
+            Err(RecvTimeoutError::Timeout) => Err(anyhow!(
+                "Loading did not finish within {:.1}s, still loading {}",
+                timeout.as_secs_f64(),
+                loading
+                    .lock()
+                    .expect("Loading lock is poisoned")
+                    .as_deref()
+                    .unwrap_or("the configuration")
+            )),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(anyhow!("Loading thread stopped without a result"))
+            }
+        }
+    }
+
+    /// Load, recording a description of what is being loaded in `loading`.
+    fn load_reporting(&self, background: bool, loading: &Mutex<Option<String>>) -> Result<Loaded> {
+        let report = |what: String| *loading.lock().expect("Loading lock is poisoned") = Some(what);
+
+        report("tokenizers".to_string());
+
+        if let Some(max_loaded) = self.max_loaded_models {
+            return self.load_lazy(max_loaded);
+        }
+
+        let tokenizers = self.load_tokenizers()?;
+
+        let annotators: IndexMap<_, _> = self
+            .annotators
+            .iter()
+            .map(|(name, annotator_config)| {
+                let cell = AnnotatorCell::loading()
+                    .max_concurrent_chunks(annotator_config.max_concurrent_chunks);
+                (name.to_string(), Arc::new(cell))
+            })
+            .collect();
+
+        let mut pipelines = IndexMap::new();
+        for (name, pipeline_config) in &self.pipelines {
+            let pipeline = pipeline_config.new_pipeline(
+                name,
+                self.max_chunk_len(),
+                &annotators,
+                &tokenizers,
+            )?;
+            pipelines.insert(name.to_string(), pipeline);
+        }
+
         for (name, annotator_config) in &self.annotators {
-            let annotator = annotator_config.load()?;
-            annotators.insert(name.to_string(), Arc::new(annotator));
+            let cell = annotators[name].clone();
+            let annotator_pipelines = pipelines
+                .values()
+                .zip(self.pipelines.values())
+                .filter(|(_, pipeline_config)| &pipeline_config.annotator == name)
+                .map(|(pipeline, _)| pipeline.clone())
+                .collect::<Vec<_>>();
+
+            if background {
+                let annotator_config = annotator_config.clone();
+                let name = name.clone();
+                thread::spawn(move || {
+                    let annotator = annotator_config.load(&name);
+                    let loaded = annotator.is_ok();
+                    match &annotator {
+                        Ok(_) => log::info!("Loaded annotator `{}`", name),
+                        Err(err) => log::error!("Cannot load annotator `{}`: {:#}", name, err),
+                    }
+                    cell.set(annotator);
+
+                    // The pipelines cannot be warmed up without the
+                    // annotator, their status reports the failure.
+                    if !loaded {
+                        for pipeline in &annotator_pipelines {
+                            pipeline.skip_warmup();
+                        }
+                        return;
+                    }
+
+                    warmup_pipelines(&annotator_pipelines);
+                });
+            } else {
+                let pipeline_names = annotator_pipelines
+                    .iter()
+                    .map(|pipeline| format!("`{}`", pipeline.name()))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                report(format!(
+                    "pipelines {} (annotator `{}`)",
+                    pipeline_names, name
+                ));
+
+                cell.set(Ok(annotator_config.load(name)?));
+                warmup_pipelines(&annotator_pipelines);
+            }
+        }
+
+        Ok(Loaded {
+            annotators,
+            pipelines,
+            tokenizers,
+        })
+    }
+
+    /// Load tokenizers and construct pipelines with annotators that are
+    /// loaded on demand.
+    fn load_lazy(&self, max_loaded: usize) -> Result<Loaded> {
+        if max_loaded == 0 {
+            return Err(anyhow!("max_loaded_models must be at least 1"));
         }
 
+        let tokenizers = self.load_tokenizers()?;
+
+        let resident = Arc::new(ResidentAnnotators::new(max_loaded));
+        let annotators: IndexMap<_, _> = self
+            .annotators
+            .iter()
+            .map(|(name, annotator_config)| {
+                let max_concurrent_chunks = annotator_config.max_concurrent_chunks;
+                let annotator_config = annotator_config.clone();
+                let loader_name = name.clone();
+                let loader: AnnotatorLoader = Box::new(move || {
+                    let annotator = annotator_config.load(&loader_name)?;
+                    log::info!("Loaded annotator `{}`", loader_name);
+                    Ok(Arc::new(annotator) as Arc<dyn Annotate + Send + Sync>)
+                });
+                let cell = AnnotatorCell::lazy(name.clone(), loader, resident.clone())
+                    .max_concurrent_chunks(max_concurrent_chunks);
+                (name.to_string(), Arc::new(cell))
+            })
+            .collect();
+
         let mut pipelines = IndexMap::new();
         for (name, pipeline_config) in &self.pipelines {
-            let pipeline = pipeline_config.new_pipeline(name, &annotators, &tokenizers)?;
+            let pipeline = pipeline_config.new_pipeline(
+                name,
+                self.max_chunk_len(),
+                &annotators,
+                &tokenizers,
+            )?;
+            pipeline.skip_warmup();
             pipelines.insert(name.to_string(), pipeline);
         }
 
-        Ok(pipelines)
+        Ok(Loaded {
+            annotators,
+            pipelines,
+            tokenizers,
+        })
+    }
+}
+
+/// Annotators and tokenizers of a configuration, with the pipelines
+/// constructed from them.
+pub struct Loaded {
+    pub annotators: IndexMap<String, Arc<AnnotatorCell>>,
+    pub pipelines: IndexMap<String, Pipeline>,
+    pub tokenizers: IndexMap<String, Arc<dyn Tokenizer + Send + Sync>>,
+}
+
+/// Warm up pipelines, logging failures.
+fn warmup_pipelines(pipelines: &[Pipeline]) {
+    for pipeline in pipelines {
+        if let Err(err) = pipeline.warmup() {
+            log::error!("Cannot warm up pipeline `{}`: {:#}", pipeline.name(), err);
+        }
+    }
+}
+
+/// API key authentication configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuthConfig {
+    /// Accepted API keys.
+    #[serde(skip_serializing)]
+    pub api_keys: Vec<String>,
+
+    /// Paths that can be accessed without an API key.
+    ///
+    /// A trailing `*` matches any path with the given prefix.
+    #[serde(default)]
+    pub public_paths: Vec<String>,
+}
+
+/// Configuration of pinning annotation threads to CPU cores.
+///
+/// Each thread of the annotation thread pool is pinned to one core, the
+/// cores are assigned round-robin. On multi-socket machines, listing the
+/// cores of a single NUMA node keeps annotation threads close to the
+/// memory of the models. Pinning is skipped with a warning on platforms
+/// where the cores cannot be queried.
+///
+/// Pinning is experimental and requires the `thread-affinity` feature,
+/// the server does not start when it is configured without the feature.
+/// No throughput benefit has been measured, so it should only be enabled
+/// after comparing runs with and without pinning on the target machine.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ThreadAffinityConfig {
+    /// Cores that annotation threads are pinned to, all cores when empty.
+    #[serde(default)]
+    pub cores: Vec<usize>,
+}
+
+/// Configuration of the annotation response cache.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CacheConfig {
+    /// Maximum number of cached responses.
+    pub max_entries: usize,
+
+    /// Maximum size in bytes of inputs whose annotations are cached.
+    ///
+    /// Cached requests are read in memory before annotation, so this
+    /// bounds the memory use of such requests.
+    #[serde(default = "default_cache_max_input_size")]
+    pub max_input_size: u64,
+}
+
+fn default_cache_max_input_size() -> u64 {
+    64 * 1024
+}
+
+/// Configuration of spooling request bodies to disk.
+///
+/// Spooled bodies are read from disk by the annotation pipeline, so that
+/// large documents do not have to be buffered while the client uploads.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SpoolConfig {
+    /// Directory of the spool files, the system temporary directory when
+    /// absent.
+    pub dir: Option<String>,
+
+    /// Maximum size in bytes of request bodies that are spooled.
+    ///
+    /// Larger bodies are rejected with *413 Payload Too Large*.
+    pub max_size: Option<u64>,
+
+    /// Minimum size in bytes of request bodies that are spooled.
+    ///
+    /// Bodies of unknown length, such as chunked uploads, are never
+    /// spooled, so that they are annotated while they are uploaded.
+    #[serde(default = "default_spool_min_size")]
+    pub min_size: u64,
+}
+
+impl SpoolConfig {
+    /// Directory of the spool files.
+    pub fn dir(&self) -> PathBuf {
+        self.dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir)
+    }
+
+    /// Check whether a body with the given length exceeds the maximum
+    /// spool size.
+    pub fn exceeds_max_size(&self, len: Option<usize>) -> bool {
+        match (len, self.max_size) {
+            (Some(len), Some(max_size)) => len as u64 > max_size,
+            _ => false,
+        }
+    }
+
+    /// Check whether a body with the given length should be spooled.
+    pub fn should_spool(&self, len: Option<usize>) -> bool {
+        len.is_some_and(|len| len as u64 >= self.min_size)
     }
 }
 
+fn default_spool_min_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+/// Configuration for annotating input fetched from URLs.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UrlInputConfig {
+    /// Hosts that input can be fetched from.
+    pub allowed_hosts: Vec<String>,
+
+    /// Maximum input size in bytes.
+    ///
+    /// Defaults to the maximum input size of the server.
+    pub max_size: Option<u64>,
+
+    /// Timeout for receiving the response headers in seconds.
+    #[serde(default = "default_url_timeout")]
+    pub timeout: u64,
+}
+
+fn default_url_timeout() -> u64 {
+    30
+}
+
 /// Annotator configuration.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AnnotatorConfig {
+    /// Torch device that the model is loaded on, e.g. `cpu` or `cuda:0`.
+    ///
+    /// The model is loaded on the CPU when absent.
+    device: Option<String>,
+
+    /// Language of the model, e.g. `nl`.
+    ///
+    /// Used to check that pipelines use a tokenizer for the same language.
+    language: Option<String>,
+
     /// Maximum sentence length in pieces.
     max_len: Option<usize>,
 
+    /// Maximum number of chunks that are annotated concurrently.
+    ///
+    /// When set, waiting chunks of requests with a higher priority are
+    /// annotated first. When absent, chunks are annotated as they arrive.
+    max_concurrent_chunks: Option<usize>,
+
+    /// Maximum total number of pieces in a batch.
+    max_pieces_per_batch: Option<usize>,
+
+    /// Minimum sentence length, in the unit set by `min_len_unit`.
+    min_len: Option<usize>,
+
+    /// Unit of the minimum sentence length, pieces by default.
+    #[serde(default)]
+    min_len_unit: LengthUnit,
+
+    /// Handling of sentences that exceed the maximum length.
+    #[serde(default)]
+    oversized_sentences: OversizedSentences,
+
+    /// Precision of the model parameters during inference.
+    ///
+    /// Only `fp32` can be loaded for now, other precisions are rejected.
+    #[serde(default)]
+    precision: Precision,
+
+    /// Handling of sentences that are shorter than the minimum length.
+    #[serde(default)]
+    undersized_sentences: UndersizedSentences,
+
+    /// Periodically log a histogram of sentence lengths in pieces.
+    #[serde(default)]
+    log_piece_lengths: bool,
+
     /// SyntaxDot model configuration.
     syntaxdot_config: String,
 }
 
 impl AnnotatorConfig {
     /// Load an annotator.
-    fn load(&self) -> Result<Annotator> {
-        Annotator::load(Device::Cpu, &self.syntaxdot_config, self.max_len)
+    fn load(&self, name: &str) -> Result<Annotator> {
+        let device = match &self.device {
+            Some(device) => parse_device(device)?,
+            None => Device::Cpu,
+        };
+
+        Ok(Annotator::load(
+            device,
+            &self.syntaxdot_config,
+            self.max_len,
+            self.oversized_sentences,
+            self.precision,
+        )?
+        .max_pieces_per_batch(self.max_pieces_per_batch)
+        .min_len(self.min_len, self.min_len_unit, self.undersized_sentences)
+        .log_piece_lengths(if self.log_piece_lengths {
+            Some(name)
+        } else {
+            None
+        }))
     }
 }
 
 /// Pipeline configuration.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PipelineConfig {
     /// Name of the annotator to use.
     annotator: String,
@@ -105,18 +747,48 @@ pub struct PipelineConfig {
     /// Pipeline description.
     description: String,
 
+    /// Kafka topic that the annotated sentences are published to.
+    kafka: Option<KafkaConfig>,
+
     /// Number of batches to read ahead.
     read_ahead: usize,
 
+    /// Metadata for logging, such as the team or cost center.
+    #[serde(default)]
+    tags: IndexMap<String, String>,
+
+    /// YAML file with `upos` and `deprel` mappings that are applied to
+    /// the annotations, e.g. to map to a custom tag set.
+    tag_map: Option<String>,
+
     /// Name of the tokenizer to use.
     tokenizer: String,
+
+    /// WASM plugin that post-processes the annotations.
+    wasm_plugin: Option<WasmPluginConfig>,
+
+    /// Optional pipeline behavior.
+    #[serde(flatten)]
+    options: PipelineOptions,
 }
 
 impl PipelineConfig {
-    fn new_pipeline(
+    /// Name of the tokenizer to use.
+    pub fn tokenizer(&self) -> &str {
+        &self.tokenizer
+    }
+
+    /// Construct a pipeline from loaded annotators and tokenizers.
+    ///
+    /// Fails when the pipeline refers to an unknown annotator or tokenizer,
+    /// when its tag map or plugin cannot be read, or when its Kafka sink
+    /// cannot be created. Read-ahead chunks are capped at `max_chunk_len`
+    /// sentences.
+    pub fn new_pipeline(
         &self,
         name: &str,
-        annotators: &IndexMap<String, Arc<Annotator>>,
+        max_chunk_len: usize,
+        annotators: &IndexMap<String, Arc<AnnotatorCell>>,
         tokenizers: &IndexMap<String, Arc<dyn Tokenizer + Send + Sync>>,
     ) -> Result<Pipeline> {
         let annotator = annotators
@@ -127,26 +799,244 @@ impl PipelineConfig {
             .get(&self.tokenizer)
             .ok_or_else(|| anyhow!("Unknown tokenizer `{}`", self.tokenizer))?;
 
-        Ok(Pipeline::new(
-            &self.description,
-            name,
-            annotator.clone(),
-            tokenizer.clone(),
-            self.batch_size,
-            self.read_ahead,
-        ))
+        let tag_map = self
+            .tag_map
+            .as_ref()
+            .map(|path| {
+                TagMap::read(path).map_err(|err| anyhow!("Cannot read tag map `{}`: {}", path, err))
+            })
+            .transpose()?;
+
+        let plugin = self
+            .wasm_plugin
+            .as_ref()
+            .map(|plugin| {
+                WasmPlugin::load(&plugin.path, plugin.fuel, plugin.max_memory)
+                    .map_err(|err| anyhow!("Cannot load WASM plugin `{}`: {}", plugin.path, err))
+            })
+            .transpose()?;
+
+        let kafka = self
+            .kafka
+            .clone()
+            .map(|kafka| {
+                KafkaSink::new(kafka).map_err(|err| anyhow!("Cannot create Kafka sink: {}", err))
+            })
+            .transpose()?;
+
+        Ok(
+            Pipeline::builder(name, annotator.clone(), tokenizer.clone())
+                .batch_size(self.batch_size)
+                .description(&self.description)
+                .kafka(kafka)
+                .max_chunk_len(max_chunk_len)
+                .options(self.options.clone())
+                .plugin(plugin)
+                .read_ahead(self.read_ahead)
+                .tag_map(tag_map)
+                .tags(self.tags.clone())
+                .build(),
+        )
     }
 }
 
+/// WASM plugin configuration.
+///
+/// The plugin is sandboxed: it cannot access the host and processing a
+/// sentence fails when the plugin exceeds its fuel or memory limit.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WasmPluginConfig {
+    /// Path of the WASM module.
+    path: String,
+
+    /// Fuel per sentence, roughly the number of executed instructions.
+    #[serde(default = "default_plugin_fuel")]
+    fuel: u64,
+
+    /// Maximum memory size of the plugin in bytes.
+    #[serde(default = "default_plugin_max_memory")]
+    max_memory: usize,
+}
+
+fn default_plugin_fuel() -> u64 {
+    DEFAULT_PLUGIN_FUEL
+}
+
+fn default_plugin_max_memory() -> usize {
+    DEFAULT_PLUGIN_MAX_MEMORY
+}
+
+/// Optional pipeline settings.
+///
+/// All options have defaults, so that they can be omitted in the
+/// pipeline configuration.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PipelineOptions {
+    /// Treat blank lines in the input as document separators, adding a
+    /// `# newdoc` comment to the first sentence of each document.
+    pub blank_line_documents: bool,
+
+    /// Casing transformation of token forms, the original form is
+    /// stored in the `orth` misc feature.
+    pub casing: Casing,
+
+    /// Apply the casing transformation after annotation, rather than
+    /// before.
+    ///
+    /// By default, the annotator sees the transformed forms, which
+    /// can affect the annotations. When applied after annotation, the
+    /// annotator sees the original forms and only the output forms
+    /// are transformed.
+    pub casing_after_annotation: bool,
+
+    /// Strip or replace C0 and C1 control characters, except tabs,
+    /// before tokenization.
+    pub control_characters: ControlCharacters,
+
+    /// Annotate identical sentences within a read-ahead chunk only once.
+    pub dedup_sentences: bool,
+
+    /// Detect whether the input of the annotations endpoint is CoNLL-U
+    /// or text, rather than always tokenizing it as text.
+    ///
+    /// The input is CoNLL-U when its first non-blank line is a `#`
+    /// comment or has ten tab-separated columns. Text that starts with
+    /// `#` is misdetected as CoNLL-U, the `input` query parameter can
+    /// be used to force a format. Out-of-order annotation and caching
+    /// are only used for input that is known to be text.
+    pub detect_input_format: bool,
+
+    /// Add a `# device = ...` comment with the device of the annotator,
+    /// e.g. `cpu` or `cuda:0`.
+    pub device_comment: bool,
+
+    /// Respond with *422 Unprocessable Entity* when the input contains
+    /// sentences, but all of them are removed by the sentence filter.
+    pub error_on_all_filtered: bool,
+
+    /// Order of morphological features in CoNLL-U output.
+    ///
+    /// Features in this list are written first, in the listed order.
+    /// Other features follow in alphabetical order, which is also the
+    /// order when this option is not set.
+    pub feats_order: Option<Vec<String>>,
+
+    /// Annotate an incomplete read-ahead chunk when this number of
+    /// milliseconds passed since its first sentence was received.
+    ///
+    /// This bounds the latency of streaming responses when the input
+    /// arrives slowly.
+    pub flush_interval_ms: Option<u64>,
+
+    /// Unicode normalization of the input text before tokenization.
+    ///
+    /// Tokens are always cleaned up after tokenization, this is only
+    /// necessary for tokenizers that are sensitive to normalization.
+    pub input_normalization: Option<Normalization>,
+
+    /// Output sentences that cannot be annotated with an `# error = ...`
+    /// comment, rather than aborting the response.
+    pub isolate_errors: bool,
+
+    /// Keep a byte order mark at the start of the input, rather than
+    /// removing it before tokenization.
+    pub keep_bom: bool,
+
+    /// Log up to this number of bytes of the input lines that cannot be
+    /// tokenized and the sentences that cannot be annotated, disabled
+    /// when absent.
+    ///
+    /// **Warning:** the logs will contain the text of requests, which
+    /// may include personal data.
+    pub log_failed_input: Option<usize>,
+
+    /// Maximum number of lines of a request that are tokenized
+    /// concurrently, one when absent.
+    ///
+    /// Reading the input is suspended while this number of lines is
+    /// being tokenized.
+    pub max_concurrent_tokenizations: Option<usize>,
+
+    /// Skip lines that cannot be tokenized and sentences that cannot be
+    /// annotated, failing the response after more than this number of
+    /// errors. When input was skipped, the response ends with a
+    /// `# skipped = N` comment.
+    pub max_errors: Option<usize>,
+
+    /// Maximum number of chunks that are annotated concurrently for
+    /// requests with the `out_of_order` query parameter, 4 when absent.
+    ///
+    /// Larger values in requests are clamped to this maximum.
+    pub max_out_of_order_chunks: Option<usize>,
+
+    /// Perform unicode cleanup of sentences in spawned tasks.
+    ///
+    /// This keeps cleanup of large inputs off the thread that writes
+    /// the response.
+    pub offload_unicode_cleanup: bool,
+
+    /// Output format when the request does not specify one.
+    pub output_format: OutputFormat,
+
+    /// Add the vocabulary indices of the word pieces of each token to
+    /// its `pieces` misc feature.
+    pub piece_alignment: bool,
+
+    /// Keep the comments of CoNLL-U input.
+    pub preserve_comments: bool,
+
+    /// Read ahead up to this number of tokens, rather than a fixed
+    /// number of batches.
+    ///
+    /// This bounds the memory use of a read-ahead chunk, regardless of
+    /// sentence lengths.
+    pub read_ahead_tokens: Option<usize>,
+
+    /// Output exactly one sentence per input line.
+    ///
+    /// The tokenizer is still used for splitting tokens, but its
+    /// sentence segmentation is ignored.
+    pub sentence_per_line: bool,
+
+    /// Add `# text = ...` comments with the sentence text.
+    pub sentence_text: Option<SentenceText>,
+
+    /// Keep sentences without tokens.
+    ///
+    /// Tokenizers can produce empty sentences for lines that only
+    /// consist of separators. These are skipped by default.
+    pub keep_empty_sentences: bool,
+
+    /// Annotate sentences one at a time in arrival order.
+    ///
+    /// Sentences are not read ahead and batched by length, which lowers
+    /// the latency of small requests at the cost of throughput. Can be
+    /// overridden per request with the `streaming` query parameter.
+    pub streaming_mode: bool,
+
+    /// Number of times a failed tokenization is retried.
+    ///
+    /// Only tokenizer panics are retried, lines that the tokenizer
+    /// cannot tokenize fail without retries.
+    pub tokenize_retries: usize,
+
+    /// Parse CoNLL-U output again before it is sent, logging invalid
+    /// sentences or failing the response.
+    ///
+    /// This is expensive and meant for catching regressions in staging.
+    pub validate_output: OutputValidation,
+}
+
 /// Configuration for a tokenizer.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum TokenizerConfig {
     /// Alpino tokenizer.
-    AlpinoTokenizer(
-        /// Tokenizer protobuf file.
-        String,
-    ),
+    ///
+    /// Can be specified as the path of the tokenizer protobuf file or
+    /// as a mapping with the protobuf path and tokenizer options.
+    AlpinoTokenizer(AlpinoTokenizerConfig),
 
     /// Whitespace tokenizer.
     ///
@@ -155,18 +1045,100 @@ pub enum TokenizerConfig {
 }
 
 impl TokenizerConfig {
+    /// Type of the tokenizer, as used in the configuration.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TokenizerConfig::AlpinoTokenizer(_) => "alpino_tokenizer",
+            TokenizerConfig::WhitespaceTokenizer => "whitespace_tokenizer",
+        }
+    }
+
+    /// Language of the tokenizer.
+    ///
+    /// The whitespace tokenizer does not have a language.
+    pub fn language(&self) -> Option<&str> {
+        match self {
+            TokenizerConfig::AlpinoTokenizer(alpino_config) => alpino_config.language.as_deref(),
+            TokenizerConfig::WhitespaceTokenizer => None,
+        }
+    }
+
     /// Load a tokenizer.
     pub fn load(&self) -> Result<Arc<dyn Tokenizer + Send + Sync>> {
         match self {
-            TokenizerConfig::AlpinoTokenizer(protobuf) => {
-                let read = BufReader::new(File::open(protobuf)?);
-                Ok(Arc::new(AlpinoTokenizer::from_buf_read(read)?))
+            TokenizerConfig::AlpinoTokenizer(alpino_config) => {
+                let read = BufReader::new(File::open(&alpino_config.protobuf)?);
+                let tokenizer: Arc<dyn Tokenizer + Send + Sync> =
+                    Arc::new(AlpinoTokenizer::from_buf_read(read)?);
+
+                if alpino_config.abbreviations.is_empty() {
+                    Ok(tokenizer)
+                } else {
+                    Ok(Arc::new(AbbreviationTokenizer::new(
+                        tokenizer,
+                        alpino_config.abbreviations.iter().cloned(),
+                    )))
+                }
             }
             TokenizerConfig::WhitespaceTokenizer => Ok(Arc::new(WhitespaceTokenizer)),
         }
     }
 }
 
+/// Configuration for the Alpino tokenizer.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "AlpinoTokenizerConfigRepr")]
+pub struct AlpinoTokenizerConfig {
+    /// Tokenizer protobuf file.
+    pub protobuf: String,
+
+    /// Additional abbreviations.
+    ///
+    /// The tokenizer does not split off the period of these
+    /// abbreviations and does not end a sentence after them.
+    pub abbreviations: Vec<String>,
+
+    /// Language of the tokenizer, e.g. `nl`.
+    pub language: Option<String>,
+}
+
+/// Serialized forms of the Alpino tokenizer configuration.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AlpinoTokenizerConfigRepr {
+    /// Only the path of the tokenizer protobuf file.
+    Protobuf(String),
+
+    /// Protobuf path with tokenizer options.
+    Config {
+        protobuf: String,
+        #[serde(default)]
+        abbreviations: Vec<String>,
+        language: Option<String>,
+    },
+}
+
+impl From<AlpinoTokenizerConfigRepr> for AlpinoTokenizerConfig {
+    fn from(repr: AlpinoTokenizerConfigRepr) -> Self {
+        match repr {
+            AlpinoTokenizerConfigRepr::Protobuf(protobuf) => AlpinoTokenizerConfig {
+                protobuf,
+                abbreviations: Vec::new(),
+                language: None,
+            },
+            AlpinoTokenizerConfigRepr::Config {
+                protobuf,
+                abbreviations,
+                language,
+            } => AlpinoTokenizerConfig {
+                protobuf,
+                abbreviations,
+                language,
+            },
+        }
+    }
+}
+
 /// Canonicalize a (relative) filename.
 ///
 /// The configuration file can contain file names relative to the configuration
@@ -205,3 +1177,163 @@ fn canonicalize_path(config_path: &Path, filename: &str) -> Result<String> {
         })?
         .to_owned())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::{Config, PipelineConfig, SpoolConfig, TokenizerConfig};
+
+    static CONFIG: &str = r#"
+annotators:
+  nl-ud:
+    syntaxdot_config: nl-ud/syntaxdot.conf
+    max_len: 100
+pipelines:
+  nl:
+    annotator: nl-ud
+    batch_size: 32
+    description: Dutch
+    read_ahead: 10
+    tokenizer: whitespace
+tokenizers:
+  whitespace: whitespace_tokenizer
+"#;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(CONFIG.as_bytes()).unwrap();
+        assert_eq!(
+            config.annotators["nl-ud"].syntaxdot_config,
+            "nl-ud/syntaxdot.conf"
+        );
+        assert_eq!(config.pipelines["nl"].batch_size, 32);
+    }
+
+    #[test]
+    fn compatible_tokenizers_share_the_annotator() {
+        let config = CONFIG.replace(
+            "tokenizers:\n",
+            r#"  nl-alpino:
+    annotator: nl-ud
+    batch_size: 32
+    description: Dutch
+    read_ahead: 10
+    tokenizer: alpino
+tokenizers:
+  alpino:
+    alpino_tokenizer: nl.pb
+"#,
+        );
+        let config = Config::parse(config.as_bytes()).unwrap();
+        assert_eq!(
+            config.compatible_tokenizers("nl"),
+            vec!["whitespace", "alpino"]
+        );
+        assert!(config.compatible_tokenizers("de").is_empty());
+    }
+
+    #[test]
+    fn parse_rejects_language_mismatch() {
+        let config = CONFIG
+            .replace("    max_len: 100", "    max_len: 100\n    language: en")
+            .replace("tokenizer: whitespace", "tokenizer: alpino")
+            .replace(
+                "  whitespace: whitespace_tokenizer",
+                r#"  alpino:
+    alpino_tokenizer:
+      protobuf: nl.pb
+      language: nl"#,
+            );
+        assert!(Config::parse(config.as_bytes()).is_err());
+
+        let allowed = format!("allow_language_mismatch: true\n{}", config);
+        assert!(Config::parse(allowed.as_bytes()).is_ok());
+
+        let matching = config.replace("language: en", "language: NL");
+        assert!(Config::parse(matching.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_unknown_tokenizer() {
+        let config = CONFIG.replace("tokenizer: whitespace", "tokenizer: alpino");
+        assert!(Config::parse(config.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn parse_alpino_tokenizer_forms() {
+        let config = CONFIG.replace(
+            "  whitespace: whitespace_tokenizer",
+            r#"  whitespace: whitespace_tokenizer
+  alpino:
+    alpino_tokenizer: nl.pb
+  alpino-abbr:
+    alpino_tokenizer:
+      protobuf: nl.pb
+      abbreviations: [dr, prof]"#,
+        );
+        let config = Config::parse(config.as_bytes()).unwrap();
+
+        match &config.tokenizers["alpino"] {
+            TokenizerConfig::AlpinoTokenizer(alpino_config) => {
+                assert_eq!(alpino_config.protobuf, "nl.pb");
+                assert!(alpino_config.abbreviations.is_empty());
+            }
+            _ => panic!("Expected the Alpino tokenizer"),
+        }
+
+        match &config.tokenizers["alpino-abbr"] {
+            TokenizerConfig::AlpinoTokenizer(alpino_config) => {
+                assert_eq!(alpino_config.protobuf, "nl.pb");
+                assert_eq!(alpino_config.abbreviations, vec!["dr", "prof"]);
+            }
+            _ => panic!("Expected the Alpino tokenizer"),
+        }
+
+        assert_eq!(config.tokenizers["alpino-abbr"].kind(), "alpino_tokenizer");
+        assert_eq!(
+            config.tokenizers["whitespace"].kind(),
+            "whitespace_tokenizer"
+        );
+    }
+
+    #[test]
+    fn runtime_pipelines_are_restricted() {
+        let dir = env::temp_dir().join(format!("syntaxdot-rest-config-{}", std::process::id()));
+        fs::create_dir_all(dir.join("config")).unwrap();
+        let config_path = dir.join("config/config.yaml");
+        fs::write(&config_path, CONFIG).unwrap();
+        fs::write(dir.join("config/tag-map.yaml"), "").unwrap();
+        fs::write(dir.join("outside.yaml"), "").unwrap();
+        let config = Config::read(&config_path, CONFIG.as_bytes()).unwrap();
+
+        let check = |extra: &str| {
+            let mut pipeline_config: PipelineConfig = serde_yaml::from_str(&format!(
+                "annotator: nl-ud\nbatch_size: 32\ndescription: Dutch\nread_ahead: 10\ntokenizer: whitespace\n{}",
+                extra
+            ))
+            .unwrap();
+            config
+                .check_runtime_pipeline(&mut pipeline_config)
+                .map(|_| pipeline_config.tag_map)
+        };
+
+        let tag_map = check("tag_map: tag-map.yaml").unwrap().unwrap();
+        assert!(tag_map.ends_with("tag-map.yaml"));
+        assert!(check("tag_map: ../outside.yaml").is_err());
+        assert!(check(&format!("tag_map: {}", dir.join("outside.yaml").display())).is_err());
+        assert!(check("kafka:\n  brokers: localhost:9092\n  topic: nl").is_err());
+        assert!(check("wasm_plugin:\n  path: plugin.wasm").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bodies_of_unknown_length_are_not_spooled() {
+        let spool: SpoolConfig = serde_yaml::from_str("min_size: 10").unwrap();
+        assert!(spool.should_spool(Some(10)));
+        assert!(!spool.should_spool(Some(9)));
+        assert!(!spool.should_spool(None));
+    }
+}
@@ -0,0 +1,105 @@
+use std::io::{self, ErrorKind};
+
+use futures::stream::{self, TryStreamExt};
+use serde_json::{Map, Value};
+use udgraph::graph::Sentence;
+
+use crate::format::JsonSentence;
+use crate::inflight::InflightGuard;
+use crate::pipeline::Pipeline;
+
+/// Field of a document that holds its annotations.
+const ANNOTATIONS_FIELD: &str = "annotations";
+
+/// Annotate a document, given as a line of NDJSON.
+///
+/// The text in `text_field` is annotated, the output document consists
+/// of the other fields of the input document and an `annotations`
+/// field with the sentences in the JSON output format.
+pub async fn annotate_document(
+    pipeline: Pipeline,
+    text_field: &str,
+    line: &str,
+    inflight: &InflightGuard,
+) -> io::Result<Vec<u8>> {
+    let (document, text) = parse_document(line, text_field)?;
+
+    let lines = text
+        .lines()
+        .map(|line| Ok(line.to_string()))
+        .collect::<Vec<_>>();
+    let sentences = pipeline
+        .annotations(stream::iter(lines))
+        .map_err(io::Error::other)?
+        .try_concat()
+        .await?;
+    inflight.add_sentences(sentences.len());
+
+    write_document(document, &sentences)
+}
+
+/// Parse a document, splitting off its text.
+fn parse_document(line: &str, text_field: &str) -> io::Result<(Map<String, Value>, String)> {
+    let mut document: Map<String, Value> = serde_json::from_str(line).map_err(|err| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Document is not a JSON object: {}", err),
+        )
+    })?;
+
+    match document.remove(text_field) {
+        Some(Value::String(text)) => Ok((document, text)),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Document does not have a `{}` string field", text_field),
+        )),
+    }
+}
+
+/// Write a document with its annotations as a line of NDJSON.
+fn write_document(mut document: Map<String, Value>, sentences: &[Sentence]) -> io::Result<Vec<u8>> {
+    let annotations = sentences.iter().map(JsonSentence::from).collect::<Vec<_>>();
+    document.insert(
+        ANNOTATIONS_FIELD.to_string(),
+        serde_json::to_value(annotations)?,
+    );
+
+    let mut output = serde_json::to_vec(&document)?;
+    output.push(b'\n');
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use udgraph::graph::Sentence;
+    use udgraph::token::Token;
+
+    use super::{parse_document, write_document};
+
+    #[test]
+    fn documents_keep_other_fields() {
+        let (document, text) =
+            parse_document(r#"{"id":"x","text":"Een zin.","lang":"nl"}"#, "text").unwrap();
+        assert_eq!(text, "Een zin.");
+
+        let sentence: Sentence = vec![Token::new("Een"), Token::new("zin.")]
+            .into_iter()
+            .collect();
+        let output = write_document(document, &[sentence]).unwrap();
+        assert_eq!(output.last(), Some(&b'\n'));
+
+        let output: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(output["id"], "x");
+        assert_eq!(output["lang"], "nl");
+        assert!(output.get("text").is_none());
+        assert_eq!(output["annotations"][0]["tokens"][1]["form"], "zin.");
+    }
+
+    #[test]
+    fn documents_must_have_text() {
+        assert!(parse_document(r#"{"id":"x"}"#, "text").is_err());
+        assert!(parse_document(r#"{"id":"x","text":1}"#, "text").is_err());
+        assert!(parse_document(r#"["x"]"#, "text").is_err());
+    }
+}
@@ -0,0 +1,882 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use conllu::io::{WriteSentence, Writer};
+use futures::io::{Error, ErrorKind};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use udgraph::graph::{Comment, Node, Sentence};
+use udgraph::token::Token;
+
+/// Output state that is carried from sentence to sentence.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FormatState {
+    /// Byte offset in the brat text after the last token that was found.
+    brat_cursor: usize,
+
+    /// Number of brat entities written.
+    brat_entities: usize,
+
+    /// Whether the brat text is the input text of the sentences.
+    brat_input_text: bool,
+
+    /// Character offset in the brat text after the last token that was
+    /// found.
+    brat_offset: usize,
+
+    /// Number of brat relations written.
+    brat_relations: usize,
+
+    /// Text that the brat offsets refer to.
+    brat_text: String,
+}
+
+/// Number of columns of a CoNLL-U token line.
+const CONLLU_COLUMNS: usize = 10;
+
+/// Annotation input formats.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InputFormat {
+    /// CoNLL-U sentences, which are annotated without tokenization.
+    Conllu,
+
+    /// Raw text, which is tokenized before annotation.
+    Text,
+}
+
+impl InputFormat {
+    /// Guess the input format from the start of the input.
+    ///
+    /// The input is CoNLL-U when its first non-blank line is a comment,
+    /// starting with `#`, or a token line with ten tab-separated columns.
+    /// Otherwise, the input is text. Only the first line is inspected,
+    /// so text that starts with a `#` (e.g. a hashtag or Markdown
+    /// heading) is taken to be CoNLL-U, and CoNLL-U that starts with a
+    /// malformed line is taken to be text.
+    pub fn detect(prefix: &[u8]) -> Self {
+        let prefix = String::from_utf8_lossy(prefix);
+        let first_line = prefix
+            .trim_start_matches('\u{feff}')
+            .lines()
+            .find(|line| !line.trim().is_empty());
+
+        match first_line {
+            Some(line) if line.starts_with('#') || line.split('\t').count() == CONLLU_COLUMNS => {
+                InputFormat::Conllu
+            }
+            _ => InputFormat::Text,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "conllu" => Ok(InputFormat::Conllu),
+            "text" => Ok(InputFormat::Text),
+            _ => Err(anyhow!("Unknown input format: {}", s)),
+        }
+    }
+}
+
+/// Annotation output formats.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// brat standoff annotations.
+    ///
+    /// A JSON object with the `.ann` annotations in `ann` and the `.txt`
+    /// text that their offsets refer to in `txt`. For tokenized text, the
+    /// text is the input text from `# input_text` comments. Otherwise, it
+    /// consists of the tokens separated by spaces, with one sentence per
+    /// line.
+    Brat,
+
+    /// CoNLL-U.
+    #[default]
+    Conllu,
+
+    /// A JSON array of sentences.
+    Json,
+
+    /// One JSON sentence per line.
+    Ndjson,
+}
+
+impl OutputFormat {
+    /// Get the output format for a media type.
+    pub fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.trim() {
+            "text/x-conllu" | "text/plain" => Some(OutputFormat::Conllu),
+            "application/json" => Some(OutputFormat::Json),
+            "application/x-ndjson" => Some(OutputFormat::Ndjson),
+            "application/x-brat" => Some(OutputFormat::Brat),
+            _ => None,
+        }
+    }
+
+    /// Get the output format from an `Accept` header value.
+    ///
+    /// Media types are tried in order, quality values are ignored.
+    /// Returns `None` when none of the media types is supported.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        accept
+            .split(',')
+            .filter_map(|media_range| media_range.split(';').next())
+            .find_map(Self::from_media_type)
+    }
+
+    /// Media type of the format.
+    pub fn media_type(self) -> &'static str {
+        match self {
+            OutputFormat::Brat => "application/json",
+            OutputFormat::Conllu => "text/x-conllu; charset=utf-8",
+            OutputFormat::Json => "application/json",
+            OutputFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// Name of the format, as used in configurations.
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Brat => "brat",
+            OutputFormat::Conllu => "conllu",
+            OutputFormat::Json => "json",
+            OutputFormat::Ndjson => "ndjson",
+        }
+    }
+
+    /// Output that precedes the sentences.
+    pub fn header(self) -> &'static [u8] {
+        match self {
+            OutputFormat::Brat => br#"{"ann":""#,
+            OutputFormat::Json => b"[",
+            _ => b"",
+        }
+    }
+
+    /// Write the output that follows the sentences.
+    ///
+    /// `state` must be the state that the sentences were written with.
+    pub fn write_footer(self, buf: &mut Vec<u8>, state: &FormatState) {
+        match self {
+            OutputFormat::Brat => {
+                buf.extend_from_slice(br#"","txt":"#);
+                write_json_string(buf, &state.brat_text);
+                buf.push(b'}');
+            }
+            OutputFormat::Json => buf.push(b']'),
+            _ => (),
+        }
+    }
+
+    /// Output that separates two consecutive sentences.
+    pub fn separator(self) -> &'static [u8] {
+        match self {
+            OutputFormat::Brat => b"",
+            OutputFormat::Conllu => b"\n",
+            OutputFormat::Json => b",",
+            OutputFormat::Ndjson => b"",
+        }
+    }
+
+    /// Write a sentence in this format.
+    ///
+    /// Only the sentence itself is written, separators are written by
+    /// the caller. `state` must be the same for all sentences of an output.
+    pub fn write_sentence(
+        self,
+        buf: &mut Vec<u8>,
+        sentence: &Sentence,
+        state: &mut FormatState,
+    ) -> Result<(), Error> {
+        match self {
+            OutputFormat::Brat => write_brat_sentence(buf, sentence, state)?,
+            OutputFormat::Conllu => Writer::new(&mut *buf)
+                .write_sentence(sentence)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?,
+            OutputFormat::Json => serde_json::to_writer(&mut *buf, &JsonSentence::from(sentence))?,
+            OutputFormat::Ndjson => {
+                serde_json::to_writer(&mut *buf, &JsonSentence::from(sentence))?;
+                buf.push(b'\n');
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Original form of a token, before it was cleaned up or lowercased.
+fn orth(token: &Token) -> &str {
+    token
+        .misc()
+        .get("orth")
+        .and_then(Option::as_deref)
+        .unwrap_or_else(|| token.form())
+}
+
+/// Replace characters that cannot be used in brat annotation types.
+fn brat_type(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Write the contents of a JSON string, without the quotes.
+fn write_json_string_contents(buf: &mut Vec<u8>, s: &str) {
+    let start = buf.len();
+    write_json_string(buf, s);
+    buf.pop();
+    buf.remove(start);
+}
+
+/// Write a string as a JSON string.
+fn write_json_string(buf: &mut Vec<u8>, s: &str) {
+    serde_json::to_writer(buf, s).expect("Cannot serialize string");
+}
+
+/// Input text of a sentence from its `# input_text` comment.
+fn input_text(sentence: &Sentence) -> Option<&str> {
+    sentence
+        .comments()
+        .iter()
+        .find_map(|comment| match comment {
+            Comment::AttrVal { attr, val } if attr == "input_text" => Some(val.as_str()),
+            _ => None,
+        })
+}
+
+/// Write a sentence as brat standoff annotations.
+///
+/// Every token becomes an entity with its universal part-of-speech tag
+/// as the type, every dependency relation becomes a relation. Tokens are
+/// looked up in the text by their original form. Tokens that cannot be
+/// found, e.g. because normalization changed them, do not become
+/// entities.
+fn write_brat_sentence(
+    buf: &mut Vec<u8>,
+    sentence: &Sentence,
+    state: &mut FormatState,
+) -> Result<(), Error> {
+    match input_text(sentence) {
+        Some(text) => {
+            state.brat_input_text = true;
+            state.brat_text.push_str(text);
+        }
+        None if !state.brat_input_text => {
+            // Tokens are separated by spaces, sentences by newlines.
+            let tokens = sentence
+                .iter()
+                .filter_map(Node::token)
+                .map(|token| token.form())
+                .collect::<Vec<_>>();
+            state.brat_text.push_str(&tokens.join(" "));
+            state.brat_text.push('\n');
+        }
+        None => (),
+    }
+
+    let mut ann = Vec::new();
+
+    // Entity identifiers of the tokens, by token index.
+    let mut entities = vec![None; sentence.len()];
+    for (idx, token) in sentence.iter().enumerate() {
+        let token = match token.token() {
+            Some(token) => token,
+            None => continue,
+        };
+
+        let form = orth(token);
+        let found = match state.brat_text[state.brat_cursor..].find(form) {
+            Some(found) => state.brat_cursor + found,
+            None => continue,
+        };
+
+        let start = state.brat_offset + state.brat_text[state.brat_cursor..found].chars().count();
+        let end = start + form.chars().count();
+        state.brat_cursor = found + form.len();
+        state.brat_offset = end;
+
+        state.brat_entities += 1;
+        entities[idx] = Some(state.brat_entities);
+
+        writeln!(
+            ann,
+            "T{}\t{} {} {}\t{}",
+            state.brat_entities,
+            brat_type(token.upos().unwrap_or("Token")),
+            start,
+            end,
+            form
+        )?;
+    }
+
+    let dep_graph = sentence.dep_graph();
+    for dependent in 1..sentence.len() {
+        let triple = match dep_graph.head(dependent) {
+            Some(triple) if triple.head() != 0 => triple,
+            _ => continue,
+        };
+
+        let (head, dependent) = match (entities[triple.head()], entities[dependent]) {
+            (Some(head), Some(dependent)) => (head, dependent),
+            _ => continue,
+        };
+
+        state.brat_relations += 1;
+        writeln!(
+            ann,
+            "R{}\t{} Arg1:T{} Arg2:T{}",
+            state.brat_relations,
+            brat_type(triple.relation().unwrap_or("dep")),
+            head,
+            dependent
+        )?;
+    }
+
+    let ann = String::from_utf8(ann).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    write_json_string_contents(buf, &ann);
+
+    Ok(())
+}
+
+/// JSON representation of a sentence.
+#[derive(Serialize)]
+pub struct JsonSentence<'a> {
+    /// Attribute-value comments.
+    #[serde(skip_serializing_if = "IndexMap::is_empty")]
+    pub metadata: IndexMap<&'a str, &'a str>,
+
+    /// Other comments.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub comments: Vec<&'a str>,
+
+    pub tokens: Vec<JsonToken<'a>>,
+}
+
+impl<'a> From<&'a Sentence> for JsonSentence<'a> {
+    fn from(sentence: &'a Sentence) -> Self {
+        let mut metadata = IndexMap::new();
+        let mut comments = Vec::new();
+        for comment in sentence.comments() {
+            match comment {
+                Comment::AttrVal { attr, val } => {
+                    metadata.insert(attr.as_str(), val.as_str());
+                }
+                Comment::String(comment) => comments.push(comment.as_str()),
+            }
+        }
+
+        let dep_graph = sentence.dep_graph();
+        let tokens = sentence
+            .iter()
+            .enumerate()
+            .filter_map(|(id, node)| node.token().map(|token| (id, token)))
+            .map(|(id, token)| {
+                let triple = dep_graph.head(id);
+                JsonToken {
+                    id,
+                    form: token.form(),
+                    lemma: token.lemma(),
+                    upos: token.upos(),
+                    xpos: token.xpos(),
+                    features: token.features(),
+                    head: triple.as_ref().map(|triple| triple.head()),
+                    deprel: triple
+                        .as_ref()
+                        .and_then(|triple| triple.relation().map(ToOwned::to_owned)),
+                    misc: token.misc(),
+                }
+            })
+            .collect();
+
+        JsonSentence {
+            metadata,
+            comments,
+            tokens,
+        }
+    }
+}
+
+/// Combined JSON representation of the tokenization and the annotation
+/// of a sentence.
+///
+/// Both layers are taken from the same annotated sentence, so that the
+/// input only has to be tokenized once.
+#[derive(Serialize)]
+pub struct CombinedSentence<'a> {
+    /// Attribute-value comments.
+    #[serde(skip_serializing_if = "IndexMap::is_empty")]
+    pub metadata: IndexMap<&'a str, &'a str>,
+
+    /// Token forms in the input, see the `orth` misc feature.
+    pub tokens: Vec<&'a str>,
+
+    /// Annotated tokens.
+    pub annotation: Vec<JsonToken<'a>>,
+}
+
+impl<'a> From<&'a Sentence> for CombinedSentence<'a> {
+    fn from(sentence: &'a Sentence) -> Self {
+        let json_sentence = JsonSentence::from(sentence);
+        CombinedSentence {
+            metadata: json_sentence.metadata,
+            tokens: sentence.iter().filter_map(Node::token).map(orth).collect(),
+            annotation: json_sentence.tokens,
+        }
+    }
+}
+
+/// Pair of values from two pipelines.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Diff<T> {
+    pub a: T,
+    pub b: T,
+}
+
+/// Differences between the annotations of a sentence by two pipelines.
+#[derive(Serialize)]
+pub struct SentenceDiff<'a> {
+    /// Number of sentences of both pipelines, only set when the
+    /// pipelines split the same text into different sentences.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sentences: Option<Diff<usize>>,
+
+    /// Token forms of both sentences, only set when the pipelines
+    /// tokenized the sentence differently.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokenization: Option<Diff<Vec<&'a str>>>,
+
+    /// Tokens with different annotations.
+    ///
+    /// Tokens are only compared when both pipelines have the same
+    /// tokenization.
+    pub tokens: Vec<Diff<JsonToken<'a>>>,
+}
+
+impl<'a> SentenceDiff<'a> {
+    /// Compare the annotations of a sentence by two pipelines.
+    pub fn new(a: &'a Sentence, b: &'a Sentence) -> Self {
+        let a = JsonSentence::from(a);
+        let b = JsonSentence::from(b);
+
+        let forms_a = a.tokens.iter().map(|token| token.form).collect::<Vec<_>>();
+        let forms_b = b.tokens.iter().map(|token| token.form).collect::<Vec<_>>();
+        if forms_a != forms_b {
+            return SentenceDiff {
+                sentences: None,
+                tokenization: Some(Diff {
+                    a: forms_a,
+                    b: forms_b,
+                }),
+                tokens: Vec::new(),
+            };
+        }
+
+        SentenceDiff {
+            sentences: None,
+            tokenization: None,
+            tokens: a
+                .tokens
+                .into_iter()
+                .zip(b.tokens)
+                .filter(|(a, b)| !a.same_annotation(b))
+                .map(|(a, b)| Diff { a, b })
+                .collect(),
+        }
+    }
+}
+
+impl<'a> SentenceDiff<'a> {
+    /// Compare sentences that cover the same text.
+    ///
+    /// When both pipelines produced a single sentence, its annotations
+    /// are compared. Otherwise, the pipelines split the text differently
+    /// and only the sentence counts and token forms are reported.
+    pub fn aligned(a: &'a [Sentence], b: &'a [Sentence]) -> Self {
+        if let ([a], [b]) = (a, b) {
+            return SentenceDiff::new(a, b);
+        }
+
+        let forms = |sentences: &'a [Sentence]| {
+            sentences
+                .iter()
+                .flat_map(|sentence| sentence.iter().filter_map(Node::token))
+                .map(|token| token.form())
+                .collect()
+        };
+
+        SentenceDiff {
+            sentences: Some(Diff {
+                a: a.len(),
+                b: b.len(),
+            }),
+            tokenization: Some(Diff {
+                a: forms(a),
+                b: forms(b),
+            }),
+            tokens: Vec::new(),
+        }
+    }
+}
+
+/// Length of the text of a sentence, not counting whitespace.
+///
+/// This is used to align the sentences of two pipelines that split the
+/// same text differently.
+pub fn text_len(sentence: &Sentence) -> usize {
+    sentence
+        .iter()
+        .filter_map(Node::token)
+        .map(|token| token.form().chars().filter(|c| !c.is_whitespace()).count())
+        .sum()
+}
+
+/// Reorder the features in the FEATS column of CoNLL-U output.
+///
+/// Features in `order` come first, in that order, followed by the other
+/// features in alphabetical order. Other lines are copied as-is.
+pub fn reorder_features(conllu: &str, order: &[String]) -> String {
+    let rank = |feature: &str| {
+        let name = feature.split('=').next().unwrap_or(feature);
+        order
+            .iter()
+            .position(|ordered| ordered == name)
+            .unwrap_or(order.len())
+    };
+
+    conllu
+        .split('\n')
+        .map(|line| {
+            let mut columns = line.split('\t').collect::<Vec<_>>();
+            if line.starts_with('#') || columns.len() < 6 || columns[5] == "_" {
+                return line.to_string();
+            }
+
+            let mut features = columns[5].split('|').collect::<Vec<_>>();
+            features.sort_by(|a, b| rank(a).cmp(&rank(b)).then_with(|| a.cmp(b)));
+            let features = features.join("|");
+            columns[5] = &features;
+            columns.join("\t")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Write the universal part-of-speech tags of a sentence as a line of
+/// space-separated tags.
+///
+/// Tokens without a tag are written as `_`. Sentences without tokens,
+/// such as markers of truncated responses, are not written.
+pub fn write_upos_line(buf: &mut Vec<u8>, sentence: &Sentence) {
+    let tags = sentence
+        .iter()
+        .filter_map(Node::token)
+        .map(|token| token.upos().unwrap_or("_"))
+        .collect::<Vec<_>>();
+
+    if !tags.is_empty() {
+        buf.extend_from_slice(tags.join(" ").as_bytes());
+        buf.push(b'\n');
+    }
+}
+
+/// JSON representation of a token.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct JsonToken<'a> {
+    pub id: usize,
+    pub form: &'a str,
+    pub lemma: Option<&'a str>,
+    pub upos: Option<&'a str>,
+    pub xpos: Option<&'a str>,
+    pub features: &'a BTreeMap<String, String>,
+    pub head: Option<usize>,
+    pub deprel: Option<String>,
+    pub misc: &'a BTreeMap<String, Option<String>>,
+}
+
+impl<'a> JsonToken<'a> {
+    /// Check whether two tokens have the same annotation layers.
+    ///
+    /// The miscellaneous features are not compared, since they contain
+    /// pipeline-specific information rather than annotations.
+    fn same_annotation(&self, other: &JsonToken) -> bool {
+        self.lemma == other.lemma
+            && self.upos == other.upos
+            && self.xpos == other.xpos
+            && self.features == other.features
+            && self.head == other.head
+            && self.deprel == other.deprel
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use udgraph::graph::{Comment, DepTriple, Sentence};
+    use udgraph::token::{Token, TokenBuilder};
+
+    use super::{
+        reorder_features, text_len, write_upos_line, CombinedSentence, Diff, FormatState,
+        InputFormat, OutputFormat, SentenceDiff,
+    };
+
+    #[test]
+    fn detects_input_format() {
+        assert_eq!(
+            InputFormat::detect(b"\n# sent_id = 1\n1\tKatten\t_\t_\t_\t_\t_\t_\t_\t_\n"),
+            InputFormat::Conllu
+        );
+        assert_eq!(
+            InputFormat::detect(b"1\tKatten\tkat\tNOUN\t_\t_\t2\tnsubj\t_\t_\n"),
+            InputFormat::Conllu
+        );
+        assert_eq!(
+            InputFormat::detect(b"Katten slapen.\tHonden niet.\n"),
+            InputFormat::Text
+        );
+        assert_eq!(InputFormat::detect(b""), InputFormat::Text);
+    }
+
+    #[test]
+    fn writes_brat_annotations() {
+        let mut sentence: Sentence = vec![
+            TokenBuilder::new("Katten").upos("NOUN").into(),
+            TokenBuilder::new("slapen").upos("VERB").into(),
+        ]
+        .into_iter()
+        .collect();
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(0, Some("root"), 2));
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(2, Some("nsubj:pass"), 1));
+
+        let (ann, txt) = write_brat(&[sentence.clone(), sentence]);
+        assert_eq!(
+            ann,
+            "T1\tNOUN 0 6\tKatten\n\
+             T2\tVERB 7 13\tslapen\n\
+             R1\tnsubj_pass Arg1:T2 Arg2:T1\n\
+             T3\tNOUN 14 20\tKatten\n\
+             T4\tVERB 21 27\tslapen\n\
+             R2\tnsubj_pass Arg1:T4 Arg2:T3\n"
+        );
+        assert_eq!(txt, "Katten slapen\nKatten slapen\n");
+    }
+
+    #[test]
+    fn brat_offsets_refer_to_input_text() {
+        let mut first: Sentence = vec![
+            TokenBuilder::new("De").upos("DET").into(),
+            TokenBuilder::new("kat").upos("NOUN").into(),
+        ]
+        .into_iter()
+        .collect();
+        first.comments_mut().push(Comment::AttrVal {
+            attr: "input_text".to_string(),
+            val: "\u{e9}\u{e9}n  De\tkat. Ze\n".to_string(),
+        });
+        first
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(2, Some("det"), 1));
+
+        // Lowercased before annotation, with the original form in `orth`.
+        let second: Sentence = vec![TokenBuilder::new("ze")
+            .upos("PRON")
+            .misc(iter::once(("orth".to_string(), Some("Ze".to_string()))).collect())
+            .into()]
+        .into_iter()
+        .collect();
+
+        let (ann, txt) = write_brat(&[first, second]);
+        assert_eq!(
+            ann,
+            "T1\tDET 5 7\tDe\n\
+             T2\tNOUN 8 11\tkat\n\
+             R1\tdet Arg1:T2 Arg2:T1\n\
+             T3\tPRON 13 15\tZe\n"
+        );
+        assert_eq!(txt, "\u{e9}\u{e9}n  De\tkat. Ze\n");
+    }
+
+    /// Write sentences in the brat format, returning the `.ann` and `.txt`.
+    fn write_brat(sentences: &[Sentence]) -> (String, String) {
+        let mut buf = OutputFormat::Brat.header().to_vec();
+        let mut state = FormatState::default();
+        for sentence in sentences {
+            OutputFormat::Brat
+                .write_sentence(&mut buf, sentence, &mut state)
+                .unwrap();
+        }
+        OutputFormat::Brat.write_footer(&mut buf, &state);
+
+        let output: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        (
+            output["ann"].as_str().unwrap().to_string(),
+            output["txt"].as_str().unwrap().to_string(),
+        )
+    }
+
+    #[test]
+    fn writes_contraction_tokens_with_word_ids() {
+        // A contraction that the tokenizer split into two words.
+        let sentence: Sentence = vec![
+            TokenBuilder::new("Zij").into(),
+            TokenBuilder::new("do").into(),
+            TokenBuilder::new("n't").into(),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut buf = Vec::new();
+        OutputFormat::Conllu
+            .write_sentence(&mut buf, &sentence, &mut FormatState::default())
+            .unwrap();
+
+        let output = String::from_utf8(buf).unwrap();
+        let ids = output
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.split('\t').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn combined_sentence_has_both_layers() {
+        let sentence: Sentence = vec![
+            TokenBuilder::new("Katten").upos("NOUN").into(),
+            TokenBuilder::new("slapen").upos("VERB").into(),
+        ]
+        .into_iter()
+        .collect();
+
+        let combined = serde_json::to_value(CombinedSentence::from(&sentence)).unwrap();
+        assert_eq!(combined["tokens"], serde_json::json!(["Katten", "slapen"]));
+        assert_eq!(combined["annotation"][1]["form"], "slapen");
+        assert_eq!(combined["annotation"][1]["upos"], "VERB");
+        assert!(combined.get("metadata").is_none());
+    }
+
+    #[test]
+    fn combined_tokens_are_original_forms() {
+        let sentence: Sentence = iter::once(
+            TokenBuilder::new("katten")
+                .misc(iter::once(("orth".to_string(), Some("Katten".to_string()))).collect())
+                .into(),
+        )
+        .collect();
+
+        let combined = serde_json::to_value(CombinedSentence::from(&sentence)).unwrap();
+        assert_eq!(combined["tokens"], serde_json::json!(["Katten"]));
+        assert_eq!(combined["annotation"][0]["form"], "katten");
+    }
+
+    #[test]
+    fn diff_has_tokens_with_different_annotations() {
+        let a: Sentence = vec![
+            TokenBuilder::new("Katten").upos("NOUN").into(),
+            TokenBuilder::new("slapen").upos("VERB").into(),
+        ]
+        .into_iter()
+        .collect();
+        let b: Sentence = vec![
+            TokenBuilder::new("Katten").upos("PROPN").into(),
+            TokenBuilder::new("slapen").upos("VERB").into(),
+        ]
+        .into_iter()
+        .collect();
+
+        let diff = SentenceDiff::new(&a, &b);
+        assert!(diff.tokenization.is_none());
+        assert_eq!(diff.tokens.len(), 1);
+        assert_eq!(diff.tokens[0].a.id, 1);
+        assert_eq!(diff.tokens[0].a.upos, Some("NOUN"));
+        assert_eq!(diff.tokens[0].b.upos, Some("PROPN"));
+
+        let c: Sentence = vec![TokenBuilder::new("Kattenslapen").into()]
+            .into_iter()
+            .collect();
+        let diff = SentenceDiff::new(&a, &c);
+        assert_eq!(diff.tokenization.unwrap().b, ["Kattenslapen"]);
+        assert!(diff.tokens.is_empty());
+    }
+
+    #[test]
+    fn diff_reports_different_sentence_splits() {
+        let sentence =
+            |forms: &[&str]| -> Sentence { forms.iter().map(|form| Token::new(*form)).collect() };
+        let a = vec![sentence(&["Katten", "slapen", "."]), sentence(&["Honden"])];
+        let b = vec![sentence(&["Katten", "slapen", ".", "Honden"])];
+        assert_eq!(text_len(&a[0]) + text_len(&a[1]), text_len(&b[0]));
+
+        let diff = SentenceDiff::aligned(&a, &b);
+        assert_eq!(diff.sentences, Some(Diff { a: 2, b: 1 }));
+        assert_eq!(
+            diff.tokenization.unwrap().a,
+            ["Katten", "slapen", ".", "Honden"]
+        );
+
+        let diff = SentenceDiff::aligned(&a[..1], &a[..1]);
+        assert!(diff.sentences.is_none());
+        assert!(diff.tokenization.is_none());
+    }
+
+    #[test]
+    fn reorders_features() {
+        let conllu = "# sent_id = 1\n\
+                      1\thuis\thuis\tNOUN\t_\tDefinite=Def|Gender=Neut|Number=Sing\t0\troot\t_\t_\n\
+                      2\t.\t.\tPUNCT\t_\t_\t1\tpunct\t_\t_\n";
+        let order = vec!["Number".to_string(), "Gender".to_string()];
+        assert_eq!(
+            reorder_features(conllu, &order),
+            conllu.replace(
+                "Definite=Def|Gender=Neut|Number=Sing",
+                "Number=Sing|Gender=Neut|Definite=Def"
+            )
+        );
+        assert_eq!(reorder_features(conllu, &[]), conllu);
+    }
+
+    #[test]
+    fn writes_upos_lines() {
+        let sentence: Sentence = vec![
+            TokenBuilder::new("Katten").upos("NOUN").into(),
+            TokenBuilder::new("slapen").into(),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut buf = Vec::new();
+        write_upos_line(&mut buf, &sentence);
+        write_upos_line(&mut buf, &Sentence::new());
+        assert_eq!(String::from_utf8(buf).unwrap(), "NOUN _\n");
+    }
+
+    #[test]
+    fn format_from_accept() {
+        assert_eq!(
+            OutputFormat::from_accept("application/json"),
+            Some(OutputFormat::Json)
+        );
+        assert_eq!(
+            OutputFormat::from_accept("text/html, application/x-ndjson;q=0.9, */*;q=0.8"),
+            Some(OutputFormat::Ndjson)
+        );
+        assert_eq!(OutputFormat::from_accept("*/*"), None);
+    }
+}
@@ -0,0 +1,57 @@
+//! gRPC health checking protocol.
+//!
+//! Serves the standard `grpc.health.v1.Health` service next to the HTTP
+//! server. tonic requires tokio, so the service runs on its own tokio
+//! runtime in a separate thread, regardless of the runtime of the HTTP
+//! server.
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tonic::transport::Server;
+use tonic_health::ServingStatus;
+
+/// Interval between readiness checks.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Serve the gRPC health service on `addr`.
+///
+/// The overall status, with an empty service name, is `SERVING` when
+/// `ready` returns `true` and `NOT_SERVING` otherwise. Readiness is
+/// checked every second.
+pub fn serve_health<F>(addr: SocketAddr, ready: F) -> Result<()>
+where
+    F: Fn() -> bool + Send + 'static,
+{
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Cannot construct gRPC runtime")?;
+
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            let (mut reporter, service) = tonic_health::server::health_reporter();
+
+            tokio::spawn(async move {
+                loop {
+                    let status = if ready() {
+                        ServingStatus::Serving
+                    } else {
+                        ServingStatus::NotServing
+                    };
+                    reporter.set_service_status("", status).await;
+                    tokio::time::sleep(CHECK_INTERVAL).await;
+                }
+            });
+
+            log::info!("Serving gRPC health checks on {}", addr);
+            if let Err(err) = Server::builder().add_service(service).serve(addr).await {
+                log::error!("gRPC health server failed: {}", err);
+            }
+        })
+    });
+
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bound (exclusive) of the first bucket, buckets double in size.
+const FIRST_BUCKET_END: usize = 16;
+
+/// Interval between histogram log messages.
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Histogram of sentence lengths in pieces that is logged periodically.
+pub struct PieceLengthHistogram {
+    inner: Mutex<HistogramInner>,
+    name: String,
+}
+
+struct HistogramInner {
+    counts: Vec<usize>,
+    last_log: Instant,
+}
+
+impl PieceLengthHistogram {
+    pub fn new(name: impl Into<String>) -> Self {
+        PieceLengthHistogram {
+            inner: Mutex::new(HistogramInner {
+                counts: Vec::new(),
+                last_log: Instant::now(),
+            }),
+            name: name.into(),
+        }
+    }
+
+    /// Record sentence lengths, logging the histogram if it is due.
+    pub fn record(&self, lengths: impl IntoIterator<Item = usize>) {
+        let mut inner = self.inner.lock().expect("Histogram lock is poisoned");
+
+        for len in lengths {
+            let bucket = bucket(len);
+            if bucket >= inner.counts.len() {
+                inner.counts.resize(bucket + 1, 0);
+            }
+            inner.counts[bucket] += 1;
+        }
+
+        if inner.last_log.elapsed() >= LOG_INTERVAL {
+            log::info!(
+                "Piece lengths of `{}`: {}",
+                self.name,
+                format_counts(&inner.counts)
+            );
+            inner.last_log = Instant::now();
+        }
+    }
+}
+
+/// Get the bucket of a length.
+fn bucket(len: usize) -> usize {
+    let mut bucket = 0;
+    let mut end = FIRST_BUCKET_END;
+    while len >= end {
+        bucket += 1;
+        end *= 2;
+    }
+    bucket
+}
+
+/// Get the length range of a bucket.
+fn bucket_range(bucket: usize) -> (usize, usize) {
+    let end = FIRST_BUCKET_END << bucket;
+    let start = if bucket == 0 { 0 } else { end / 2 };
+    (start, end - 1)
+}
+
+fn format_counts(counts: &[usize]) -> String {
+    counts
+        .iter()
+        .enumerate()
+        .map(|(bucket, count)| {
+            let (start, end) = bucket_range(bucket);
+            format!("{}-{}: {}", start, end, count)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket, format_counts};
+
+    #[test]
+    fn lengths_are_bucketed() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(15), 0);
+        assert_eq!(bucket(16), 1);
+        assert_eq!(bucket(31), 1);
+        assert_eq!(bucket(32), 2);
+        assert_eq!(bucket(100), 3);
+    }
+
+    #[test]
+    fn counts_are_formatted() {
+        assert_eq!(format_counts(&[1, 0, 3]), "0-15: 1, 16-31: 0, 32-63: 3");
+    }
+}
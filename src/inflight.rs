@@ -0,0 +1,129 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use indexmap::IndexMap;
+use serde::Serialize;
+
+/// Description of an annotation request that is in progress.
+#[derive(Debug, Serialize)]
+pub struct InflightRequest {
+    pub id: u64,
+    pub pipeline: String,
+    pub elapsed_ms: u128,
+    pub sentences: usize,
+}
+
+struct InflightEntry {
+    pipeline: String,
+    sentences: Arc<AtomicUsize>,
+    start: Instant,
+}
+
+/// Registry of annotation requests that are in progress.
+#[derive(Default)]
+pub struct InflightRequests {
+    next_id: AtomicU64,
+    requests: Mutex<IndexMap<u64, InflightEntry>>,
+}
+
+impl InflightRequests {
+    /// Register a request, it is deregistered when the guard is dropped.
+    pub fn register(self: &Arc<Self>, pipeline: impl Into<String>) -> InflightGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let sentences = Arc::new(AtomicUsize::new(0));
+
+        self.requests
+            .lock()
+            .expect("In-flight requests lock is poisoned")
+            .insert(
+                id,
+                InflightEntry {
+                    pipeline: pipeline.into(),
+                    sentences: sentences.clone(),
+                    start: Instant::now(),
+                },
+            );
+
+        InflightGuard {
+            id,
+            requests: self.clone(),
+            sentences,
+        }
+    }
+
+    /// Number of requests that are in progress.
+    pub fn count(&self) -> usize {
+        self.requests
+            .lock()
+            .expect("In-flight requests lock is poisoned")
+            .len()
+    }
+
+    /// Requests that are in progress, oldest first.
+    pub fn requests(&self) -> Vec<InflightRequest> {
+        self.requests
+            .lock()
+            .expect("In-flight requests lock is poisoned")
+            .iter()
+            .map(|(&id, entry)| InflightRequest {
+                id,
+                pipeline: entry.pipeline.clone(),
+                elapsed_ms: entry.start.elapsed().as_millis(),
+                sentences: entry.sentences.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// Registration of an in-flight request.
+pub struct InflightGuard {
+    id: u64,
+    requests: Arc<InflightRequests>,
+    sentences: Arc<AtomicUsize>,
+}
+
+impl InflightGuard {
+    /// Count sentences that were processed for the request.
+    pub fn add_sentences(&self, n_sentences: usize) {
+        self.sentences.fetch_add(n_sentences, Ordering::Relaxed);
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.requests
+            .requests
+            .lock()
+            .expect("In-flight requests lock is poisoned")
+            .shift_remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::InflightRequests;
+
+    #[test]
+    fn requests_are_tracked_until_dropped() {
+        let inflight = Arc::new(InflightRequests::default());
+
+        let first = inflight.register("nl");
+        let second = inflight.register("de");
+        second.add_sentences(3);
+        second.add_sentences(2);
+
+        let requests = inflight.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].pipeline, "de");
+        assert_eq!(requests[1].sentences, 5);
+
+        drop(first);
+        assert_eq!(inflight.count(), 1);
+        let requests = inflight.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].pipeline, "de");
+    }
+}
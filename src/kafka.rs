@@ -0,0 +1,167 @@
+//! Publishing of annotated sentences to Kafka.
+//!
+//! Sentences are published as messages in the JSON output format, one
+//! message per sentence.
+
+use serde::{Deserialize, Serialize};
+
+/// Configuration of a Kafka sink.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KafkaConfig {
+    /// Comma-separated list of brokers, e.g. `kafka1:9092,kafka2:9092`.
+    pub brokers: String,
+
+    /// Fail the request when a sentence cannot be published.
+    ///
+    /// Otherwise, publishing failures are only logged.
+    #[serde(default)]
+    pub fail_on_error: bool,
+
+    /// Only publish the annotations, leaving them out of the response.
+    #[serde(default)]
+    pub skip_response: bool,
+
+    /// Topic to publish the sentences to.
+    pub topic: String,
+}
+
+#[cfg(feature = "kafka")]
+pub use self::rdkafka_sink::KafkaSink;
+
+#[cfg(feature = "kafka")]
+mod rdkafka_sink {
+    use std::io::Error;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use futures::future::{self, Future};
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+    use rdkafka::ClientConfig;
+    use udgraph::graph::Sentence;
+
+    use super::KafkaConfig;
+    use crate::format::JsonSentence;
+    use crate::runtime::spawn;
+
+    /// Maximum time that a message can take to be delivered.
+    const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Sink that publishes sentences to a Kafka topic.
+    pub struct KafkaSink {
+        config: KafkaConfig,
+        producer: FutureProducer,
+    }
+
+    impl KafkaSink {
+        pub fn new(config: KafkaConfig) -> Result<Self> {
+            let producer = ClientConfig::new()
+                .set("bootstrap.servers", &config.brokers)
+                .set(
+                    "message.timeout.ms",
+                    MESSAGE_TIMEOUT.as_millis().to_string(),
+                )
+                .create()?;
+
+            Ok(KafkaSink { config, producer })
+        }
+
+        /// Only publish the annotations, leaving them out of the response.
+        pub fn skip_response(&self) -> bool {
+            self.config.skip_response
+        }
+
+        /// Publish a batch of sentences.
+        ///
+        /// The future resolves when all sentences are delivered.
+        pub fn publish(
+            &self,
+            sentences: &[Sentence],
+        ) -> impl Future<Output = Result<(), Error>> + Send + Sync {
+            let deliveries = sentences
+                .iter()
+                .map(|sentence| -> Result<_> {
+                    let payload = serde_json::to_vec(&JsonSentence::from(sentence))?;
+                    self.producer
+                        .send_result(
+                            FutureRecord::<(), _>::to(&self.config.topic).payload(&payload),
+                        )
+                        .map_err(|(err, _)| err.into())
+                })
+                .collect::<Result<Vec<_>>>();
+
+            let fail_on_error = self.config.fail_on_error;
+            let topic = self.config.topic.clone();
+
+            // Deliveries are awaited in a task, so that the returned future
+            // is `Sync` like the other stages of the response.
+            spawn(async move {
+                let failed = match deliveries {
+                    Ok(deliveries) => future::join_all(deliveries)
+                        .await
+                        .into_iter()
+                        .filter_map(|delivery| match delivery {
+                            Ok(Ok(_)) => None,
+                            Ok(Err((err, _))) => Some(err.to_string()),
+                            Err(_) => Some("producer was dropped".to_string()),
+                        })
+                        .collect::<Vec<_>>(),
+                    Err(err) => vec![format!("{:#}", err)],
+                };
+
+                if failed.is_empty() {
+                    return Ok(());
+                }
+
+                log::warn!(
+                    "Cannot publish {} sentence(s) to Kafka topic `{}`: {}",
+                    failed.len(),
+                    topic,
+                    failed[0]
+                );
+
+                if fail_on_error {
+                    Err(Error::other(format!(
+                        "Cannot publish to Kafka topic `{}`: {}",
+                        topic, failed[0]
+                    )))
+                } else {
+                    Ok(())
+                }
+            })
+        }
+    }
+}
+
+#[cfg(not(feature = "kafka"))]
+pub use self::unsupported::KafkaSink;
+
+#[cfg(not(feature = "kafka"))]
+mod unsupported {
+    use std::io::Error;
+
+    use anyhow::{anyhow, Result};
+    use futures::future::Ready;
+    use udgraph::graph::Sentence;
+
+    use super::KafkaConfig;
+
+    /// Placeholder for Kafka sinks, which require the `kafka` feature.
+    pub struct KafkaSink {
+        _private: (),
+    }
+
+    impl KafkaSink {
+        /// Fails, since Kafka is not supported by this build.
+        pub fn new(_config: KafkaConfig) -> Result<Self> {
+            Err(anyhow!("Kafka sinks require the `kafka` feature"))
+        }
+
+        pub fn skip_response(&self) -> bool {
+            unreachable!("Kafka sinks cannot be constructed without the `kafka` feature")
+        }
+
+        pub fn publish(&self, _sentences: &[Sentence]) -> Ready<Result<(), Error>> {
+            unreachable!("Kafka sinks cannot be constructed without the `kafka` feature")
+        }
+    }
+}
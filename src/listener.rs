@@ -0,0 +1,383 @@
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use futures::io::{AsyncBufRead, AsyncRead, AsyncWrite};
+use tide::http::headers::CONNECTION;
+use tide::http::{Body, Response};
+use tide::listener::{Listener, ToListener};
+use tide::Server;
+
+use crate::runtime;
+
+/// TCP listener that bounds connections.
+///
+/// Connections that are accepted while the limit is reached are closed
+/// immediately. The limit applies to connections, not requests: a
+/// client can send multiple requests over one keep-alive connection,
+/// unless keep-alive is disabled or the connection reached the maximum
+/// number of requests.
+#[derive(Debug)]
+pub struct LimitedListener {
+    addr: String,
+    connections: Arc<AtomicUsize>,
+    keep_alive: bool,
+    max_connections: Option<usize>,
+    max_requests: Option<usize>,
+}
+
+impl LimitedListener {
+    /// Construct a listener, a limit of `None` accepts any number of
+    /// connections.
+    pub fn new(addr: impl Into<String>, max_connections: Option<usize>) -> Self {
+        LimitedListener {
+            addr: addr.into(),
+            connections: Arc::new(AtomicUsize::new(0)),
+            keep_alive: true,
+            max_connections,
+            max_requests: None,
+        }
+    }
+
+    /// Keep connections alive after a response, the default is `true`.
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Close connections after `max_requests` requests.
+    ///
+    /// This bounds the resources that accumulate on keep-alive
+    /// connections. A limit of `None` serves any number of requests.
+    pub fn max_requests(mut self, max_requests: Option<usize>) -> Self {
+        self.max_requests = max_requests;
+        self
+    }
+
+    fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            keep_alive: self.keep_alive,
+            max_requests: self.max_requests,
+        }
+    }
+}
+
+impl fmt::Display for LimitedListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}", self.addr)
+    }
+}
+
+#[async_trait::async_trait]
+impl<State> Listener<State> for LimitedListener
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn listen(&mut self, app: Server<State>) -> io::Result<()> {
+        let listener = TcpListener::bind(&self.addr).await?;
+        match self.max_connections {
+            Some(max_connections) => log::info!(
+                "Server listening on {}, accepting at most {} connections",
+                self,
+                max_connections
+            ),
+            None => log::info!("Server listening on {}", self),
+        }
+
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::warn!("Cannot accept connection: {}", err);
+                    continue;
+                }
+            };
+
+            let guard = match self.max_connections {
+                Some(max_connections) => {
+                    match ConnectionGuard::acquire(&self.connections, max_connections) {
+                        Some(guard) => Some(guard),
+                        None => {
+                            log::warn!(
+                                "Rejecting connection, limit of {} connections reached",
+                                max_connections
+                            );
+                            continue;
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            handle_connection(app.clone(), stream, guard, self.connection_limits());
+        }
+
+        Ok(())
+    }
+}
+
+impl<State> ToListener<State> for LimitedListener
+where
+    State: Clone + Send + Sync + 'static,
+{
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self::Listener> {
+        Ok(self)
+    }
+}
+
+/// Keep-alive limits of a connection.
+#[derive(Clone, Copy, Debug)]
+struct ConnectionLimits {
+    keep_alive: bool,
+    max_requests: Option<usize>,
+}
+
+impl ConnectionLimits {
+    /// Check whether the `n`-th request (starting at 1) is the last
+    /// request of a connection.
+    fn is_last_request(&self, n: usize) -> bool {
+        !self.keep_alive || self.max_requests.is_some_and(|max| n >= max)
+    }
+}
+
+fn handle_connection<State>(
+    app: Server<State>,
+    stream: TcpStream,
+    guard: Option<ConnectionGuard>,
+    limits: ConnectionLimits,
+) where
+    State: Clone + Send + Sync + 'static,
+{
+    // The connection task is detached, it ends with the connection.
+    drop(runtime::spawn(async move {
+        let _guard = guard;
+        let local_addr = stream.local_addr().ok();
+        let peer_addr = stream.peer_addr().ok();
+
+        let closing = Arc::new(AtomicBool::new(false));
+        let requests = AtomicUsize::new(0);
+        let stream = ClosingStream {
+            closing: closing.clone(),
+            stream,
+        };
+
+        let result = async_h1::accept(stream, |mut request| async {
+            request.set_local_addr(local_addr);
+            request.set_peer_addr(peer_addr);
+            let mut response: Response = app.respond(request).await?;
+
+            let n = requests.fetch_add(1, Ordering::SeqCst) + 1;
+            if limits.is_last_request(n) {
+                response.insert_header(CONNECTION, "close");
+                close_after(&mut response, closing.clone());
+            }
+
+            Ok(response)
+        })
+        .await;
+
+        if let Err(err) = result {
+            log::error!("Error handling connection: {}", err);
+        }
+    }));
+}
+
+/// Close the connection once `response` is written.
+///
+/// The body is replaced by a body that marks the connection as closing
+/// when it is dropped, which happens after the response is written.
+fn close_after(response: &mut Response, closing: Arc<AtomicBool>) {
+    let body = response.take_body();
+    let len = body.len();
+    let mime = body.mime().clone();
+    let mut closing_body = Body::from_reader(ClosingBody { body, closing }, len);
+    closing_body.set_mime(mime);
+    response.set_body(closing_body);
+}
+
+/// Response body that marks the connection as closing when dropped.
+struct ClosingBody {
+    body: Body,
+    closing: Arc<AtomicBool>,
+}
+
+impl Drop for ClosingBody {
+    fn drop(&mut self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+}
+
+impl AsyncRead for ClosingBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for ClosingBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
+
+/// Connection stream that reads end-of-file once it is closing.
+///
+/// The HTTP server stops reading requests at end-of-file, which closes
+/// the connection.
+#[derive(Clone)]
+struct ClosingStream<S> {
+    closing: Arc<AtomicBool>,
+    stream: S,
+}
+
+impl<S> AsyncRead for ClosingStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.closing.load(Ordering::SeqCst) {
+            return Poll::Ready(Ok(0));
+        }
+
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for ClosingStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+/// Connection slot, released when dropped.
+struct ConnectionGuard {
+    connections: Arc<AtomicUsize>,
+}
+
+impl ConnectionGuard {
+    /// Acquire a connection slot, returns `None` when all slots are taken.
+    fn acquire(connections: &Arc<AtomicUsize>, max_connections: usize) -> Option<Self> {
+        let mut current = connections.load(Ordering::SeqCst);
+        loop {
+            if current >= max_connections {
+                return None;
+            }
+
+            match connections.compare_exchange(
+                current,
+                current + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    return Some(ConnectionGuard {
+                        connections: connections.clone(),
+                    })
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+    use futures::io::{AsyncReadExt, Cursor};
+    use tide::http::{Response, StatusCode};
+
+    use super::{close_after, ClosingStream, ConnectionGuard, ConnectionLimits};
+
+    #[test]
+    fn guards_bound_connections() {
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let first = ConnectionGuard::acquire(&connections, 2).unwrap();
+        let _second = ConnectionGuard::acquire(&connections, 2).unwrap();
+        assert!(ConnectionGuard::acquire(&connections, 2).is_none());
+
+        drop(first);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert!(ConnectionGuard::acquire(&connections, 2).is_some());
+    }
+
+    #[test]
+    fn last_request_follows_limits() {
+        let limits = ConnectionLimits {
+            keep_alive: true,
+            max_requests: Some(2),
+        };
+        assert!(!limits.is_last_request(1));
+        assert!(limits.is_last_request(2));
+
+        let limits = ConnectionLimits {
+            keep_alive: false,
+            max_requests: None,
+        };
+        assert!(limits.is_last_request(1));
+    }
+
+    #[test]
+    fn stream_ends_after_last_response() {
+        let closing = Arc::new(AtomicBool::new(false));
+        let mut stream = ClosingStream {
+            closing: closing.clone(),
+            stream: Cursor::new(b"GET / HTTP/1.1\r\n".to_vec()),
+        };
+
+        let mut response = Response::new(StatusCode::Ok);
+        response.set_body("Written");
+        close_after(&mut response, closing.clone());
+        let mut buf = [0; 4];
+        assert_eq!(block_on(stream.read(&mut buf)).unwrap(), 4);
+
+        // Writing the response drops its body.
+        let body = block_on(response.body_string()).unwrap();
+        assert_eq!(body, "Written");
+        assert!(closing.load(Ordering::SeqCst));
+        assert_eq!(block_on(stream.read(&mut buf)).unwrap(), 0);
+    }
+}
@@ -1,100 +1,1642 @@
 use std::fs::File;
-use std::path::PathBuf;
+use std::io;
+use std::iter;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use alpino_tokenizer::Tokenizer;
 use anyhow::{anyhow, Context};
-use clap::{App, Arg};
-use futures::io::{AsyncBufReadExt, BufReader as AsyncBufReader};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use futures::future::{self, Either, FutureExt};
+use futures::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader as AsyncBufReader, Cursor,
+};
+use futures::stream::{self, StreamExt};
+use futures::{Stream, TryStreamExt};
 use indexmap::IndexMap;
-use serde::Serialize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tide::http::Url;
 use tide::{Body, Error, Request, Response, Server, StatusCode};
+use udgraph::graph::{Node, Sentence};
+
+mod affinity;
+use affinity::pin_current_thread;
 
 mod async_conllu;
 use async_conllu::SentenceStreamReader;
 
-mod async_syntaxdot;
+mod async_syntaxdot;
+use async_syntaxdot::{
+    AnnotationStats, Layer, StageTimings, ToFilterSentences, ToFilterUpos, ToFinishOnShutdown,
+    ToLogSlow, ToSelectLayers, ToTruncate, UposFilterMode,
+};
+
+mod async_util;
+use async_util::{LimitReader, ProgressReader, ToTryChunks, ToTryLimit};
+
+mod annotator;
+use annotator::{AnnotatorCell, AnnotatorStatus};
+
+mod auth;
+use auth::{ApiKeyAuth, AuthenticatedKey};
+
+mod cache;
+use cache::{
+    is_cacheable, is_cut_short_marker, normalize_input, AnnotationCache, CacheKey, CachingReader,
+};
+
+mod client;
+use client::client_ip;
+
+mod client_limit;
+use client_limit::{ClientConnections, ClientLimit};
+
+mod config;
+pub use config::{Config, PipelineConfig};
+use config::{Loaded, ThreadAffinityConfig};
+
+mod documents;
+use documents::annotate_document;
+
+mod format;
+use format::{
+    text_len, write_upos_line, CombinedSentence, InputFormat, OutputFormat, SentenceDiff,
+};
+
+#[cfg(feature = "grpc-health")]
+mod grpc_health;
+
+mod histogram;
+
+mod inflight;
+use inflight::{InflightRequest, InflightRequests};
+
+mod kafka;
+
+mod listener;
+use listener::LimitedListener;
+
+mod pipeline;
+
+mod plugin;
+use pipeline::Pipeline;
+
+mod priority;
+
+mod query;
+use query::Query;
+
+mod runtime;
+use runtime::{sleep, spawn_blocking};
+
+mod schema;
+use schema::output_schema;
+
+mod shutdown;
+use shutdown::Shutdown;
+
+mod spool;
+use spool::SpooledBody;
+
+#[cfg(test)]
+mod test_annotator;
+
+mod tokenizer;
+
+mod util;
+use util::{parse_static_dirs, ServeStatic};
+
+/// Route prefixes of the API, which are not handled by the static file server.
+const API_ROUTES: &[&str] = &[
+    "/admin",
+    "/annotations",
+    "/count",
+    "/diff",
+    "/estimate",
+    "/health",
+    "/pieces",
+    "/pipelines",
+    "/pos",
+    "/tokenizers",
+    "/tokens",
+];
+
+/// Number of bytes at the start of the input that are used to detect
+/// its format.
+const INPUT_DETECTION_BYTES: u64 = 4096;
+
+/// Maximum size in bytes of a configuration that is validated.
+const MAX_CONFIG_SIZE: u64 = 1 << 20;
+
+/// Lowest priority of a request.
+const MIN_PRIORITY: i32 = -100;
+
+/// Highest priority of a request with an authenticated API key.
+const MAX_PRIORITY: i32 = 100;
+
+/// Document field with the text to annotate, unless set in the request.
+const DEFAULT_TEXT_FIELD: &str = "text";
+
+/// Interval between reports of the requests that are in flight during
+/// shutdown.
+const DRAIN_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+const ANNOTATE: &str = "annotate";
+const BACKGROUND_LOAD: &str = "BACKGROUND_LOAD";
+const GRPC_ADDR: &str = "GRPC_ADDR";
+const LOAD_TIMEOUT: &str = "LOAD_TIMEOUT";
+const MAX_CLIENT_CONNECTIONS: &str = "MAX_CLIENT_CONNECTIONS";
+const MAX_CONNECTION_REQUESTS: &str = "MAX_CONNECTION_REQUESTS";
+const MAX_CONNECTIONS: &str = "MAX_CONNECTIONS";
+const MAX_INPUT_SIZE: &str = "MAX_INPUT_SIZE";
+const MAX_RESPONSE_TIME: &str = "MAX_RESPONSE_TIME";
+const MAX_TOKENS_INPUT_SIZE: &str = "MAX_TOKENS_INPUT_SIZE";
+const MAX_TOKENS_SENTENCES: &str = "MAX_TOKENS_SENTENCES";
+const NUM_ANNOTATION_THREADS: &str = "NUM_ANNOTATION_THREADS";
+const NUM_INTEROP_THREADS: &str = "NUM_INTEROP_THREADS";
+const NUM_INTRAOP_THREADS: &str = "NUM_INTRAOP_THREADS";
+const NO_KEEP_ALIVE: &str = "NO_KEEP_ALIVE";
+const OUTPUT_BUFFER_SIZE: &str = "OUTPUT_BUFFER_SIZE";
+const PIPELINE: &str = "PIPELINE";
+const SEED: &str = "SEED";
+const SERVER_ADDR: &str = "SERVER_ADDR";
+const SHUTDOWN_TIMEOUT: &str = "SHUTDOWN_TIMEOUT";
+
+#[derive(Serialize)]
+struct PipelineDescription {
+    name: String,
+    description: String,
+    #[serde(skip_serializing_if = "IndexMap::is_empty")]
+    tags: IndexMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct TokenizerDescription {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+/// Annotation requests that are in progress.
+#[derive(Serialize)]
+struct InflightResponse {
+    count: usize,
+    requests: Vec<InflightRequest>,
+}
+
+/// Load status of a pipeline.
+#[derive(Serialize)]
+struct PipelineStatus {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Request to add a pipeline.
+#[derive(Deserialize)]
+struct NewPipeline {
+    name: String,
+    #[serde(flatten)]
+    config: PipelineConfig,
+}
+
+/// Request to annotate the text at a URL.
+#[derive(Deserialize)]
+struct UrlRequest {
+    url: String,
+}
+
+/// Number of sentences and tokens of an input.
+#[derive(Serialize)]
+struct Count {
+    /// Number of sentences in the input.
+    sentences: usize,
+
+    /// Number of tokens in the input.
+    tokens: usize,
+}
+
+/// Estimated annotation time of an input.
+#[derive(Serialize)]
+struct Estimate {
+    /// Number of sentences in the input.
+    sentences: usize,
+
+    /// Number of tokens in the input.
+    tokens: usize,
+
+    /// Estimated annotation time in seconds, if the pipeline throughput
+    /// is known.
+    seconds: Option<f64>,
+}
+
+/// Word pieces of a sentence.
+#[derive(Serialize)]
+struct SentencePieces {
+    /// Token forms.
+    tokens: Vec<String>,
+
+    /// Vocabulary indices of the word pieces.
+    pieces: Vec<i64>,
+
+    /// Index of the first piece of each token.
+    token_offsets: Vec<usize>,
+}
+
+/// Get the pipeline in the `pipeline` parameter of the request path.
+fn pipeline_from_request(request: &Request<State>) -> Result<Pipeline, Error> {
+    let pipeline_name: String = request.param("pipeline")?;
+    pipeline_by_name(request.state(), &pipeline_name)
+}
+
+/// Get the pipeline of an annotation request, with the annotation
+/// options from the query parameters and headers of the request.
+///
+/// An annotator that is loaded on demand is loaded first, waiting for at
+/// most the load timeout.
+async fn annotating_pipeline_from_request(request: &Request<State>) -> Result<Pipeline, Error> {
+    let mut pipeline = pipeline_from_request(request)?;
+    let query = Query::from_request(request);
+
+    // Add the annotation latency of each sentence with `latency=true`.
+    if query.flag("latency")? {
+        pipeline.set_latency(true);
+    }
+
+    // Only produce the part-of-speech and morphology layers with
+    // `skip_parser=true`.
+    if query.flag("skip_parser")? {
+        pipeline.set_skip_parser(true);
+    }
+
+    // Requests with a higher priority are served first by annotators
+    // that are shared with other requests.
+    if let Some(priority) = request_priority(request, &query)? {
+        pipeline.set_priority(priority);
+    }
+
+    // Override the streaming mode of the pipeline.
+    if let Some(streaming_mode) = query.get::<bool>("streaming")? {
+        pipeline.set_streaming_mode(streaming_mode);
+    }
+
+    pipeline
+        .wait_for_annotator(request.state().load_timeout)
+        .await;
+    Ok(pipeline)
+}
+
+/// Set the options of a pipeline for the output of
+/// [`annotations_response`].
+///
+/// This must be done before the annotations are constructed, since the
+/// annotation stages collect the statistics and timings of the footers.
+fn set_response_options(request: &Request<State>, pipeline: &mut Pipeline) -> Result<(), Error> {
+    let query = Query::from_request(request);
+
+    // Collect statistics for a footer with `stats=true`.
+    if query.flag("stats")? {
+        pipeline.set_stats(AnnotationStats::new());
+    }
+
+    // Record stage timings for a footer with `timing=true`.
+    if query.flag("timing")? {
+        pipeline.set_timings(StageTimings::new());
+    }
+
+    // brat offsets refer to the input text.
+    if output_format(request, pipeline) == OutputFormat::Brat {
+        pipeline.set_input_text(true);
+    }
+
+    Ok(())
+}
+
+/// Priority from the `priority` query parameter or the `X-Priority`
+/// header, the query parameter takes precedence.
+///
+/// Priorities are clamped to [`MIN_PRIORITY`] and [`MAX_PRIORITY`]. Only
+/// requests with an authenticated API key can raise their priority above
+/// the default priority of 0.
+fn request_priority<S>(request: &Request<S>, query: &Query) -> Result<Option<i32>, Error> {
+    let priority = match query.get::<i32>("priority")? {
+        Some(priority) => Some(priority),
+        None => request
+            .header("X-Priority")
+            .map(|priority| {
+                priority.last().as_str().trim().parse().map_err(|err| {
+                    Error::new(
+                        StatusCode::BadRequest,
+                        anyhow!("Invalid value for header `X-Priority`: {}", err),
+                    )
+                })
+            })
+            .transpose()?,
+    };
+
+    let max_priority = if request.ext::<AuthenticatedKey>().is_some() {
+        MAX_PRIORITY
+    } else {
+        0
+    };
+
+    Ok(priority.map(|priority| priority.clamp(MIN_PRIORITY, max_priority)))
+}
+
+/// Get a pipeline by its name.
+fn pipeline_by_name(state: &State, pipeline_name: &str) -> Result<Pipeline, Error> {
+    state
+        .pipelines
+        .read()
+        .expect("Pipelines lock is poisoned")
+        .get(pipeline_name)
+        .cloned()
+        .ok_or_else(|| {
+            Error::new(
+                StatusCode::NotFound,
+                anyhow!("Unknown pipeline: {}", pipeline_name),
+            )
+        })
+}
+
+/// Error for requests to a pipeline whose annotator is not loaded.
+fn unavailable(err: anyhow::Error) -> Error {
+    Error::new(StatusCode::ServiceUnavailable, err)
+}
+
+/// Output format from the `Accept` header, falling back to the pipeline default.
+fn output_format(request: &Request<State>, pipeline: &Pipeline) -> OutputFormat {
+    request
+        .header("Accept")
+        .and_then(|accept| OutputFormat::from_accept(accept.last().as_str()))
+        .unwrap_or_else(|| pipeline.output_format())
+}
+
+/// Annotations of a response, see [`response_annotations`].
+struct ResponseAnnotations {
+    annotations: Pin<Box<dyn Stream<Item = Result<Vec<Sentence>, io::Error>> + Send + Sync>>,
+
+    /// Cleared when the response is cut short or a batch with failures
+    /// is returned.
+    cacheable: Arc<AtomicBool>,
+
+    /// Cleared when the response is cut short.
+    complete: Arc<AtomicBool>,
+}
+
+/// Prepare the annotations of a request for its response.
+///
+/// This applies the `filter` and `upos` query parameters, cuts the
+/// response short at the deadline or on shutdown, and registers the
+/// request as in flight until the annotations are dropped. Every
+/// endpoint that streams annotations should go through this function.
+async fn response_annotations<A>(
+    request: &Request<State>,
+    pipeline: &Pipeline,
+    annotations: A,
+) -> Result<ResponseAnnotations, Error>
+where
+    A: Stream<Item = Result<Vec<Sentence>, io::Error>> + Send + Sync + 'static,
+{
+    let client = client_ip(request, request.state().config.trusted_proxies())
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "unknown client".to_string());
+    log::debug!(
+        "Annotation request for pipeline {} from {}",
+        pipeline.log_label(),
+        client
+    );
+
+    // Deregistered when the annotations are dropped.
+    let inflight = request.state().inflight.register(pipeline.name());
+
+    // Only return sentences with a token form that fully matches the
+    // filter regular expression.
+    let query = Query::from_request(request);
+    let filter = query
+        .get_str("filter")
+        .map(|filter| Regex::new(&format!("^(?:{})$", filter)))
+        .transpose()
+        .map_err(|err| {
+            Error::new(
+                StatusCode::BadRequest,
+                anyhow!("Invalid value for query parameter `filter`: {}", err),
+            )
+        })?;
+
+    // Only retain tokens with one of the given universal part-of-speech
+    // tags, e.g. `upos=NOUN,VERB,ADJ`.
+    let upos_filter = query.get_str("upos").map(|tags| {
+        tags.split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>()
+    });
+    let upos_filter = match upos_filter {
+        Some(tags) => Some((
+            tags,
+            query
+                .get::<UposFilterMode>("upos_mode")?
+                .unwrap_or_default(),
+        )),
+        None => None,
+    };
+
+    let state = request.state();
+
+    // The deadline also bounds the wait for the first sentence below.
+    let mut annotations = Box::pin(
+        annotations
+            .filter_sentences(filter)
+            .filter_upos(upos_filter)
+            .truncate_after(state.max_response_time),
+    );
+
+    // Wait for the first sentence, so that we can report an error when
+    // the input only contained sentences that were filtered out. Sentences
+    // without tokens, such as the `# skipped = N` summary, do not count.
+    let mut first_batch = None;
+    if pipeline.error_on_all_filtered() {
+        let mut saw_input = false;
+        let mut without_tokens = Vec::new();
+        loop {
+            match annotations.next().await {
+                Some(Ok(batch)) if batch.iter().all(|sentence| sentence.len() == 1) => {
+                    saw_input = true;
+                    without_tokens.extend(batch);
+                }
+                Some(Ok(batch)) => {
+                    without_tokens.extend(batch);
+                    first_batch = Some(Ok(without_tokens));
+                    break;
+                }
+                Some(Err(err)) => {
+                    first_batch = Some(Err(err));
+                    break;
+                }
+                None if saw_input => {
+                    return Err(Error::from_str(
+                        StatusCode::UnprocessableEntity,
+                        "All sentences of the input were filtered out",
+                    ))
+                }
+                None => break,
+            }
+        }
+    }
+
+    // Responses that are cut short or have failures should not be cached.
+    let cacheable = Arc::new(AtomicBool::new(true));
+    let complete = Arc::new(AtomicBool::new(true));
+
+    let annotations = {
+        let cacheable = cacheable.clone();
+        let complete = complete.clone();
+        let stats = pipeline.stats().cloned();
+        stream::iter(first_batch)
+            .chain(annotations)
+            .finish_on_shutdown(state.shutdown.clone())
+            .log_slow(pipeline.log_label(), state.config.slow_request_threshold())
+            .inspect(move |batch| {
+                if let Ok(batch) = batch {
+                    inflight.add_sentences(batch.len());
+                    if let Some(stats) = &stats {
+                        stats.record_sentences(batch);
+                    }
+                    if !batch.iter().all(is_cacheable) {
+                        cacheable.store(false, Ordering::SeqCst);
+                    }
+                    if batch.iter().any(is_cut_short_marker) {
+                        complete.store(false, Ordering::SeqCst);
+                    }
+                }
+            })
+    };
+
+    Ok(ResponseAnnotations {
+        annotations: Box::pin(annotations),
+        cacheable,
+        complete,
+    })
+}
+
+/// Response with the annotations of a request.
+///
+/// When `cache` is set, the complete response is stored in the cache
+/// under the given key.
+async fn annotations_response<A>(
+    request: &Request<State>,
+    pipeline: &Pipeline,
+    annotations: A,
+    cache: Option<(Arc<AnnotationCache>, CacheKey)>,
+) -> tide::Result
+where
+    A: Stream<Item = Result<Vec<Sentence>, io::Error>> + Send + Sync + 'static,
+{
+    let format = output_format(request, pipeline);
+    let stats = pipeline.stats().cloned();
+    if stats.is_some() && format != OutputFormat::Ndjson {
+        return Err(Error::from_str(
+            StatusCode::BadRequest,
+            "Statistics are only supported for NDJSON output",
+        ));
+    }
+    let timings = pipeline.timings().cloned();
+    if timings.is_some() && !matches!(format, OutputFormat::Conllu | OutputFormat::Ndjson) {
+        return Err(Error::from_str(
+            StatusCode::BadRequest,
+            "Timings are only supported for CoNLL-U and NDJSON output",
+        ));
+    }
+
+    // Split large responses into documents of at most this number of
+    // sentences.
+    let query = Query::from_request(request);
+    let split_every = query.get::<NonZeroUsize>("split_every")?;
+
+    // Replace the blank line between CoNLL-U sentences by a custom
+    // separator, e.g. `separator=%1E%0A` for a record separator line.
+    let separator = query
+        .get_str("separator")
+        .map(|separator| separator.as_bytes().to_vec());
+    if separator.is_some() && format != OutputFormat::Conllu {
+        return Err(Error::from_str(
+            StatusCode::BadRequest,
+            "Custom separators are only supported for CoNLL-U output",
+        ));
+    }
+
+    let ResponseAnnotations {
+        annotations,
+        cacheable,
+        complete,
+    } = response_annotations(request, pipeline, annotations).await?;
+
+    let state = request.state();
+
+    let annotator_reader = SentenceStreamReader::new(annotations)
+        .buffer_size(state.output_buffer_size)
+        .feats_order(pipeline.feats_order().map(ToOwned::to_owned))
+        .format(format)
+        .separator(separator.clone())
+        .split_every(split_every)
+        .timings(timings.clone())
+        .validation(pipeline.output_validation());
+
+    let reader: Box<dyn AsyncRead + Unpin + Send + Sync> = match cache {
+        Some((cache, key)) => Box::new(CachingReader::new(cache, key, cacheable, annotator_reader)),
+        None => Box::new(annotator_reader),
+    };
+
+    // The statistics footer is serialized once the annotations are written.
+    let reader: Box<dyn AsyncRead + Unpin + Send + Sync> = match stats {
+        Some(stats) => Box::new(reader.chain(stats_footer(stats))),
+        None => reader,
+    };
+    let reader: Box<dyn AsyncRead + Unpin + Send + Sync> = match timings {
+        Some(timings) => Box::new(reader.chain(timing_footer(timings, format, separator))),
+        None => reader,
+    };
+
+    // Since the status is sent before the annotations, a response that is
+    // cut short by the deadline or a shutdown still has status *200 OK*.
+    // Clients can check for a sentence with a `# truncated` or `# shutdown`
+    // comment at the end of the body. With `buffer`, the response is read
+    // completely before it is sent, so that a response that is cut short
+    // gets status *206 Partial Content* instead.
+    if query.flag("buffer")? {
+        let mut body = Vec::new();
+        AsyncBufReader::new(reader).read_to_end(&mut body).await?;
+        let status = if complete.load(Ordering::SeqCst) {
+            StatusCode::Ok
+        } else {
+            StatusCode::PartialContent
+        };
+        return Ok(Response::builder(status)
+            .header("Content-Type", format.media_type())
+            .body(body)
+            .build());
+    }
+
+    Ok(Response::builder(StatusCode::Ok)
+        .header("Content-Type", format.media_type())
+        .body(Body::from_reader(AsyncBufReader::new(reader), None))
+        .build())
+}
+
+/// NDJSON line with the statistics of a request.
+fn stats_footer(stats: AnnotationStats) -> impl AsyncRead + Unpin + Send + Sync {
+    Box::pin(stream::once(async move {
+        let mut line = serde_json::to_vec(&serde_json::json!({ "stats": stats.summary() }))?;
+        line.push(b'\n');
+        Ok::<_, io::Error>(line)
+    }))
+    .into_async_read()
+}
+
+/// Footer with the stage timings of a request.
+///
+/// This is an NDJSON line for NDJSON output. For CoNLL-U output, the
+/// footer is a sentence without tokens that only has a `# timing = ...`
+/// comment. It is separated from the last sentence by `separator`, or
+/// by a blank line when `separator` is `None`.
+fn timing_footer(
+    timings: StageTimings,
+    format: OutputFormat,
+    separator: Option<Vec<u8>>,
+) -> impl AsyncRead + Unpin + Send + Sync {
+    Box::pin(stream::once(async move {
+        let summary = timings.summary();
+        let footer = match format {
+            OutputFormat::Ndjson => {
+                let mut line = serde_json::to_vec(&serde_json::json!({ "timing": summary }))?;
+                line.push(b'\n');
+                line
+            }
+            _ => {
+                let mut footer = separator.unwrap_or_else(|| format.separator().to_vec());
+                footer.extend_from_slice(
+                    format!("# timing = {}\n", summary.to_attr_val()).as_bytes(),
+                );
+                footer
+            }
+        };
+        Ok::<_, io::Error>(footer)
+    }))
+    .into_async_read()
+}
+
+/// Replace the tokenizer of a pipeline by the one in the `tokenizer`
+/// query parameter, if any.
+///
+/// The tokenizer must be used by a configured pipeline with the same
+/// annotator.
+fn select_tokenizer(request: &Request<State>, pipeline: &mut Pipeline) -> Result<(), Error> {
+    let query = Query::from_request(request);
+    let tokenizer_name = match query.get_str("tokenizer") {
+        Some(tokenizer_name) => tokenizer_name,
+        None => return Ok(()),
+    };
+
+    let state = request.state();
+    let compatible = state
+        .config
+        .compatible_tokenizers(pipeline.name())
+        .contains(&tokenizer_name)
+        || state.pipeline_tokenizer(pipeline.name()).as_deref() == Some(tokenizer_name);
+    if !compatible {
+        return Err(Error::new(
+            StatusCode::BadRequest,
+            anyhow!(
+                "Tokenizer `{}` cannot be used with pipeline `{}`",
+                tokenizer_name,
+                pipeline.name()
+            ),
+        ));
+    }
+
+    let tokenizer = state
+        .tokenizers
+        .read()
+        .expect("Tokenizers lock is poisoned")
+        .get(tokenizer_name)
+        .cloned()
+        .ok_or_else(|| {
+            Error::new(
+                StatusCode::BadRequest,
+                anyhow!("Unknown tokenizer: {}", tokenizer_name),
+            )
+        })?;
+    pipeline.set_tokenizer(tokenizer);
+
+    Ok(())
+}
+
+/// Detect the format of the input from its start.
+///
+/// Returns the format with a reader that still provides the complete
+/// input.
+async fn detect_input_format(
+    mut reader: Box<dyn AsyncBufRead + Unpin + Send + Sync>,
+) -> io::Result<(InputFormat, Box<dyn AsyncBufRead + Unpin + Send + Sync>)> {
+    let mut prefix = Vec::new();
+    (&mut reader)
+        .take(INPUT_DETECTION_BYTES)
+        .read_to_end(&mut prefix)
+        .await?;
+    let format = InputFormat::detect(&prefix);
+
+    Ok((
+        format,
+        Box::new(AsyncBufReader::new(Cursor::new(prefix).chain(reader))),
+    ))
+}
+
+/// Error for inputs that exceed the maximum size.
+fn input_too_large() -> Error {
+    Error::from_str(
+        StatusCode::PayloadTooLarge,
+        "Input exceeds the maximum size",
+    )
+}
+
+/// Limit a request body to `max_size` bytes.
+///
+/// Bodies with a known length that exceeds the maximum are rejected
+/// upfront, other bodies fail while reading once the maximum is exceeded.
+fn limit_body(body: Body, max_size: Option<u64>) -> tide::Result<Body> {
+    let max_size = match max_size {
+        Some(max_size) => max_size,
+        None => return Ok(body),
+    };
+
+    let len = body.len();
+    if len.map(|len| len as u64 > max_size) == Some(true) {
+        return Err(input_too_large());
+    }
+
+    Ok(Body::from_reader(
+        AsyncBufReader::new(LimitReader::new(body.into_reader(), max_size)),
+        len,
+    ))
+}
+
+/// Read a request body of at most `max_size` bytes into a string.
+///
+/// This is used by endpoints that need the complete input before they
+/// respond, inputs that are too large are rejected with 413.
+async fn limited_body_string(body: Body, max_size: Option<u64>) -> tide::Result<String> {
+    let max_size = max_size.unwrap_or(u64::MAX);
+    if body.len().map(|len| len as u64 > max_size) == Some(true) {
+        return Err(input_too_large());
+    }
+
+    let mut input = Vec::new();
+    body.into_reader()
+        .take(max_size.saturating_add(1))
+        .read_to_end(&mut input)
+        .await?;
+    if input.len() as u64 > max_size {
+        return Err(input_too_large());
+    }
+
+    String::from_utf8(input).map_err(|err| Error::new(StatusCode::BadRequest, err))
+}
+
+async fn handle_annotations(mut request: Request<State>) -> tide::Result {
+    let body = limit_body(request.take_body(), request.state().max_input_size)?;
+    let mut pipeline = annotating_pipeline_from_request(&request).await?;
+    set_response_options(&request, &mut pipeline)?;
+    select_tokenizer(&request, &mut pipeline)?;
+
+    // The input format can be forced with `input=conllu` or `input=text`,
+    // otherwise it is detected when enabled for the pipeline.
+    let input_format = Query::from_request(&request).get::<InputFormat>("input")?;
+    let detect = input_format.is_none() && pipeline.detect_input_format();
+    let text_input = input_format == Some(InputFormat::Text) || (input_format.is_none() && !detect);
+
+    // Annotate up to this number of chunks concurrently, outputting
+    // them as they complete, possibly out of input order. This is only
+    // supported for text input.
+    let out_of_order = Query::from_request(&request).get::<NonZeroUsize>("out_of_order")?;
+    if let (Some(max_in_flight), true) = (out_of_order, text_input) {
+        let annotations = pipeline
+            .unordered_annotations(body.into_reader().lines(), max_in_flight)
+            .map_err(unavailable)?;
+        return annotations_response(&request, &pipeline, annotations, None).await;
+    }
+
+    // Only text input is cached.
+    if let (Some(cache), true) = (request.state().cache.clone(), text_input) {
+        let no_cache = Query::from_request(&request).flag("no-cache")?;
+        let max_input_size = request
+            .state()
+            .config
+            .cache()
+            .expect("Cache is enabled without configuration")
+            .max_input_size;
+        // Statistics, timings, and latencies would not be accurate for
+        // cached responses. Annotations that are published to Kafka
+        // must not be served from the cache, since a cache hit would
+        // not publish them.
+        if !no_cache
+            && !pipeline.publishes_to_kafka()
+            && pipeline.stats().is_none()
+            && pipeline.timings().is_none()
+            && !pipeline.latency()
+            && body.len().map(|len| len as u64 <= max_input_size) == Some(true)
+        {
+            return cached_annotations(request, &pipeline, cache, body).await;
+        }
+    }
+
+    let body_len = body.len();
+
+    // Log progress of uploads with a known length.
+    let reader: Box<dyn AsyncBufRead + Unpin + Send + Sync> = match body.len() {
+        Some(len) => Box::new(AsyncBufReader::new(ProgressReader::new(
+            body.into_reader(),
+            format!("Annotations upload ({})", pipeline.name()),
+            len as u64,
+        ))),
+        None => body.into_reader(),
+    };
+
+    // Spool large bodies to disk, so that they are not buffered in memory
+    // while the client uploads. Bodies of unknown length are streamed
+    // instead, since spooling them would delay annotation until the
+    // upload is complete. The spool file is removed when the response is
+    // dropped.
+    let reader: Box<dyn AsyncBufRead + Unpin + Send + Sync> = match request.state().config.spool() {
+        Some(spool) if spool.should_spool(body_len) => {
+            if spool.exceeds_max_size(body_len) {
+                return Err(input_too_large());
+            }
+            match SpooledBody::spool(reader, &spool.dir(), spool.max_size).await? {
+                Some(spooled) => Box::new(spooled),
+                None => return Err(input_too_large()),
+            }
+        }
+        _ => reader,
+    };
+
+    let (input_format, reader) = match input_format {
+        Some(input_format) => (input_format, reader),
+        None if detect => detect_input_format(reader).await?,
+        None => (InputFormat::Text, reader),
+    };
+
+    let annotations = match input_format {
+        InputFormat::Conllu => pipeline
+            .conllu_annotations(reader.lines())
+            .map_err(unavailable)?
+            .left_stream(),
+        InputFormat::Text => pipeline
+            .annotations(reader.lines())
+            .map_err(unavailable)?
+            .right_stream(),
+    };
+
+    annotations_response(&request, &pipeline, annotations, None).await
+}
+
+/// Annotate a request through the annotation cache.
+async fn cached_annotations(
+    request: Request<State>,
+    pipeline: &Pipeline,
+    cache: Arc<AnnotationCache>,
+    body: Body,
+) -> tide::Result {
+    let input = body.into_string().await?;
+
+    // Outputs with the input text, such as `# input_text` comments and
+    // brat offsets, depend on the exact input. Other outputs do not depend
+    // on the differences that are normalized away.
+    let format = output_format(&request, pipeline);
+    let text = if pipeline.outputs_input_text() {
+        input.clone()
+    } else {
+        normalize_input(&input)
+    };
+    let mut params = request
+        .url()
+        .query_pairs()
+        .filter(|(name, _)| name != "no-cache")
+        .collect::<Vec<_>>();
+    params.sort();
+    let params = params
+        .iter()
+        .map(|(name, value)| (name.as_ref(), value.as_ref()))
+        .chain(iter::once(("format", format.name())))
+        .collect::<Vec<_>>();
+    let key = AnnotationCache::key(pipeline.name(), &params, &text);
+
+    if let Some(output) = cache.get(&key) {
+        return Ok(Response::builder(StatusCode::Ok)
+            .header("Content-Type", format.media_type())
+            .body(Body::from_bytes(output.to_vec()))
+            .build());
+    }
+
+    let lines = input
+        .lines()
+        .map(|line| Ok(line.to_string()))
+        .collect::<Vec<_>>();
+    let annotations = pipeline
+        .annotations(stream::iter(lines))
+        .map_err(unavailable)?;
+
+    annotations_response(&request, pipeline, annotations, Some((cache, key))).await
+}
+
+async fn handle_annotations_combined(mut request: Request<State>) -> tide::Result {
+    let body = limit_body(request.take_body(), request.state().max_input_size)?;
+    let pipeline = annotating_pipeline_from_request(&request).await?;
+
+    // The annotated sentences provide both the tokenization and the
+    // annotation layer, so the input is tokenized once.
+    let annotations = pipeline
+        .annotations(body.into_reader().lines())
+        .map_err(unavailable)?;
+    let combined = response_annotations(&request, &pipeline, annotations)
+        .await?
+        .annotations
+        .map(|batch| {
+            let mut output = Vec::new();
+            for sentence in &batch? {
+                serde_json::to_writer(&mut output, &CombinedSentence::from(sentence))?;
+                output.push(b'\n');
+            }
+            Ok::<_, io::Error>(output)
+        });
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_reader(
+            AsyncBufReader::new(Box::pin(combined).into_async_read()),
+            None,
+        ))
+        .content_type(OutputFormat::Ndjson.media_type())
+        .build())
+}
+
+/// Annotate a text, returning a line with the space-separated universal
+/// part-of-speech tags of each sentence.
+async fn handle_pos(mut request: Request<State>) -> tide::Result {
+    let body = limit_body(request.take_body(), request.state().max_input_size)?;
+    let pipeline = annotating_pipeline_from_request(&request).await?;
+
+    let annotations = pipeline
+        .annotations(body.into_reader().lines())
+        .map_err(unavailable)?;
+    let tags = response_annotations(&request, &pipeline, annotations)
+        .await?
+        .annotations
+        .map(|batch| {
+            let mut output = Vec::new();
+            for sentence in &batch? {
+                write_upos_line(&mut output, sentence);
+            }
+            Ok::<_, io::Error>(output)
+        });
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_reader(
+            AsyncBufReader::new(Box::pin(tags).into_async_read()),
+            None,
+        ))
+        .content_type("text/plain; charset=utf-8")
+        .build())
+}
+
+/// Annotate a text with the pipelines in the `a` and `b` query
+/// parameters, returning per sentence the tokens on which they differ.
+///
+/// Both pipelines annotate the text concurrently. Sentences are aligned
+/// on the text that they cover, when the pipelines split a part of the
+/// text into different sentences, that part is reported as a single
+/// difference with the sentence counts and tokens of both pipelines.
+async fn handle_diff(mut request: Request<State>) -> tide::Result {
+    let query = Query::from_request(&request);
+    let mut pipelines = Vec::with_capacity(2);
+    for param in &["a", "b"] {
+        let pipeline_name = query.get_str(param).ok_or_else(|| {
+            Error::new(
+                StatusCode::BadRequest,
+                anyhow!("Missing query parameter: {}", param),
+            )
+        })?;
+        let pipeline = pipeline_by_name(request.state(), pipeline_name)?;
+        pipeline
+            .wait_for_annotator(request.state().load_timeout)
+            .await;
+        pipelines.push(pipeline);
+    }
+
+    let max_input_size = request.state().max_input_size;
+    let text = limited_body_string(request.take_body(), max_input_size).await?;
+    let sentences = |pipeline: &Pipeline| {
+        let lines = text
+            .lines()
+            .map(|line| Ok(line.to_string()))
+            .collect::<Vec<_>>();
+        // Deregistered when the diffs are dropped.
+        let inflight = request.state().inflight.register(pipeline.name());
+        pipeline
+            .annotations(stream::iter(lines))
+            .map(|annotations| {
+                annotations
+                    .inspect_ok(move |batch| inflight.add_sentences(batch.len()))
+                    .map_ok(|batch| stream::iter(batch.into_iter().map(Ok)))
+                    .try_flatten()
+            })
+            .map_err(unavailable)
+    };
+    let sentences_a = Box::pin(sentences(&pipelines[0])?.fuse());
+    let sentences_b = Box::pin(sentences(&pipelines[1])?.fuse());
+
+    // The stream ends after the first error.
+    let diffs = stream::unfold(Some((sentences_a, sentences_b)), |streams| async move {
+        let (mut sentences_a, mut sentences_b) = streams?;
+        let output = match next_aligned(&mut sentences_a, &mut sentences_b).await {
+            Ok(Some((a, b))) => serde_json::to_vec(&SentenceDiff::aligned(&a, &b))
+                .map(|mut output| {
+                    output.push(b'\n');
+                    output
+                })
+                .map_err(io::Error::from),
+            Ok(None) => return None,
+            Err(err) => Err(err),
+        };
+        let streams = output.as_ref().ok().map(|_| (sentences_a, sentences_b));
+        Some((output, streams))
+    });
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_reader(
+            AsyncBufReader::new(Box::pin(diffs).into_async_read()),
+            None,
+        ))
+        .content_type(OutputFormat::Ndjson.media_type())
+        .build())
+}
+
+/// Read the next sentences of two pipelines that cover the same text.
+///
+/// Usually this is a single sentence of each pipeline. When the
+/// pipelines split the text differently, sentences are read until both
+/// sides cover the same text. Returns `None` when both streams are
+/// exhausted.
+async fn next_aligned<A, B>(
+    sentences_a: &mut A,
+    sentences_b: &mut B,
+) -> io::Result<Option<(Vec<Sentence>, Vec<Sentence>)>>
+where
+    A: Stream<Item = io::Result<Sentence>> + Unpin,
+    B: Stream<Item = io::Result<Sentence>> + Unpin,
+{
+    let (mut a, mut b) = (Vec::new(), Vec::new());
+    let (mut len_a, mut len_b) = (0, 0);
+
+    loop {
+        let read_a = a.is_empty() || len_a < len_b;
+        let read_b = b.is_empty() || len_b < len_a;
+        if !read_a && !read_b {
+            break;
+        }
+
+        // Stop when the sides cannot be aligned, because one of the
+        // streams is exhausted.
+        let mut progress = false;
+        if read_a {
+            if let Some(sentence) = sentences_a.next().await {
+                let sentence = sentence?;
+                len_a += text_len(&sentence);
+                a.push(sentence);
+                progress = true;
+            }
+        }
+        if read_b {
+            if let Some(sentence) = sentences_b.next().await {
+                let sentence = sentence?;
+                len_b += text_len(&sentence);
+                b.push(sentence);
+                progress = true;
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+
+    if a.is_empty() && b.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some((a, b)))
+    }
+}
+
+async fn handle_annotations_conllu(mut request: Request<State>) -> tide::Result {
+    let body = limit_body(request.take_body(), request.state().max_input_size)?;
+    let mut pipeline = annotating_pipeline_from_request(&request).await?;
+    set_response_options(&request, &mut pipeline)?;
+
+    // Keep the layers that are present in the input with `partial=true`.
+    if Query::from_request(&request).flag("partial")? {
+        pipeline.set_partial(true);
+    }
+
+    let annotations = pipeline
+        .conllu_annotations(body.into_reader().lines())
+        .map_err(unavailable)?;
+
+    annotations_response(&request, &pipeline, annotations, None).await
+}
+
+async fn handle_annotations_documents(mut request: Request<State>) -> tide::Result {
+    let body = limit_body(request.take_body(), request.state().max_input_size)?;
+    let pipeline = annotating_pipeline_from_request(&request).await?;
+    let text_field = Query::from_request(&request)
+        .get_str("text_field")
+        .unwrap_or(DEFAULT_TEXT_FIELD)
+        .to_string();
+
+    // Documents are annotated one by one, fail before the response starts
+    // when the annotator is not loaded.
+    let _ = pipeline.annotations(stream::empty()).map_err(unavailable)?;
+
+    // Deregistered when the documents are dropped.
+    let inflight = Arc::new(request.state().inflight.register(pipeline.name()));
+
+    let documents = body
+        .into_reader()
+        .lines()
+        .try_filter(|line| future::ready(!line.trim().is_empty()))
+        .and_then(move |line| {
+            let pipeline = pipeline.clone();
+            let text_field = text_field.clone();
+            let inflight = inflight.clone();
+            async move { annotate_document(pipeline, &text_field, &line, &inflight).await }
+        });
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_reader(
+            AsyncBufReader::new(Box::pin(documents).into_async_read()),
+            None,
+        ))
+        .content_type(OutputFormat::Ndjson.media_type())
+        .build())
+}
+
+async fn handle_annotations_layer(mut request: Request<State>) -> tide::Result {
+    let layer_name: String = request.param("layer")?;
+    let layer: Layer = layer_name
+        .parse()
+        .map_err(|err| Error::new(StatusCode::NotFound, err))?;
+
+    let body = limit_body(request.take_body(), request.state().max_input_size)?;
+    let mut pipeline = annotating_pipeline_from_request(&request).await?;
+    set_response_options(&request, &mut pipeline)?;
+
+    let annotations = pipeline
+        .annotations(body.into_reader().lines())
+        .map_err(unavailable)?
+        .select_layers(vec![layer]);
+
+    annotations_response(&request, &pipeline, annotations, None).await
+}
+
+async fn handle_annotations_url(mut request: Request<State>) -> tide::Result {
+    let url_request: UrlRequest = request.body_json().await?;
+    let mut pipeline = annotating_pipeline_from_request(&request).await?;
+    set_response_options(&request, &mut pipeline)?;
+
+    let url_input = request.state().config.url_input().ok_or_else(|| {
+        Error::from_str(
+            StatusCode::Forbidden,
+            "Annotation of URL input is not enabled",
+        )
+    })?;
+
+    let url = Url::parse(&url_request.url)
+        .map_err(|err| Error::new(StatusCode::BadRequest, anyhow!("Invalid URL: {}", err)))?;
+    let host_allowed = match url.host_str() {
+        Some(host) => url_input
+            .allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host)),
+        None => false,
+    };
+    if !matches!(url.scheme(), "http" | "https") || !host_allowed {
+        return Err(Error::new(
+            StatusCode::Forbidden,
+            anyhow!("Fetching input from {} is not allowed", url),
+        ));
+    }
+
+    // Fail before fetching when the annotator is not loaded.
+    let _ = pipeline.annotations(stream::empty()).map_err(unavailable)?;
+
+    // The timeout applies until the response headers are received, the
+    // body is then streamed into the pipeline.
+    let max_size = url_input.max_size.or(request.state().max_input_size);
+    let input = async_std::future::timeout(
+        Duration::from_secs(url_input.timeout),
+        fetch_input(&url, max_size),
+    )
+    .await
+    .map_err(|_| {
+        Error::new(
+            StatusCode::GatewayTimeout,
+            anyhow!("Timeout fetching {}", url),
+        )
+    })??;
+
+    let annotations = pipeline
+        .annotations(input.into_reader().lines())
+        .map_err(unavailable)?;
+
+    annotations_response(&request, &pipeline, annotations, None).await
+}
+
+/// Fetch the input at `url`, limiting its body to `max_size` bytes.
+async fn fetch_input(url: &Url, max_size: Option<u64>) -> tide::Result<Body> {
+    let bad_gateway = |err: String| {
+        Error::new(
+            StatusCode::BadGateway,
+            anyhow!("Cannot fetch {}: {}", url, err),
+        )
+    };
+    let mut response = surf::get(url.as_str())
+        .await
+        .map_err(|err| bad_gateway(err.to_string()))?;
+    if !response.status().is_success() {
+        return Err(Error::new(
+            StatusCode::BadGateway,
+            anyhow!("Cannot fetch {}: status {}", url, response.status()),
+        ));
+    }
+
+    limit_body(response.take_body(), max_size)
+}
+
+/// Count the sentences and tokens of a text, without annotating it.
+async fn count_sentences(pipeline: &Pipeline, body: Body) -> io::Result<Count> {
+    // The root node is not a token.
+    let (sentences, tokens) = pipeline
+        .sentences(body.into_reader().lines())
+        .try_fold((0, 0), |(sentences, tokens), sentence| async move {
+            Ok((sentences + 1, tokens + sentence.len() - 1))
+        })
+        .await?;
+
+    Ok(Count { sentences, tokens })
+}
+
+/// Tokenize a text, returning its number of sentences and tokens.
+///
+/// This is much cheaper than annotating, since only the tokenizer runs.
+async fn handle_count(mut request: Request<State>) -> tide::Result {
+    let body = limit_body(request.take_body(), request.state().max_input_size)?;
+    let mut pipeline = pipeline_from_request(&request)?;
+    select_tokenizer(&request, &mut pipeline)?;
+
+    let count = count_sentences(&pipeline, body).await?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&count)?)
+        .build())
+}
+
+async fn handle_estimate(mut request: Request<State>) -> tide::Result {
+    let body = limit_body(request.take_body(), request.state().max_input_size)?;
+    let pipeline = pipeline_from_request(&request)?;
+
+    let Count { sentences, tokens } = count_sentences(&pipeline, body).await?;
+
+    let estimate = Estimate {
+        sentences,
+        tokens,
+        seconds: pipeline
+            .estimate(tokens)
+            .map(|duration| duration.as_secs_f64()),
+    };
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&estimate)?)
+        .build())
+}
+
+/// Readiness check, succeeds once all pipelines are warmed up.
+///
+/// Pipelines whose annotator failed to load are also considered to be
+/// warmed up, their status is available from the pipeline status route.
+async fn handle_health(request: Request<State>) -> tide::Result {
+    if !is_ready(request.state()) {
+        return Err(Error::from_str(
+            StatusCode::ServiceUnavailable,
+            "Pipelines are not warmed up yet",
+        ));
+    }
+
+    Ok(Response::new(StatusCode::Ok))
+}
+
+/// Check whether all pipelines are warmed up.
+///
+/// Once the server is ready, it stays ready.
+fn is_ready(state: &State) -> bool {
+    if !state.ready.load(Ordering::SeqCst) {
+        let warmed_up = state
+            .pipelines
+            .read()
+            .expect("Pipelines lock is poisoned")
+            .values()
+            .all(Pipeline::is_warmed_up);
+        if !warmed_up {
+            return false;
+        }
+
+        state.ready.store(true, Ordering::SeqCst);
+    }
+
+    true
+}
+
+/// Serve gRPC health checks that reflect the readiness of the server.
+#[cfg(feature = "grpc-health")]
+fn serve_grpc_health(addr: &str, state: State) -> anyhow::Result<()> {
+    let addr = addr.parse().context("Cannot parse gRPC address")?;
+    grpc_health::serve_health(addr, move || is_ready(&state))
+}
+
+#[cfg(not(feature = "grpc-health"))]
+fn serve_grpc_health(_addr: &str, _state: State) -> anyhow::Result<()> {
+    Err(anyhow!(
+        "gRPC health checks require the `grpc-health` feature"
+    ))
+}
+
+async fn handle_pieces(mut request: Request<State>) -> tide::Result {
+    let max_input_size = request.state().max_input_size;
+    let text = limited_body_string(request.take_body(), max_input_size).await?;
+    let pipeline = pipeline_from_request(&request)?;
+
+    let lines = text
+        .lines()
+        .map(|line| Ok(line.to_string()))
+        .collect::<Vec<_>>();
+    let sentences: Vec<Sentence> = pipeline
+        .sentences(stream::iter(lines))
+        .try_collect()
+        .await?;
+
+    let pieces = sentences
+        .into_iter()
+        .map(|sentence| {
+            let sentence_with_pieces = pipeline.pieces(sentence)?;
+            Ok(SentencePieces {
+                tokens: sentence_with_pieces
+                    .sentence
+                    .iter()
+                    .filter_map(Node::token)
+                    .map(|token| token.form().to_string())
+                    .collect(),
+                pieces: sentence_with_pieces.pieces.to_vec(),
+                token_offsets: sentence_with_pieces.token_offsets,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(unavailable)?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&pieces)?)
+        .build())
+}
+
+async fn handle_pipelines(request: Request<State>) -> tide::Result {
+    let pipelines = request
+        .state()
+        .pipelines
+        .read()
+        .expect("Pipelines lock is poisoned")
+        .iter()
+        .map(|(k, v)| PipelineDescription {
+            name: k.to_string(),
+            description: v.description().to_string(),
+            tags: v.tags().clone(),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&pipelines)?)
+        .build())
+}
+
+async fn handle_output_schema(request: Request<State>) -> tide::Result {
+    let pipeline = pipeline_from_request(&request)?;
+    let schema = output_schema(|layer| pipeline.labels(layer));
 
-mod async_util;
-use async_util::ToTryChunks;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&schema)?)
+        .content_type("application/schema+json")
+        .build())
+}
 
-mod annotator;
+async fn handle_add_pipeline(mut request: Request<State>) -> tide::Result {
+    // Pipelines can only be added by clients with an API key, so the
+    // endpoint is unavailable when authentication is disabled.
+    if request.ext::<AuthenticatedKey>().is_none() {
+        return Err(Error::from_str(
+            StatusCode::Forbidden,
+            "Adding pipelines requires an authenticated API key",
+        ));
+    }
 
-mod config;
-pub use config::{Config, PipelineConfig};
+    let NewPipeline { name, mut config } = request.body_json().await?;
+    let state = request.state();
+    state
+        .config
+        .check_runtime_pipeline(&mut config)
+        .map_err(|err| Error::new(StatusCode::UnprocessableEntity, err))?;
 
-mod pipeline;
-use pipeline::Pipeline;
+    let pipeline = {
+        let tokenizers = state
+            .tokenizers
+            .read()
+            .expect("Tokenizers lock is poisoned");
+        config
+            .new_pipeline(
+                &name,
+                state.config.max_chunk_len(),
+                &state.annotators,
+                &tokenizers,
+            )
+            .map_err(|err| Error::new(StatusCode::UnprocessableEntity, err))?
+    };
 
-mod tokenizer;
+    {
+        let mut pipelines = state.pipelines.write().expect("Pipelines lock is poisoned");
+        if pipelines.contains_key(&name) {
+            return Err(Error::new(
+                StatusCode::Conflict,
+                anyhow!("Pipeline `{}` already exists", name),
+            ));
+        }
+        pipelines.insert(name.clone(), pipeline.clone());
+        state
+            .added_pipeline_tokenizers
+            .write()
+            .expect("Pipeline tokenizers lock is poisoned")
+            .insert(name.clone(), config.tokenizer().to_string());
+    }
 
-mod util;
-use util::ServeFile;
+    log::info!("Added pipeline {}", pipeline.log_label());
 
-const NUM_ANNOTATION_THREADS: &str = "NUM_ANNOTATION_THREADS";
-const NUM_INTEROP_THREADS: &str = "NUM_INTEROP_THREADS";
-const NUM_INTRAOP_THREADS: &str = "NUM_INTRAOP_THREADS";
-const SERVER_ADDR: &str = "SERVER_ADDR";
+    // Warm up in the background, the pipeline can be used in the meanwhile.
+    // Annotators that are not loaded are not warmed up, since that would
+    // load annotators that are loaded on demand or fail.
+    match pipeline.status() {
+        AnnotatorStatus::Ready(_) => {
+            thread::spawn(move || {
+                if let Err(err) = pipeline.warmup() {
+                    log::error!("Cannot warm up pipeline `{}`: {:#}", pipeline.name(), err);
+                }
+            });
+        }
+        _ => pipeline.skip_warmup(),
+    }
 
-#[derive(Serialize)]
-struct PipelineDescription {
-    name: String,
-    description: String,
+    Ok(Response::new(StatusCode::Created))
 }
 
-fn pipeline_from_request(request: &Request<State>) -> Result<&Pipeline, Error> {
-    let pipeline_name: String = request.param("pipeline")?;
-
-    request
+async fn handle_tokenizers(request: Request<State>) -> tide::Result {
+    let tokenizers = request
         .state()
-        .pipelines
-        .get(&pipeline_name)
-        .ok_or_else(|| {
-            Error::new(
-                StatusCode::NotFound,
-                anyhow!("Unknown pipeline: {}", pipeline_name),
-            )
+        .config
+        .tokenizers()
+        .iter()
+        .map(|(name, tokenizer_config)| TokenizerDescription {
+            name: name.to_string(),
+            kind: tokenizer_config.kind(),
         })
+        .collect::<Vec<_>>();
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&tokenizers)?)
+        .build())
 }
 
-async fn handle_annotations(mut request: Request<State>) -> tide::Result {
-    let body = request.take_body();
+async fn handle_validate_config(mut request: Request<State>) -> tide::Result {
+    let yaml = limited_body_string(request.take_body(), Some(MAX_CONFIG_SIZE)).await?;
+
+    match Config::parse(yaml.as_bytes()) {
+        Ok(config) => Ok(Response::builder(StatusCode::Ok)
+            .body(Body::from_json(&config)?)
+            .build()),
+        Err(err) => Ok(Response::builder(StatusCode::BadRequest)
+            .body(format!("{:#}", err))
+            .build()),
+    }
+}
+
+async fn handle_pipeline_status(request: Request<State>) -> tide::Result {
     let pipeline = pipeline_from_request(&request)?;
 
-    let annotator_reader =
-        SentenceStreamReader::new(pipeline.annotations(body.into_reader().lines()));
+    let status = match pipeline.status() {
+        AnnotatorStatus::Loading => PipelineStatus {
+            status: "loading",
+            error: None,
+        },
+        AnnotatorStatus::Ready(_) => PipelineStatus {
+            status: "ready",
+            error: None,
+        },
+        AnnotatorStatus::Failed(err) => PipelineStatus {
+            status: "error",
+            error: Some(err),
+        },
+        AnnotatorStatus::Unloaded => PipelineStatus {
+            status: "unloaded",
+            error: None,
+        },
+    };
 
     Ok(Response::builder(StatusCode::Ok)
-        .body(Body::from_reader(
-            AsyncBufReader::new(annotator_reader),
-            None,
-        ))
+        .body(Body::from_json(&status)?)
         .build())
 }
 
-async fn handle_pipelines(request: Request<State>) -> tide::Result {
-    let pipelines = request
+async fn handle_cache_stats(request: Request<State>) -> tide::Result {
+    let cache = request
         .state()
-        .pipelines
-        .iter()
-        .map(|(k, v)| PipelineDescription {
-            name: k.to_string(),
-            description: v.description().to_string(),
-        })
-        .collect::<Vec<_>>();
+        .cache
+        .as_ref()
+        .ok_or_else(|| Error::from_str(StatusCode::NotFound, "Annotation cache is disabled"))?;
 
     Ok(Response::builder(StatusCode::Ok)
-        .body(Body::from_json(&pipelines)?)
+        .body(Body::from_json(&cache.stats())?)
+        .build())
+}
+
+async fn handle_inflight(request: Request<State>) -> tide::Result {
+    let requests = request.state().inflight.requests();
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&InflightResponse {
+            count: requests.len(),
+            requests,
+        })?)
         .build())
 }
 
+async fn handle_reload_tokenizers(request: Request<State>) -> tide::Result {
+    let config = request.state().config.clone();
+    let tokenizers = spawn_blocking(move || config.load_tokenizers())
+        .await
+        .map_err(|err| Error::new(StatusCode::InternalServerError, err))?;
+
+    let state = request.state();
+    let n_tokenizers = tokenizers.len();
+    {
+        let mut pipelines = state.pipelines.write().expect("Pipelines lock is poisoned");
+        for (name, pipeline) in pipelines.iter_mut() {
+            if let Some(tokenizer) = state
+                .pipeline_tokenizer(name)
+                .and_then(|tokenizer_name| tokenizers.get(&tokenizer_name))
+            {
+                pipeline.set_tokenizer(tokenizer.clone());
+            }
+        }
+
+        *state
+            .tokenizers
+            .write()
+            .expect("Tokenizers lock is poisoned") = tokenizers;
+    }
+
+    // Cached annotations may differ with the new tokenizers. The cache is
+    // cleared after the swap, so that it does not get annotations with
+    // the old tokenizers.
+    if let Some(cache) = &state.cache {
+        cache.clear();
+    }
+
+    log::info!("Reloaded {} tokenizer(s)", n_tokenizers);
+
+    Ok(Response::new(StatusCode::NoContent))
+}
+
 async fn handle_tokens(mut request: Request<State>) -> tide::Result {
     let body = request.take_body();
     let pipeline = pipeline_from_request(&request)?;
+    let state = request.state();
 
-    let tokens_reader = SentenceStreamReader::new(
-        pipeline
-            .sentences(body.into_reader().lines())
-            .try_chunks(16),
-    );
+    // Reject inputs that are known to be too large before tokenizing.
+    let body = limit_body(body, state.tokens_max_input_size)?;
+
+    // Mark the input line of the output sentences.
+    let line_numbers = Query::from_request(&request).flag("lines")?;
+    let lines = body.into_reader().lines();
+    let sentences: Pin<Box<dyn Stream<Item = Result<Sentence, io::Error>> + Send + Sync>> =
+        if line_numbers {
+            Box::pin(pipeline.numbered_sentences(lines))
+        } else {
+            Box::pin(pipeline.sentences(lines))
+        };
+    let sentences = sentences.try_limit(state.tokens_max_sentences.unwrap_or(usize::MAX));
+
+    // Deregistered when the sentences are dropped.
+    let inflight = state.inflight.register(pipeline.name());
+    let sentences = ToTryChunks::try_chunks(sentences, 16)
+        .inspect_ok(move |chunk| inflight.add_sentences(chunk.len()));
+
+    let tokens_reader = SentenceStreamReader::new(sentences).buffer_size(state.output_buffer_size);
 
     Ok(Response::builder(StatusCode::Ok)
         .body(Body::from_reader(AsyncBufReader::new(tokens_reader), None))
@@ -103,19 +1645,201 @@ async fn handle_tokens(mut request: Request<State>) -> tide::Result {
 
 #[derive(Clone)]
 struct State {
-    pipelines: IndexMap<String, Pipeline>,
+    pipelines: Arc<RwLock<IndexMap<String, Pipeline>>>,
+    added_pipeline_tokenizers: Arc<RwLock<IndexMap<String, String>>>,
+    annotators: Arc<IndexMap<String, Arc<AnnotatorCell>>>,
+    cache: Option<Arc<AnnotationCache>>,
     config: Config,
+    inflight: Arc<InflightRequests>,
+    load_timeout: Option<Duration>,
+    max_input_size: Option<u64>,
+    max_response_time: Option<Duration>,
+    output_buffer_size: usize,
+    ready: Arc<AtomicBool>,
+    shutdown: Shutdown,
+    tokens_max_input_size: Option<u64>,
+    tokens_max_sentences: Option<usize>,
+    tokenizers: Arc<RwLock<IndexMap<String, Arc<dyn Tokenizer + Send + Sync>>>>,
+}
+
+impl State {
+    /// Name of the tokenizer that is used by a pipeline.
+    fn pipeline_tokenizer(&self, pipeline: &str) -> Option<String> {
+        match self.config.pipeline_tokenizer(pipeline) {
+            Some(tokenizer) => Some(tokenizer.to_string()),
+            None => self
+                .added_pipeline_tokenizers
+                .read()
+                .expect("Pipeline tokenizers lock is poisoned")
+                .get(pipeline)
+                .cloned(),
+        }
+    }
+}
+
+/// Construct the global thread pool for annotation.
+///
+/// With a thread affinity configuration, each thread is pinned to a
+/// core. This fails when the build does not support pinning.
+fn build_annotation_pool(
+    num_threads: usize,
+    affinity: Option<&ThreadAffinityConfig>,
+) -> anyhow::Result<()> {
+    let mut builder = rayon::ThreadPoolBuilder::new().num_threads(num_threads);
+    if let Some(affinity) = affinity {
+        if !affinity::SUPPORTED {
+            return Err(anyhow!(
+                "Pinning annotation threads requires the experimental `thread-affinity` feature"
+            ));
+        }
+        let cores = affinity.cores.clone();
+        builder = builder.start_handler(move |index| pin_current_thread(index, &cores));
+    }
+
+    builder
+        .build_global()
+        .context("Cannot construct annotation thread pool")
+}
+
+/// Annotate standard input, writing CoNLL-U to standard output.
+async fn annotate_stdin(
+    matches: &ArgMatches<'_>,
+    num_annotation_threads: usize,
+) -> anyhow::Result<()> {
+    let config_filename = matches.value_of("config").unwrap();
+    let config = Config::read(config_filename, File::open(config_filename)?)?;
+    build_annotation_pool(num_annotation_threads, config.annotation_thread_affinity())?;
+
+    let pipeline_name = matches.value_of(PIPELINE).unwrap();
+    let pipeline = config
+        .load(false)?
+        .pipelines
+        .remove(pipeline_name)
+        .ok_or_else(|| anyhow!("Unknown pipeline: {}", pipeline_name))?;
+
+    let annotations = pipeline.annotations(AsyncBufReader::new(async_std::io::stdin()).lines())?;
+    let mut annotator_reader = SentenceStreamReader::new(annotations);
+
+    futures::io::copy(&mut annotator_reader, &mut async_std::io::stdout())
+        .await
+        .context("Cannot annotate standard input")?;
+
+    Ok(())
+}
+
+/// Parse a duration in (fractional) seconds.
+///
+/// Negative and non-finite durations are rejected, since they cannot be
+/// represented as a `Duration`.
+fn parse_secs(secs: &str) -> anyhow::Result<Duration> {
+    let secs: f64 = secs.parse()?;
+    if !secs.is_finite() || secs < 0. {
+        return Err(anyhow!(
+            "Duration should be a non-negative number of seconds, was: {}",
+            secs
+        ));
+    }
+
+    Ok(Duration::from_secs_f64(secs))
 }
 
-#[async_std::main]
+#[cfg_attr(not(feature = "tokio-runtime"), async_std::main)]
+#[cfg_attr(feature = "tokio-runtime", tokio::main)]
 async fn main() -> anyhow::Result<()> {
     let matches = App::new("SyntaxDot REST server")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("config").required(true).index(1))
+        .arg(
+            Arg::with_name(BACKGROUND_LOAD)
+                .long("background-load")
+                .help("Load annotators in the background after the server has started"),
+        )
         .arg(
             Arg::with_name("static")
                 .long("static")
+                .multiple(true)
+                .number_of_values(1)
                 .takes_value(true)
-                .help("Static files to serve"),
+                .value_name("[PREFIX=]DIR")
+                .help("Static files to serve below PREFIX, / when absent (repeatable)"),
+        )
+        .arg(
+            Arg::with_name(GRPC_ADDR)
+                .long("grpc-addr")
+                .help("Address to serve gRPC health checks on (e.g. 127.0.0.1:4001)")
+                .value_name("ADDR")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(LOAD_TIMEOUT)
+                .help(
+                    "Abort startup when loading the pipelines takes more than this number of \
+                     seconds. With max_loaded_models, requests wait at most this long for an \
+                     annotator that is loaded on demand",
+                )
+                .long("load-timeout")
+                .value_name("SECS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MAX_CLIENT_CONNECTIONS)
+                .help(
+                    "Maximum number of concurrent requests per client, identified by API key \
+                     or IP address, further requests get 429 Too Many Requests",
+                )
+                .long("max-client-connections")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MAX_CONNECTION_REQUESTS)
+                .help("Close connections after this number of requests")
+                .long("max-connection-requests")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MAX_CONNECTIONS)
+                .help(
+                    "Maximum number of concurrent connections, further connections are closed \
+                     (connections can carry multiple requests)",
+                )
+                .long("max-connections")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MAX_INPUT_SIZE)
+                .help("Maximum input size of annotation requests in bytes")
+                .long("max-input-size")
+                .value_name("BYTES")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MAX_RESPONSE_TIME)
+                .help("Truncate annotation responses after this number of seconds")
+                .long("max-response-time")
+                .value_name("SECS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MAX_TOKENS_INPUT_SIZE)
+                .help("Maximum input size of tokenization requests in bytes")
+                .long("max-tokens-input-size")
+                .value_name("BYTES")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(MAX_TOKENS_SENTENCES)
+                .help("Maximum number of sentences in tokenization requests")
+                .long("max-tokens-sentences")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(NO_KEEP_ALIVE)
+                .help("Close connections after every response")
+                .long("no-keep-alive"),
         )
         .arg(
             Arg::with_name(NUM_ANNOTATION_THREADS)
@@ -138,12 +1862,39 @@ async fn main() -> anyhow::Result<()> {
                 .value_name("N")
                 .default_value("1"),
         )
+        .arg(
+            Arg::with_name(OUTPUT_BUFFER_SIZE)
+                .help("Output buffer size in bytes")
+                .long("output-buffer-size")
+                .value_name("BYTES")
+                .default_value("65536"),
+        )
+        .arg(
+            Arg::with_name(SEED)
+                .help("Seed for Torch's random number generator")
+                .long("seed")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(SHUTDOWN_TIMEOUT)
+                .help("Time that in-flight responses get to finish after a shutdown signal")
+                .long("shutdown-timeout")
+                .value_name("SECS")
+                .default_value("5"),
+        )
         .arg(
             Arg::with_name(SERVER_ADDR)
                 .long("addr")
                 .help("Address to bind to (e.g. localhost:4000)")
                 .default_value("localhost:4000"),
         )
+        .subcommand(
+            SubCommand::with_name(ANNOTATE)
+                .about("Annotate standard input, writing CoNLL-U to standard output")
+                .arg(Arg::with_name("config").required(true).index(1))
+                .arg(Arg::with_name(PIPELINE).required(true).index(2)),
+        )
         .get_matches();
 
     let num_annotation_threads = matches
@@ -161,41 +1912,746 @@ async fn main() -> anyhow::Result<()> {
         .unwrap()
         .parse()
         .context("Cannot number of intra op threads")?;
+    let output_buffer_size = matches
+        .value_of(OUTPUT_BUFFER_SIZE)
+        .unwrap()
+        .parse()
+        .context("Cannot parse output buffer size")?;
+    let load_timeout = matches
+        .value_of(LOAD_TIMEOUT)
+        .map(parse_secs)
+        .transpose()
+        .context("Cannot parse load timeout")?;
+    let max_client_connections = matches
+        .value_of(MAX_CLIENT_CONNECTIONS)
+        .map(str::parse)
+        .transpose()
+        .context("Cannot parse maximum number of connections per client")?;
+    let max_connection_requests = matches
+        .value_of(MAX_CONNECTION_REQUESTS)
+        .map(str::parse)
+        .transpose()
+        .context("Cannot parse maximum number of requests per connection")?;
+    let max_connections = matches
+        .value_of(MAX_CONNECTIONS)
+        .map(str::parse)
+        .transpose()
+        .context("Cannot parse maximum number of connections")?;
+    let shutdown_timeout = matches
+        .value_of(SHUTDOWN_TIMEOUT)
+        .map(parse_secs)
+        .transpose()
+        .context("Cannot parse shutdown timeout")?
+        .expect("Shutdown timeout should have a default");
+    let max_response_time = matches
+        .value_of(MAX_RESPONSE_TIME)
+        .map(parse_secs)
+        .transpose()
+        .context("Cannot parse maximum response time")?;
+    let max_input_size = matches
+        .value_of(MAX_INPUT_SIZE)
+        .map(str::parse)
+        .transpose()
+        .context("Cannot parse maximum input size")?;
+    let tokens_max_input_size = matches
+        .value_of(MAX_TOKENS_INPUT_SIZE)
+        .map(str::parse)
+        .transpose()
+        .context("Cannot parse maximum tokens input size")?;
+    let tokens_max_sentences = matches
+        .value_of(MAX_TOKENS_SENTENCES)
+        .map(str::parse)
+        .transpose()
+        .context("Cannot parse maximum number of tokens sentences")?;
+
+    let seed = matches
+        .value_of(SEED)
+        .map(str::parse)
+        .transpose()
+        .context("Cannot parse seed")?;
 
     // Set number of Torch threads.
     tch::set_num_interop_threads(num_interop_threads);
     tch::set_num_threads(num_intraop_threads);
 
-    // Rayon threads.
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(num_annotation_threads)
-        .build_global()
-        .unwrap();
+    // Models are constructed without dropout, seeding only guards against
+    // other stochastic components. The seed is global, so it cannot be
+    // set per request.
+    if let Some(seed) = seed {
+        tch::manual_seed(seed);
+    }
+
+    if let Some(annotate_matches) = matches.subcommand_matches(ANNOTATE) {
+        return annotate_stdin(annotate_matches, num_annotation_threads).await;
+    }
 
     let config_filename = matches.value_of("config").unwrap();
     let config = Config::read(config_filename, File::open(config_filename)?)?;
 
-    let pipelines = config.load()?;
+    // Rayon threads, constructed before the annotators are loaded.
+    build_annotation_pool(num_annotation_threads, config.annotation_thread_affinity())?;
+
+    let Loaded {
+        annotators,
+        pipelines,
+        tokenizers,
+    } = match load_timeout {
+        Some(timeout) => config.load_with_timeout(matches.is_present(BACKGROUND_LOAD), timeout)?,
+        None => config.load(matches.is_present(BACKGROUND_LOAD))?,
+    };
+    let auth = config.auth().cloned();
+    let trusted_proxies = config.trusted_proxies().to_vec();
+
+    let shutdown = Shutdown::default();
+    {
+        let shutdown = shutdown.clone();
+        ctrlc::set_handler(move || shutdown.trigger()).context("Cannot set signal handler")?;
+    }
 
     tide::log::start();
-    let mut app = Server::with_state(State { pipelines, config });
+    let cache = config
+        .cache()
+        .map(|cache_config| Arc::new(AnnotationCache::new(cache_config.max_entries)));
+
+    let inflight = Arc::new(InflightRequests::default());
+    let state = State {
+        pipelines: Arc::new(RwLock::new(pipelines)),
+        added_pipeline_tokenizers: Arc::new(RwLock::new(IndexMap::new())),
+        annotators: Arc::new(annotators),
+        cache,
+        config,
+        inflight: inflight.clone(),
+        load_timeout,
+        max_input_size,
+        max_response_time,
+        output_buffer_size,
+        ready: Arc::new(AtomicBool::new(false)),
+        shutdown: shutdown.clone(),
+        tokens_max_input_size,
+        tokens_max_sentences,
+        tokenizers: Arc::new(RwLock::new(tokenizers)),
+    };
+
+    if let Some(grpc_addr) = matches.value_of(GRPC_ADDR) {
+        serve_grpc_health(grpc_addr, state.clone())?;
+    }
+
+    let mut app = Server::with_state(state);
+
+    if let Some(auth) = auth {
+        app.with(ApiKeyAuth::new(auth));
+    }
 
-    if let Some(dir) = matches.value_of("static") {
-        let mut index_path = PathBuf::from(dir);
-        index_path.push("index.html");
+    // Added after authentication, so that only valid keys are counted.
+    if let Some(max_client_connections) = max_client_connections {
+        app.with(ClientLimit::new(
+            Arc::new(ClientConnections::new(max_client_connections)),
+            trusted_proxies,
+        ));
+    }
 
-        app.at("/").get(ServeFile::new(index_path)?);
-        app.at("/").serve_dir(dir)?;
+    let static_dirs = parse_static_dirs(
+        matches.values_of("static").into_iter().flatten(),
+        API_ROUTES,
+    )?;
+    for (prefix, dir) in static_dirs {
+        let serve_static = ServeStatic::new(dir, prefix.as_str(), API_ROUTES)
+            .with_context(|| format!("Cannot serve static files from {}", dir))?;
+        if prefix.is_empty() {
+            app.at("/").get(serve_static.clone());
+        } else {
+            app.at(&prefix).get(serve_static.clone());
+        }
+        app.at(&format!("{}/*", prefix)).get(serve_static);
     }
 
+    app.at("/admin/cache").get(handle_cache_stats);
+    app.at("/admin/inflight").get(handle_inflight);
+    app.at("/admin/pipelines").post(handle_add_pipeline);
+    app.at("/admin/reload-tokenizers")
+        .post(handle_reload_tokenizers);
+    app.at("/admin/validate-config")
+        .post(handle_validate_config);
     app.at("/annotations/:pipeline").post(handle_annotations);
+    app.at("/annotations/:pipeline/output-schema")
+        .get(handle_output_schema);
+    app.at("/annotations/:pipeline/documents")
+        .post(handle_annotations_documents);
+    app.at("/annotations/:pipeline/combined")
+        .post(handle_annotations_combined);
+    app.at("/annotations/:pipeline/conllu")
+        .post(handle_annotations_conllu);
+    app.at("/annotations/:pipeline/url")
+        .post(handle_annotations_url);
+    app.at("/annotations/:pipeline/:layer")
+        .post(handle_annotations_layer);
+    app.at("/count/:pipeline").post(handle_count);
+    app.at("/diff").post(handle_diff);
+    app.at("/estimate/:pipeline").post(handle_estimate);
+    app.at("/health").get(handle_health);
+    app.at("/pieces/:pipeline").post(handle_pieces);
     app.at("/pipelines").get(handle_pipelines);
+    app.at("/pipelines/:pipeline/status")
+        .get(handle_pipeline_status);
+    app.at("/pos/:pipeline").post(handle_pos);
+    app.at("/tokenizers").get(handle_tokenizers);
     app.at("/tokens/:pipeline").post(handle_tokens);
-    app.listen(
-        matches
-            .value_of(SERVER_ADDR)
-            .expect("Server address should be set"),
-    )
-    .await?;
+
+    let addr = matches
+        .value_of(SERVER_ADDR)
+        .expect("Server address should be set");
+    let keep_alive = !matches.is_present(NO_KEEP_ALIVE);
+    let listen = if max_connections.is_some() || max_connection_requests.is_some() || !keep_alive {
+        app.listen(
+            LimitedListener::new(addr, max_connections)
+                .keep_alive(keep_alive)
+                .max_requests(max_connection_requests),
+        )
+        .left_future()
+    } else {
+        app.listen(addr).right_future()
+    };
+
+    match future::select(Box::pin(listen), shutdown.wait()).await {
+        Either::Left((result, _)) => result?,
+        Either::Right(_) => {
+            // Connections are handled in their own tasks, give streaming
+            // responses the chance to finish their current batch.
+            log::info!(
+                "Shutting down, waiting up to {}s for responses to finish",
+                shutdown_timeout.as_secs_f64()
+            );
+            drain(&inflight, shutdown_timeout).await;
+        }
+    }
+
     Ok(())
 }
+
+/// Wait for in-flight annotation requests to finish after a shutdown.
+///
+/// The number of requests in flight is logged every second, until all
+/// requests are done or the timeout is reached. The listener is closed
+/// at this point, so the progress is reported in the log rather than
+/// through an endpoint.
+async fn drain(inflight: &InflightRequests, timeout: Duration) {
+    let start = Instant::now();
+    loop {
+        let elapsed = start.elapsed();
+        let remaining = inflight.count();
+
+        if remaining == 0 {
+            log::info!(
+                "Drain complete after {:.1}s: inflight_requests=0",
+                elapsed.as_secs_f64()
+            );
+            return;
+        }
+
+        if elapsed >= timeout {
+            log::warn!(
+                "Drain timeout of {:.1}s reached: inflight_requests={}",
+                timeout.as_secs_f64(),
+                remaining
+            );
+            return;
+        }
+
+        log::info!(
+            "Draining: inflight_requests={} remaining_secs={:.1}",
+            remaining,
+            (timeout - elapsed).as_secs_f64()
+        );
+        sleep(DRAIN_REPORT_INTERVAL.min(timeout - elapsed)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+
+    use async_std::os::unix::net::UnixStream;
+    use futures::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use futures::stream;
+    use futures::{Stream, StreamExt};
+    use indexmap::IndexMap;
+    use serde_json::{json, Value};
+    use tch::nn::{self, Module, VarStore};
+    use tch::{Device, Kind, Tensor};
+    use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, StatusCode, Url};
+    use tide::{Body, Request, Server};
+    use udgraph::graph::Sentence;
+    use udgraph::token::Token;
+
+    use super::{
+        handle_add_pipeline, handle_annotations, handle_count, handle_estimate,
+        handle_output_schema, handle_pos, handle_validate_config, next_aligned, parse_secs,
+        request_priority, State, MAX_CONFIG_SIZE,
+    };
+    use crate::annotator::AnnotatorCell;
+    use crate::auth::{ApiKeyAuth, AuthenticatedKey};
+    use crate::cache::AnnotationCache;
+    use crate::config::{AuthConfig, Config, PipelineOptions};
+    use crate::inflight::InflightRequests;
+    use crate::pipeline::Pipeline;
+    use crate::query::Query;
+    use crate::shutdown::Shutdown;
+    use crate::test_annotator::TestAnnotator;
+    use crate::tokenizer::WhitespaceTokenizer;
+
+    static CONFIG: &str = r#"
+annotators: {}
+pipelines: {}
+tokenizers: {}
+"#;
+
+    static CACHE_CONFIG: &str = r#"
+annotators: {}
+cache:
+  max_entries: 16
+pipelines: {}
+tokenizers: {}
+"#;
+
+    fn app() -> Server<State> {
+        app_with(PipelineOptions::default(), None)
+    }
+
+    fn app_with(options: PipelineOptions, max_response_time: Option<Duration>) -> Server<State> {
+        app_with_annotator(TestAnnotator::tagging("X"), options, max_response_time)
+    }
+
+    fn app_with_annotator(
+        test_annotator: TestAnnotator,
+        options: PipelineOptions,
+        max_response_time: Option<Duration>,
+    ) -> Server<State> {
+        app_with_state(state_with(test_annotator, options, max_response_time))
+    }
+
+    fn state_with(
+        test_annotator: TestAnnotator,
+        options: PipelineOptions,
+        max_response_time: Option<Duration>,
+    ) -> State {
+        let annotator = Arc::new(AnnotatorCell::loading());
+        annotator.set(Ok(test_annotator));
+        let pipeline = Pipeline::builder("test", annotator, Arc::new(WhitespaceTokenizer))
+            .batch_size(1)
+            .options(options)
+            .read_ahead(1)
+            .build();
+
+        let mut pipelines = IndexMap::new();
+        pipelines.insert("test".to_string(), pipeline);
+
+        State {
+            pipelines: Arc::new(RwLock::new(pipelines)),
+            added_pipeline_tokenizers: Arc::new(RwLock::new(IndexMap::new())),
+            annotators: Arc::new(IndexMap::new()),
+            cache: None,
+            config: Config::parse(CONFIG.as_bytes()).unwrap(),
+            inflight: Arc::new(InflightRequests::default()),
+            load_timeout: None,
+            max_input_size: None,
+            max_response_time,
+            output_buffer_size: 0,
+            ready: Arc::new(AtomicBool::new(true)),
+            shutdown: Shutdown::default(),
+            tokens_max_input_size: None,
+            tokens_max_sentences: None,
+            tokenizers: Arc::new(RwLock::new(IndexMap::new())),
+        }
+    }
+
+    fn app_with_state(state: State) -> Server<State> {
+        let mut app = Server::with_state(state);
+        app.at("/admin/pipelines").post(handle_add_pipeline);
+        app.at("/admin/validate-config")
+            .post(handle_validate_config);
+        app.at("/annotations/:pipeline").post(handle_annotations);
+        app.at("/annotations/:pipeline/output-schema")
+            .get(handle_output_schema);
+        app.at("/count/:pipeline").post(handle_count);
+        app.at("/estimate/:pipeline").post(handle_estimate);
+        app.at("/pos/:pipeline").post(handle_pos);
+        app
+    }
+
+    /// Respond to a request with a body that exceeds the maximum input size.
+    async fn oversized_request_status(path: &str) -> StatusCode {
+        let mut state = state_with(
+            TestAnnotator::tagging("X"),
+            PipelineOptions::default(),
+            None,
+        );
+        state.max_input_size = Some(4);
+
+        let mut request = HttpRequest::new(
+            Method::Post,
+            Url::parse(&format!("http://localhost{}", path)).unwrap(),
+        );
+        request.set_body("Een zin\n");
+        let response: HttpResponse = app_with_state(state).respond(request).await.unwrap();
+        response.status()
+    }
+
+    /// Read output lines until a line with a token.
+    async fn next_token_line<R>(lines: &mut R) -> String
+    where
+        R: Stream<Item = std::io::Result<String>> + Unpin,
+    {
+        loop {
+            let line = lines.next().await.unwrap().unwrap();
+            if line.starts_with("1\t") {
+                return line;
+            }
+        }
+    }
+
+    fn sentence(forms: &[&str]) -> Sentence {
+        forms.iter().map(|form| Token::new(*form)).collect()
+    }
+
+    #[test]
+    fn durations_must_be_non_negative() {
+        assert_eq!(parse_secs("1.5").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_secs("0").unwrap(), Duration::from_secs(0));
+        assert!(parse_secs("-1").is_err());
+        assert!(parse_secs("inf").is_err());
+        assert!(parse_secs("NaN").is_err());
+        assert!(parse_secs("soon").is_err());
+    }
+
+    #[test]
+    fn seeded_outputs_are_identical() {
+        // Both the initialization and dropout draw from the generator.
+        let forward = || {
+            tch::manual_seed(42);
+            let vs = VarStore::new(Device::Cpu);
+            let linear = nn::linear(vs.root(), 8, 4, Default::default());
+            let input = Tensor::ones(&[2, 8], (Kind::Float, Device::Cpu));
+            Vec::<f32>::from(&linear.forward(&input).dropout(0.5, true).view(-1))
+        };
+
+        assert_eq!(forward(), forward());
+    }
+
+    #[test]
+    fn priorities_are_clamped() {
+        let priority = |query: &str, header: Option<&str>, authenticated: bool| {
+            let url = Url::parse(&format!("http://localhost/annotations/test?{}", query)).unwrap();
+            let mut request = HttpRequest::new(Method::Post, url);
+            if let Some(header) = header {
+                request.insert_header("X-Priority", header);
+            }
+            let mut request: Request<()> = request.into();
+            if authenticated {
+                request.set_ext(AuthenticatedKey("secret".to_string()));
+            }
+            request_priority(&request, &Query::from_request(&request)).unwrap()
+        };
+
+        assert_eq!(priority("", None, false), None);
+        assert_eq!(priority("priority=-5", None, false), Some(-5));
+        assert_eq!(priority("priority=-1000", None, false), Some(-100));
+        assert_eq!(priority("", Some("10"), false), Some(0));
+        assert_eq!(priority("", Some("10"), true), Some(10));
+        assert_eq!(priority("priority=2147483647", None, true), Some(100));
+    }
+
+    #[async_std::test]
+    async fn diff_aligns_differently_split_sentences() {
+        let mut a = stream::iter(vec![
+            Ok(sentence(&["Een", "zin", "."])),
+            Ok(sentence(&["Nog", "een", "."])),
+            Ok(sentence(&["Laatste"])),
+        ]);
+        let mut b = stream::iter(vec![
+            Ok(sentence(&["Een", "zin", ".", "Nog", "een", "."])),
+            Ok(sentence(&["Laatste"])),
+        ]);
+
+        let (first_a, first_b) = next_aligned(&mut a, &mut b).await.unwrap().unwrap();
+        assert_eq!((first_a.len(), first_b.len()), (2, 1));
+
+        let (last_a, last_b) = next_aligned(&mut a, &mut b).await.unwrap().unwrap();
+        assert_eq!(last_a, last_b);
+
+        assert!(next_aligned(&mut a, &mut b).await.unwrap().is_none());
+    }
+
+    fn filtered_options() -> PipelineOptions {
+        PipelineOptions {
+            error_on_all_filtered: true,
+            ..PipelineOptions::default()
+        }
+    }
+
+    #[async_std::test]
+    async fn all_filtered_input_is_unprocessable() {
+        let mut request = HttpRequest::new(
+            Method::Post,
+            Url::parse("http://localhost/annotations/test?filter=Kat").unwrap(),
+        );
+        request.set_body("Een zin\nNog een zin\n");
+
+        let response: HttpResponse = app_with(filtered_options(), None)
+            .respond(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UnprocessableEntity);
+    }
+
+    #[async_std::test]
+    async fn skipped_summary_is_not_a_sentence() {
+        let options = PipelineOptions {
+            max_errors: Some(1),
+            ..filtered_options()
+        };
+
+        let mut request = HttpRequest::new(
+            Method::Post,
+            Url::parse("http://localhost/annotations/test?filter=Kat").unwrap(),
+        );
+        request.set_body("Een zin\nboem\n");
+        let response: HttpResponse = app_with_annotator(
+            TestAnnotator::tagging("X").fail_on("boem"),
+            options.clone(),
+            None,
+        )
+        .respond(request)
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::UnprocessableEntity);
+
+        // Without errors, there is no summary.
+        let mut request = HttpRequest::new(
+            Method::Post,
+            Url::parse("http://localhost/annotations/test").unwrap(),
+        );
+        request.set_body("Een zin\n");
+        let mut response: HttpResponse = app_with(options, None).respond(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert!(!response.body_string().await.unwrap().contains("# skipped"));
+    }
+
+    #[async_std::test]
+    async fn all_filtered_wait_is_bounded_by_response_time() {
+        // The input never ends, so the filter cannot be decided.
+        let (_input, body) = UnixStream::pair().unwrap();
+        let mut request = HttpRequest::new(
+            Method::Post,
+            Url::parse("http://localhost/annotations/test?filter=Kat").unwrap(),
+        );
+        request.set_body(Body::from_reader(BufReader::new(body), None));
+
+        let mut response: HttpResponse =
+            app_with(filtered_options(), Some(Duration::from_millis(50)))
+                .respond(request)
+                .await
+                .unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert!(response
+            .body_string()
+            .await
+            .unwrap()
+            .contains("# truncated = true"));
+    }
+
+    #[async_std::test]
+    async fn buffered_responses_that_are_cut_short_are_partial() {
+        let mut request = HttpRequest::new(
+            Method::Post,
+            Url::parse("http://localhost/annotations/test?buffer").unwrap(),
+        );
+        request.set_body("Een zin\n");
+        let response: HttpResponse = app_with(PipelineOptions::default(), None)
+            .respond(request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+
+        // The input never ends, so the response is cut short.
+        let (_input, body) = UnixStream::pair().unwrap();
+        let mut request = HttpRequest::new(
+            Method::Post,
+            Url::parse("http://localhost/annotations/test?buffer").unwrap(),
+        );
+        request.set_body(Body::from_reader(BufReader::new(body), None));
+        let mut response: HttpResponse =
+            app_with(PipelineOptions::default(), Some(Duration::from_millis(50)))
+                .respond(request)
+                .await
+                .unwrap();
+        assert_eq!(response.status(), StatusCode::PartialContent);
+        assert!(response
+            .body_string()
+            .await
+            .unwrap()
+            .contains("# truncated = true"));
+    }
+
+    #[async_std::test]
+    async fn cached_annotations_are_reused() {
+        let cache = Arc::new(AnnotationCache::new(16));
+        let mut state = state_with(
+            TestAnnotator::tagging("X"),
+            PipelineOptions::default(),
+            None,
+        );
+        state.cache = Some(cache.clone());
+        state.config = Config::parse(CACHE_CONFIG.as_bytes()).unwrap();
+        let app = app_with_state(state);
+
+        async fn annotate(app: &Server<State>) -> String {
+            let mut request = HttpRequest::new(
+                Method::Post,
+                Url::parse("http://localhost/annotations/test").unwrap(),
+            );
+            request.set_body("Een zin\n");
+            let mut response: HttpResponse = app.respond(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::Ok);
+            response.body_string().await.unwrap()
+        }
+
+        let output = annotate(&app).await;
+        assert_eq!(cache.stats().misses, 1);
+        assert_eq!(cache.stats().entries, 1);
+
+        assert_eq!(annotate(&app).await, output);
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[async_std::test]
+    async fn adding_pipelines_requires_authentication() {
+        async fn add_pipeline(app: &Server<State>, pipeline: &str) -> StatusCode {
+            let mut request = HttpRequest::new(
+                Method::Post,
+                Url::parse("http://localhost/admin/pipelines").unwrap(),
+            );
+            request.insert_header("Authorization", "Bearer secret");
+            request.set_body(Body::from_string(pipeline.to_string()));
+            request.set_content_type(tide::http::mime::JSON);
+            let response: HttpResponse = app.respond(request).await.unwrap();
+            response.status()
+        }
+
+        let pipeline = r#"{"name": "added", "annotator": "test", "batch_size": 1,
+            "description": "Added", "read_ahead": 1, "tokenizer": "whitespace"}"#;
+        let kafka_pipeline = pipeline.replace(
+            r#""tokenizer""#,
+            r#""kafka": {"brokers": "localhost:9092", "topic": "nl"}, "tokenizer""#,
+        );
+
+        let app = app();
+        assert_eq!(add_pipeline(&app, pipeline).await, StatusCode::Forbidden);
+
+        let mut app = app_with_state(state_with(
+            TestAnnotator::tagging("X"),
+            PipelineOptions::default(),
+            None,
+        ));
+        app.with(ApiKeyAuth::new(AuthConfig {
+            api_keys: vec!["secret".to_string()],
+            public_paths: Vec::new(),
+        }));
+        assert_eq!(
+            add_pipeline(&app, &kafka_pipeline).await,
+            StatusCode::UnprocessableEntity
+        );
+    }
+
+    #[async_std::test]
+    async fn count_rejects_oversized_input() {
+        assert_eq!(
+            oversized_request_status("/count/test").await,
+            StatusCode::PayloadTooLarge
+        );
+    }
+
+    #[async_std::test]
+    async fn estimate_rejects_oversized_input() {
+        assert_eq!(
+            oversized_request_status("/estimate/test").await,
+            StatusCode::PayloadTooLarge
+        );
+    }
+
+    #[async_std::test]
+    async fn validate_config_rejects_oversized_config() {
+        let mut request = HttpRequest::new(
+            Method::Post,
+            Url::parse("http://localhost/admin/validate-config").unwrap(),
+        );
+        let config = " ".repeat(MAX_CONFIG_SIZE as usize + 1);
+        request.set_body(Body::from_reader(
+            BufReader::new(futures::io::Cursor::new(config)),
+            None,
+        ));
+        let response: HttpResponse = app().respond(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PayloadTooLarge);
+    }
+
+    #[async_std::test]
+    async fn pos_tags_are_filtered() {
+        let mut request = HttpRequest::new(
+            Method::Post,
+            Url::parse("http://localhost/pos/test?filter=Nog").unwrap(),
+        );
+        request.set_body("Een zin\nNog een zin\n");
+
+        let mut response: HttpResponse = app().respond(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.body_string().await.unwrap(), "X X X\n");
+    }
+
+    #[async_std::test]
+    async fn output_schema_enumerates_labels() {
+        let request = HttpRequest::new(
+            Method::Get,
+            Url::parse("http://localhost/annotations/test/output-schema").unwrap(),
+        );
+
+        let mut response: HttpResponse = app().respond(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+
+        let schema: Value = response.body_json().await.unwrap();
+        let token = &schema["definitions"]["token"]["properties"];
+        assert_eq!(token["upos"]["enum"], json!(["X", null]));
+        assert!(token["xpos"].get("enum").is_none());
+    }
+
+    #[async_std::test]
+    async fn annotations_stream_chunked_input() {
+        // The request body has no length, so it is sent chunked.
+        let (mut input, body) = UnixStream::pair().unwrap();
+        let mut request = HttpRequest::new(
+            Method::Post,
+            Url::parse("http://localhost/annotations/test").unwrap(),
+        );
+        request.set_body(Body::from_reader(BufReader::new(body), None));
+
+        input.write_all(b"Een zin\n").await.unwrap();
+
+        let response: HttpResponse = app().respond(request).await.unwrap();
+        let mut lines = BufReader::new(response).lines();
+
+        // The first sentence is annotated before the input is complete.
+        assert!(next_token_line(&mut lines)
+            .await
+            .starts_with("1\tEen\t_\tX\t"));
+
+        input.write_all(b"Nog een zin\n").await.unwrap();
+        drop(input);
+
+        assert!(next_token_line(&mut lines)
+            .await
+            .starts_with("1\tNog\t_\tX\t"));
+        while let Some(line) = lines.next().await {
+            line.unwrap();
+        }
+    }
+}
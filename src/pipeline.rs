@@ -1,56 +1,262 @@
-use std::sync::Arc;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use alpino_tokenizer::Tokenizer;
 use futures::io::Error;
-use futures::stream::Stream;
+use futures::stream::{Stream, StreamExt};
+use indexmap::IndexMap;
+use syntaxdot_tokenizers::SentenceWithPieces;
 use udgraph::graph::Sentence;
+use udgraph::token::Token;
 
-use crate::annotator::Annotator;
+use crate::annotator::{AnnotatorCell, AnnotatorStatus};
+use crate::async_conllu::{OutputValidation, ToConlluSentences};
 use crate::async_syntaxdot::{
-    Normalization, ToAnnotations, ToMetadata, ToSentences, ToUnicodeCleanup,
+    AnnotationStats, Casing, ErrorBudget, Layer, Normalization, SentenceText, StageTimings, TagMap,
+    ToAnnotations, ToApplyCasing, ToMapTags, ToMetadata, ToPostProcess, ToPublish, ToSelectLayers,
+    ToSentences, ToUnicodeCleanup,
 };
-use crate::async_util::ToTryChunks;
+use crate::async_util::{ToTryChunks, ToTryChunksByWeight};
+use crate::config::PipelineOptions;
+use crate::format::OutputFormat;
+use crate::kafka::KafkaSink;
+use crate::plugin::WasmPlugin;
+
+/// Default batch size of pipelines that are constructed with a builder.
+const DEFAULT_BATCH_SIZE: usize = 32;
+
+/// Default number of batches to read ahead of pipelines that are
+/// constructed with a builder.
+const DEFAULT_READ_AHEAD: usize = 10;
+
+/// Default maximum number of sentences in a read-ahead chunk.
+pub const DEFAULT_MAX_CHUNK_LEN: usize = 10_000;
+
+/// Default maximum number of chunks that are annotated concurrently for
+/// out-of-order requests.
+const DEFAULT_MAX_OUT_OF_ORDER_CHUNKS: usize = 4;
+
+/// Length in tokens of the sentences used to warm up a pipeline.
+const WARMUP_SENTENCE_LEN: usize = 20;
 
 /// An annotation pipeline.
+///
+/// Cloning a pipeline is cheap, the annotator and other shared state are
+/// reference-counted. Requests that change the pipeline behavior, such as
+/// the output options, do so through the `set_*` methods on a clone of the
+/// pipeline for that single request.
 #[derive(Clone)]
 pub struct Pipeline {
-    annotator: Arc<Annotator>,
+    annotator: Arc<AnnotatorCell>,
     tokenizer: Arc<dyn Tokenizer + Send + Sync>,
     batch_size: usize,
     description: String,
+    input_text: bool,
+    kafka: Option<Arc<KafkaSink>>,
+    latency: bool,
+    max_chunk_len: usize,
     name: String,
+    options: PipelineOptions,
+    partial: bool,
+    plugin: Option<Arc<WasmPlugin>>,
+    priority: i32,
     read_ahead: usize,
+    skip_parser: bool,
+    stats: Option<AnnotationStats>,
+    tag_map: Option<Arc<TagMap>>,
+    tags: Arc<IndexMap<String, String>>,
+    throughput: Arc<RwLock<Option<f64>>>,
+    timings: Option<StageTimings>,
+    warmed_up: Arc<AtomicBool>,
 }
 
 impl Pipeline {
-    /// Construct a new pipeline.
-    pub fn new(
-        description: impl ToString,
+    /// Construct a pipeline builder.
+    ///
+    /// The annotator and tokenizer do not have to come from a
+    /// configuration, any [`Annotate`](crate::annotator::Annotate) and [`Tokenizer`] implementation
+    /// can be used.
+    pub fn builder(
         name: impl ToString,
-        annotator: Arc<Annotator>,
+        annotator: Arc<AnnotatorCell>,
         tokenizer: Arc<dyn Tokenizer + Send + Sync>,
-        batch_size: usize,
-        read_ahead: usize,
-    ) -> Self {
-        Self {
+    ) -> PipelineBuilder {
+        PipelineBuilder {
             annotator,
-            tokenizer,
-            batch_size,
-            description: description.to_string(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            description: String::new(),
+            kafka: None,
+            max_chunk_len: DEFAULT_MAX_CHUNK_LEN,
             name: name.to_string(),
-            read_ahead,
+            options: PipelineOptions::default(),
+            plugin: None,
+            read_ahead: DEFAULT_READ_AHEAD,
+            tag_map: None,
+            tags: IndexMap::new(),
+            tokenizer,
         }
     }
 
     /// Annotate a text stream.
-    pub fn annotations<S>(&self, text_stream: S) -> impl Stream<Item = Result<Vec<Sentence>, Error>>
+    ///
+    /// Fails when the annotator of the pipeline is not loaded.
+    pub fn annotations<S>(
+        &self,
+        text_stream: S,
+    ) -> anyhow::Result<impl Stream<Item = Result<Vec<Sentence>, Error>>>
     where
         S: Stream<Item = Result<String, Error>>,
     {
-        self.sentences(text_stream)
-            .try_chunks(self.batch_size * self.read_ahead)
-            .annotations(self.annotator.clone(), self.batch_size)
+        let error_budget = self.options.max_errors.map(ErrorBudget::new);
+        self.annotate(
+            self.tokenize(text_stream, false, false, error_budget.clone()),
+            error_budget,
+            1,
+        )
+    }
+
+    /// Annotate a text stream, producing chunks as they are annotated.
+    ///
+    /// Up to `max_in_flight` read-ahead chunks are annotated concurrently,
+    /// clamped to the `max_out_of_order_chunks` option of the pipeline,
+    /// so chunks can be produced out of input order. Every sentence gets
+    /// an `# input_id = N` comment with the number of its input line.
+    ///
+    /// Fails when the annotator of the pipeline is not loaded.
+    pub fn unordered_annotations<S>(
+        &self,
+        text_stream: S,
+        max_in_flight: NonZeroUsize,
+    ) -> anyhow::Result<impl Stream<Item = Result<Vec<Sentence>, Error>>>
+    where
+        S: Stream<Item = Result<String, Error>>,
+    {
+        let max_in_flight = max_in_flight.get().min(
+            self.options
+                .max_out_of_order_chunks
+                .unwrap_or(DEFAULT_MAX_OUT_OF_ORDER_CHUNKS)
+                .max(1),
+        );
+        let error_budget = self.options.max_errors.map(ErrorBudget::new);
+        self.annotate(
+            self.tokenize(text_stream, false, true, error_budget.clone()),
+            error_budget,
+            max_in_flight,
+        )
+    }
+
+    /// Annotate a stream of CoNLL-U lines.
+    ///
+    /// Fails when the annotator of the pipeline is not loaded.
+    pub fn conllu_annotations<S>(
+        &self,
+        conllu_stream: S,
+    ) -> anyhow::Result<impl Stream<Item = Result<Vec<Sentence>, Error>>>
+    where
+        S: Stream<Item = Result<String, Error>>,
+    {
+        self.annotate(
+            conllu_stream
+                .conllu_sentences()
+                .preserve_comments(self.options.preserve_comments)
+                .unicode_cleanup(Normalization::Nfc),
+            self.options.max_errors.map(ErrorBudget::new),
+            1,
+        )
+    }
+
+    fn annotate<S>(
+        &self,
+        sentences: S,
+        error_budget: Option<ErrorBudget>,
+        max_in_flight: usize,
+    ) -> anyhow::Result<impl Stream<Item = Result<Vec<Sentence>, Error>>>
+    where
+        S: Stream<Item = Result<Sentence, Error>>,
+    {
+        let annotator = self.annotator.get()?;
+        let device = if self.options.device_comment {
+            annotator.device()
+        } else {
+            None
+        };
+
+        let flush_interval = self.options.flush_interval_ms.map(Duration::from_millis);
+        let chunks = if self.options.streaming_mode {
+            // Annotate sentences one by one as they arrive, without read-ahead.
+            sentences.try_chunks(1).left_stream()
+        } else {
+            match self.options.read_ahead_tokens {
+                // The root node is not a token.
+                Some(max_tokens) => sentences
+                    .try_chunks_by_weight(max_tokens, |sentence: &Sentence| sentence.len() - 1)
+                    .flush_interval(flush_interval)
+                    .max_len(Some(self.max_chunk_len))
+                    .left_stream(),
+                // Chunks are only read as far as they are consumed, so the
+                // cap also holds back the input when annotation stalls.
+                None => sentences
+                    .try_chunks((self.batch_size * self.read_ahead).min(self.max_chunk_len))
+                    .flush_interval(flush_interval)
+                    .right_stream(),
+            }
+            .right_stream()
+        };
+
+        let (casing_before, casing_after) = if self.options.casing_after_annotation {
+            (Casing::None, self.options.casing)
+        } else {
+            (self.options.casing, Casing::None)
+        };
+
+        let annotations = chunks
+            .apply_casing(casing_before)
+            .annotations(annotator, self.batch_size)
+            .queue(self.annotator.queue(), self.priority)
+            .dedup(self.options.dedup_sentences)
+            .error_budget(error_budget)
+            .isolate_errors(self.options.isolate_errors)
+            .latency(self.latency)
+            .log_failed_input(self.options.log_failed_input)
+            .partial(self.partial)
+            .piece_alignment(self.options.piece_alignment)
+            .stats(self.stats.clone())
+            .timings(self.timings.clone())
+            .unordered(max_in_flight)
+            .map_tags(self.tag_map.clone())
+            .post_process(self.plugin.clone())
             .metadata(self.name())
+            .device(device)
+            .sentence_text(self.options.sentence_text)
+            .apply_casing(casing_after);
+
+        // The tagger always runs all heads of the model, so the parser
+        // output can only be removed after annotation.
+        let annotations = if self.skip_parser {
+            annotations
+                .select_layers(vec![
+                    Layer::Features,
+                    Layer::Lemma,
+                    Layer::Upos,
+                    Layer::Xpos,
+                ])
+                .left_stream()
+        } else {
+            annotations.right_stream()
+        };
+
+        Ok(annotations.publish(self.kafka.clone()))
+    }
+
+    /// Estimate the time needed to annotate the given number of tokens.
+    ///
+    /// Returns `None` when the throughput of the pipeline is not
+    /// measured yet.
+    pub fn estimate(&self, n_tokens: usize) -> Option<Duration> {
+        self.throughput()
+            .map(|throughput| Duration::from_secs_f64(n_tokens as f64 / throughput))
     }
 
     /// Pipeline description.
@@ -63,13 +269,470 @@ impl Pipeline {
         &self.name
     }
 
+    /// Whether annotations are published to Kafka.
+    pub fn publishes_to_kafka(&self) -> bool {
+        self.kafka.is_some()
+    }
+
+    /// Pipeline name with its tags, for use in log messages.
+    pub fn log_label(&self) -> String {
+        if self.tags.is_empty() {
+            return format!("`{}`", self.name);
+        }
+
+        let tags = self
+            .tags
+            .iter()
+            .map(|(tag, value)| format!("{}={}", tag, value))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("`{}` ({})", self.name, tags)
+    }
+
+    /// Pipeline tags.
+    pub fn tags(&self) -> &IndexMap<String, String> {
+        &self.tags
+    }
+
+    /// Split a sentence into the word pieces used by the annotator's model.
+    ///
+    /// Fails when the annotator of the pipeline is not loaded.
+    pub fn pieces(&self, sentence: Sentence) -> anyhow::Result<SentenceWithPieces> {
+        Ok(self.annotator.get()?.pieces(sentence))
+    }
+
+    /// Annotation throughput in tokens per second, measured at warmup.
+    pub fn throughput(&self) -> Option<f64> {
+        *self.throughput.read().expect("Throughput lock is poisoned")
+    }
+
+    /// Warm up the pipeline and measure its throughput.
+    ///
+    /// A batch of synthetic sentences is annotated twice: the first
+    /// time to warm up the model, the second time to measure the
+    /// throughput.
+    pub fn warmup(&self) -> anyhow::Result<()> {
+        let result = self.warmup_annotator();
+        self.warmed_up.store(true, Ordering::SeqCst);
+        result
+    }
+
+    /// Mark the pipeline as warmed up without warming it up.
+    ///
+    /// This is used for pipelines with annotators that are loaded on
+    /// demand, since warming up would load the annotator.
+    pub fn skip_warmup(&self) {
+        self.warmed_up.store(true, Ordering::SeqCst);
+    }
+
+    /// Check whether warmup was attempted, regardless of its outcome.
+    pub fn is_warmed_up(&self) -> bool {
+        self.warmed_up.load(Ordering::SeqCst)
+    }
+
+    fn warmup_annotator(&self) -> anyhow::Result<()> {
+        let annotator = self.annotator.get()?;
+
+        let sentences = (0..self.batch_size)
+            .map(|_| {
+                (0..WARMUP_SENTENCE_LEN)
+                    .map(|_| Token::new("warmup"))
+                    .collect::<Sentence>()
+            })
+            .collect::<Vec<_>>();
+
+        annotator.annotate_sentences(&sentences, self.batch_size, false)?;
+
+        let start = Instant::now();
+        annotator.annotate_sentences(&sentences, self.batch_size, false)?;
+        let elapsed = start.elapsed().as_secs_f64();
+
+        let n_tokens = sentences.len() * WARMUP_SENTENCE_LEN;
+        let throughput = n_tokens as f64 / elapsed.max(f64::EPSILON);
+
+        log::info!(
+            "Pipeline `{}` throughput: {:.0} tokens/s",
+            self.name,
+            throughput
+        );
+
+        *self
+            .throughput
+            .write()
+            .expect("Throughput lock is poisoned") = Some(throughput);
+
+        Ok(())
+    }
+
+    /// Record statistics of the annotations that the pipeline produces.
+    pub fn set_stats(&mut self, stats: AnnotationStats) {
+        self.stats = Some(stats);
+    }
+
+    /// Skip the dependency parser, only producing the other layers.
+    ///
+    /// SyntaxDot's tagger does not support running a subset of the heads
+    /// of a model, so all layers are still computed. The dependency
+    /// relations are removed from the annotations, this does not reduce
+    /// the annotation time.
+    ///
+    /// Constructing the tagger without a biaffine decoder would only skip
+    /// decoding the dependency trees, the biaffine head is part of the
+    /// model and still runs. Since the tagger owns the model, a second
+    /// tagger without decoder would also need a second copy of the model
+    /// parameters, doubling the memory use of the annotator.
+    pub fn set_skip_parser(&mut self, skip_parser: bool) {
+        self.skip_parser = skip_parser;
+    }
+
+    /// Add `# input_text = ...` comments with the input text to tokenized
+    /// sentences, see [`Sentences::input_text`](crate::async_syntaxdot::sentences::Sentences::input_text).
+    pub fn set_input_text(&mut self, input_text: bool) {
+        self.input_text = input_text;
+    }
+
+    /// Whether the output contains the input text as is, rather than only
+    /// its tokens.
+    pub fn outputs_input_text(&self) -> bool {
+        self.input_text || self.options.sentence_text == Some(SentenceText::Line)
+    }
+
+    /// Add a `# latency_ms = X` comment with the annotation latency to
+    /// every sentence.
+    pub fn set_latency(&mut self, latency: bool) {
+        self.latency = latency;
+    }
+
+    /// Whether sentences get latency comments, see
+    /// [`Pipeline::set_latency`].
+    pub fn latency(&self) -> bool {
+        self.latency
+    }
+
+    /// Only fill in the layers that are missing from the input sentences.
+    ///
+    /// This is only useful for CoNLL-U input, since tokenized text does
+    /// not have any layers.
+    pub fn set_partial(&mut self, partial: bool) {
+        self.partial = partial;
+    }
+
+    /// Set the priority of the annotations, higher priorities first.
+    ///
+    /// Priorities only have an effect when the annotator limits the
+    /// number of chunks that are annotated concurrently, in which case
+    /// waiting chunks with a higher priority are annotated first. The
+    /// default priority is 0.
+    pub fn set_priority(&mut self, priority: i32) {
+        self.priority = priority;
+    }
+
+    /// Enable or disable streaming mode, see
+    /// [`PipelineOptions::streaming_mode`].
+    pub fn set_streaming_mode(&mut self, streaming_mode: bool) {
+        self.options.streaming_mode = streaming_mode;
+    }
+
+    /// Statistics that are recorded, see [`Pipeline::set_stats`].
+    pub fn stats(&self) -> Option<&AnnotationStats> {
+        self.stats.as_ref()
+    }
+
+    /// Record the time spent in the stages of the pipeline.
+    pub fn set_timings(&mut self, timings: StageTimings) {
+        self.timings = Some(timings);
+    }
+
+    /// Stage timings that are recorded, see [`Pipeline::set_timings`].
+    pub fn timings(&self) -> Option<&StageTimings> {
+        self.timings.as_ref()
+    }
+
+    /// Replace the tokenizer of the pipeline.
+    pub fn set_tokenizer(&mut self, tokenizer: Arc<dyn Tokenizer + Send + Sync>) {
+        self.tokenizer = tokenizer;
+    }
+
+    /// Report an error when the sentence filter removes all sentences.
+    pub fn error_on_all_filtered(&self) -> bool {
+        self.options.error_on_all_filtered
+    }
+
+    /// Whether the format of annotation input is detected.
+    pub fn detect_input_format(&self) -> bool {
+        self.options.detect_input_format
+    }
+
+    /// Order of morphological features in CoNLL-U output.
+    pub fn feats_order(&self) -> Option<&[String]> {
+        self.options.feats_order.as_deref()
+    }
+
+    /// Default output format of the pipeline.
+    pub fn output_format(&self) -> OutputFormat {
+        self.options.output_format
+    }
+
+    /// Validation of the serialized output.
+    pub fn output_validation(&self) -> OutputValidation {
+        self.options.validate_output
+    }
+
+    /// Label set of an annotation layer, `None` when it is not known.
+    pub fn labels(&self, layer: Layer) -> Option<Vec<String>> {
+        self.annotator.get().ok()?.labels(layer)
+    }
+
+    /// Load status of the pipeline's annotator.
+    pub fn status(&self) -> AnnotatorStatus {
+        self.annotator.status()
+    }
+
+    /// Wait for an annotator that is loaded on demand, for at most
+    /// `timeout`, see [`AnnotatorCell::wait_loaded`].
+    pub async fn wait_for_annotator(&self, timeout: Option<Duration>) {
+        self.annotator.wait_loaded(timeout).await
+    }
+
     /// Tokenize sentences and apply unicode cleanup.
     pub fn sentences<S>(&self, text_stream: S) -> impl Stream<Item = Result<Sentence, Error>>
+    where
+        S: Stream<Item = Result<String, Error>>,
+    {
+        self.tokenize(text_stream, false, false, None)
+    }
+
+    /// Tokenize sentences, marking the input line of each sentence.
+    ///
+    /// The first sentence of input line `N` gets a `# line = N` comment.
+    pub fn numbered_sentences<S>(
+        &self,
+        text_stream: S,
+    ) -> impl Stream<Item = Result<Sentence, Error>>
+    where
+        S: Stream<Item = Result<String, Error>>,
+    {
+        self.tokenize(text_stream, true, false, None)
+    }
+
+    fn tokenize<S>(
+        &self,
+        text_stream: S,
+        line_numbers: bool,
+        input_ids: bool,
+        error_budget: Option<ErrorBudget>,
+    ) -> impl Stream<Item = Result<Sentence, Error>>
     where
         S: Stream<Item = Result<String, Error>>,
     {
         text_stream
             .sentences(self.tokenizer.clone())
+            .control_characters(self.options.control_characters)
+            .documents(self.options.blank_line_documents)
+            .error_budget(error_budget)
+            .input_ids(input_ids)
+            .input_text(self.input_text)
+            .normalization(self.options.input_normalization)
+            .line_numbers(line_numbers)
+            .log_failed_input(self.options.log_failed_input)
+            .max_concurrent_tokenizations(self.options.max_concurrent_tokenizations.unwrap_or(1))
+            .retries(self.options.tokenize_retries)
+            .sentence_per_line(self.options.sentence_per_line)
+            .sentence_text(self.options.sentence_text == Some(SentenceText::Line))
+            .skip_empty_sentences(!self.options.keep_empty_sentences)
+            .strip_bom(!self.options.keep_bom)
+            .timings(self.timings.clone())
             .unicode_cleanup(Normalization::Nfc)
+            .offload(self.options.offload_unicode_cleanup)
+    }
+}
+
+/// Builder for pipelines, see [`Pipeline::builder`].
+pub struct PipelineBuilder {
+    annotator: Arc<AnnotatorCell>,
+    batch_size: usize,
+    description: String,
+    kafka: Option<KafkaSink>,
+    max_chunk_len: usize,
+    name: String,
+    options: PipelineOptions,
+    plugin: Option<WasmPlugin>,
+    read_ahead: usize,
+    tag_map: Option<TagMap>,
+    tags: IndexMap<String, String>,
+    tokenizer: Arc<dyn Tokenizer + Send + Sync>,
+}
+
+impl PipelineBuilder {
+    /// Set the batch size.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Set the pipeline description.
+    pub fn description(mut self, description: impl ToString) -> Self {
+        self.description = description.to_string();
+        self
+    }
+
+    /// Set the Kafka sink that annotated sentences are published to.
+    pub fn kafka(mut self, kafka: Option<KafkaSink>) -> Self {
+        self.kafka = kafka;
+        self
+    }
+
+    /// Set the maximum number of sentences in a read-ahead chunk.
+    ///
+    /// This caps the number of sentences that are buffered per request,
+    /// regardless of the batch size and read-ahead. The default is
+    /// [`DEFAULT_MAX_CHUNK_LEN`].
+    pub fn max_chunk_len(mut self, max_chunk_len: usize) -> Self {
+        self.max_chunk_len = max_chunk_len.max(1);
+        self
+    }
+
+    /// Set the optional pipeline settings.
+    pub fn options(mut self, options: PipelineOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Set the WASM plugin that post-processes annotated sentences.
+    pub fn plugin(mut self, plugin: Option<WasmPlugin>) -> Self {
+        self.plugin = plugin;
+        self
+    }
+
+    /// Set the number of batches to read ahead.
+    pub fn read_ahead(mut self, read_ahead: usize) -> Self {
+        self.read_ahead = read_ahead;
+        self
+    }
+
+    /// Set the mapping of annotated tags to a custom tag set.
+    pub fn tag_map(mut self, tag_map: Option<TagMap>) -> Self {
+        self.tag_map = tag_map;
+        self
+    }
+
+    /// Set the tags of the pipeline.
+    pub fn tags(mut self, tags: IndexMap<String, String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Construct the pipeline.
+    pub fn build(self) -> Pipeline {
+        Pipeline {
+            annotator: self.annotator,
+            tokenizer: self.tokenizer,
+            batch_size: self.batch_size,
+            description: self.description,
+            input_text: false,
+            kafka: self.kafka.map(Arc::new),
+            latency: false,
+            max_chunk_len: self.max_chunk_len,
+            name: self.name,
+            options: self.options,
+            partial: false,
+            plugin: self.plugin.map(Arc::new),
+            priority: 0,
+            read_ahead: self.read_ahead,
+            skip_parser: false,
+            stats: None,
+            tag_map: self.tag_map.map(Arc::new),
+            tags: Arc::new(self.tags),
+            throughput: Arc::new(RwLock::new(None)),
+            timings: None,
+            warmed_up: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::executor::block_on_stream;
+    use futures::stream;
+    use indexmap::IndexMap;
+    use udgraph::graph::Node;
+
+    use super::Pipeline;
+    use crate::annotator::AnnotatorCell;
+    use crate::config::PipelineOptions;
+    use crate::test_annotator::TestAnnotator;
+    use crate::tokenizer::WhitespaceTokenizer;
+
+    #[test]
+    fn builder_constructs_ready_pipeline() {
+        let annotator = Arc::new(AnnotatorCell::loading());
+        annotator.set(Ok(TestAnnotator::tagging("X")));
+        let pipeline = Pipeline::builder("test", annotator, Arc::new(WhitespaceTokenizer))
+            .batch_size(2)
+            .build();
+
+        let batches = block_on_stream(
+            pipeline
+                .annotations(stream::iter(vec![Ok("Dit is een test".to_string())]))
+                .unwrap(),
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        let tokens = batches
+            .iter()
+            .flatten()
+            .flat_map(|sentence| sentence.iter().filter_map(Node::token))
+            .collect::<Vec<_>>();
+        assert_eq!(tokens.len(), 4);
+        assert!(tokens.iter().all(|token| token.upos() == Some("X")));
+    }
+
+    #[test]
+    fn empty_sentences_are_skipped_by_default() {
+        let annotator = Arc::new(AnnotatorCell::loading());
+        annotator.set(Ok(TestAnnotator::tagging("X")));
+        let count_sentences = |options: PipelineOptions| {
+            let pipeline =
+                Pipeline::builder("test", annotator.clone(), Arc::new(WhitespaceTokenizer))
+                    .batch_size(2)
+                    .options(options)
+                    .build();
+            // The whitespace tokenizer splits the input into sentences by
+            // line, producing an empty sentence for the blank line.
+            let lines = vec![Ok("Een zin\n \nNog een zin".to_string())];
+            block_on_stream(pipeline.annotations(stream::iter(lines)).unwrap())
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+                .iter()
+                .flatten()
+                .count()
+        };
+
+        assert_eq!(count_sentences(PipelineOptions::default()), 2);
+        assert_eq!(
+            count_sentences(PipelineOptions {
+                keep_empty_sentences: true,
+                ..PipelineOptions::default()
+            }),
+            3
+        );
+    }
+
+    #[test]
+    fn log_label_contains_tags() {
+        let annotator = Arc::new(AnnotatorCell::loading());
+        let builder = || Pipeline::builder("nl", annotator.clone(), Arc::new(WhitespaceTokenizer));
+        assert_eq!(builder().build().log_label(), "`nl`");
+
+        let mut tags = IndexMap::new();
+        tags.insert("team".to_string(), "nlp".to_string());
+        tags.insert("cost-center".to_string(), "42".to_string());
+        assert_eq!(
+            builder().tags(tags).build().log_label(),
+            "`nl` (team=nlp, cost-center=42)"
+        );
     }
 }
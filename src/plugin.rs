@@ -0,0 +1,337 @@
+//! Post-processing of annotations by WASM plugins.
+//!
+//! A plugin is a WebAssembly module that exports:
+//!
+//! * `memory`: the linear memory of the module.
+//! * `alloc(len: i32) -> i32`: allocates `len` bytes and returns their
+//!   offset in `memory`.
+//! * `process(ptr: i32, len: i32) -> i64`: processes the JSON sentence
+//!   of `len` bytes at offset `ptr` and returns the offset of the
+//!   modified JSON sentence in the upper 32 bits and its length in the
+//!   lower 32 bits.
+//!
+//! Sentences are exchanged in the JSON output format. The module cannot
+//! import any functions, so it has no access to the host. Each sentence
+//! is processed by a fresh instance with limits on fuel and memory.
+
+use std::collections::BTreeMap;
+
+use indexmap::IndexMap;
+use serde::Deserialize;
+use udgraph::graph::{Comment, DepTriple, Sentence};
+use udgraph::token::{Features, Misc, Token, TokenBuilder};
+
+/// Default amount of fuel, roughly the number of WASM instructions, that
+/// a plugin can use per sentence.
+pub const DEFAULT_PLUGIN_FUEL: u64 = 10_000_000;
+
+/// Default maximum size in bytes of the memory of a plugin.
+pub const DEFAULT_PLUGIN_MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+/// Sentence in the JSON output format, as returned by a plugin.
+#[cfg_attr(not(feature = "wasm-plugins"), allow(dead_code))]
+#[derive(Deserialize)]
+struct PluginSentence {
+    #[serde(default)]
+    metadata: IndexMap<String, String>,
+
+    #[serde(default)]
+    comments: Vec<String>,
+
+    tokens: Vec<PluginToken>,
+}
+
+/// Token in the JSON output format, as returned by a plugin.
+///
+/// Tokens are numbered by their position, the `id` field is ignored.
+#[cfg_attr(not(feature = "wasm-plugins"), allow(dead_code))]
+#[derive(Deserialize)]
+struct PluginToken {
+    form: String,
+    lemma: Option<String>,
+    upos: Option<String>,
+    xpos: Option<String>,
+    #[serde(default)]
+    features: BTreeMap<String, String>,
+    head: Option<usize>,
+    deprel: Option<String>,
+    #[serde(default)]
+    misc: BTreeMap<String, Option<String>>,
+}
+
+#[cfg_attr(not(feature = "wasm-plugins"), allow(dead_code))]
+impl PluginSentence {
+    fn into_sentence(self) -> anyhow::Result<Sentence> {
+        let n_tokens = self.tokens.len();
+        let mut relations = Vec::new();
+
+        let mut tokens: Vec<Token> = Vec::with_capacity(n_tokens);
+        for (idx, token) in self.tokens.into_iter().enumerate() {
+            if let Some(head) = token.head {
+                if head > n_tokens {
+                    anyhow::bail!("Head {} of token {} is out of range", head, idx + 1);
+                }
+                relations.push(DepTriple::new(head, token.deprel, idx + 1));
+            }
+
+            let mut builder = TokenBuilder::new(token.form)
+                .features(token.features.into_iter().collect::<Features>())
+                .misc(Misc::from(token.misc));
+            if let Some(lemma) = token.lemma {
+                builder = builder.lemma(lemma);
+            }
+            if let Some(upos) = token.upos {
+                builder = builder.upos(upos);
+            }
+            if let Some(xpos) = token.xpos {
+                builder = builder.xpos(xpos);
+            }
+            tokens.push(builder.into());
+        }
+
+        let mut sentence = tokens.into_iter().collect::<Sentence>();
+        for triple in relations {
+            sentence.dep_graph_mut().add_deprel(triple);
+        }
+
+        let comments = sentence.comments_mut();
+        comments.extend(
+            self.metadata
+                .into_iter()
+                .map(|(attr, val)| Comment::AttrVal { attr, val }),
+        );
+        comments.extend(self.comments.into_iter().map(Comment::String));
+
+        Ok(sentence)
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+pub use self::wasm::WasmPlugin;
+
+#[cfg(feature = "wasm-plugins")]
+mod wasm {
+    use std::path::Path;
+
+    use anyhow::{anyhow, Context, Result};
+    use udgraph::graph::Sentence;
+    use wasmtime::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+    use super::PluginSentence;
+    use crate::format::JsonSentence;
+
+    /// A compiled WASM plugin.
+    pub struct WasmPlugin {
+        engine: Engine,
+        fuel: u64,
+        linker: Linker<StoreLimits>,
+        max_memory: usize,
+        module: Module,
+    }
+
+    impl WasmPlugin {
+        /// Load and compile a plugin.
+        ///
+        /// Processing a sentence fails when it takes more than `fuel`
+        /// units of fuel or more than `max_memory` bytes of memory.
+        pub fn load(path: impl AsRef<Path>, fuel: u64, max_memory: usize) -> Result<Self> {
+            Self::from_bytes(std::fs::read(path)?, fuel, max_memory)
+        }
+
+        /// Compile a plugin from a binary or text WASM module.
+        fn from_bytes(bytes: impl AsRef<[u8]>, fuel: u64, max_memory: usize) -> Result<Self> {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config)?;
+            let module = Module::new(&engine, bytes)?;
+
+            Ok(WasmPlugin {
+                linker: Linker::new(&engine),
+                engine,
+                fuel,
+                max_memory,
+                module,
+            })
+        }
+
+        /// Process a sentence with the plugin.
+        pub fn process(&self, sentence: &Sentence) -> Result<Sentence> {
+            let input = serde_json::to_vec(&JsonSentence::from(sentence))?;
+
+            let mut store = Store::new(
+                &self.engine,
+                StoreLimitsBuilder::new()
+                    .memory_size(self.max_memory)
+                    .build(),
+            );
+            store.limiter(|limits| limits);
+            store.add_fuel(self.fuel)?;
+
+            let instance = self.linker.instantiate(&mut store, &self.module)?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| anyhow!("Plugin does not export `memory`"))?;
+            let alloc = instance.get_typed_func::<i32, i32, _>(&mut store, "alloc")?;
+            let process = instance.get_typed_func::<(i32, i32), i64, _>(&mut store, "process")?;
+
+            let input_len = input.len() as i32;
+            let input_ptr = alloc.call(&mut store, input_len)?;
+            memory.write(&mut store, input_ptr as usize, &input)?;
+
+            let output = process.call(&mut store, (input_ptr, input_len))? as u64;
+            let output_ptr = (output >> 32) as usize;
+            let output_len = (output & 0xffff_ffff) as usize;
+            let output = memory
+                .data(&store)
+                .get(output_ptr..output_ptr + output_len)
+                .ok_or_else(|| anyhow!("Plugin output is out of bounds"))?;
+
+            serde_json::from_slice::<PluginSentence>(output)
+                .context("Cannot parse plugin output")?
+                .into_sentence()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use udgraph::graph::Sentence;
+        use udgraph::token::Token;
+
+        use super::WasmPlugin;
+
+        /// Plugin that returns its input, after growing its memory by
+        /// `grow_pages` pages of 64 KiB. It traps when memory cannot be
+        /// grown.
+        fn identity_plugin(grow_pages: u32) -> String {
+            format!(
+                r#"(module
+  (memory (export "memory") 1)
+  (func (export "alloc") (param i32) (result i32)
+    i32.const 0)
+  (func (export "process") (param i32 i32) (result i64)
+    (if (i32.eq (memory.grow (i32.const {})) (i32.const -1))
+      (then unreachable))
+    (i64.or
+      (i64.shl (i64.extend_i32_u (local.get 0)) (i64.const 32))
+      (i64.extend_i32_u (local.get 1)))))"#,
+                grow_pages
+            )
+        }
+
+        fn sentence() -> Sentence {
+            vec![Token::new("Katten"), Token::new("slapen")]
+                .into_iter()
+                .collect()
+        }
+
+        #[test]
+        fn plugin_processes_sentence() {
+            let plugin = WasmPlugin::from_bytes(identity_plugin(0), 1_000_000, 1 << 20).unwrap();
+            assert_eq!(plugin.process(&sentence()).unwrap(), sentence());
+        }
+
+        #[test]
+        fn fuel_is_limited() {
+            let looping = r#"(module
+  (memory (export "memory") 1)
+  (func (export "alloc") (param i32) (result i32)
+    i32.const 0)
+  (func (export "process") (param i32 i32) (result i64)
+    (loop $forever (br $forever))
+    i64.const 0))"#;
+            let plugin = WasmPlugin::from_bytes(looping, 10_000, 1 << 20).unwrap();
+            assert!(plugin.process(&sentence()).is_err());
+        }
+
+        #[test]
+        fn memory_is_limited() {
+            // Growing by 32 pages takes the memory to 33 pages, 2.06 MiB.
+            let plugin = WasmPlugin::from_bytes(identity_plugin(32), 1_000_000, 4 << 20).unwrap();
+            assert!(plugin.process(&sentence()).is_ok());
+
+            let plugin = WasmPlugin::from_bytes(identity_plugin(32), 1_000_000, 1 << 20).unwrap();
+            assert!(plugin.process(&sentence()).is_err());
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+pub use self::unsupported::WasmPlugin;
+
+#[cfg(not(feature = "wasm-plugins"))]
+mod unsupported {
+    use std::path::Path;
+
+    use anyhow::{anyhow, Result};
+    use udgraph::graph::Sentence;
+
+    /// Placeholder for WASM plugins, which require the `wasm-plugins`
+    /// feature.
+    pub struct WasmPlugin {
+        _private: (),
+    }
+
+    impl WasmPlugin {
+        /// Fails, since WASM plugins are not supported by this build.
+        pub fn load(_path: impl AsRef<Path>, _fuel: u64, _max_memory: usize) -> Result<Self> {
+            Err(anyhow!("WASM plugins require the `wasm-plugins` feature"))
+        }
+
+        pub fn process(&self, _sentence: &Sentence) -> Result<Sentence> {
+            unreachable!("WASM plugins cannot be loaded without the `wasm-plugins` feature")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udgraph::graph::Comment;
+
+    use super::PluginSentence;
+
+    #[test]
+    fn plugin_output_becomes_sentence() {
+        let sentence: PluginSentence = serde_json::from_str(
+            r#"{
+  "metadata": {"sent_id": "1"},
+  "tokens": [
+    {"id": 1, "form": "Katten", "lemma": "kat", "upos": "NOUN", "head": 2, "deprel": "nsubj"},
+    {"id": 2, "form": "slapen", "upos": "VERB", "head": 0, "deprel": "root", "features": {"Number": "Plur"}}
+  ]
+}"#,
+        )
+        .unwrap();
+        let sentence = sentence.into_sentence().unwrap();
+
+        assert_eq!(sentence.len(), 3);
+        let katten = sentence[1].token().unwrap();
+        assert_eq!(katten.lemma(), Some("kat"));
+        assert_eq!(katten.upos(), Some("NOUN"));
+        let slapen = sentence[2].token().unwrap();
+        assert_eq!(
+            slapen.features().get("Number").map(String::as_str),
+            Some("Plur")
+        );
+        assert_eq!(slapen.lemma(), None);
+
+        let dep_graph = sentence.dep_graph();
+        assert_eq!(dep_graph.head(1).unwrap().head(), 2);
+        assert_eq!(dep_graph.head(1).unwrap().relation(), Some("nsubj"));
+        assert_eq!(dep_graph.head(2).unwrap().head(), 0);
+
+        assert_eq!(
+            sentence.comments(),
+            &[Comment::AttrVal {
+                attr: "sent_id".to_string(),
+                val: "1".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn plugin_output_with_invalid_head_is_rejected() {
+        let sentence: PluginSentence =
+            serde_json::from_str(r#"{"tokens": [{"form": "Katten", "head": 2}]}"#).unwrap();
+        assert!(sentence.into_sentence().is_err());
+    }
+}
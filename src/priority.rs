@@ -0,0 +1,164 @@
+//! Prioritized annotation of chunks.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot;
+
+/// Queue of chunks that wait for an annotator.
+///
+/// At most a fixed number of chunks is annotated concurrently. Waiting
+/// chunks with a higher priority are annotated first; chunks with the
+/// same priority are annotated in the order in which they arrived.
+pub struct AnnotationQueue {
+    state: Mutex<QueueState>,
+}
+
+struct QueueState {
+    available: usize,
+    next_seq: u64,
+    waiters: BinaryHeap<Waiter>,
+}
+
+impl AnnotationQueue {
+    /// Construct a queue that annotates `max_concurrent` chunks at a time.
+    pub fn new(max_concurrent: usize) -> Self {
+        AnnotationQueue {
+            state: Mutex::new(QueueState {
+                available: max_concurrent.max(1),
+                next_seq: 0,
+                waiters: BinaryHeap::new(),
+            }),
+        }
+    }
+
+    /// Wait for the annotator, the slot is held until the permit is dropped.
+    pub async fn acquire(self: Arc<Self>, priority: i32) -> QueuePermit {
+        let receiver = {
+            let mut state = self.state.lock().expect("Queue lock is poisoned");
+            if state.available > 0 && state.waiters.is_empty() {
+                state.available -= 1;
+                drop(state);
+                return QueuePermit { queue: Some(self) };
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiters.push(Waiter {
+                priority,
+                sender,
+                seq,
+            });
+            receiver
+        };
+
+        receiver
+            .await
+            .expect("Queue waiter was dropped without a permit")
+    }
+
+    /// Pass a released slot to the waiter with the highest priority.
+    fn release(self: Arc<Self>) {
+        loop {
+            let waiter = {
+                let mut state = self.state.lock().expect("Queue lock is poisoned");
+                match state.waiters.pop() {
+                    Some(waiter) => waiter,
+                    None => {
+                        state.available += 1;
+                        return;
+                    }
+                }
+            };
+
+            // The waiter is gone when its request was dropped, try the next one.
+            match waiter.sender.send(QueuePermit {
+                queue: Some(self.clone()),
+            }) {
+                Ok(()) => return,
+                Err(mut permit) => {
+                    permit.queue.take();
+                }
+            }
+        }
+    }
+}
+
+/// Annotator slot, which is passed on when dropped.
+pub struct QueuePermit {
+    queue: Option<Arc<AnnotationQueue>>,
+}
+
+impl Drop for QueuePermit {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.release();
+        }
+    }
+}
+
+struct Waiter {
+    priority: i32,
+    sender: oneshot::Sender<QueuePermit>,
+    seq: u64,
+}
+
+impl Eq for Waiter {}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Higher priorities first, then earlier arrivals first.
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+    use futures::FutureExt;
+
+    use super::AnnotationQueue;
+
+    #[test]
+    fn higher_priorities_are_served_first() {
+        let queue = Arc::new(AnnotationQueue::new(1));
+        let first = block_on(queue.clone().acquire(0));
+
+        let mut low = Box::pin(queue.clone().acquire(0));
+        let mut dropped = Box::pin(queue.clone().acquire(10));
+        let mut high = Box::pin(queue.clone().acquire(5));
+        assert!((&mut low).now_or_never().is_none());
+        assert!((&mut dropped).now_or_never().is_none());
+        assert!((&mut high).now_or_never().is_none());
+
+        // Requests that are dropped while waiting are skipped.
+        drop(dropped);
+        drop(first);
+        assert!((&mut low).now_or_never().is_none());
+        let high = (&mut high).now_or_never().unwrap();
+
+        drop(high);
+        let low = (&mut low).now_or_never().unwrap();
+
+        drop(low);
+        assert!(block_on(queue.acquire(0)).queue.is_some());
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use indexmap::IndexMap;
+use tide::{Error, Request, StatusCode};
+
+/// Query parameters of a request.
+///
+/// Parameter values are parsed on access. Invalid values result in a
+/// *400 Bad Request* error that names the parameter.
+pub struct Query {
+    params: IndexMap<String, String>,
+}
+
+impl Query {
+    /// Get the query parameters of a request.
+    ///
+    /// When a parameter occurs multiple times, the last value is used.
+    pub fn from_request<State>(request: &Request<State>) -> Self {
+        Query {
+            params: request.url().query_pairs().into_owned().collect(),
+        }
+    }
+
+    /// Get a boolean parameter.
+    ///
+    /// A parameter without a value (e.g. `?lines`) is `true`, an absent
+    /// parameter is `false`.
+    pub fn flag(&self, name: &str) -> Result<bool, Error> {
+        match self.params.get(name).map(String::as_str) {
+            None => Ok(false),
+            Some("") => Ok(true),
+            Some(_) => Ok(self.get(name)?.unwrap_or(false)),
+        }
+    }
+
+    /// Get a parameter, parsing it using its `FromStr` implementation.
+    pub fn get<T>(&self, name: &str) -> Result<Option<T>, Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.params
+            .get(name)
+            .map(|value| {
+                value.parse().map_err(|err| {
+                    Error::new(
+                        StatusCode::BadRequest,
+                        anyhow!("Invalid value for query parameter `{}`: {}", name, err),
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    /// Get a parameter as a string.
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use indexmap::IndexMap;
+
+    use super::Query;
+
+    fn query(params: &[(&str, &str)]) -> Query {
+        Query {
+            params: params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<IndexMap<_, _>>(),
+        }
+    }
+
+    #[test]
+    fn parses_flags() {
+        let query = query(&[("a", ""), ("b", "true"), ("c", "false"), ("d", "yes")]);
+        assert!(query.flag("a").unwrap());
+        assert!(query.flag("b").unwrap());
+        assert!(!query.flag("c").unwrap());
+        assert!(query.flag("d").is_err());
+        assert!(!query.flag("e").unwrap());
+    }
+
+    #[test]
+    fn invalid_values_name_the_parameter() {
+        let query = query(&[("batch_size", "0"), ("max_len", "many")]);
+
+        let err = query.get::<NonZeroUsize>("batch_size").unwrap_err();
+        assert_eq!(err.status(), tide::StatusCode::BadRequest);
+        assert!(err.to_string().contains("`batch_size`"));
+
+        let err = query.get::<usize>("max_len").unwrap_err();
+        assert!(err.to_string().contains("`max_len`"));
+
+        assert_eq!(query.get::<usize>("read_ahead").unwrap(), None);
+    }
+}
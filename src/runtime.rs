@@ -0,0 +1,82 @@
+//! Shim over the async runtime.
+//!
+//! Tasks and timers are provided by async-std, or by tokio when the
+//! `tokio-runtime` feature is enabled. The server itself always uses
+//! async-std through tide.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+#[cfg(not(feature = "tokio-runtime"))]
+pub use async_std::task::{sleep, spawn, spawn_blocking};
+
+#[cfg(feature = "tokio-runtime")]
+pub use self::tokio_runtime::{sleep, spawn, spawn_blocking};
+
+/// Timer that completes after a duration.
+///
+/// Response bodies must be `Sync`, which the sleep futures of the
+/// runtimes are not guaranteed to be. Rather than spawning a task for
+/// every timer, the timer is wrapped in a mutex. The timer is only
+/// polled through a mutable reference, so the mutex is never locked.
+pub struct Delay {
+    sleep: Mutex<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl Delay {
+    pub fn new(duration: Duration) -> Self {
+        Delay {
+            sleep: Mutex::new(Box::pin(sleep(duration))),
+        }
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.sleep
+            .get_mut()
+            .expect("Delay lock is poisoned")
+            .as_mut()
+            .poll(cx)
+    }
+}
+
+#[cfg(feature = "tokio-runtime")]
+mod tokio_runtime {
+    use std::future::Future;
+    use std::time::Duration;
+
+    use futures::FutureExt;
+
+    /// Spawn a task, the returned future resolves to the task's output.
+    ///
+    /// Panics when the task panicked, like `async_std::task::spawn`.
+    pub fn spawn<F, T>(future: F) -> impl Future<Output = T> + Send + Sync
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        tokio::spawn(future).map(|result| result.expect("Spawned task failed"))
+    }
+
+    /// Run blocking code on a thread for blocking tasks.
+    ///
+    /// Panics when the closure panicked, like `async_std::task::spawn_blocking`.
+    pub fn spawn_blocking<F, T>(f: F) -> impl Future<Output = T> + Send + Sync
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        tokio::task::spawn_blocking(f).map(|result| result.expect("Blocking task failed"))
+    }
+
+    /// Sleep for the given duration.
+    pub async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
@@ -0,0 +1,94 @@
+use serde_json::{json, Map, Value};
+
+use crate::async_syntaxdot::Layer;
+
+/// JSON Schema of the JSON annotation output.
+///
+/// `labels` provides the label set of a layer. Layers with a label set
+/// are described as enumerations, other layers as strings.
+pub fn output_schema(labels: impl Fn(Layer) -> Option<Vec<String>>) -> Value {
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "Annotations",
+        "description": "Sentences in the `json` output format, `ndjson` output consists of the same sentences, one per line.",
+        "type": "array",
+        "items": { "$ref": "#/definitions/sentence" },
+        "definitions": {
+            "sentence": {
+                "type": "object",
+                "properties": {
+                    "metadata": {
+                        "description": "Attribute-value comments.",
+                        "type": "object",
+                        "additionalProperties": { "type": "string" }
+                    },
+                    "comments": {
+                        "description": "Other comments.",
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "tokens": {
+                        "type": "array",
+                        "items": { "$ref": "#/definitions/token" }
+                    }
+                },
+                "required": ["tokens"]
+            },
+            "token": {
+                "type": "object",
+                "properties": {
+                    "id": { "type": "integer", "minimum": 1 },
+                    "form": { "type": "string" },
+                    "lemma": nullable_string(None),
+                    "upos": nullable_string(labels(Layer::Upos)),
+                    "xpos": nullable_string(labels(Layer::Xpos)),
+                    "features": {
+                        "type": "object",
+                        "additionalProperties": { "type": "string" }
+                    },
+                    "head": { "type": ["integer", "null"], "minimum": 0 },
+                    "deprel": nullable_string(labels(Layer::Deps)),
+                    "misc": {
+                        "type": "object",
+                        "additionalProperties": { "type": ["string", "null"] }
+                    }
+                },
+                "required": ["id", "form", "lemma", "upos", "xpos", "features", "head", "deprel", "misc"]
+            }
+        }
+    })
+}
+
+/// Schema of a string that can be `null`, optionally from a label set.
+fn nullable_string(labels: Option<Vec<String>>) -> Value {
+    let mut schema = Map::new();
+    schema.insert("type".to_string(), json!(["string", "null"]));
+    if let Some(mut labels) = labels {
+        labels.sort();
+        let mut values = labels.into_iter().map(Value::String).collect::<Vec<_>>();
+        values.push(Value::Null);
+        schema.insert("enum".to_string(), Value::Array(values));
+    }
+    Value::Object(schema)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::output_schema;
+    use crate::async_syntaxdot::Layer;
+
+    #[test]
+    fn label_sets_become_enums() {
+        let schema = output_schema(|layer| match layer {
+            Layer::Upos => Some(vec!["VERB".to_string(), "NOUN".to_string()]),
+            _ => None,
+        });
+
+        let token = &schema["definitions"]["token"]["properties"];
+        assert_eq!(token["upos"]["enum"], json!(["NOUN", "VERB", null]));
+        assert!(token["xpos"].get("enum").is_none());
+        assert_eq!(token["xpos"]["type"], json!(["string", "null"]));
+    }
+}
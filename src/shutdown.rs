@@ -0,0 +1,102 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::task::{Context, Poll, Waker};
+
+/// Shutdown signal that is shared between the server and responses.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<ShutdownInner>,
+}
+
+#[derive(Default)]
+struct ShutdownInner {
+    triggered: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl Shutdown {
+    /// Check whether shutdown was triggered.
+    pub fn is_triggered(&self) -> bool {
+        self.inner.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Trigger shutdown, waking up all tasks that wait for it.
+    pub fn trigger(&self) {
+        self.inner.triggered.store(true, Ordering::SeqCst);
+
+        for waker in self
+            .inner
+            .wakers
+            .lock()
+            .expect("Shutdown wakers lock is poisoned")
+            .drain(..)
+        {
+            waker.wake();
+        }
+    }
+
+    /// Wait until shutdown is triggered.
+    pub fn wait(&self) -> WaitShutdown {
+        WaitShutdown {
+            shutdown: self.clone(),
+        }
+    }
+}
+
+/// Future that completes when shutdown is triggered.
+pub struct WaitShutdown {
+    shutdown: Shutdown,
+}
+
+impl Future for WaitShutdown {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.shutdown.is_triggered() {
+            return Poll::Ready(());
+        }
+
+        let mut wakers = self
+            .shutdown
+            .inner
+            .wakers
+            .lock()
+            .expect("Shutdown wakers lock is poisoned");
+
+        // Check again, shutdown could have been triggered before locking.
+        if self.shutdown.is_triggered() {
+            return Poll::Ready(());
+        }
+
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use futures::executor::block_on;
+
+    use super::Shutdown;
+
+    #[test]
+    fn wait_completes_on_trigger() {
+        let shutdown = Shutdown::default();
+        assert!(!shutdown.is_triggered());
+
+        let trigger = shutdown.clone();
+        let handle = thread::spawn(move || trigger.trigger());
+
+        block_on(shutdown.wait());
+        handle.join().unwrap();
+        assert!(shutdown.is_triggered());
+    }
+}
@@ -0,0 +1,155 @@
+//! Spooling of request bodies to disk.
+
+use std::fmt;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use async_std::fs::{File, OpenOptions};
+use futures::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+
+/// Number of spool files that were created by this process.
+static SPOOL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Request body that is spooled to a temporary file.
+///
+/// The body is read from the file once it is completely written. The
+/// file is removed when the body is dropped, which happens when the
+/// response is complete or when the client disconnects.
+pub struct SpooledBody {
+    // Declared before the path, so that the file is closed before it
+    // is removed.
+    reader: BufReader<File>,
+    // Only held to remove the file on drop.
+    #[cfg_attr(not(test), allow(dead_code))]
+    path: SpoolPath,
+}
+
+impl SpooledBody {
+    /// Spool `body` to a new file in `dir`.
+    ///
+    /// Returns `None` when the body is larger than `max_size` bytes, the
+    /// body is then only read up to the first byte past the maximum.
+    pub async fn spool<R>(body: R, dir: &Path, max_size: Option<u64>) -> io::Result<Option<Self>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let path = SpoolPath::new(dir);
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path.0)
+            .await?;
+
+        let limit = max_size.map_or(u64::MAX, |max_size| max_size.saturating_add(1));
+        let len = futures::io::copy(body.take(limit), &mut file).await?;
+        if max_size.is_some_and(|max_size| len > max_size) {
+            log::debug!("Body exceeds the maximum spool size, removing {}", path);
+            return Ok(None);
+        }
+        file.flush().await?;
+        file.seek(SeekFrom::Start(0)).await?;
+        log::debug!("Spooled {} bytes to {}", len, path);
+
+        Ok(Some(SpooledBody {
+            reader: BufReader::new(file),
+            path,
+        }))
+    }
+}
+
+impl AsyncRead for SpooledBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for SpooledBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().reader).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.reader).consume(amt)
+    }
+}
+
+/// Path of a spool file, which is removed when dropped.
+///
+/// The path is owned before the file is created, so that partially
+/// written files are also removed.
+struct SpoolPath(PathBuf);
+
+impl SpoolPath {
+    fn new(dir: &Path) -> Self {
+        let n = SPOOL_FILES.fetch_add(1, Ordering::SeqCst);
+        SpoolPath(dir.join(format!("syntaxdot-rest-{}-{}.spool", process::id(), n)))
+    }
+}
+
+impl fmt::Display for SpoolPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+impl Drop for SpoolPath {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.0) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => log::warn!("Cannot remove spool file {}: {}", self, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use futures::io::{AsyncReadExt, Cursor};
+
+    use super::SpooledBody;
+
+    #[async_std::test]
+    async fn spooled_body_is_read_and_removed() {
+        let dir = env::temp_dir();
+        let mut body = SpooledBody::spool(Cursor::new(b"Katten slapen.\n".to_vec()), &dir, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let path = body.path.0.clone();
+        assert!(path.exists());
+
+        let mut text = String::new();
+        body.read_to_string(&mut text).await.unwrap();
+        assert_eq!(text, "Katten slapen.\n");
+
+        drop(body);
+        assert!(!path.exists());
+    }
+
+    #[async_std::test]
+    async fn oversized_body_is_not_spooled() {
+        let dir = env::temp_dir();
+        let input = b"Katten slapen.\n".to_vec();
+
+        let body = SpooledBody::spool(Cursor::new(input.clone()), &dir, Some(15))
+            .await
+            .unwrap();
+        assert!(body.is_some());
+
+        let body = SpooledBody::spool(Cursor::new(input), &dir, Some(14))
+            .await
+            .unwrap();
+        assert!(body.is_none());
+    }
+}
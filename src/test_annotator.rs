@@ -0,0 +1,76 @@
+//! Annotator for tests.
+
+use anyhow::{anyhow, Result};
+use syntaxdot_tokenizers::SentenceWithPieces;
+use udgraph::graph::{Node, Sentence};
+
+use crate::annotator::Annotate;
+use crate::async_syntaxdot::Layer;
+
+/// Annotator for tests that does not require a model.
+///
+/// By default, sentences are returned unchanged. The label set of the
+/// universal part-of-speech layer is the tag set by [`tagging`].
+///
+/// [`tagging`]: TestAnnotator::tagging
+#[derive(Clone, Debug, Default)]
+pub struct TestAnnotator {
+    fail_on: Option<String>,
+    upos: Option<String>,
+}
+
+impl TestAnnotator {
+    /// Tag every token with the universal part-of-speech tag `upos`.
+    pub fn tagging(upos: &str) -> Self {
+        TestAnnotator {
+            upos: Some(upos.to_string()),
+            ..TestAnnotator::default()
+        }
+    }
+
+    /// Fail on sentences that contain a token with the form `form`.
+    pub fn fail_on(mut self, form: &str) -> Self {
+        self.fail_on = Some(form.to_string());
+        self
+    }
+}
+
+impl Annotate for TestAnnotator {
+    fn pieces(&self, sentence: Sentence) -> SentenceWithPieces {
+        SentenceWithPieces {
+            pieces: Default::default(),
+            sentence,
+            token_offsets: Vec::new(),
+        }
+    }
+
+    fn labels(&self, layer: Layer) -> Option<Vec<String>> {
+        match layer {
+            Layer::Upos => self.upos.clone().map(|upos| vec![upos]),
+            _ => None,
+        }
+    }
+
+    fn annotate_sentences(
+        &self,
+        sentences: &[Sentence],
+        _batch_size: usize,
+        _dedup: bool,
+    ) -> Result<Vec<SentenceWithPieces>> {
+        sentences
+            .iter()
+            .cloned()
+            .map(|mut sentence| {
+                for token in sentence.iter_mut().filter_map(Node::token_mut) {
+                    if self.fail_on.as_deref() == Some(token.form()) {
+                        return Err(anyhow!("Cannot annotate\n`{}`", token.form()));
+                    }
+                    if let Some(upos) = &self.upos {
+                        token.set_upos(Some(upos.as_str()));
+                    }
+                }
+                Ok(self.pieces(sentence))
+            })
+            .collect()
+    }
+}
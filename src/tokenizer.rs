@@ -1,5 +1,68 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
 use alpino_tokenizer::Tokenizer;
 
+/// Tokenizer wrapper that handles additional abbreviations.
+///
+/// When the wrapped tokenizer splits the period off a known abbreviation,
+/// the period is attached to the abbreviation again. If the wrapped
+/// tokenizer ended a sentence after the abbreviation, the sentence is
+/// merged with the next sentence.
+pub struct AbbreviationTokenizer {
+    abbreviations: HashSet<String>,
+    inner: Arc<dyn Tokenizer + Send + Sync>,
+}
+
+impl AbbreviationTokenizer {
+    pub fn new(
+        inner: Arc<dyn Tokenizer + Send + Sync>,
+        abbreviations: impl IntoIterator<Item = String>,
+    ) -> Self {
+        AbbreviationTokenizer {
+            abbreviations: abbreviations
+                .into_iter()
+                .map(|abbr| abbr.trim_end_matches('.').to_owned())
+                .collect(),
+            inner,
+        }
+    }
+}
+
+impl Tokenizer for AbbreviationTokenizer {
+    fn tokenize(&self, text: &str) -> Option<Vec<Vec<String>>> {
+        let mut sentences: Vec<Vec<String>> = Vec::new();
+        let mut continue_sentence = false;
+
+        for sentence in self.inner.tokenize(text)? {
+            if !continue_sentence || sentences.is_empty() {
+                sentences.push(Vec::new());
+            }
+
+            let tokens = sentences.last_mut().expect("No current sentence");
+            continue_sentence = false;
+
+            for token in sentence {
+                let is_abbreviation = token == "."
+                    && tokens
+                        .last()
+                        .map(|prev| self.abbreviations.contains(prev))
+                        .unwrap_or(false);
+
+                if is_abbreviation {
+                    tokens.last_mut().expect("No previous token").push('.');
+                    continue_sentence = true;
+                } else {
+                    tokens.push(token);
+                    continue_sentence = false;
+                }
+            }
+        }
+
+        Some(sentences)
+    }
+}
+
 /// Simple whitespace-based tokenizer.
 ///
 /// Splits sentences on newlines (`\n` or `\r\n`) and tokens on ASCII whitespace.
@@ -17,9 +80,54 @@ impl Tokenizer for WhitespaceTokenizer {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use alpino_tokenizer::Tokenizer;
 
-    use crate::tokenizer::WhitespaceTokenizer;
+    use crate::tokenizer::{AbbreviationTokenizer, WhitespaceTokenizer};
+
+    #[test]
+    fn test_abbreviation_tokenizer() {
+        let tokenizer = AbbreviationTokenizer::new(
+            Arc::new(WhitespaceTokenizer),
+            vec!["dr".to_string(), "prof.".to_string()],
+        );
+        assert_eq!(
+            tokenizer.tokenize("Ik zag dr .\nJansen en prof . Pietersen .\nEen zin ."),
+            Some(vec![
+                vec![
+                    "Ik".to_string(),
+                    "zag".to_string(),
+                    "dr.".to_string(),
+                    "Jansen".to_string(),
+                    "en".to_string(),
+                    "prof.".to_string(),
+                    "Pietersen".to_string(),
+                    ".".to_string()
+                ],
+                vec!["Een".to_string(), "zin".to_string(), ".".to_string()]
+            ])
+        )
+    }
+
+    #[test]
+    fn test_whitespace_tokenizer_ignores_extra_whitespace() {
+        let tokenizer = WhitespaceTokenizer;
+        assert_eq!(
+            tokenizer.tokenize("  Dit  is\t een zin . \n\nEn nog een \r\n"),
+            Some(vec![
+                vec![
+                    "Dit".to_string(),
+                    "is".to_string(),
+                    "een".to_string(),
+                    "zin".to_string(),
+                    ".".to_string()
+                ],
+                vec![],
+                vec!["En".to_string(), "nog".to_string(), "een".to_string()]
+            ])
+        )
+    }
 
     #[test]
     fn test_whitespace_tokenizer() {
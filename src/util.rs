@@ -1,31 +1,544 @@
-use std::io;
+use std::cmp::Ordering;
+use std::io::{self, Write};
 use std::os::raw::c_int;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
+use anyhow::{bail, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use indexmap::IndexMap;
+use percent_encoding::percent_decode_str;
+use tide::http::Mime;
 use tide::{Body, Endpoint, Request, Response, StatusCode};
 
+use crate::runtime::spawn_blocking;
+
+/// Files of at least this size are compressed on the fly.
+const MIN_COMPRESS_SIZE: u64 = 1024;
+
+/// Maximum total size in bytes of the compressed files kept in memory.
+const MAX_GZIP_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Extensions of files that benefit from compression.
+const COMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "css", "html", "js", "json", "map", "mjs", "svg", "txt", "wasm", "xml",
+];
+
+/// Content encoding of a static file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Parse the encodings of the values of an `Accept-Encoding` header.
+    ///
+    /// The encodings are ordered by their quality values, brotli is
+    /// preferred over gzip when the quality values are equal. Encodings
+    /// with a quality value of zero or an invalid quality value are not
+    /// accepted.
+    fn accepted<'a>(accept_encoding: impl IntoIterator<Item = &'a str>) -> Vec<Encoding> {
+        let mut accepted = accept_encoding
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(|coding| {
+                let mut parts = coding.split(';').map(str::trim);
+                let encoding = match parts.next()? {
+                    "br" => Encoding::Brotli,
+                    "gzip" => Encoding::Gzip,
+                    _ => return None,
+                };
+                let quality = match parts.find_map(|param| param.strip_prefix("q=")) {
+                    Some(q) => q.parse::<f32>().ok()?,
+                    None => 1.0,
+                };
+                if quality > 0.0 {
+                    Some((encoding, quality))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // A stable sort keeps the first occurrence of an encoding first.
+        accepted.sort_by(|(encoding, quality), (other_encoding, other_quality)| {
+            other_quality
+                .partial_cmp(quality)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| {
+                    (*encoding != Encoding::Brotli).cmp(&(*other_encoding != Encoding::Brotli))
+                })
+        });
+
+        let mut encodings = Vec::new();
+        for (encoding, _) in accepted {
+            if !encodings.contains(&encoding) {
+                encodings.push(encoding);
+            }
+        }
+        encodings
+    }
+
+    /// Extension of pre-compressed files.
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    /// Name as used in the `Content-Encoding` header.
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Path of the pre-compressed variant of a file.
+fn compressed_path(file: &Path, encoding: Encoding) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".");
+    path.push(encoding.extension());
+    PathBuf::from(path)
+}
+
+fn is_compressible(file: &Path) -> bool {
+    file.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| COMPRESSIBLE_EXTENSIONS.contains(&extension))
+        .unwrap_or(false)
+}
+
+/// In-memory gzip compression of a static file.
+#[derive(Clone)]
+struct GzippedFile {
+    data: Arc<Vec<u8>>,
+    modified: SystemTime,
+}
+
+/// Compressed files, bounded by their total size.
+///
+/// When the maximum size is exceeded, the files that were compressed
+/// first are evicted. Files that are larger than the maximum size are
+/// not kept.
+struct GzipCache {
+    files: IndexMap<PathBuf, GzippedFile>,
+    max_size: usize,
+    size: usize,
+}
+
+impl GzipCache {
+    fn new(max_size: usize) -> Self {
+        GzipCache {
+            files: IndexMap::new(),
+            max_size,
+            size: 0,
+        }
+    }
+
+    /// Get a compressed file, unless it was modified since compression.
+    fn get(&self, file: &Path, modified: SystemTime) -> Option<Arc<Vec<u8>>> {
+        self.files
+            .get(file)
+            .filter(|gzipped| gzipped.modified == modified)
+            .map(|gzipped| gzipped.data.clone())
+    }
+
+    fn insert(&mut self, file: PathBuf, gzipped: GzippedFile) {
+        if let Some(replaced) = self.files.shift_remove(&file) {
+            self.size -= replaced.data.len();
+        }
+
+        if gzipped.data.len() > self.max_size {
+            return;
+        }
+
+        self.size += gzipped.data.len();
+        self.files.insert(file, gzipped);
+
+        while self.size > self.max_size {
+            let (_, evicted) = self
+                .files
+                .shift_remove_index(0)
+                .expect("Cache size is not zero without files");
+            self.size -= evicted.data.len();
+        }
+    }
+}
+
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
 #[allow(dead_code)]
 #[no_mangle]
 extern "C" fn mkl_serv_intel_cpu_true() -> c_int {
     1
 }
 
-pub struct ServeFile(PathBuf);
+/// Parse `--static` arguments of the form `PREFIX=DIR` or `DIR`.
+///
+/// A directory without a prefix is served at `/`. Returns the prefixes,
+/// without trailing slashes, with their directories. Fails when a prefix
+/// does not start with a slash, is used more than once, or collides with
+/// one of the API routes.
+pub fn parse_static_dirs<'a>(
+    args: impl IntoIterator<Item = &'a str>,
+    api_routes: &[&str],
+) -> Result<Vec<(String, &'a str)>> {
+    let mut dirs: Vec<(String, &str)> = Vec::new();
+    for arg in args {
+        let (prefix, dir) = match arg.split_once('=') {
+            Some((prefix, dir)) => (prefix.trim_end_matches('/'), dir),
+            None => ("", arg),
+        };
+
+        if !prefix.is_empty() && !prefix.starts_with('/') {
+            bail!("Static prefix `{}` does not start with `/`", prefix);
+        }
+
+        if let Some(route) = api_routes.iter().find(|&&route| {
+            prefix
+                .strip_prefix(route)
+                .map(|rest| rest.is_empty() || rest.starts_with('/'))
+                .unwrap_or(false)
+        }) {
+            bail!(
+                "Static prefix `{}` collides with API route `{}`",
+                prefix,
+                route
+            );
+        }
 
-impl ServeFile {
-    pub fn new(path: impl Into<PathBuf>) -> io::Result<Self> {
-        Ok(Self(path.into().canonicalize()?))
+        if dirs.iter().any(|(other, _)| other == prefix) {
+            bail!("Static prefix `{}/` is used more than once", prefix);
+        }
+
+        dirs.push((prefix.to_string(), dir));
+    }
+
+    Ok(dirs)
+}
+
+/// Serve static files for a single-page application.
+///
+/// Files are served from a directory, below a route prefix. Requests
+/// for paths that do not correspond to a file get `index.html`, so that
+/// client-side routes can be loaded directly. Paths below one of the excluded prefixes
+/// (such as API routes) get a *404 Not Found* response instead.
+///
+/// Responses are compressed when the client accepts it. Pre-compressed
+/// `.br` and `.gz` files next to a file are served when they exist,
+/// other compressible files are compressed with gzip on the fly. The
+/// compressed files are kept in memory until the file is modified, up
+/// to a maximum total size.
+#[derive(Clone)]
+pub struct ServeStatic {
+    dir: PathBuf,
+    excluded_prefixes: Vec<String>,
+    gzipped: Arc<Mutex<GzipCache>>,
+    index: PathBuf,
+    prefix: String,
+}
+
+impl ServeStatic {
+    /// Serve the files in `dir` below `prefix`.
+    ///
+    /// The prefix does not have a trailing slash, the empty prefix
+    /// serves the files at `/`.
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        prefix: impl Into<String>,
+        excluded_prefixes: &[&str],
+    ) -> io::Result<Self> {
+        let dir = dir.into().canonicalize()?;
+        let index = dir.join("index.html").canonicalize()?;
+
+        Ok(ServeStatic {
+            dir,
+            excluded_prefixes: excluded_prefixes.iter().map(ToString::to_string).collect(),
+            gzipped: Arc::new(Mutex::new(GzipCache::new(MAX_GZIP_CACHE_SIZE))),
+            index,
+            prefix: prefix.into(),
+        })
+    }
+
+    /// Get the file for a request path, if it exists.
+    fn file(&self, path: &str) -> Option<PathBuf> {
+        let path = path.strip_prefix(self.prefix.as_str())?;
+        let path = percent_decode_str(path).decode_utf8().ok()?;
+        let relative_path = Path::new(path.trim_start_matches('/'));
+
+        // Do not serve files outside the static directory.
+        if !relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+
+        let file = self.dir.join(relative_path);
+        if file.is_file() {
+            Some(file)
+        } else {
+            None
+        }
+    }
+
+    /// Get the gzip-compressed contents of a file.
+    ///
+    /// The file is only compressed when it was not compressed before or
+    /// when it was modified since. Compression runs on a thread for
+    /// blocking tasks, so that it does not stall other requests.
+    async fn gzipped(&self, file: &Path, modified: SystemTime) -> io::Result<Arc<Vec<u8>>> {
+        if let Some(data) = self.gzipped.lock().unwrap().get(file, modified) {
+            return Ok(data);
+        }
+
+        let contents = async_std::fs::read(file).await?;
+        let data = Arc::new(spawn_blocking(move || gzip(&contents)).await?);
+        self.gzipped.lock().unwrap().insert(
+            file.to_owned(),
+            GzippedFile {
+                data: data.clone(),
+                modified,
+            },
+        );
+
+        Ok(data)
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        self.excluded_prefixes.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .map(|rest| rest.is_empty() || rest.starts_with('/'))
+                .unwrap_or(false)
+        })
     }
 }
 
 #[async_trait::async_trait]
-impl<State> Endpoint<State> for ServeFile
+impl<State> Endpoint<State> for ServeStatic
 where
     State: Clone + Send + Sync + 'static,
 {
-    async fn call(&self, _req: Request<State>) -> tide::Result {
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        let path = req.url().path();
+
+        if self.is_excluded(path) {
+            return Ok(Response::new(StatusCode::NotFound));
+        }
+
+        let file = self.file(path).unwrap_or_else(|| self.index.clone());
+        let accepted = req
+            .header("Accept-Encoding")
+            .map(|accept_encoding| {
+                Encoding::accepted(accept_encoding.iter().map(|value| value.as_str()))
+            })
+            .unwrap_or_default();
+        let mime = file
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(Mime::from_extension);
+
+        for &encoding in &accepted {
+            let compressed = compressed_path(&file, encoding);
+            if compressed.is_file() {
+                let mut body = Body::from_file(compressed).await?;
+                if let Some(mime) = mime {
+                    body.set_mime(mime);
+                }
+                return Ok(Response::builder(StatusCode::Ok)
+                    .body(body)
+                    .header("Content-Encoding", encoding.name())
+                    .header("Vary", "Accept-Encoding")
+                    .build());
+            }
+        }
+
+        let metadata = async_std::fs::metadata(&file).await?;
+        let compress = accepted.contains(&Encoding::Gzip)
+            && is_compressible(&file)
+            && metadata.len() >= MIN_COMPRESS_SIZE;
+        if compress {
+            let data = self.gzipped(&file, metadata.modified()?).await?;
+            let mut body = Body::from_bytes(data.to_vec());
+            if let Some(mime) = mime {
+                body.set_mime(mime);
+            }
+            return Ok(Response::builder(StatusCode::Ok)
+                .body(body)
+                .header("Content-Encoding", Encoding::Gzip.name())
+                .header("Vary", "Accept-Encoding")
+                .build());
+        }
+
         Ok(Response::builder(StatusCode::Ok)
-            .body(Body::from_file(&self.0).await?)
+            .body(Body::from_file(file).await?)
+            .header("Vary", "Accept-Encoding")
             .build())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+
+    use super::{
+        compressed_path, gzip, parse_static_dirs, Encoding, GzipCache, GzippedFile, ServeStatic,
+        MAX_GZIP_CACHE_SIZE,
+    };
+
+    fn serve_static() -> ServeStatic {
+        ServeStatic {
+            dir: PathBuf::from(env!("CARGO_MANIFEST_DIR")),
+            excluded_prefixes: vec!["/annotations".to_string()],
+            gzipped: Arc::new(Mutex::new(GzipCache::new(MAX_GZIP_CACHE_SIZE))),
+            index: PathBuf::from("index.html"),
+            prefix: String::new(),
+        }
+    }
+
+    #[test]
+    fn api_routes_are_excluded() {
+        let serve_static = serve_static();
+        assert!(serve_static.is_excluded("/annotations"));
+        assert!(serve_static.is_excluded("/annotations/nl"));
+        assert!(!serve_static.is_excluded("/annotationsfoo"));
+        assert!(!serve_static.is_excluded("/app/annotations"));
+    }
+
+    #[test]
+    fn accepted_encodings() {
+        assert_eq!(
+            Encoding::accepted(vec!["gzip, deflate, br"]),
+            [Encoding::Brotli, Encoding::Gzip]
+        );
+        assert_eq!(
+            Encoding::accepted(vec!["br;q=0, gzip;q=0.5"]),
+            [Encoding::Gzip]
+        );
+        assert!(Encoding::accepted(vec!["identity"]).is_empty());
+        assert!(Encoding::accepted(vec!["gzip;q=high"]).is_empty());
+    }
+
+    #[test]
+    fn encodings_are_ordered_by_quality() {
+        assert_eq!(
+            Encoding::accepted(vec!["br;q=0.5, gzip;q=0.8"]),
+            [Encoding::Gzip, Encoding::Brotli]
+        );
+        assert_eq!(
+            Encoding::accepted(vec!["gzip;q=0.5", "deflate, br;q=0.9"]),
+            [Encoding::Brotli, Encoding::Gzip]
+        );
+    }
+
+    #[async_std::test]
+    async fn gzipped_files_are_cached() {
+        let serve_static = serve_static();
+        let file = serve_static.file("/Cargo.toml").unwrap();
+        let modified = std::fs::metadata(&file).unwrap().modified().unwrap();
+
+        let gzipped = serve_static.gzipped(&file, modified).await.unwrap();
+        assert!(Arc::ptr_eq(
+            &gzipped,
+            &serve_static.gzipped(&file, modified).await.unwrap()
+        ));
+
+        let later = modified + std::time::Duration::from_secs(1);
+        assert!(!Arc::ptr_eq(
+            &gzipped,
+            &serve_static.gzipped(&file, later).await.unwrap()
+        ));
+    }
+
+    #[test]
+    fn gzip_cache_is_bounded() {
+        let gzipped = |len: usize| GzippedFile {
+            data: Arc::new(vec![0; len]),
+            modified: SystemTime::UNIX_EPOCH,
+        };
+        let mut cache = GzipCache::new(10);
+
+        cache.insert(PathBuf::from("a"), gzipped(4));
+        cache.insert(PathBuf::from("b"), gzipped(4));
+        cache.insert(PathBuf::from("c"), gzipped(4));
+        assert!(cache
+            .get(&PathBuf::from("a"), SystemTime::UNIX_EPOCH)
+            .is_none());
+        assert!(cache
+            .get(&PathBuf::from("b"), SystemTime::UNIX_EPOCH)
+            .is_some());
+        assert!(cache
+            .get(&PathBuf::from("c"), SystemTime::UNIX_EPOCH)
+            .is_some());
+        assert_eq!(cache.size, 8);
+
+        // Files that do not fit are not kept.
+        cache.insert(PathBuf::from("d"), gzipped(11));
+        assert!(cache
+            .get(&PathBuf::from("d"), SystemTime::UNIX_EPOCH)
+            .is_none());
+        assert_eq!(cache.size, 8);
+    }
+
+    #[test]
+    fn compressed_files() {
+        assert_eq!(
+            compressed_path(&PathBuf::from("static/app.js"), Encoding::Brotli),
+            PathBuf::from("static/app.js.br")
+        );
+
+        let data = b"annotation ".repeat(100);
+        assert!(gzip(&data).unwrap().len() < data.len());
+    }
+
+    #[test]
+    fn files_outside_dir_are_not_served() {
+        let serve_static = serve_static();
+        assert!(serve_static.file("/Cargo.toml").is_some());
+        assert!(serve_static.file("/src/../Cargo.toml").is_none());
+        assert!(serve_static.file("/%2E%2E/Cargo.toml").is_none());
+        assert!(serve_static.file("/app/some/route").is_none());
+    }
+
+    #[test]
+    fn files_are_served_below_prefix() {
+        let serve_static = ServeStatic {
+            prefix: "/app".to_string(),
+            ..serve_static()
+        };
+        assert!(serve_static.file("/app/Cargo.toml").is_some());
+        assert!(serve_static.file("/app/../Cargo.toml").is_none());
+        assert!(serve_static.file("/Cargo.toml").is_none());
+    }
+
+    #[test]
+    fn static_dirs_are_parsed() {
+        let api_routes = &["/annotations", "/health"];
+        assert_eq!(
+            parse_static_dirs(vec!["dist", "/app/=app/dist", "/ops=ops"], api_routes).unwrap(),
+            [
+                ("".to_string(), "dist"),
+                ("/app".to_string(), "app/dist"),
+                ("/ops".to_string(), "ops")
+            ]
+        );
+
+        assert!(parse_static_dirs(vec!["app=dist"], api_routes).is_err());
+        assert!(parse_static_dirs(vec!["/health=dist"], api_routes).is_err());
+        assert!(parse_static_dirs(vec!["/annotations/app=dist"], api_routes).is_err());
+        assert!(parse_static_dirs(vec!["/app=a", "/app/=b"], api_routes).is_err());
+        assert!(parse_static_dirs(vec!["/healthy=dist"], api_routes).is_ok());
+    }
+}